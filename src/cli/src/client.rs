@@ -0,0 +1,196 @@
+/*
+* Typed requests of the configuration protocol over a transport
+*/
+use kallisto_keymap::{decode_entry, encode_entry, Entry, KeyMap, ENTRY_LEN};
+
+use crate::device::Transport;
+use crate::protocol::{
+    request, response_data, Command, Error, KeyStats, KeyboardInfo, Result, Timing, PROTOCOL_VERSION,
+};
+
+pub struct Client<T: Transport> {
+    transport: T,
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(transport: T) -> Self {
+        Client { transport }
+    }
+
+    fn call(&mut self, command: Command, args: &[u8]) -> Result<[u8; 30]> {
+        let response = self.transport.request(&request(command, args))?;
+        response_data(command, &response)
+    }
+
+    pub fn info(&mut self) -> Result<KeyboardInfo> {
+        let info = KeyboardInfo::from_bytes(&self.call(Command::GetInfo, &[])?);
+        if info.protocol_version != PROTOCOL_VERSION {
+            return Err(Error::BadResponse);
+        }
+        Ok(info)
+    }
+
+    pub fn key_map_entry(&mut self, layer: u8, key: u8) -> Result<Option<Entry>> {
+        let data = self.call(Command::GetKeyMap, &[layer, key])?;
+        decode_entry(&data[2..2 + ENTRY_LEN]).ok_or(Error::BadResponse)
+    }
+
+    pub fn set_key_map_entry(&mut self, layer: u8, key: u8, entry: Option<Entry>) -> Result<()> {
+        let mut args = [0; 2 + ENTRY_LEN];
+        args[0] = layer;
+        args[1] = key;
+        args[2..].copy_from_slice(&encode_entry(entry));
+        self.call(Command::SetKeyMap, &args).map(|_| ())
+    }
+
+    pub fn timing(&mut self) -> Result<Timing> {
+        Ok(Timing::from_bytes(&self.call(Command::GetTiming, &[])?))
+    }
+
+    pub fn set_timing(&mut self, timing: &Timing) -> Result<Timing> {
+        Ok(Timing::from_bytes(&self.call(Command::SetTiming, &timing.to_bytes())?))
+    }
+
+    // Makes the changes survive a power cycle
+    pub fn commit(&mut self) -> Result<()> {
+        self.call(Command::Commit, &[]).map(|_| ())
+    }
+
+    pub fn key_stats(&mut self, key: u8) -> Result<KeyStats> {
+        let data = self.call(Command::GetKeyStats, &[key])?;
+        Ok(KeyStats::from_bytes(&data[1..]))
+    }
+
+    // The keyboard restarts after answering, so the connection is lost
+    pub fn enter_bootloader(&mut self) -> Result<()> {
+        self.call(Command::EnterBootloader, &[]).map(|_| ())
+    }
+
+    pub fn read_key_map(&mut self, info: &KeyboardInfo) -> Result<KeyMap> {
+        let mut key_map = KeyMap::new(info.n_keys as usize);
+        for (layer, entries) in key_map.layers.iter_mut().enumerate().take(info.n_layers as usize) {
+            for (key, entry) in entries.iter_mut().enumerate() {
+                *entry = self.key_map_entry(layer as u8, key as u8)?;
+            }
+        }
+        Ok(key_map)
+    }
+
+    // Writes the entries that differ from the keyboard's, then commits them
+    pub fn write_key_map(&mut self, info: &KeyboardInfo, key_map: &KeyMap) -> Result<usize> {
+        if key_map.n_keys() != info.n_keys as usize {
+            return Err(Error::KeyMap(format!("{} keys, the keyboard has {}", key_map.n_keys(), info.n_keys)));
+        }
+        let current = self.read_key_map(info)?;
+        let mut changed = 0;
+        for (layer, entries) in key_map.layers.iter().enumerate().take(info.n_layers as usize) {
+            for (key, entry) in entries.iter().enumerate() {
+                if current.layers[layer][key] != *entry {
+                    self.set_key_map_entry(layer as u8, key as u8, *entry)?;
+                    changed += 1;
+                }
+            }
+        }
+        self.commit()?;
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::protocol::Status;
+    use kallisto_keymap::{compile, keys::key_code, to_text, Mapping};
+
+    fn client() -> (Client<Emulator>, KeyboardInfo) {
+        let mut client = Client::new(Emulator::with_default_key_map());
+        let info = client.info().unwrap();
+        (client, info)
+    }
+
+    fn entry(key: &str) -> Option<Entry> {
+        Some(Entry {
+            pressed: Some(Mapping { key: key_code(key).unwrap(), modifier: 0 }),
+            held_press: None,
+        })
+    }
+
+    #[test]
+    fn dump_reads_the_key_map() {
+        let (mut client, info) = client();
+        assert_eq!(info.n_keys, 42);
+        let key_map = client.read_key_map(&info).unwrap();
+        assert_eq!(key_map, client.transport.key_map);
+
+        // Dumped as text, the key-map compiles back to the same one
+        let text = to_text(&key_map, info.matrix_rows as usize, info.matrix_cols as usize);
+        assert_eq!(compile(&text).unwrap().key_map, key_map);
+        assert_eq!(KeyMap::from_blob(&key_map.to_blob()).unwrap(), key_map);
+    }
+
+    #[test]
+    fn apply_writes_the_changed_keys_and_commits() {
+        let (mut client, info) = client();
+        let mut key_map = client.read_key_map(&info).unwrap();
+        key_map.layers[0][0] = entry("Z");
+        key_map.layers[1][41] = None;
+        key_map.layers[4][20] = Some(Entry {
+            pressed: entry("A").unwrap().pressed,
+            held_press: entry("B").unwrap().pressed,
+        });
+        let changed = [(0, 0), (1, 41), (4, 20)]
+            .iter()
+            .filter(|(layer, key)| client.transport.key_map.layers[*layer][*key] != key_map.layers[*layer][*key])
+            .count();
+
+        assert_eq!(client.write_key_map(&info, &key_map).unwrap(), changed);
+        assert_eq!(client.transport.key_map, key_map);
+        assert_eq!(client.transport.committed_key_map, key_map);
+        assert_eq!(client.read_key_map(&info).unwrap(), key_map);
+        // Nothing left to change
+        assert_eq!(client.write_key_map(&info, &key_map).unwrap(), 0);
+
+        let error = client.write_key_map(&info, &KeyMap::new(21)).unwrap_err();
+        assert!(matches!(error, Error::KeyMap(_)));
+    }
+
+    #[test]
+    fn changes_are_kept_on_commit() {
+        let (mut client, _) = client();
+        let committed = client.transport.committed_key_map.clone();
+        client.set_key_map_entry(2, 5, entry("Q")).unwrap();
+        assert_eq!(client.key_map_entry(2, 5).unwrap(), entry("Q"));
+        assert_eq!(client.transport.committed_key_map, committed);
+        client.commit().unwrap();
+        assert_eq!(client.transport.committed_key_map.layers[2][5], entry("Q"));
+
+        let error = client.set_key_map_entry(5, 0, None).unwrap_err();
+        assert!(matches!(error, Error::Status(Command::SetKeyMap, Status::OutOfRange)));
+        let error = client.key_map_entry(0, 42).unwrap_err();
+        assert!(matches!(error, Error::Status(Command::GetKeyMap, Status::OutOfRange)));
+    }
+
+    #[test]
+    fn timing_is_changed_and_committed() {
+        let (mut client, _) = client();
+        let committed = client.timing().unwrap();
+        let timing = Timing { debounce_us: 8000, settle_us: 12, anti_ghosting: false };
+        assert_eq!(client.set_timing(&timing).unwrap(), timing);
+        assert_eq!(client.timing().unwrap(), timing);
+        assert_eq!(client.transport.committed_timing, committed);
+        client.commit().unwrap();
+        assert_eq!(client.transport.committed_timing, timing);
+    }
+
+    #[test]
+    fn stats_are_kept_for_the_master_half() {
+        let (mut client, info) = client();
+        let answered: Vec<u8> = (0..info.n_keys).filter(|key| client.key_stats(*key).is_ok()).collect();
+        assert_eq!(answered, (21..42).collect::<Vec<u8>>());
+        let error = client.key_stats(0).unwrap_err();
+        assert!(matches!(error, Error::Status(Command::GetKeyStats, Status::OutOfRange)));
+        assert_eq!(client.key_stats(21).unwrap().min_press_us, u32::MAX);
+        assert_eq!(client.key_stats(22).unwrap().presses, 2200);
+    }
+}
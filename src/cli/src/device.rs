@@ -0,0 +1,75 @@
+/*
+* Transports carrying the configuration requests: the raw HID interface
+* of a connected keyboard, or the emulator in emulator.rs
+*/
+use std::ffi::CString;
+
+use hidapi::{HidApi, HidDevice};
+
+use crate::protocol::{Error, Result, PRODUCT_ID, REPORT_LEN, USAGE_PAGE, VENDOR_ID};
+
+// Time to wait for a response, Commit waits for the EEPROM to be written
+const RESPONSE_TIMEOUT_MS: i32 = 2000;
+
+pub trait Transport {
+    // Sends a request and returns the response to it
+    fn request(&mut self, report: &[u8; REPORT_LEN]) -> Result<[u8; REPORT_LEN]>;
+}
+
+fn hid_error(e: hidapi::HidError) -> Error {
+    Error::Hid(e.to_string())
+}
+
+// A configuration interface found on the bus
+pub struct DeviceEntry {
+    pub path: CString,
+    pub product: String,
+    pub serial: String,
+}
+
+pub fn list_devices(api: &HidApi) -> Vec<DeviceEntry> {
+    api.device_list()
+        .filter(|d| d.vendor_id() == VENDOR_ID && d.product_id() == PRODUCT_ID && d.usage_page() == USAGE_PAGE)
+        .map(|d| DeviceEntry {
+            path: d.path().to_owned(),
+            product: d.product_string().unwrap_or("").to_string(),
+            serial: d.serial_number().unwrap_or("").to_string(),
+        })
+        .collect()
+}
+
+pub struct HidTransport {
+    device: HidDevice,
+}
+
+impl HidTransport {
+    // Opens the keyboard at `path`, or the first one found
+    pub fn open(api: &HidApi, path: Option<&str>) -> Result<Self> {
+        let path = match path {
+            Some(path) => CString::new(path).map_err(|_| Error::NoDevice)?,
+            None => list_devices(api).into_iter().next().ok_or(Error::NoDevice)?.path,
+        };
+        let device = api.open_path(&path).map_err(hid_error)?;
+        Ok(HidTransport { device })
+    }
+}
+
+impl Transport for HidTransport {
+    fn request(&mut self, report: &[u8; REPORT_LEN]) -> Result<[u8; REPORT_LEN]> {
+        // The interface has no report ids, which is written as id 0
+        let mut buf = [0; REPORT_LEN + 1];
+        buf[1..].copy_from_slice(report);
+        self.device.write(&buf).map_err(hid_error)?;
+        // Skips responses left over from an earlier, abandoned request
+        loop {
+            let mut response = [0; REPORT_LEN];
+            let len = self.device.read_timeout(&mut response, RESPONSE_TIMEOUT_MS).map_err(hid_error)?;
+            if len == 0 {
+                return Err(Error::BadResponse);
+            }
+            if len == REPORT_LEN && response[0] == report[0] {
+                return Ok(response);
+            }
+        }
+    }
+}
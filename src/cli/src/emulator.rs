@@ -0,0 +1,152 @@
+/*
+* In-process stand-in for the keyboard, answering requests the way the
+* configuration handler of the master half does, see firmware/src/master.rs.
+* Lets the client be exercised without a keyboard attached. Changes are
+* live at once and only become the committed state on Commit, like the
+* key-map in RAM and in the EEPROM
+*/
+use kallisto_keymap::{compile, decode_entry, encode_entry, KeyMap, ENTRY_LEN, N_LAYERS};
+
+use crate::device::Transport;
+use crate::protocol::{Command, KeyStats, KeyboardInfo, Result, Status, Timing, PROTOCOL_VERSION, REPORT_LEN};
+
+const MATRIX_ROWS: u8 = 3;
+const MATRIX_COLS: u8 = 7;
+// Both halves
+const N_KEYS: usize = 2 * (MATRIX_ROWS as usize) * (MATRIX_COLS as usize);
+// Statistics are only kept for the keys of the master half, taken to be
+// the right one, numbered after those of the left half
+const MASTER_KEYS: std::ops::Range<usize> = N_KEYS / 2..N_KEYS;
+const N_PROFILES: u8 = 4;
+const FIRMWARE_VERSION: u32 = 0x00_01_00;
+
+pub struct Emulator {
+    pub key_map: KeyMap,
+    pub committed_key_map: KeyMap,
+    pub timing: Timing,
+    pub committed_timing: Timing,
+    // Statistics of each key, None for the keys of the slave half
+    pub stats: Vec<Option<KeyStats>>,
+    // Set once EnterBootloader has been answered
+    pub in_bootloader: bool,
+}
+
+impl Emulator {
+    pub fn new(key_map: KeyMap) -> Self {
+        let timing = Timing {
+            debounce_us: 5000,
+            settle_us: 5,
+            anti_ghosting: true,
+        };
+        let stats = (0..N_KEYS)
+            .map(|key| {
+                let i = key as u32;
+                MASTER_KEYS.contains(&key).then_some(KeyStats {
+                    presses: 100 * i,
+                    chatter: i % 3,
+                    min_press_us: if key == MASTER_KEYS.start { u32::MAX } else { 20_000 + 1000 * i },
+                })
+            })
+            .collect();
+        Emulator {
+            committed_key_map: key_map.clone(),
+            key_map,
+            committed_timing: timing,
+            timing,
+            stats,
+            in_bootloader: false,
+        }
+    }
+
+    // Starts from the key-map compiled into the firmware
+    pub fn with_default_key_map() -> Self {
+        let key_map = compile(include_str!("../../firmware/key_map.toml")).expect("bad default key-map");
+        assert_eq!(key_map.key_map.n_keys(), N_KEYS);
+        Emulator::new(key_map.key_map)
+    }
+
+    fn info(&self) -> KeyboardInfo {
+        KeyboardInfo {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: FIRMWARE_VERSION,
+            n_layers: N_LAYERS as u8,
+            matrix_rows: MATRIX_ROWS,
+            matrix_cols: MATRIX_COLS,
+            n_keys: N_KEYS as u8,
+            n_profiles: N_PROFILES,
+            active_profile: 0,
+            extra_buttons: 4,
+            has_encoder: true,
+        }
+    }
+
+    // Returns the status and fills in the data of the response
+    fn handle(&mut self, command: Option<Command>, args: &[u8], data: &mut [u8]) -> Status {
+        let in_range = |layer: u8, key: u8| (layer as usize) < N_LAYERS && (key as usize) < N_KEYS;
+        match command {
+            None => Status::UnknownCommand,
+            Some(Command::GetInfo) => {
+                data[..13].copy_from_slice(&self.info().to_bytes());
+                Status::Ok
+            }
+            Some(Command::GetKeyMap) => {
+                let (layer, key) = (args[0], args[1]);
+                if !in_range(layer, key) {
+                    return Status::OutOfRange;
+                }
+                data[0] = layer;
+                data[1] = key;
+                data[2..2 + ENTRY_LEN].copy_from_slice(&encode_entry(self.key_map.layers[layer as usize][key as usize]));
+                Status::Ok
+            }
+            Some(Command::SetKeyMap) => {
+                let (layer, key) = (args[0], args[1]);
+                let Some(entry) = decode_entry(&args[2..2 + ENTRY_LEN]) else {
+                    return Status::BadRequest;
+                };
+                if !in_range(layer, key) {
+                    return Status::OutOfRange;
+                }
+                self.key_map.layers[layer as usize][key as usize] = entry;
+                data[..2 + ENTRY_LEN].copy_from_slice(&args[..2 + ENTRY_LEN]);
+                Status::Ok
+            }
+            Some(Command::GetTiming) => {
+                data[..9].copy_from_slice(&self.timing.to_bytes());
+                Status::Ok
+            }
+            Some(Command::SetTiming) => {
+                self.timing = Timing::from_bytes(args);
+                data[..9].copy_from_slice(&self.timing.to_bytes());
+                Status::Ok
+            }
+            Some(Command::Commit) => {
+                self.committed_key_map = self.key_map.clone();
+                self.committed_timing = self.timing;
+                Status::Ok
+            }
+            Some(Command::GetKeyStats) => match self.stats.get(args[0] as usize).copied().flatten() {
+                Some(stats) => {
+                    data[0] = args[0];
+                    data[1..13].copy_from_slice(&stats.to_bytes());
+                    Status::Ok
+                }
+                None => Status::OutOfRange,
+            },
+            Some(Command::EnterBootloader) => {
+                self.in_bootloader = true;
+                Status::Ok
+            }
+        }
+    }
+}
+
+impl Transport for Emulator {
+    fn request(&mut self, report: &[u8; REPORT_LEN]) -> Result<[u8; REPORT_LEN]> {
+        let mut response = [0; REPORT_LEN];
+        response[0] = report[0];
+        let status = self.handle(Command::from_u8(report[0]), &report[1..], &mut response[2..]);
+        response[1] = status as u8;
+        Ok(response)
+    }
+}
@@ -0,0 +1,263 @@
+/*
+* Command line tool for the configuration interface of the keyboard
+*
+*   kallisto-cli [--emulator] [--device PATH] COMMAND
+*
+*   list                          the keyboards connected
+*   info                          firmware version and key-map size
+*   dump [FILE]                   print the key-map, or save it to FILE
+*   apply FILE                    write a key-map to the keyboard
+*   compile FILE OUT              compile a key-map into the stored format
+*   timing [DEBOUNCE_US SETTLE_US [on|off]]
+*                                 print or change the matrix timing, and
+*                                 turn anti-ghosting on or off
+*   stats                         switch statistics of the master half
+*   bootloader                    restart in the USB boot mode
+*
+* Key-map files ending in .toml are in the text format, see the
+* kallisto-keymap crate, others in the format stored by the firmware.
+*
+* --emulator talks to an emulated keyboard instead, see emulator.rs
+*/
+mod client;
+mod device;
+mod emulator;
+mod protocol;
+
+use std::path::Path;
+use std::process::ExitCode;
+
+use hidapi::HidApi;
+use kallisto_keymap::{compile, to_text, KeyMap};
+
+use client::Client;
+use device::{list_devices, HidTransport, Transport};
+use emulator::Emulator;
+use protocol::{Error, KeyboardInfo, Result, Timing};
+
+const USAGE: &str = "usage: kallisto-cli [--emulator] [--device PATH] \
+                     list|info|dump [FILE]|apply FILE|compile FILE OUT|timing [DEBOUNCE_US SETTLE_US [on|off]]|stats|bootloader";
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let emulator = take_flag(&mut args, "--emulator");
+    let device = take_option(&mut args, "--device");
+    let Some(command) = args.first().cloned() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    let result = if command == "list" {
+        list()
+    } else if command == "compile" {
+        match &args[1..] {
+            [file, out] => compile_file(file, out),
+            _ => Err(Error::Usage),
+        }
+    } else if emulator {
+        run(Client::new(Emulator::with_default_key_map()), &command, &args[1..])
+    } else {
+        HidApi::new()
+            .map_err(|e| Error::Hid(e.to_string()))
+            .and_then(|api| HidTransport::open(&api, device.as_deref()))
+            .and_then(|transport| run(Client::new(transport), &command, &args[1..]))
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::Usage) => {
+            eprintln!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let found = args.iter().any(|a| a == flag);
+    args.retain(|a| a != flag);
+    found
+}
+
+fn take_option(args: &mut Vec<String>, option: &str) -> Option<String> {
+    let i = args.iter().position(|a| a == option)?;
+    args.remove(i);
+    (i < args.len()).then(|| args.remove(i))
+}
+
+fn list() -> Result<()> {
+    let api = HidApi::new().map_err(|e| Error::Hid(e.to_string()))?;
+    let devices = list_devices(&api);
+    if devices.is_empty() {
+        println!("no keyboards found");
+    }
+    for device in devices {
+        println!("{}  {} {}", device.path.to_string_lossy(), device.product, device.serial);
+    }
+    Ok(())
+}
+
+fn run<T: Transport>(mut client: Client<T>, command: &str, args: &[String]) -> Result<()> {
+    let info = client.info()?;
+    match (command, args) {
+        ("info", []) => {
+            let v = info.firmware_version;
+            println!("firmware   {}.{}.{}", (v >> 16) & 0xFF, (v >> 8) & 0xFF, v & 0xFF);
+            println!("layers     {}", info.n_layers);
+            println!("matrix     {} x {} per half", info.matrix_rows, info.matrix_cols);
+            println!("keys       {}", info.n_keys);
+            println!("buttons    {}{}", info.extra_buttons, if info.has_encoder { " and an encoder" } else { "" });
+            println!("profile    {} of {}", info.active_profile, info.n_profiles);
+        }
+        ("dump", []) => {
+            let key_map = client.read_key_map(&info)?;
+            print!("{}", to_text(&key_map, info.matrix_rows as usize, info.matrix_cols as usize));
+        }
+        ("dump", [file]) => {
+            let key_map = client.read_key_map(&info)?;
+            let contents = if is_text(file) {
+                to_text(&key_map, info.matrix_rows as usize, info.matrix_cols as usize).into_bytes()
+            } else {
+                key_map.to_blob()
+            };
+            std::fs::write(file, contents).map_err(|e| file_error(file, e))?;
+        }
+        ("apply", [file]) => {
+            let key_map = read_key_map(file, Some(&info))?;
+            let changed = client.write_key_map(&info, &key_map)?;
+            println!("{} keys changed", changed);
+        }
+        ("timing", []) => print_timing(&client.timing()?),
+        ("timing", [debounce_us, settle_us, anti_ghosting @ ..]) if anti_ghosting.len() <= 1 => {
+            let timing = Timing {
+                debounce_us: parse_us(debounce_us)?,
+                settle_us: parse_us(settle_us)?,
+                // Left as it is unless given
+                anti_ghosting: match anti_ghosting.first() {
+                    Some(arg) => parse_on_off(arg)?,
+                    None => client.timing()?.anti_ghosting,
+                },
+            };
+            print_timing(&client.set_timing(&timing)?);
+            client.commit()?;
+        }
+        ("stats", []) => {
+            println!("key  presses  chatter  shortest press");
+            // The master half answers for its own keys only
+            for key in 0..info.n_keys {
+                let stats = match client.key_stats(key) {
+                    Err(Error::Status(_, protocol::Status::OutOfRange)) => continue,
+                    stats => stats?,
+                };
+                let min_press = match stats.min_press_us {
+                    u32::MAX => "-".to_string(),
+                    us => format!("{} us", us),
+                };
+                println!("{:3}  {:7}  {:7}  {}", key, stats.presses, stats.chatter, min_press);
+            }
+        }
+        ("bootloader", []) => {
+            client.enter_bootloader()?;
+            println!("the keyboard is restarting in the USB boot mode");
+        }
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+fn parse_us(arg: &str) -> Result<u32> {
+    arg.parse().map_err(|_| Error::Usage)
+}
+
+fn parse_on_off(arg: &str) -> Result<bool> {
+    match arg {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(Error::Usage),
+    }
+}
+
+fn print_timing(timing: &Timing) {
+    println!("debounce   {} us", timing.debounce_us);
+    println!("settle     {} us", timing.settle_us);
+    println!("anti-ghosting {}", if timing.anti_ghosting { "on" } else { "off" });
+}
+
+fn is_text(file: &str) -> bool {
+    Path::new(file).extension().is_some_and(|ext| ext == "toml")
+}
+
+fn file_error(file: &str, e: std::io::Error) -> Error {
+    Error::KeyMap(format!("{}: {}", file, e))
+}
+
+// Reads a key-map file, checking that a text key-map was written for the keyboard
+fn read_key_map(file: &str, info: Option<&KeyboardInfo>) -> Result<KeyMap> {
+    if !is_text(file) {
+        let blob = std::fs::read(file).map_err(|e| file_error(file, e))?;
+        return KeyMap::from_blob(&blob).map_err(|e| Error::KeyMap(format!("{}: {}", file, e)));
+    }
+    let source = std::fs::read_to_string(file).map_err(|e| file_error(file, e))?;
+    let compiled = compile(&source).map_err(|e| Error::KeyMap(format!("{}:{}", file, e)))?;
+    if let Some(info) = info {
+        if (compiled.rows, compiled.columns) != (info.matrix_rows as usize, info.matrix_cols as usize) {
+            return Err(Error::KeyMap(format!(
+                "{}: written for {} x {} keys per half, the keyboard has {} x {}",
+                file, compiled.rows, compiled.columns, info.matrix_rows, info.matrix_cols
+            )));
+        }
+    }
+    Ok(compiled.key_map)
+}
+
+fn compile_file(file: &str, out: &str) -> Result<()> {
+    let key_map = read_key_map(file, None)?;
+    std::fs::write(out, key_map.to_blob()).map_err(|e| file_error(out, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_emulated(command: &str, args: &[&str]) -> Result<()> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        run(Client::new(Emulator::with_default_key_map()), command, &args)
+    }
+
+    fn temp_file(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("kallisto-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn dumped_key_maps_are_read_back() {
+        let key_map = Emulator::with_default_key_map().key_map;
+        for name in ["dump.toml", "dump.bin"] {
+            let file = temp_file(name);
+            run_emulated("dump", &[&file]).unwrap();
+            assert_eq!(read_key_map(&file, None).unwrap(), key_map);
+            run_emulated("apply", &[&file]).unwrap();
+        }
+    }
+
+    #[test]
+    fn key_maps_for_other_matrices_are_not_applied() {
+        let file = temp_file("small.toml");
+        std::fs::write(&file, "rows = 1\ncolumns = 1\n\n[[layers]]\nname = \"base\"\nkeys = [\"A | B\"]\n").unwrap();
+        let error = run_emulated("apply", &[&file]).unwrap_err();
+        assert!(matches!(&error, Error::KeyMap(e) if e.contains("written for 1 x 1 keys")), "{}", error);
+    }
+
+    #[test]
+    fn timing_arguments_are_checked() {
+        run_emulated("timing", &[]).unwrap();
+        run_emulated("timing", &["8000", "12"]).unwrap();
+        run_emulated("timing", &["8000", "12", "off"]).unwrap();
+        assert!(matches!(run_emulated("timing", &["8000", "fast"]), Err(Error::Usage)));
+        assert!(matches!(run_emulated("timing", &["8000", "12", "maybe"]), Err(Error::Usage)));
+        assert!(matches!(run_emulated("timing", &["8000", "12", "on", "on"]), Err(Error::Usage)));
+        assert!(matches!(run_emulated("timing", &["8000"]), Err(Error::Usage)));
+    }
+}
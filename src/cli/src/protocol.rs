@@ -0,0 +1,160 @@
+/*
+* Host side of the configuration protocol of the keyboard, see
+* components/src/config.rs. The command ids and statuses are shared with
+* the firmware, the components crate itself only builds for the RP2040,
+* so the layout of the data is repeated here
+*/
+use std::fmt;
+
+pub use kallisto_config_protocol::{
+    Command, Status, PRODUCT_ID, PROTOCOL_VERSION, REPORT_LEN, USAGE_PAGE, VENDOR_ID,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Hid(String),
+    // The keyboard answered with an error status
+    Status(Command, Status),
+    // No answer, or one that doesn't follow the protocol
+    BadResponse,
+    NoDevice,
+    // A malformed key-map, or one that doesn't fit the keyboard
+    KeyMap(String),
+    // Bad command line arguments
+    Usage,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Hid(e) => write!(f, "HID error: {}", e),
+            Error::Status(command, status) => write!(f, "{:?} failed: {:?}", command, status),
+            Error::BadResponse => write!(f, "unexpected response from the keyboard"),
+            Error::NoDevice => write!(f, "no keyboard found"),
+            Error::KeyMap(e) => write!(f, "bad key-map: {}", e),
+            Error::Usage => write!(f, "bad arguments"),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub fn request(command: Command, args: &[u8]) -> [u8; REPORT_LEN] {
+    let mut report = [0; REPORT_LEN];
+    report[0] = command as u8;
+    report[1..1 + args.len()].copy_from_slice(args);
+    report
+}
+
+// Checks a response to `command`, returning its data
+pub fn response_data(command: Command, report: &[u8; REPORT_LEN]) -> Result<[u8; REPORT_LEN - 2]> {
+    if report[0] != command as u8 {
+        return Err(Error::BadResponse);
+    }
+    match Status::from_u8(report[1]) {
+        Some(Status::Ok) => Ok(report[2..].try_into().unwrap()),
+        Some(status) => Err(Error::Status(command, status)),
+        None => Err(Error::BadResponse),
+    }
+}
+
+pub fn be_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyboardInfo {
+    pub protocol_version: u8,
+    pub firmware_version: u32,
+    pub n_layers: u8,
+    pub matrix_rows: u8,
+    pub matrix_cols: u8,
+    pub n_keys: u8,
+    pub n_profiles: u8,
+    pub active_profile: u8,
+    pub extra_buttons: u8,
+    pub has_encoder: bool,
+}
+
+impl KeyboardInfo {
+    pub fn to_bytes(self) -> [u8; 13] {
+        let mut buf = [0; 13];
+        buf[0] = self.protocol_version;
+        buf[1..5].copy_from_slice(&self.firmware_version.to_be_bytes());
+        buf[5] = self.n_layers;
+        buf[6] = self.matrix_rows;
+        buf[7] = self.matrix_cols;
+        buf[8] = self.n_keys;
+        buf[9] = self.n_profiles;
+        buf[10] = self.active_profile;
+        buf[11] = self.extra_buttons;
+        buf[12] = self.has_encoder as u8;
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        KeyboardInfo {
+            protocol_version: buf[0],
+            firmware_version: be_u32(&buf[1..5]),
+            n_layers: buf[5],
+            matrix_rows: buf[6],
+            matrix_cols: buf[7],
+            n_keys: buf[8],
+            n_profiles: buf[9],
+            active_profile: buf[10],
+            extra_buttons: buf[11],
+            has_encoder: buf[12] != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    pub debounce_us: u32,
+    pub settle_us: u32,
+    pub anti_ghosting: bool,
+}
+
+impl Timing {
+    pub fn to_bytes(self) -> [u8; 9] {
+        let mut buf = [0; 9];
+        buf[0..4].copy_from_slice(&self.debounce_us.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.settle_us.to_be_bytes());
+        buf[8] = self.anti_ghosting as u8;
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        Timing {
+            debounce_us: be_u32(&buf[0..4]),
+            settle_us: be_u32(&buf[4..8]),
+            anti_ghosting: buf[8] != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyStats {
+    pub presses: u32,
+    pub chatter: u32,
+    // u32::MAX if the key has never been released
+    pub min_press_us: u32,
+}
+
+impl KeyStats {
+    pub fn to_bytes(self) -> [u8; 12] {
+        let mut buf = [0; 12];
+        buf[0..4].copy_from_slice(&self.presses.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.chatter.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.min_press_us.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        KeyStats {
+            presses: be_u32(&buf[0..4]),
+            chatter: be_u32(&buf[4..8]),
+            min_press_us: be_u32(&buf[8..12]),
+        }
+    }
+}
@@ -1,133 +1,851 @@
 /*
-* Code fore interacting with the AT24C256C and AT24C128C flash memory chips
+* Code fore interacting with the AT24C32 to AT24CM01 EEPROM chips.
+*
+* The chips are addressed as 0b1010 followed by the levels of their A2,
+* A1 and A0 pins, so that up to eight can share a bus. The AT24CM01 has
+* a 17-bit memory address, the top bit of which takes the place of A0 in
+* the device address, so only A2 and A1 can be strapped on it
 */
-use embedded_hal::blocking::i2c::{Write, Read};
+use embedded_hal::blocking::i2c::{Read, Write};
+use embedded_storage::nor_flash::{
+    ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use embedded_storage::{ReadStorage, Storage};
 use rp_pico::hal;
 
 const DEVICE_ADDRESS: u8 = 0b1010000;
 
-pub enum I2cError {
-    ReadError,
-    WriteError,
+// I2C errors that can tell a device that did not acknowledge its
+// address, i.e. one that is missing, apart from other failures
+pub trait BusError: core::fmt::Debug {
+    fn is_address_nack(&self) -> bool;
 }
 
-pub enum At24cError {
-    I2cError(I2cError),
-    // Raised when a a buffer larger than 64 bytes is written
-    InvalidSize,
+// Bit of the abort source of the RP2040 I2C controller
+// set when a 7-bit address is not acknowledged
+const ABRT_7B_ADDR_NOACK: u32 = 0x1;
+
+impl BusError for hal::i2c::Error {
+    fn is_address_nack(&self) -> bool {
+        matches!(self, hal::i2c::Error::Abort(reason) if reason & ABRT_7B_ADDR_NOACK != 0)
+    }
+}
+
+// Time source for the write cycle timeout, the timer of the RP2040 on the board
+pub trait Clock {
+    // Time in microseconds, wrapping around
+    fn now_us(&self) -> u32;
+}
+
+impl Clock for hal::Timer {
+    fn now_us(&self) -> u32 {
+        self.get_counter_low()
+    }
+}
+
+// Errors of the driver, generic over the error type of the bus
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum At24cError<E> {
+    // The device did not acknowledge its address, it is not
+    // fitted, not powered or strapped to another address
+    NotPresent,
+    // Any other failure of a read or write transfer on the bus
+    Read(E),
+    Write(E),
+    // Raised when an access extends past the end of the memory
     AddressOutOfRange,
+    // Raised when a write is started while one started
+    // with `start_write` has not yet completed
+    Busy,
+    // Raised when an erase does not cover whole erase blocks
+    NotAligned,
 }
 
-impl Into<At24cError> for I2cError {
-    fn into(self) -> At24cError {
-        At24cError::I2cError(self)
+impl<E: BusError> At24cError<E> {
+    fn read(error: E) -> Self {
+        if error.is_address_nack() {
+            At24cError::NotPresent
+        } else {
+            At24cError::Read(error)
+        }
+    }
+
+    fn write(error: E) -> Self {
+        if error.is_address_nack() {
+            At24cError::NotPresent
+        } else {
+            At24cError::Write(error)
+        }
     }
 }
 
+impl<E: core::fmt::Debug> core::fmt::Display for At24cError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            At24cError::NotPresent => write!(f, "EEPROM not present"),
+            At24cError::Read(e) => write!(f, "EEPROM read failed: {:?}", e),
+            At24cError::Write(e) => write!(f, "EEPROM write failed: {:?}", e),
+            At24cError::AddressOutOfRange => write!(f, "EEPROM address out of range"),
+            At24cError::Busy => write!(f, "EEPROM busy with another write"),
+            At24cError::NotAligned => write!(f, "EEPROM erase not aligned"),
+        }
+    }
+}
+
+// The size of the chip in Kbit, AT24C32 to AT24CM01
 #[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum At24cMemSize {
+    Kb32 = 32,
+    Kb64 = 64,
     Kb128 = 128,
     Kb256 = 256,
+    Kb512 = 512,
+    Mb1 = 1024,
+}
+
+impl At24cMemSize {
+    // Size of the memory in bytes
+    pub fn bytes(&self) -> usize {
+        *self as usize * 128
+    }
+
+    // Writes are done one page at a time, a write that reaches the end
+    // of a page wraps around to the start of the same page
+    pub fn page_size(&self) -> usize {
+        match self {
+            At24cMemSize::Kb32 | At24cMemSize::Kb64 => 32,
+            At24cMemSize::Kb128 | At24cMemSize::Kb256 => 64,
+            At24cMemSize::Kb512 => 128,
+            At24cMemSize::Mb1 => 256,
+        }
+    }
+
+    // Maximum write cycle time, after which the device is assumed
+    // to be done even if it has not acknowledged a poll
+    pub fn write_cycle_us(&self) -> u32 {
+        match self {
+            At24cMemSize::Kb32 | At24cMemSize::Kb64 => 10_000,
+            _ => 5_000,
+        }
+    }
+}
+
+// The largest page size of the family
+pub const MAX_PAGE_SIZE: usize = 256;
+// Erases, which the chips don't have, are emulated in blocks of
+// this size for the NOR flash traits, each taking one or more page writes
+pub const ERASE_SIZE: usize = 64;
+const ADDRESS_LEN: usize = 2;
+
+// Progress of a write started with `start_write`
+struct PendingWrite {
+    address: u32,
+    written: usize,
 }
 
-pub struct At24c<'t, T> {
+pub struct At24c<'t, T, C = hal::Timer> {
     size: At24cMemSize,
+    // Device address with the A2..A0 straps
+    device_address: u8,
     i2c: T,
-    tx_buffer: [u8; 66],
+    tx_buffer: [u8; ADDRESS_LEN + MAX_PAGE_SIZE],
     last_write: u32,
-    timer: &'t hal::Timer,
+    // Set from the end of a page write until the device acknowledges again
+    write_cycle: bool,
+    pending: Option<PendingWrite>,
+    timer: &'t C,
 }
 
-impl<'t, T: Read + Write> At24c<'t, T> {
-    pub fn new(i2c: T, size: At24cMemSize, timer: &'t hal::Timer) -> At24c<'t, T> {
+impl<'t, T, E, C> At24c<'t, T, C>
+where
+    T: Read<Error = E> + Write<Error = E>,
+    E: BusError,
+    C: Clock,
+{
+    // `straps` holds the levels of the A2, A1 and A0 pins in its lowest
+    // three bits. On the AT24CM01 the A0 bit is ignored
+    pub fn new(i2c: T, size: At24cMemSize, straps: u8, timer: &'t C) -> At24c<'t, T, C> {
+        let straps = match size {
+            At24cMemSize::Mb1 => straps & 0b110,
+            _ => straps & 0b111,
+        };
         At24c {
             size,
+            device_address: DEVICE_ADDRESS | straps,
             i2c,
             timer,
-            tx_buffer: [0; 66],
-            last_write: 0
+            tx_buffer: [0; ADDRESS_LEN + MAX_PAGE_SIZE],
+            last_write: 0,
+            write_cycle: false,
+            pending: None,
         }
     }
 
+    pub fn capacity(&self) -> usize {
+        self.size.bytes()
+    }
 
-    // The AT24C* supports sequential reads of up to 64 bytes during at once
-    pub fn read(&mut self, address: u16, buf: &mut [u8]) -> Result<(), At24cError> {
-        // Check address and page boundries
-        let _ = self.is_address_valid(address)?;
-        if buf.len() > 64 {
-            return Err(At24cError::InvalidSize);
+    // Checks that the device acknowledges its address, so that
+    // the firmware can carry on without it if it is missing
+    pub fn is_present(&mut self) -> bool {
+        self.wait_write_cycle();
+        match self.i2c.read(self.device_address, &mut [0]) {
+            Err(e) => !e.is_address_nack(),
+            Ok(..) => true,
         }
+    }
 
-        let addrs_hi = ((address & 0xFF00) >> 8) as u8;
-        let addrs_lo = (address & 0x00FF) as u8;
+    // Reads any number of bytes. Unlike writes, reads are not limited
+    // to a page, but on the AT24CM01 a read is split where the top
+    // address bit, which is part of the device address, changes
+    pub fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), At24cError<E>> {
+        self.check_range(address, buf.len())?;
         self.wait_write_cycle();
-        match self.i2c.write(DEVICE_ADDRESS, &[addrs_hi, addrs_lo]) {
-            Ok(..) => {},
-            Err(_) => return Err(I2cError::WriteError.into()), 
+
+        let mut read = 0;
+        while read < buf.len() {
+            let block_address = address as usize + read;
+            let len = (buf.len() - read).min(0x1_0000 - block_address % 0x1_0000);
+            let (device_address, word_address) = self.split_address(block_address as u32);
+            // The address is sent as part of the read, so a failure is a read error
+            match self.i2c.write(device_address, &word_address) {
+                Ok(..) => {}
+                Err(e) => return Err(At24cError::read(e)),
+            }
+            match self.i2c.read(device_address, &mut buf[read..read + len]) {
+                Ok(..) => {}
+                Err(e) => return Err(At24cError::read(e)),
+            }
+            read += len;
+        }
+        Ok(())
+    }
+
+    // Writes any number of bytes, split into one page write per page
+    // touched, each of which takes a write cycle. Returns once the last
+    // page has been sent, without waiting for its write cycle
+    pub fn write(&mut self, address: u32, buf: &[u8]) -> Result<(), At24cError<E>> {
+        self.check_range(address, buf.len())?;
+        if self.pending.is_some() {
+            return Err(At24cError::Busy);
+        }
+
+        let mut written = 0;
+        while written < buf.len() {
+            self.wait_write_cycle();
+            written += self.write_next_page(address, written, buf)?;
         }
-        match self.i2c.read(DEVICE_ADDRESS, buf) {
-            Ok(..) => {},
-            Err(_) => return Err(I2cError::ReadError.into()), 
+        Ok(())
+    }
+
+    // Starts a write of any number of bytes, which is then carried out
+    // by calling `poll` with the same buffer until it returns Ok. This
+    // lets writes be interleaved with other work, only a page is sent
+    // per call and the write cycles are waited for without blocking
+    pub fn start_write(&mut self, address: u32, buf: &[u8]) -> Result<(), At24cError<E>> {
+        self.check_range(address, buf.len())?;
+        if self.pending.is_some() {
+            return Err(At24cError::Busy);
         }
+        self.pending = Some(PendingWrite {
+            address,
+            written: 0,
+        });
         Ok(())
     }
 
-    // The AT24C* support page writes of up to 64 bytes during
-    // a single write cycle
-    pub fn write(&mut self, address: u16, buf: &[u8]) -> Result<(), At24cError> {
-        // Check address and page boundries
-        let _ = self.is_address_valid(address)?;
-        if buf.len() > 64 {
-            return Err(At24cError::InvalidSize);
+    // Advances a write started with `start_write`. Returns WouldBlock
+    // until the whole buffer has been written and the device has finished
+    // its last write cycle. On an error the write is abandoned
+    pub fn poll(&mut self, buf: &[u8]) -> nb::Result<(), At24cError<E>> {
+        if !self.is_write_cycle_done() {
+            return Err(nb::Error::WouldBlock);
+        }
+        let (address, written) = match &self.pending {
+            Some(pending) => (pending.address, pending.written),
+            None => return Ok(()),
+        };
+        if written >= buf.len() {
+            self.pending = None;
+            return Ok(());
         }
+        match self.write_next_page(address, written, buf) {
+            Ok(len) => {
+                if let Some(pending) = self.pending.as_mut() {
+                    pending.written += len;
+                }
+                Err(nb::Error::WouldBlock)
+            }
+            Err(e) => {
+                self.pending = None;
+                Err(nb::Error::Other(e))
+            }
+        }
+    }
 
-        buf.iter().enumerate().for_each(|(i, b)| self.tx_buffer[2 + i] = *b);
+    // Writes the bytes of `buf` from `written` up to the end of the
+    // page they start in, returns the number of bytes written
+    fn write_next_page(
+        &mut self,
+        address: u32,
+        written: usize,
+        buf: &[u8],
+    ) -> Result<usize, At24cError<E>> {
+        let page_size = self.size.page_size();
+        let page_address = address as usize + written;
+        let len = (buf.len() - written).min(page_size - page_address % page_size);
+        let (device_address, word_address) = self.split_address(page_address as u32);
+        self.tx_buffer[..ADDRESS_LEN].copy_from_slice(&word_address);
+        self.tx_buffer[ADDRESS_LEN..ADDRESS_LEN + len]
+            .copy_from_slice(&buf[written..written + len]);
 
-        let addrs_hi = ((address & 0xFF00) >> 8) as u8;
-        let addrs_lo = (address & 0x00FF) as u8;
-        self.tx_buffer[0] = addrs_hi;
-        self.tx_buffer[1] = addrs_lo;
+        match self
+            .i2c
+            .write(device_address, &self.tx_buffer[..ADDRESS_LEN + len])
+        {
+            Ok(..) => {}
+            Err(e) => return Err(At24cError::write(e)),
+        }
+        self.last_write = self.timer.now_us();
+        self.write_cycle = true;
+        Ok(len)
+    }
 
-        self.wait_write_cycle();
-        match self.i2c.write(DEVICE_ADDRESS, &self.tx_buffer) {
-            Ok(..) => {},
-            Err(_) => return Err(I2cError::WriteError.into()), 
+    // After a write the device runs an internal write cycle, during which
+    // it does not acknowledge its address. It is polled with a one byte
+    // read until it acknowledges, or the maximum write cycle time of the
+    // chip has passed
+    fn is_write_cycle_done(&mut self) -> bool {
+        if !self.write_cycle {
+            return true;
+        }
+        if self.i2c.read(self.device_address, &mut [0]).is_ok()
+            || self.timer.now_us().wrapping_sub(self.last_write) >= self.size.write_cycle_us()
+        {
+            self.write_cycle = false;
+        }
+        !self.write_cycle
+    }
+
+    // Blocks until the write cycle of the last write, if any, has completed
+    pub fn wait_write_cycle(&mut self) {
+        while !self.is_write_cycle_done() {
+            core::hint::spin_loop();
+        }
+    }
+
+    // Checks that an access of `len` bytes starting at the
+    // address lies within the address range of the device
+    fn check_range(&self, address: u32, len: usize) -> Result<(), At24cError<E>> {
+        if address as usize + len > self.capacity() {
+            return Err(At24cError::AddressOutOfRange);
         }
-        self.last_write = self.timer.get_counter_low();
-        self.clear_tx_buffer();
         Ok(())
     }
 
-    // The AT24C256C and AT24C128C have a maximum read cycle time of 5ms
-    // after a write the internal write cycle time starts and you are not
-    // allowed to read/write to the device during this time
-    // If the last write happened later than 5ms ago this function blocks
-    // until 5ms have passed
-    pub fn wait_write_cycle(&self) {
-        while self.timer.get_counter_low() - self.last_write < 5000 {
-            cortex_m::asm::nop(); 
+    // Splits a memory address into the device address, which holds
+    // bit 16 on the AT24CM01, and the two byte word address
+    fn split_address(&self, address: u32) -> (u8, [u8; ADDRESS_LEN]) {
+        let word_address = [(address >> 8) as u8, address as u8];
+        (
+            self.device_address | ((address >> 16) & 0x1) as u8,
+            word_address,
+        )
+    }
+}
+
+// Byte addressed storage, writes replace the stored bytes
+impl<'t, T, E, C> ReadStorage for At24c<'t, T, C>
+where
+    T: Read<Error = E> + Write<Error = E>,
+    E: BusError,
+    C: Clock,
+{
+    type Error = At24cError<E>;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), At24cError<E>> {
+        At24c::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        At24c::capacity(self)
+    }
+}
+
+impl<'t, T, E, C> Storage for At24c<'t, T, C>
+where
+    T: Read<Error = E> + Write<Error = E>,
+    E: BusError,
+    C: Clock,
+{
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), At24cError<E>> {
+        At24c::write(self, offset, bytes)
+    }
+}
+
+impl<E: core::fmt::Debug> NorFlashError for At24cError<E> {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            At24cError::AddressOutOfRange => NorFlashErrorKind::OutOfBounds,
+            At24cError::NotAligned => NorFlashErrorKind::NotAligned,
+            _ => NorFlashErrorKind::Other,
         }
     }
+}
 
-    // Checks that the requrested address is within the address range
-    // of the device
-    fn is_address_valid(&self, address: u16) -> Result<(), At24cError> {
-        match self.size {
-            At24cMemSize::Kb128 => {
-                if address >= 16_384 {
-                    return Err(At24cError::AddressOutOfRange);
-                }
-            },
-            At24cMemSize::Kb256 => {
-                if address >= 32_768 {
-                    return Err(At24cError::AddressOutOfRange);
-                }
-            },
+// The EEPROM has no erase, so for use with crates written for NOR flash
+// erasing a block fills it with 0xFF and writes are combined with the
+// stored bytes, so that like on flash they can only clear bits
+impl<'t, T, E, C> ErrorType for At24c<'t, T, C>
+where
+    T: Read<Error = E> + Write<Error = E>,
+    E: BusError,
+    C: Clock,
+{
+    type Error = At24cError<E>;
+}
+
+impl<'t, T, E, C> ReadNorFlash for At24c<'t, T, C>
+where
+    T: Read<Error = E> + Write<Error = E>,
+    E: BusError,
+    C: Clock,
+{
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), At24cError<E>> {
+        At24c::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        At24c::capacity(self)
+    }
+}
+
+impl<'t, T, E, C> NorFlash for At24c<'t, T, C>
+where
+    T: Read<Error = E> + Write<Error = E>,
+    E: BusError,
+    C: Clock,
+{
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), At24cError<E>> {
+        if from > to || to as usize > At24c::capacity(self) {
+            return Err(At24cError::AddressOutOfRange);
+        }
+        if from as usize % ERASE_SIZE != 0 || to as usize % ERASE_SIZE != 0 {
+            return Err(At24cError::NotAligned);
+        }
+        let erased = [0xFF; ERASE_SIZE];
+        for page in (from..to).step_by(ERASE_SIZE) {
+            At24c::write(self, page, &erased)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), At24cError<E>> {
+        self.check_range(offset, bytes.len())?;
+        let mut stored = [0; ERASE_SIZE];
+        let mut offset = offset;
+        for chunk in bytes.chunks(ERASE_SIZE) {
+            let stored = &mut stored[..chunk.len()];
+            At24c::read(self, offset, stored)?;
+            stored
+                .iter_mut()
+                .zip(chunk.iter())
+                .for_each(|(s, b)| *s &= *b);
+            At24c::write(self, offset, stored)?;
+            offset += chunk.len() as u32;
         }
         Ok(())
     }
+}
+
+// Bits can be cleared any number of times, as the EEPROM has no
+// limit on the number of writes to a location between erases
+impl<'t, T, E, C> MultiwriteNorFlash for At24c<'t, T, C>
+where
+    T: Read<Error = E> + Write<Error = E>,
+    E: BusError,
+    C: Clock,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use embedded_storage::nor_flash::{check_erase, check_write};
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum FakeError {
+        AddressNack,
+        ArbitrationLoss,
+    }
+
+    impl BusError for FakeError {
+        fn is_address_nack(&self) -> bool {
+            *self == FakeError::AddressNack
+        }
+    }
+
+    // A clock that advances with every reading
+    struct FakeClock {
+        now: Cell<u32>,
+        step_us: u32,
+    }
+
+    impl Clock for FakeClock {
+        fn now_us(&self) -> u32 {
+            self.now.set(self.now.get().wrapping_add(self.step_us));
+            self.now.get()
+        }
+    }
+
+    impl FakeClock {
+        fn new(step_us: u32) -> Self {
+            FakeClock {
+                now: Cell::new(0),
+                step_us,
+            }
+        }
+    }
+
+    // Model of a chip on the bus. Writes wrap around within their page,
+    // reads run on across the whole memory, and after a write the chip
+    // does not acknowledge its address during its write cycle
+    struct FakeEeprom {
+        size: At24cMemSize,
+        // Device address with the straps, A0 is bit 16 of the memory address on the AT24CM01
+        address: u8,
+        memory: Vec<u8>,
+        pointer: usize,
+        // Number of polls a write cycle lasts
+        write_cycle_polls: usize,
+        busy_polls: usize,
+        // Failure of the next transfer
+        fail_next: Option<FakeError>,
+        // Device address and length of the data of every write with data
+        page_writes: Vec<(u8, usize)>,
+        // Device address of every read, which starts by sending the
+        // word address without data, unlike the polls of write cycles
+        reads: Vec<u8>,
+        // Number of transfers not acknowledged during write cycles
+        nacked_polls: usize,
+    }
+
+    impl FakeEeprom {
+        fn new(size: At24cMemSize, straps: u8) -> Self {
+            FakeEeprom {
+                size,
+                address: DEVICE_ADDRESS | straps,
+                memory: vec![0xFF; size.bytes()],
+                pointer: 0,
+                write_cycle_polls: 0,
+                busy_polls: 0,
+                fail_next: None,
+                page_writes: Vec::new(),
+                reads: Vec::new(),
+                nacked_polls: 0,
+            }
+        }
+
+        // Checks the device address and write cycle, returns bit 16 of
+        // the memory address on the AT24CM01
+        fn select(&mut self, address: u8) -> Result<usize, FakeError> {
+            if let Some(e) = self.fail_next.take() {
+                return Err(e);
+            }
+            let a16 = if self.size == At24cMemSize::Mb1 {
+                address & 1
+            } else {
+                0
+            };
+            if address & !a16 != self.address {
+                return Err(FakeError::AddressNack);
+            }
+            if self.busy_polls > 0 {
+                self.busy_polls -= 1;
+                self.nacked_polls += 1;
+                return Err(FakeError::AddressNack);
+            }
+            Ok((a16 as usize) << 16)
+        }
+    }
+
+    impl Write for &mut FakeEeprom {
+        type Error = FakeError;
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), FakeError> {
+            let a16 = self.select(address)?;
+            let word = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+            self.pointer = (a16 | word) % self.size.bytes();
+            let data = &bytes[ADDRESS_LEN..];
+            if data.is_empty() {
+                self.reads.push(address);
+                return Ok(());
+            }
+            let page_size = self.size.page_size();
+            let page = self.pointer - self.pointer % page_size;
+            for (i, byte) in data.iter().enumerate() {
+                self.memory[page + (self.pointer - page + i) % page_size] = *byte;
+            }
+            self.page_writes.push((address, data.len()));
+            self.busy_polls = self.write_cycle_polls;
+            Ok(())
+        }
+    }
 
-    fn clear_tx_buffer(&mut self) {
-        self.tx_buffer.iter_mut().for_each(|b| *b = 0);
+    impl Read for &mut FakeEeprom {
+        type Error = FakeError;
+
+        fn read(&mut self, address: u8, buf: &mut [u8]) -> Result<(), FakeError> {
+            self.select(address)?;
+            for byte in buf.iter_mut() {
+                *byte = self.memory[self.pointer];
+                self.pointer = (self.pointer + 1) % self.size.bytes();
+            }
+            Ok(())
+        }
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 13 + 1) as u8).collect()
+    }
+
+    #[test]
+    fn fake_wraps_writes_within_the_page() {
+        let mut fake = FakeEeprom::new(At24cMemSize::Kb256, 0);
+        let mut bus = &mut fake;
+        bus.write(DEVICE_ADDRESS, &[0, 60, 1, 2, 3, 4, 5, 6])
+            .unwrap();
+        assert_eq!(fake.memory[60..64], [1, 2, 3, 4]);
+        assert_eq!(fake.memory[0..2], [5, 6]);
+        assert_eq!(fake.memory[64], 0xFF);
+    }
+
+    #[test]
+    fn writes_are_split_at_pages() {
+        let clock = FakeClock::new(100);
+        let mut fake = FakeEeprom::new(At24cMemSize::Kb256, 0);
+        let data = pattern(200);
+        {
+            let mut eeprom = At24c::new(&mut fake, At24cMemSize::Kb256, 0, &clock);
+            eeprom.write(50, &data).unwrap();
+            let mut read = vec![0; 200];
+            eeprom.read(50, &mut read).unwrap();
+            assert_eq!(read, data);
+        }
+        let lens: Vec<usize> = fake.page_writes.iter().map(|(_, len)| *len).collect();
+        assert_eq!(lens, [14, 64, 64, 58]);
+        assert_eq!(fake.memory[50..250], data[..]);
+        assert!(fake.memory[..50]
+            .iter()
+            .chain(&fake.memory[250..])
+            .all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn started_writes_are_split_at_pages() {
+        let clock = FakeClock::new(100);
+        let mut fake = FakeEeprom::new(At24cMemSize::Kb32, 0);
+        let data = pattern(70);
+        {
+            let mut eeprom = At24c::new(&mut fake, At24cMemSize::Kb32, 0, &clock);
+            eeprom.start_write(30, &data).unwrap();
+            assert!(matches!(eeprom.write(0, &[0]), Err(At24cError::Busy)));
+            nb::block!(eeprom.poll(&data)).unwrap();
+        }
+        let lens: Vec<usize> = fake.page_writes.iter().map(|(_, len)| *len).collect();
+        assert_eq!(lens, [2, 32, 32, 4]);
+        assert_eq!(fake.memory[30..100], data[..]);
+    }
+
+    #[test]
+    fn accesses_past_the_end_are_rejected() {
+        let clock = FakeClock::new(100);
+        let mut fake = FakeEeprom::new(At24cMemSize::Kb32, 0);
+        {
+            let mut eeprom = At24c::new(&mut fake, At24cMemSize::Kb32, 0, &clock);
+            let end = eeprom.capacity() as u32;
+            assert_eq!(end, 4096);
+            let mut buf = [0; 11];
+            assert!(matches!(
+                eeprom.read(end - 10, &mut buf),
+                Err(At24cError::AddressOutOfRange)
+            ));
+            assert!(matches!(
+                eeprom.write(end - 10, &buf),
+                Err(At24cError::AddressOutOfRange)
+            ));
+            assert!(matches!(
+                eeprom.start_write(end - 10, &buf),
+                Err(At24cError::AddressOutOfRange)
+            ));
+            assert!(matches!(
+                eeprom.read(end, &mut buf[..1]),
+                Err(At24cError::AddressOutOfRange)
+            ));
+            assert!(eeprom.write(end - 10, &buf[..10]).is_ok());
+            assert!(eeprom.read(end - 10, &mut buf[..10]).is_ok());
+        }
+        // Nothing of the rejected accesses reached the bus
+        assert_eq!(fake.page_writes.len(), 1);
+        assert_eq!(fake.reads.len(), 1);
+    }
+
+    #[test]
+    fn write_cycle_is_polled() {
+        let clock = FakeClock::new(100);
+        let mut fake = FakeEeprom::new(At24cMemSize::Kb32, 0);
+        fake.write_cycle_polls = 3;
+        {
+            let mut eeprom = At24c::new(&mut fake, At24cMemSize::Kb32, 0, &clock);
+            eeprom.write(0, &pattern(64)).unwrap();
+        }
+        // The second page is written once the first write cycle is over
+        assert_eq!(fake.page_writes.len(), 2);
+        assert_eq!(fake.nacked_polls, 3);
+        assert_eq!(fake.busy_polls, 3);
+
+        // A device that never acknowledges is given up on after the write cycle time
+        fake.write_cycle_polls = usize::MAX;
+        fake.busy_polls = 0;
+        fake.nacked_polls = 0;
+        let clock = FakeClock::new(1_000);
+        {
+            let mut eeprom = At24c::new(&mut fake, At24cMemSize::Kb32, 0, &clock);
+            eeprom.write(0, &[0]).unwrap();
+            eeprom.wait_write_cycle();
+        }
+        assert_eq!(fake.nacked_polls, 10);
+    }
+
+    #[test]
+    fn bit_16_is_sent_in_the_device_address() {
+        let clock = FakeClock::new(100);
+        let mut fake = FakeEeprom::new(At24cMemSize::Mb1, 0b100);
+        let data = pattern(600);
+        {
+            // A0 is ignored on the AT24CM01
+            let mut eeprom = At24c::new(&mut fake, At24cMemSize::Mb1, 0b101, &clock);
+            assert_eq!(eeprom.capacity(), 128 * 1024);
+            eeprom.write(0xFF00, &data).unwrap();
+            let mut read = vec![0; 600];
+            eeprom.read(0xFF00, &mut read).unwrap();
+            assert_eq!(read, data);
+        }
+        assert_eq!(fake.memory[0xFF00..0xFF00 + 600], data[..]);
+        let addresses: Vec<u8> = fake
+            .page_writes
+            .iter()
+            .map(|(address, _)| *address)
+            .collect();
+        assert_eq!(addresses, [0x54, 0x55, 0x55]);
+        // The read is split where bit 16 changes
+        assert_eq!(fake.reads, [0x54, 0x55]);
+    }
+
+    #[test]
+    fn errors_are_told_apart() {
+        let clock = FakeClock::new(100);
+        let mut fake = FakeEeprom::new(At24cMemSize::Kb32, 0);
+        {
+            let mut eeprom = At24c::new(&mut fake, At24cMemSize::Kb32, 1, &clock);
+            assert!(!eeprom.is_present());
+            assert!(matches!(
+                eeprom.read(0, &mut [0]),
+                Err(At24cError::NotPresent)
+            ));
+            assert!(matches!(eeprom.write(0, &[0]), Err(At24cError::NotPresent)));
+        }
+        let mut eeprom = At24c::new(&mut fake, At24cMemSize::Kb32, 0, &clock);
+        assert!(eeprom.is_present());
+        // The address phase of a read is part of the read
+        eeprom.i2c.fail_next = Some(FakeError::ArbitrationLoss);
+        assert!(matches!(
+            eeprom.read(0, &mut [0]),
+            Err(At24cError::Read(FakeError::ArbitrationLoss))
+        ));
+        eeprom.i2c.fail_next = Some(FakeError::ArbitrationLoss);
+        assert!(matches!(
+            eeprom.write(0, &[0]),
+            Err(At24cError::Write(FakeError::ArbitrationLoss))
+        ));
+    }
+
+    #[test]
+    fn storage_conformance() {
+        for size in [
+            At24cMemSize::Kb32,
+            At24cMemSize::Kb64,
+            At24cMemSize::Kb128,
+            At24cMemSize::Kb256,
+            At24cMemSize::Kb512,
+            At24cMemSize::Mb1,
+        ] {
+            let clock = FakeClock::new(100);
+            let mut fake = FakeEeprom::new(size, 0);
+            let mut eeprom = At24c::new(&mut fake, size, 0, &clock);
+            assert_eq!(ReadStorage::capacity(&eeprom), size as usize * 128);
+            assert_eq!(ReadNorFlash::capacity(&eeprom), size as usize * 128);
+
+            let end = size.bytes() as u32;
+            let data = pattern(100);
+            // Storage writes replace the bytes, setting bits as well as clearing them
+            Storage::write(&mut eeprom, end - 100, &[0; 100]).unwrap();
+            Storage::write(&mut eeprom, end - 100, &data).unwrap();
+            let mut read = vec![0; 100];
+            ReadStorage::read(&mut eeprom, end - 100, &mut read).unwrap();
+            assert_eq!(read, data);
+            let error = Storage::write(&mut eeprom, end - 99, &data).unwrap_err();
+            assert_eq!(error.kind(), NorFlashErrorKind::OutOfBounds);
+        }
+    }
+
+    #[test]
+    fn nor_flash_conformance() {
+        let clock = FakeClock::new(100);
+        let mut fake = FakeEeprom::new(At24cMemSize::Kb32, 0);
+        let mut eeprom = At24c::new(&mut fake, At24cMemSize::Kb32, 0, &clock);
+        let end = eeprom.capacity() as u32;
+
+        // Writes can only clear bits, any number of times between erases
+        NorFlash::write(&mut eeprom, 100, &[0xF0, 0x0F]).unwrap();
+        NorFlash::write(&mut eeprom, 100, &[0x3C, 0x3C]).unwrap();
+        let mut read = [0; 2];
+        ReadNorFlash::read(&mut eeprom, 100, &mut read).unwrap();
+        assert_eq!(read, [0x30, 0x0C]);
+
+        NorFlash::erase(&mut eeprom, 64, 128).unwrap();
+        ReadNorFlash::read(&mut eeprom, 100, &mut read).unwrap();
+        assert_eq!(read, [0xFF, 0xFF]);
+
+        // The same checks as the helpers of embedded-storage
+        for (from, to) in [
+            (0, 32),
+            (32, 64),
+            (64, 32),
+            (end - 64, end),
+            (end - 64, end + 64),
+            (end, end + 64),
+        ] {
+            let expected = check_erase(&eeprom, from, to).err();
+            assert_eq!(
+                NorFlash::erase(&mut eeprom, from, to)
+                    .err()
+                    .map(|e| e.kind()),
+                expected
+            );
+        }
+        for (offset, len) in [(end - 1, 1), (end - 1, 2), (end, 1)] {
+            let expected = check_write(&eeprom, offset, len).err();
+            let result = NorFlash::write(&mut eeprom, offset, &vec![0; len]);
+            assert_eq!(result.err().map(|e| e.kind()), expected);
+        }
     }
 }
@@ -0,0 +1,172 @@
+/*
+* Configuration protocol spoken with the host over the vendor-defined
+* raw HID interface, to change the key-map and timing without rebuilding
+* the firmware. Modelled after the feature set of VIA, but using the
+* key-map entries of Kallisto itself.
+*
+* Requests and responses are single 32 byte reports. A request starts
+* with the command id, followed by its arguments. The response echoes
+* the command id, followed by a `ConfigStatus` and the data returned,
+* all multi-byte fields big-endian:
+*
+*   0x01 GetInfo                      -> KeyboardInfo
+*   0x02 GetKeyMap layer key          -> layer key entry
+*   0x03 SetKeyMap layer key entry    -> layer key entry
+*   0x04 GetTiming                    -> debounce_us u32, settle_us u32,
+*                                        anti_ghosting u8, 0 for off
+*   0x05 SetTiming debounce_us settle_us anti_ghosting
+*                                     -> same as GetTiming
+*   0x06 Commit                       -> nothing, StorageFailed if the
+*                                        key-map was not stored and
+*                                        SettingsFailed if only the
+*                                        timing was not
+*   0x07 GetKeyStats key              -> key, presses u32, chatter u32,
+*                                        min_press_us u32, for the
+*                                        matrix keys of the master half
+*   0x08 EnterBootloader              -> nothing, the keyboard then
+*                                        restarts in the USB boot mode
+*
+* Key-map entries use the format of the stored key-map, see
+* `keyboard::key_map_store`. Changes take effect at once, and are only
+* stored, and kept across power cycles, by Commit. Key-map changes that
+* have not been committed are dropped when the profile is switched.
+*
+* The command ids, statuses and protocol version are defined in the
+* kallisto-config-protocol crate, which kallisto-cli shares
+*/
+use kallisto_config_protocol::{Command, PROTOCOL_VERSION, REPORT_LEN};
+
+use crate::keyboard::key_map_store::{decode_entry, encode_entry, KEY_MAP_ENTRY_LEN};
+use crate::keyboard::diagnostics::{KeyStats, KEY_STATS_SIZE};
+use crate::keyboard::key_matrix::MatrixTiming;
+use crate::keyboard::types::LayerKeyMap;
+
+pub use kallisto_config_protocol::{Status as ConfigStatus, PRODUCT_ID, VENDOR_ID};
+
+pub const CONFIG_REPORT_LEN: usize = REPORT_LEN;
+pub const CONFIG_PROTOCOL_VERSION: u8 = PROTOCOL_VERSION;
+// Command id, status and the data of a response
+const RESPONSE_HEADER_LEN: usize = 2;
+
+#[derive(Clone, Copy)]
+pub enum ConfigRequest {
+    GetInfo,
+    GetKeyMap { layer: u8, key: u8 },
+    SetKeyMap { layer: u8, key: u8, key_map: Option<LayerKeyMap> },
+    GetTiming,
+    SetTiming(MatrixTiming),
+    Commit,
+    // Switch statistics of a key of the master half
+    GetKeyStats { key: u8 },
+    EnterBootloader,
+}
+
+impl ConfigRequest {
+    // Returns the command id along with the request,
+    // so that a failed request can still be answered
+    pub fn decode(report: &[u8; CONFIG_REPORT_LEN]) -> (u8, Result<Self, ConfigStatus>) {
+        let id = report[0];
+        let args = &report[1..];
+        let request = match Command::from_u8(id) {
+            None => Err(ConfigStatus::UnknownCommand),
+            Some(Command::GetInfo) => Ok(ConfigRequest::GetInfo),
+            Some(Command::GetKeyMap) => Ok(ConfigRequest::GetKeyMap { layer: args[0], key: args[1] }),
+            Some(Command::SetKeyMap) => decode_entry(&args[2..2 + KEY_MAP_ENTRY_LEN])
+                .map(|key_map| ConfigRequest::SetKeyMap { layer: args[0], key: args[1], key_map })
+                .map_err(|_| ConfigStatus::BadRequest),
+            Some(Command::GetTiming) => Ok(ConfigRequest::GetTiming),
+            Some(Command::SetTiming) => Ok(ConfigRequest::SetTiming(MatrixTiming {
+                debounce_us: u32::from_be_bytes([args[0], args[1], args[2], args[3]]),
+                settle_us: u32::from_be_bytes([args[4], args[5], args[6], args[7]]),
+                anti_ghosting: args[8] != 0,
+            })),
+            Some(Command::Commit) => Ok(ConfigRequest::Commit),
+            Some(Command::GetKeyStats) => Ok(ConfigRequest::GetKeyStats { key: args[0] }),
+            Some(Command::EnterBootloader) => Ok(ConfigRequest::EnterBootloader),
+        };
+        (id, request)
+    }
+}
+
+// Description of the keyboard, answering GetInfo
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyboardInfo {
+    pub firmware_version: u32,
+    pub n_layers: u8,
+    // Matrix size of each half
+    pub matrix_rows: u8,
+    pub matrix_cols: u8,
+    // Number of keys that can be mapped, numbered as `KeyId`
+    pub n_keys: u8,
+    pub n_profiles: u8,
+    pub active_profile: u8,
+    // Inputs outside the matrix, which have key ids but no mappings yet
+    pub extra_buttons: u8,
+    pub has_encoder: bool,
+}
+
+impl KeyboardInfo {
+    fn to_bytes(&self, buf: &mut [u8]) -> usize {
+        buf[0] = CONFIG_PROTOCOL_VERSION;
+        buf[1..5].copy_from_slice(&self.firmware_version.to_be_bytes());
+        buf[5] = self.n_layers;
+        buf[6] = self.matrix_rows;
+        buf[7] = self.matrix_cols;
+        buf[8] = self.n_keys;
+        buf[9] = self.n_profiles;
+        buf[10] = self.active_profile;
+        buf[11] = self.extra_buttons;
+        buf[12] = self.has_encoder as u8;
+        13
+    }
+}
+
+// A response report under construction
+pub struct ConfigResponse {
+    report: [u8; CONFIG_REPORT_LEN],
+}
+
+impl ConfigResponse {
+    pub fn new(id: u8, status: ConfigStatus) -> Self {
+        let mut report = [0; CONFIG_REPORT_LEN];
+        report[0] = id;
+        report[1] = status as u8;
+        ConfigResponse { report }
+    }
+
+    fn data(&mut self) -> &mut [u8] {
+        &mut self.report[RESPONSE_HEADER_LEN..]
+    }
+
+    pub fn info(mut self, info: &KeyboardInfo) -> Self {
+        info.to_bytes(self.data());
+        self
+    }
+
+    pub fn key_map(mut self, layer: u8, key: u8, key_map: Option<LayerKeyMap>) -> Self {
+        let data = self.data();
+        data[0] = layer;
+        data[1] = key;
+        encode_entry(key_map, &mut data[2..2 + KEY_MAP_ENTRY_LEN]);
+        self
+    }
+
+    pub fn timing(mut self, timing: &MatrixTiming) -> Self {
+        let data = self.data();
+        data[0..4].copy_from_slice(&timing.debounce_us.to_be_bytes());
+        data[4..8].copy_from_slice(&timing.settle_us.to_be_bytes());
+        data[8] = timing.anti_ghosting as u8;
+        self
+    }
+
+    pub fn key_stats(mut self, key: u8, stats: &KeyStats) -> Self {
+        let data = self.data();
+        data[0] = key;
+        data[1..1 + KEY_STATS_SIZE].copy_from_slice(&stats.to_bytes());
+        self
+    }
+
+    pub fn report(&self) -> &[u8; CONFIG_REPORT_LEN] {
+        &self.report
+    }
+}
@@ -0,0 +1,380 @@
+/*
+* Access to the QSPI flash the firmware runs from.
+*
+* The flash can't be read through XIP while it is being erased or
+* programmed, so the code doing so runs from RAM with interrupts disabled,
+* and calls the flash routines of the boot ROM through pointers looked up
+* beforehand. Afterwards XIP is restored by running a copy of the second
+* stage bootloader, taken from the first 256 bytes of flash.
+*
+* The second core must not be running from flash while it is written
+*/
+use core::mem::MaybeUninit;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use rp_pico::hal as hal;
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash,
+};
+use embedded_storage::{ReadStorage, Storage};
+
+use crate::update::{install_sectors, interrupted_install, InstallFlash, STAGING_OFFSET};
+
+pub const XIP_BASE: u32 = 0x1000_0000;
+// Size of the flash on the Pico
+pub const FLASH_SIZE: u32 = 2 * 1024 * 1024;
+pub const SECTOR_SIZE: u32 = 4096;
+pub const PAGE_SIZE: u32 = 256;
+// Region at the end of flash kept for the key-map and settings, left out of FLASH in memory.x
+pub const STORAGE_SIZE: u32 = 32 * 1024;
+pub const STORAGE_OFFSET: u32 = FLASH_SIZE - STORAGE_SIZE;
+const SECTOR_ERASE_CMD: u8 = 0x20;
+const BOOT2_WORDS: usize = 64;
+// Application interrupt and reset control register, and the value requesting a reset
+const AIRCR: u32 = 0xE000_ED0C;
+const AIRCR_SYSRESETREQ: u32 = 0x05FA_0004;
+
+// Flash routines of the boot ROM
+struct RomFns {
+    connect_internal_flash: extern "C" fn(),
+    flash_exit_xip: extern "C" fn(),
+    flash_range_erase: extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: extern "C" fn(),
+    flash_enter_cmd_xip: extern "C" fn(),
+}
+
+impl RomFns {
+    fn lookup() -> Self {
+        RomFns {
+            connect_internal_flash: hal::rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: hal::rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: hal::rom_data::flash_range_erase::ptr(),
+            flash_range_program: hal::rom_data::flash_range_program::ptr(),
+            flash_flush_cache: hal::rom_data::flash_flush_cache::ptr(),
+            flash_enter_cmd_xip: hal::rom_data::flash_enter_cmd_xip::ptr(),
+        }
+    }
+}
+
+// The whole flash, offsets are relative to the start of flash
+pub struct Rp2040Flash {
+    rom: RomFns,
+    boot2: [u32; BOOT2_WORDS],
+}
+
+impl Rp2040Flash {
+    pub fn new() -> Self {
+        let mut boot2 = [0; BOOT2_WORDS];
+        unsafe {
+            core::ptr::copy_nonoverlapping(XIP_BASE as *const u32, boot2.as_mut_ptr(), BOOT2_WORDS);
+        }
+        Rp2040Flash {
+            rom: RomFns::lookup(),
+            boot2,
+        }
+    }
+
+    fn erase_range(&mut self, offset: u32, len: u32) {
+        let boot2 = self.boot2.as_ptr();
+        cortex_m::interrupt::free(|_| unsafe {
+            flash_op(&self.rom, boot2, offset, core::ptr::null(), len as usize, true);
+        });
+    }
+
+    // Programs the data a page at a time. Programming can only clear bits,
+    // so the rest of a page it doesn't fill is padded with 0xFF and left as
+    // it was. The data is copied to RAM, since it may itself be stored in flash
+    fn program_range(&mut self, offset: u32, data: &[u8]) {
+        let boot2 = self.boot2.as_ptr();
+        let mut page = [0xFF; PAGE_SIZE as usize];
+        let mut written = 0;
+        while written < data.len() {
+            let address = offset + written as u32;
+            let start = (address % PAGE_SIZE) as usize;
+            let len = (PAGE_SIZE as usize - start).min(data.len() - written);
+            page.fill(0xFF);
+            page[start..start + len].copy_from_slice(&data[written..written + len]);
+            cortex_m::interrupt::free(|_| unsafe {
+                flash_op(&self.rom, boot2, address - start as u32, page.as_ptr(), PAGE_SIZE as usize, false);
+            });
+            written += len;
+        }
+    }
+}
+
+impl ErrorType for Rp2040Flash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for Rp2040Flash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (XIP_BASE + offset) as *const u8,
+                bytes.as_mut_ptr(),
+                bytes.len(),
+            );
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        FLASH_SIZE as usize
+    }
+}
+
+impl NorFlash for Rp2040Flash {
+    const WRITE_SIZE: usize = PAGE_SIZE as usize;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.erase_range(from, to - from);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        self.program_range(offset, bytes);
+        Ok(())
+    }
+}
+
+// The storage region, used in place of the EEPROM on builds without one.
+// Offsets are relative to the start of the region.
+//
+// As NOR flash, bytes are programmed on erased space and sectors erased
+// when asked to, which is how the settings store uses it. As Storage,
+// where writes replace the stored bytes, each sector a write touches is
+// read into RAM, merged with the new data, then erased and programmed
+// again, unless its contents are unchanged. Interrupts are disabled for
+// the tens of milliseconds this takes per sector. The firmware never
+// starts the second core, which stays parked in the boot ROM
+pub struct FlashStorage {
+    flash: Rp2040Flash,
+    sector: [u8; SECTOR_SIZE as usize],
+}
+
+impl FlashStorage {
+    pub fn new() -> Self {
+        FlashStorage {
+            flash: Rp2040Flash::new(),
+            sector: [0; SECTOR_SIZE as usize],
+        }
+    }
+
+    fn check_range(offset: u32, len: usize) -> Result<(), NorFlashErrorKind> {
+        if offset as usize + len > STORAGE_SIZE as usize {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        Ok(())
+    }
+}
+
+impl ReadStorage for FlashStorage {
+    type Error = NorFlashErrorKind;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        Self::check_range(offset, bytes.len())?;
+        self.flash.read(STORAGE_OFFSET + offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        STORAGE_SIZE as usize
+    }
+}
+
+impl ErrorType for FlashStorage {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for FlashStorage {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        check_read(self, offset, bytes.len())?;
+        self.flash.read(STORAGE_OFFSET + offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        STORAGE_SIZE as usize
+    }
+}
+
+impl NorFlash for FlashStorage {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = SECTOR_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        check_erase(self, from, to)?;
+        self.flash.erase(STORAGE_OFFSET + from, STORAGE_OFFSET + to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        check_write(self, offset, bytes.len())?;
+        self.flash.program_range(STORAGE_OFFSET + offset, bytes);
+        Ok(())
+    }
+}
+
+impl Storage for FlashStorage {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        Self::check_range(offset, bytes.len())?;
+        let mut written = 0;
+        while written < bytes.len() {
+            let address = STORAGE_OFFSET + offset + written as u32;
+            let sector = address - address % SECTOR_SIZE;
+            let start = (address - sector) as usize;
+            let len = (SECTOR_SIZE as usize - start).min(bytes.len() - written);
+            let data = &bytes[written..written + len];
+            self.flash.read(sector, &mut self.sector)?;
+            if self.sector[start..start + len] != *data {
+                self.sector[start..start + len].copy_from_slice(data);
+                self.flash.erase(sector, sector + SECTOR_SIZE)?;
+                self.flash.write(sector, &self.sector)?;
+            }
+            written += len;
+        }
+        Ok(())
+    }
+}
+
+// Erases or programs a range of flash. Runs from RAM, and must not call
+// any code in flash, since flash can't be read until XIP is restored
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn flash_op(rom: &RomFns, boot2: *const u32, address: u32, data: *const u8, len: usize, erase: bool) {
+    compiler_fence(Ordering::SeqCst);
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    if erase {
+        (rom.flash_range_erase)(address, len, SECTOR_SIZE, SECTOR_ERASE_CMD);
+    } else {
+        (rom.flash_range_program)(address, data, len);
+    }
+    (rom.flash_flush_cache)();
+    // The second stage bootloader is thumb code, so the address has the lowest bit set
+    let boot2_fn: extern "C" fn() = core::mem::transmute(boot2 as usize + 1);
+    boot2_fn();
+    compiler_fence(Ordering::SeqCst);
+}
+
+// The flash routines of the boot ROM for the installer, looked up
+// through the table of the boot ROM directly rather than with the HAL,
+// since the installer may only run code in the first sector of flash
+#[derive(Clone, Copy)]
+struct InstallerRom {
+    connect_internal_flash: extern "C" fn(),
+    flash_exit_xip: extern "C" fn(),
+    flash_range_erase: extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: extern "C" fn(),
+    flash_enter_cmd_xip: extern "C" fn(),
+}
+
+impl InstallerRom {
+    #[inline(always)]
+    unsafe fn lookup() -> Self {
+        InstallerRom {
+            connect_internal_flash: core::mem::transmute::<usize, extern "C" fn()>(rom_fn(b'I', b'F')),
+            flash_exit_xip: core::mem::transmute::<usize, extern "C" fn()>(rom_fn(b'E', b'X')),
+            flash_range_erase: core::mem::transmute::<usize, extern "C" fn(u32, usize, u32, u8)>(rom_fn(b'R', b'E')),
+            flash_range_program: core::mem::transmute::<usize, extern "C" fn(u32, *const u8, usize)>(rom_fn(b'R', b'P')),
+            flash_flush_cache: core::mem::transmute::<usize, extern "C" fn()>(rom_fn(b'F', b'C')),
+            flash_enter_cmd_xip: core::mem::transmute::<usize, extern "C" fn()>(rom_fn(b'C', b'X')),
+        }
+    }
+}
+
+// Address of a function of the boot ROM, from the 16-bit pointers to
+// its function table, at 0x14, and to its lookup function, at 0x18
+#[inline(always)]
+unsafe fn rom_fn(c1: u8, c2: u8) -> usize {
+    let table = core::ptr::read_volatile(0x14 as *const u16) as usize as *const u16;
+    let lookup_fn = core::ptr::read_volatile(0x18 as *const u16) as usize;
+    let lookup = core::mem::transmute::<usize, extern "C" fn(*const u16, u32) -> usize>(lookup_fn);
+    lookup(table, c1 as u32 | (c2 as u32) << 8)
+}
+
+impl InstallFlash for InstallerRom {
+    #[inline(always)]
+    fn read_word(&mut self, offset: u32) -> u32 {
+        unsafe { core::ptr::read_volatile((XIP_BASE | offset) as *const u32) }
+    }
+
+    #[inline(always)]
+    fn erase_sector(&mut self, offset: u32) {
+        (self.connect_internal_flash)();
+        (self.flash_exit_xip)();
+        (self.flash_range_erase)(offset, SECTOR_SIZE as usize, SECTOR_SIZE, SECTOR_ERASE_CMD);
+        (self.flash_flush_cache)();
+        (self.flash_enter_cmd_xip)();
+    }
+
+    #[inline(always)]
+    fn program(&mut self, offset: u32, data: *const u8, len: usize) {
+        (self.connect_internal_flash)();
+        (self.flash_exit_xip)();
+        (self.flash_range_program)(offset, data, len);
+        (self.flash_flush_cache)();
+        (self.flash_enter_cmd_xip)();
+    }
+}
+
+// Installs the image of `len` bytes staged at `from`, which must be sector
+// aligned, over the running firmware and resets. An install interrupted by
+// a power loss is resumed at the next start-up by `resume_install`
+pub unsafe fn install_image(from: u32, len: u32) -> ! {
+    cortex_m::interrupt::disable();
+    start_installer(InstallerRom::lookup(), from, len)
+}
+
+// Resumes an install interrupted by a power loss. Until the install is
+// complete the firmware in flash is only intact in its first sector, so
+// this must be called from `__pre_init`, placed in the `.installer`
+// section, which memory.x keeps in the first sector with the reset handler.
+// No interrupts are enabled yet at that point
+#[inline(always)]
+pub unsafe fn resume_install() {
+    let mut rom = InstallerRom::lookup();
+    if let Some(len) = interrupted_install(&mut rom) {
+        start_installer(rom, STAGING_OFFSET, len)
+    }
+}
+
+// Loads the installer into RAM and runs it
+#[inline(always)]
+unsafe fn start_installer(rom: InstallerRom, from: u32, len: u32) -> ! {
+    extern "C" {
+        static mut __sinstaller_ram: u32;
+        static mut __einstaller_ram: u32;
+        static __siinstaller_ram: u32;
+    }
+    let mut dst = core::ptr::addr_of_mut!(__sinstaller_ram);
+    let end = core::ptr::addr_of_mut!(__einstaller_ram);
+    let mut src = core::ptr::addr_of!(__siinstaller_ram);
+    while dst < end {
+        core::ptr::write_volatile(dst, core::ptr::read_volatile(src));
+        dst = dst.add(1);
+        src = src.add(1);
+    }
+    // Called through a pointer, since a direct call can't reach RAM from flash
+    let install: unsafe fn(InstallerRom, u32, u32) -> ! = install_from_ram;
+    core::ptr::read_volatile(&install)(rom, from, len)
+}
+
+// Runs from RAM as it overwrites the code in flash, which also rules
+// out calls to anything but the boot ROM, see `update::install_sectors`
+#[inline(never)]
+#[link_section = ".installer_ram"]
+unsafe fn install_from_ram(mut rom: InstallerRom, from: u32, len: u32) -> ! {
+    // Left uninitialised, zeroing it could call memset in flash
+    let mut sector = MaybeUninit::<[u32; (SECTOR_SIZE / 4) as usize]>::uninit();
+    install_sectors(&mut rom, from, len, &mut sector as *mut _ as *mut u32);
+    core::ptr::write_volatile(AIRCR as *mut u32, AIRCR_SYSRESETREQ);
+    loop {}
+}
@@ -1,2 +1,200 @@
 use rp_pico::hal as hal;
 
+/*
+* Recovery of a wedged I2C bus.
+*
+* If a device is reset, or loses clock edges, in the middle of a transfer
+* it may be left holding SDA low while it waits for the rest of a byte
+* that never comes. The controller then sees the bus as busy forever.
+* Clocking SCL until the device releases SDA, at most 9 times for the 8
+* data bits and the ACK, and then issuing a STOP returns the bus to idle.
+*/
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::InputPin;
+use hal::fugit::HertzU32;
+use hal::gpio::{BankPinId, FunctionI2C, Pin, PinId, PinState, ValidPinMode};
+use hal::i2c::{Controller, SclPin, SdaPin, I2C};
+use hal::pac;
+
+// Maximum number of clock pulses sent to release SDA
+const RECOVERY_PULSES: u8 = 9;
+// Half period of the recovery clock, giving roughly 100 kHz
+const HALF_PERIOD_US: u32 = 5;
+// Number of consecutive failed transfers before a recovery is attempted
+pub const RECOVERY_THRESHOLD: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusState {
+    Free,
+    // SDA was still held low after all recovery pulses
+    Stuck,
+}
+
+// Counts consecutive failed transfers and tells
+// when a bus recovery should be attempted
+pub struct RecoveryTrigger {
+    consecutive_errors: u8,
+    recoveries: u32,
+}
+
+impl RecoveryTrigger {
+    pub const fn new() -> Self {
+        RecoveryTrigger {
+            consecutive_errors: 0,
+            recoveries: 0,
+        }
+    }
+
+    pub fn success(&mut self) {
+        self.consecutive_errors = 0;
+    }
+
+    // Returns true if the bus should be recovered
+    pub fn error(&mut self) -> bool {
+        self.consecutive_errors = self.consecutive_errors.saturating_add(1);
+        if self.consecutive_errors >= RECOVERY_THRESHOLD {
+            self.consecutive_errors = 0;
+            self.recoveries += 1;
+            return true;
+        }
+        false
+    }
+
+    // Number of recoveries triggered
+    pub fn recoveries(&self) -> u32 {
+        self.recoveries
+    }
+}
+
+// Takes the SDA and SCL pins back from the I2C peripheral, clocks the
+// bus until SDA is released and issues a STOP condition. The pins are
+// returned in I2C mode. A line is never driven high, only released to
+// its pull-up, since a device may be holding it low
+pub fn recover_bus<Sda, Scl, D>(
+    sda: Pin<Sda, FunctionI2C>,
+    scl: Pin<Scl, FunctionI2C>,
+    delay: &mut D,
+) -> (Pin<Sda, FunctionI2C>, Pin<Scl, FunctionI2C>, BusState)
+where
+    Sda: PinId,
+    Scl: PinId,
+    FunctionI2C: ValidPinMode<Sda> + ValidPinMode<Scl>,
+    D: DelayUs<u32>,
+{
+    // The lines are open-drain, a line is released by making it a
+    // pulled up input and pulled down by driving it low
+    let sda = sda.into_pull_up_input();
+    let mut scl = scl.into_pull_up_input();
+    delay.delay_us(HALF_PERIOD_US);
+
+    for _ in 0..RECOVERY_PULSES {
+        if sda.is_high().unwrap() {
+            break;
+        }
+        let scl_low = scl.into_push_pull_output_in_state(PinState::Low);
+        delay.delay_us(HALF_PERIOD_US);
+        scl = scl_low.into_pull_up_input();
+        delay.delay_us(HALF_PERIOD_US);
+    }
+    let state = if sda.is_high().unwrap() {
+        BusState::Free
+    } else {
+        BusState::Stuck
+    };
+
+    // STOP condition, SDA going high while SCL is high
+    let scl_low = scl.into_push_pull_output_in_state(PinState::Low);
+    let sda_low = sda.into_push_pull_output_in_state(PinState::Low);
+    delay.delay_us(HALF_PERIOD_US);
+    let scl = scl_low.into_pull_up_input();
+    delay.delay_us(HALF_PERIOD_US);
+    let sda = sda_low.into_pull_up_input();
+    delay.delay_us(HALF_PERIOD_US);
+
+    (sda.into_mode(), scl.into_mode(), state)
+}
+
+// An I2C block whose controller can be taken apart for a bus recovery
+// and set up again, implemented for both blocks of the RP2040
+pub trait RecoverableBlock: Sized {
+    fn free_controller<Sda, Scl>(
+        i2c: I2C<Self, (Pin<Sda, FunctionI2C>, Pin<Scl, FunctionI2C>), Controller>,
+        resets: &mut pac::RESETS,
+    ) -> (Self, (Pin<Sda, FunctionI2C>, Pin<Scl, FunctionI2C>))
+    where
+        Sda: PinId + BankPinId,
+        Scl: PinId + BankPinId;
+
+    fn new_controller<Sda, Scl>(
+        self,
+        sda: Pin<Sda, FunctionI2C>,
+        scl: Pin<Scl, FunctionI2C>,
+        freq: HertzU32,
+        resets: &mut pac::RESETS,
+        system_clock: HertzU32,
+    ) -> I2C<Self, (Pin<Sda, FunctionI2C>, Pin<Scl, FunctionI2C>), Controller>
+    where
+        Sda: PinId + BankPinId + SdaPin<Self>,
+        Scl: PinId + BankPinId + SclPin<Self>,
+        FunctionI2C: ValidPinMode<Sda> + ValidPinMode<Scl>;
+}
+
+macro_rules! recoverable_block {
+    ($block:ty) => {
+        impl RecoverableBlock for $block {
+            fn free_controller<Sda, Scl>(
+                i2c: I2C<Self, (Pin<Sda, FunctionI2C>, Pin<Scl, FunctionI2C>), Controller>,
+                resets: &mut pac::RESETS,
+            ) -> (Self, (Pin<Sda, FunctionI2C>, Pin<Scl, FunctionI2C>))
+            where
+                Sda: PinId + BankPinId,
+                Scl: PinId + BankPinId,
+            {
+                i2c.free(resets)
+            }
+
+            fn new_controller<Sda, Scl>(
+                self,
+                sda: Pin<Sda, FunctionI2C>,
+                scl: Pin<Scl, FunctionI2C>,
+                freq: HertzU32,
+                resets: &mut pac::RESETS,
+                system_clock: HertzU32,
+            ) -> I2C<Self, (Pin<Sda, FunctionI2C>, Pin<Scl, FunctionI2C>), Controller>
+            where
+                Sda: PinId + BankPinId + SdaPin<Self>,
+                Scl: PinId + BankPinId + SclPin<Self>,
+                FunctionI2C: ValidPinMode<Sda> + ValidPinMode<Scl>,
+            {
+                I2C::new_controller(self, sda, scl, freq, resets, system_clock)
+            }
+        }
+    };
+}
+
+recoverable_block!(pac::I2C0);
+recoverable_block!(pac::I2C1);
+
+// Recovers the bus of an I2C controller, and re-initialises the
+// controller since it may have been left in an inconsistent state
+pub fn recover_controller<B, Sda, Scl, D>(
+    i2c: I2C<B, (Pin<Sda, FunctionI2C>, Pin<Scl, FunctionI2C>), Controller>,
+    freq: HertzU32,
+    system_clock: HertzU32,
+    resets: &mut pac::RESETS,
+    delay: &mut D,
+) -> (I2C<B, (Pin<Sda, FunctionI2C>, Pin<Scl, FunctionI2C>), Controller>, BusState)
+where
+    B: RecoverableBlock,
+    Sda: PinId + BankPinId + SdaPin<B>,
+    Scl: PinId + BankPinId + SclPin<B>,
+    FunctionI2C: ValidPinMode<Sda> + ValidPinMode<Scl>,
+    D: DelayUs<u32>,
+{
+    let (block, (sda, scl)) = B::free_controller(i2c, resets);
+    let (sda, scl, state) = recover_bus(sda, scl, delay);
+    (
+        block.new_controller(sda, scl, freq, resets, system_clock),
+        state,
+    )
+}
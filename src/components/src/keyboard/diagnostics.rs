@@ -0,0 +1,145 @@
+/*
+* Per-key switch statistics, used to find failing switches.
+*
+* The statistics cover the matrix of a single half. Only the master
+* collects them, for its own half, the keys of the slave half have none
+*/
+use embedded_storage::{ReadStorage, Storage};
+
+use crate::keyboard::key_matrix::N_KEYS;
+use crate::keyboard::layout::{Half, KeyId};
+
+// Marks a valid statistics block in the EEPROM, "KSTA"
+const STATS_MAGIC: u32 = 0x4B53_5441;
+pub const KEY_STATS_SIZE: usize = 12;
+// Size of the serialized statistics of one half, the magic
+// number followed by the statistics of each key
+pub const MATRIX_STATS_SIZE: usize = 4 + KEY_STATS_SIZE * N_KEYS;
+
+#[derive(Clone, Copy)]
+pub struct KeyStats {
+    // Number of accepted key presses
+    pub presses: u32,
+    // Number of key state changes rejected by the debounce filter
+    pub chatter: u32,
+    // Shortest accepted press in microseconds,
+    // u32::MAX if the key has never been released
+    pub min_press_us: u32,
+}
+
+impl KeyStats {
+    pub const fn new() -> Self {
+        KeyStats {
+            presses: 0,
+            chatter: 0,
+            min_press_us: u32::MAX,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; KEY_STATS_SIZE] {
+        let mut buf = [0; KEY_STATS_SIZE];
+        buf[0..4].copy_from_slice(&self.presses.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.chatter.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.min_press_us.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        KeyStats {
+            presses: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            chatter: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            min_press_us: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        }
+    }
+}
+
+pub struct MatrixStats {
+    // The half whose matrix the statistics are collected for
+    half: Half,
+    pub keys: [KeyStats; N_KEYS],
+    // Set when the statistics change, until taken with `take_changed`
+    changed: bool,
+}
+
+impl MatrixStats {
+    pub const fn new(half: Half) -> Self {
+        MatrixStats {
+            half,
+            keys: [KeyStats::new(); N_KEYS],
+            changed: false,
+        }
+    }
+
+    // Returns the statistics of a key, None for keys
+    // outside the matrix of the half they cover
+    pub fn key(&self, id: KeyId) -> Option<&KeyStats> {
+        if id.half() != self.half {
+            return None;
+        }
+        id.matrix_index().map(|i| &self.keys[i])
+    }
+
+    pub fn reset(&mut self) {
+        self.keys = [KeyStats::new(); N_KEYS];
+        self.changed = true;
+    }
+
+    // Returns whether the statistics changed since the last call
+    pub fn take_changed(&mut self) -> bool {
+        core::mem::replace(&mut self.changed, false)
+    }
+
+    pub fn record_press(&mut self, id: usize) {
+        self.keys[id].presses = self.keys[id].presses.saturating_add(1);
+        self.changed = true;
+    }
+
+    pub fn record_release(&mut self, id: usize, press_duration_us: u32) {
+        if press_duration_us < self.keys[id].min_press_us {
+            self.keys[id].min_press_us = press_duration_us;
+            self.changed = true;
+        }
+    }
+
+    pub fn record_chatter(&mut self, id: usize) {
+        self.keys[id].chatter = self.keys[id].chatter.saturating_add(1);
+        self.changed = true;
+    }
+
+    pub fn to_bytes(&self, buf: &mut [u8; MATRIX_STATS_SIZE]) {
+        buf[0..4].copy_from_slice(&STATS_MAGIC.to_be_bytes());
+        for (id, key) in self.keys.iter().enumerate() {
+            let offset = 4 + id * KEY_STATS_SIZE;
+            buf[offset..offset + KEY_STATS_SIZE].copy_from_slice(&key.to_bytes());
+        }
+    }
+
+    // Returns None if the buffer does not hold serialized statistics,
+    // e.g. when read from an erased EEPROM
+    pub fn from_bytes(buf: &[u8; MATRIX_STATS_SIZE], half: Half) -> Option<Self> {
+        if u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) != STATS_MAGIC {
+            return None;
+        }
+        let mut stats = MatrixStats::new(half);
+        for (id, key) in stats.keys.iter_mut().enumerate() {
+            let offset = 4 + id * KEY_STATS_SIZE;
+            *key = KeyStats::from_bytes(&buf[offset..offset + KEY_STATS_SIZE]);
+        }
+        Some(stats)
+    }
+
+    // Writes the statistics to the EEPROM, or any other storage
+    pub fn save<S: Storage>(&self, storage: &mut S, address: u32) -> Result<(), S::Error> {
+        let mut buf = [0; MATRIX_STATS_SIZE];
+        self.to_bytes(&mut buf);
+        storage.write(address, &buf)
+    }
+
+    // Reads statistics previously written with `save`. Returns empty
+    // statistics if no valid statistics are stored at the address
+    pub fn load<S: ReadStorage>(storage: &mut S, address: u32, half: Half) -> Result<Self, S::Error> {
+        let mut buf = [0; MATRIX_STATS_SIZE];
+        storage.read(address, &mut buf)?;
+        Ok(MatrixStats::from_bytes(&buf, half).unwrap_or(MatrixStats::new(half)))
+    }
+}
@@ -0,0 +1,428 @@
+/*
+* Binary format of the key-map layers, as stored in the EEPROM.
+*
+* The key-map starts with a 10 byte header, all fields big-endian:
+*   0..2   magic, "KM"
+*   2      format version
+*   3      number of layers
+*   4..6   number of keys per layer
+*   6..10  CRC-32 of the entries
+* followed by one 7 byte entry per key, layer by layer:
+*   0      flags, bit 0 set if the key is mapped, bit 1 if it has a press
+*          mapping and bit 2 if it has a held press mapping
+*   1..4   press mapping, key code u16 and modifier u8, 0 for none
+*   4..7   held press mapping, same layout
+* Mappings that are absent are stored as zeros.
+*
+* A key-map that is missing, of another format version or shape,
+* or that fails the CRC is rejected as a whole
+*/
+use embedded_storage::{ReadStorage, Storage};
+
+use crate::keyboard::keyboard::N_LAYERS;
+use crate::keyboard::types::*;
+use crate::update::{crc32_finish, crc32_update, CRC32_INIT};
+
+pub const KEY_MAP_MAGIC: u16 = 0x4B4D;
+pub const KEY_MAP_FORMAT_VERSION: u8 = 1;
+pub const KEY_MAP_HEADER_LEN: usize = 10;
+pub const KEY_MAP_ENTRY_LEN: usize = 7;
+
+const FLAG_MAPPED: u8 = 0x01;
+const FLAG_PRESSED: u8 = 0x02;
+const FLAG_HELD_PRESS: u8 = 0x04;
+// Number of entries read from storage at a time while loading
+const LOAD_CHUNK_ENTRIES: usize = 32;
+
+pub type Layers<const N: usize> = [[Option<LayerKeyMap>; N]; N_LAYERS];
+
+#[derive(Debug)]
+pub enum KeyMapError<E> {
+    Storage(E),
+    // Nothing that looks like a key-map is stored
+    Missing,
+    UnsupportedVersion(u8),
+    // Stored for a different number of layers or keys
+    WrongShape,
+    BadCrc,
+    // An entry holds an unknown key or modifier code
+    BadEntry,
+    // The buffer is too small for the key-map
+    BufferTooSmall,
+}
+
+// Length of the stored key-map of N keys per layer
+pub const fn key_map_len(n_keys: usize) -> usize {
+    KEY_MAP_HEADER_LEN + N_LAYERS * n_keys * KEY_MAP_ENTRY_LEN
+}
+
+fn encode_mapping(mapping: Option<KeyMapping>, buf: &mut [u8]) {
+    match mapping {
+        Some(mapping) => {
+            buf[..2].copy_from_slice(&(mapping.key as u16).to_be_bytes());
+            buf[2] = mapping.modifier.map(|m| m as u8).unwrap_or(0);
+        },
+        None => buf[..3].fill(0),
+    }
+}
+
+fn decode_mapping(buf: &[u8]) -> Option<KeyMapping> {
+    let key = KeyPress::try_from(u16::from_be_bytes([buf[0], buf[1]])).ok()?;
+    let modifier = match buf[2] {
+        0 => None,
+        m => Some(ModifierKey::try_from(m).ok()?),
+    };
+    Some(KeyMapping { key, modifier })
+}
+
+// Serializes a single entry, KEY_MAP_ENTRY_LEN bytes
+pub fn encode_entry(key_map: Option<LayerKeyMap>, buf: &mut [u8]) {
+    let mut flags = 0;
+    if let Some(key_map) = key_map {
+        flags |= FLAG_MAPPED;
+        if key_map.pressed.is_some() {
+            flags |= FLAG_PRESSED;
+        }
+        if key_map.held_press.is_some() {
+            flags |= FLAG_HELD_PRESS;
+        }
+    }
+    buf[0] = flags;
+    encode_mapping(key_map.and_then(|k| k.pressed), &mut buf[1..4]);
+    encode_mapping(key_map.and_then(|k| k.held_press), &mut buf[4..7]);
+}
+
+// Err if the entry holds an unknown key or modifier code
+pub fn decode_entry(buf: &[u8]) -> Result<Option<LayerKeyMap>, ()> {
+    let flags = buf[0];
+    if flags & FLAG_MAPPED == 0 {
+        return Ok(None);
+    }
+    let pressed = if flags & FLAG_PRESSED != 0 {
+        Some(decode_mapping(&buf[1..4]).ok_or(())?)
+    } else {
+        None
+    };
+    let held_press = if flags & FLAG_HELD_PRESS != 0 {
+        Some(decode_mapping(&buf[4..7]).ok_or(())?)
+    } else {
+        None
+    };
+    Ok(Some(LayerKeyMap { pressed, held_press }))
+}
+
+// Serializes the layers into `buf`, returning the number of bytes used
+pub fn encode_key_map<const N: usize>(layers: &Layers<N>, buf: &mut [u8]) -> Result<usize, KeyMapError<()>> {
+    let len = key_map_len(N);
+    if buf.len() < len {
+        return Err(KeyMapError::BufferTooSmall);
+    }
+    let (header, entries) = buf[..len].split_at_mut(KEY_MAP_HEADER_LEN);
+    for (entry, key_map) in entries.chunks_mut(KEY_MAP_ENTRY_LEN).zip(layers.iter().flatten()) {
+        encode_entry(*key_map, entry);
+    }
+    let crc = crc32_finish(crc32_update(CRC32_INIT, entries));
+    header[0..2].copy_from_slice(&KEY_MAP_MAGIC.to_be_bytes());
+    header[2] = KEY_MAP_FORMAT_VERSION;
+    header[3] = N_LAYERS as u8;
+    header[4..6].copy_from_slice(&(N as u16).to_be_bytes());
+    header[6..10].copy_from_slice(&crc.to_be_bytes());
+    Ok(len)
+}
+
+// Checks a header, returning the CRC of the entries
+fn check_header<E, const N: usize>(header: &[u8]) -> Result<u32, KeyMapError<E>> {
+    if u16::from_be_bytes([header[0], header[1]]) != KEY_MAP_MAGIC {
+        return Err(KeyMapError::Missing);
+    }
+    if header[2] != KEY_MAP_FORMAT_VERSION {
+        return Err(KeyMapError::UnsupportedVersion(header[2]));
+    }
+    if header[3] as usize != N_LAYERS || u16::from_be_bytes([header[4], header[5]]) as usize != N {
+        return Err(KeyMapError::WrongShape);
+    }
+    Ok(u32::from_be_bytes([header[6], header[7], header[8], header[9]]))
+}
+
+// Deserializes a key-map from `buf`. The layers are only
+// replaced if the whole key-map is valid
+pub fn decode_key_map<const N: usize>(buf: &[u8], layers: &mut Layers<N>) -> Result<(), KeyMapError<()>> {
+    let len = key_map_len(N);
+    if buf.len() < KEY_MAP_HEADER_LEN {
+        return Err(KeyMapError::Missing);
+    }
+    let crc = check_header::<(), N>(&buf[..KEY_MAP_HEADER_LEN])?;
+    if buf.len() < len {
+        return Err(KeyMapError::BufferTooSmall);
+    }
+    let entries = &buf[KEY_MAP_HEADER_LEN..len];
+    if crc32_finish(crc32_update(CRC32_INIT, entries)) != crc {
+        return Err(KeyMapError::BadCrc);
+    }
+    let mut loaded = *layers;
+    for (entry, key_map) in entries.chunks(KEY_MAP_ENTRY_LEN).zip(loaded.iter_mut().flatten()) {
+        *key_map = decode_entry(entry).map_err(|_| KeyMapError::BadEntry)?;
+    }
+    *layers = loaded;
+    Ok(())
+}
+
+// Loads the key-map stored at `address`, reading it a few entries at a time.
+// The layers are left untouched unless the whole key-map is valid
+pub fn load_key_map<S, const N: usize>(
+    storage: &mut S,
+    address: u32,
+    layers: &mut Layers<N>,
+) -> Result<(), KeyMapError<S::Error>>
+where
+    S: ReadStorage,
+{
+    let mut header = [0; KEY_MAP_HEADER_LEN];
+    storage.read(address, &mut header).map_err(KeyMapError::Storage)?;
+    let expected_crc = check_header::<S::Error, N>(&header)?;
+
+    let mut loaded = *layers;
+    let mut crc = CRC32_INIT;
+    let mut chunk = [0; LOAD_CHUNK_ENTRIES * KEY_MAP_ENTRY_LEN];
+    let mut offset = address + KEY_MAP_HEADER_LEN as u32;
+    let mut bad_entry = false;
+    for key_maps in loaded.iter_mut().flat_map(|layer| layer.chunks_mut(LOAD_CHUNK_ENTRIES)) {
+        let chunk = &mut chunk[..key_maps.len() * KEY_MAP_ENTRY_LEN];
+        storage.read(offset, chunk).map_err(KeyMapError::Storage)?;
+        offset += chunk.len() as u32;
+        crc = crc32_update(crc, chunk);
+        for (entry, key_map) in chunk.chunks(KEY_MAP_ENTRY_LEN).zip(key_maps.iter_mut()) {
+            match decode_entry(entry) {
+                Ok(k) => *key_map = k,
+                Err(_) => bad_entry = true,
+            }
+        }
+    }
+    // A corrupt entry is reported as such only if the CRC matches
+    if crc32_finish(crc) != expected_crc {
+        return Err(KeyMapError::BadCrc);
+    }
+    if bad_entry {
+        return Err(KeyMapError::BadEntry);
+    }
+    *layers = loaded;
+    Ok(())
+}
+
+// Stores the layers at `address`, using `buf` to serialize them. A save
+// that is interrupted leaves a key-map that fails the CRC check
+pub fn save_key_map<S, const N: usize>(
+    storage: &mut S,
+    address: u32,
+    layers: &Layers<N>,
+    buf: &mut [u8],
+) -> Result<(), KeyMapError<S::Error>>
+where
+    S: Storage,
+{
+    let len = encode_key_map(layers, buf).map_err(|_| KeyMapError::BufferTooSmall)?;
+    storage.write(address, &buf[..len]).map_err(KeyMapError::Storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two layers of keys, more than are loaded at a time
+    const N: usize = LOAD_CHUNK_ENTRIES + 10;
+    const LEN: usize = key_map_len(N);
+
+    fn mapping(key: KeyPress, modifier: Option<ModifierKey>) -> Option<KeyMapping> {
+        Some(KeyMapping { key, modifier })
+    }
+
+    fn layers() -> Layers<N> {
+        let mut layers = [[None; N]; N_LAYERS];
+        layers[0][0] = Some(LayerKeyMap { pressed: mapping(KeyPress::Q, None), held_press: None });
+        layers[0][N - 1] = Some(LayerKeyMap {
+            pressed: mapping(KeyPress::A, Some(ModifierKey::LeftShift)),
+            held_press: mapping(KeyPress::LayerHold1, None),
+        });
+        layers[N_LAYERS - 1][3] = Some(LayerKeyMap { pressed: None, held_press: mapping(KeyPress::LeftShift, None) });
+        layers[2][LOAD_CHUNK_ENTRIES] = Some(LayerKeyMap { pressed: None, held_press: None });
+        layers
+    }
+
+    // The layer types can't be compared, their serialized form is
+    fn encoded(layers: &Layers<N>) -> [u8; LEN] {
+        let mut buf = [0; LEN];
+        assert_eq!(encode_key_map(layers, &mut buf).unwrap(), LEN);
+        buf
+    }
+
+    fn entry_offset(layer: usize, key: usize) -> usize {
+        KEY_MAP_HEADER_LEN + (layer * N + key) * KEY_MAP_ENTRY_LEN
+    }
+
+    // Fixes up the CRC after the entries have been changed
+    fn reseal(buf: &mut [u8]) {
+        let crc = crc32_finish(crc32_update(CRC32_INIT, &buf[KEY_MAP_HEADER_LEN..LEN]));
+        buf[6..10].copy_from_slice(&crc.to_be_bytes());
+    }
+
+    struct MemStorage {
+        mem: [u8; 2 * LEN],
+        fail_reads: bool,
+    }
+
+    impl MemStorage {
+        fn new() -> Self {
+            MemStorage { mem: [0xFF; 2 * LEN], fail_reads: false }
+        }
+    }
+
+    impl ReadStorage for MemStorage {
+        type Error = ();
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), ()> {
+            if self.fail_reads {
+                return Err(());
+            }
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.mem[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    impl Storage for MemStorage {
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), ()> {
+            let offset = offset as usize;
+            self.mem[offset..offset + bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn key_maps_are_stored_in_the_documented_format() {
+        let buf = encoded(&layers());
+        assert_eq!(buf[..6], [b'K', b'M', 1, N_LAYERS as u8, 0, N as u8]);
+        assert_eq!(u32::from_be_bytes([buf[6], buf[7], buf[8], buf[9]]), crc32_finish(crc32_update(CRC32_INIT, &buf[10..])));
+        let entry = |layer, key| &buf[entry_offset(layer, key)..entry_offset(layer, key) + KEY_MAP_ENTRY_LEN];
+        assert_eq!(entry(0, 0), [0x03, 0x00, 0x14, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(entry(0, N - 1), [0x07, 0x00, 0x04, 0xE1, 0x01, 0x08, 0x00]);
+        assert_eq!(entry(N_LAYERS - 1, 3), [0x05, 0x00, 0x00, 0x00, 0x00, 0xE1, 0x00]);
+        assert_eq!(entry(2, LOAD_CHUNK_ENTRIES), [0x01, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(entry(1, 0), [0; KEY_MAP_ENTRY_LEN]);
+
+        let mut small = [0; LEN - 1];
+        assert!(matches!(encode_key_map(&layers(), &mut small), Err(KeyMapError::BufferTooSmall)));
+    }
+
+    #[test]
+    fn key_maps_round_trip() {
+        let mut decoded = [[None; N]; N_LAYERS];
+        decode_key_map(&encoded(&layers()), &mut decoded).unwrap();
+        assert_eq!(encoded(&decoded), encoded(&layers()));
+
+        let mut storage = MemStorage::new();
+        let mut buf = [0; LEN];
+        save_key_map(&mut storage, LEN as u32, &layers(), &mut buf).unwrap();
+        let mut loaded = [[None; N]; N_LAYERS];
+        load_key_map(&mut storage, LEN as u32, &mut loaded).unwrap();
+        assert_eq!(encoded(&loaded), encoded(&layers()));
+    }
+
+    // Every failure is checked for both ways of reading a key-map, and
+    // leaves the layers as they were
+    fn check_rejected(buf: &[u8], expected: fn(&KeyMapError<()>) -> bool) {
+        let before = [[None; N]; N_LAYERS];
+        let mut decoded = before;
+        let result = decode_key_map(buf, &mut decoded);
+        assert!(result.as_ref().is_err_and(expected), "decoded: {:?}", result);
+        assert_eq!(encoded(&decoded), encoded(&before));
+
+        let mut storage = MemStorage::new();
+        storage.mem[..buf.len()].copy_from_slice(buf);
+        let mut loaded = before;
+        let result = load_key_map(&mut storage, 0, &mut loaded);
+        assert!(result.as_ref().is_err_and(expected), "loaded: {:?}", result);
+        assert_eq!(encoded(&loaded), encoded(&before));
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let good = encoded(&layers());
+        // Nothing stored yet
+        check_rejected(&[0xFF; LEN], |e| matches!(e, KeyMapError::Missing));
+        let mut buf = good;
+        buf[1] = b'X';
+        check_rejected(&buf, |e| matches!(e, KeyMapError::Missing));
+        let mut buf = good;
+        buf[2] = KEY_MAP_FORMAT_VERSION + 1;
+        check_rejected(&buf, |e| matches!(e, KeyMapError::UnsupportedVersion(v) if *v == KEY_MAP_FORMAT_VERSION + 1));
+        let mut buf = good;
+        buf[3] = N_LAYERS as u8 - 1;
+        check_rejected(&buf, |e| matches!(e, KeyMapError::WrongShape));
+        let mut buf = good;
+        buf[5] = N as u8 + 1;
+        check_rejected(&buf, |e| matches!(e, KeyMapError::WrongShape));
+
+        // A key-map stored for another number of keys
+        let mut other = [[None; N]; N_LAYERS];
+        let mut small = [0; key_map_len(N - 1)];
+        encode_key_map::<{ N - 1 }>(&[[None; N - 1]; N_LAYERS], &mut small).unwrap();
+        let result = decode_key_map(&small, &mut other);
+        assert!(matches!(result, Err(KeyMapError::WrongShape)));
+    }
+
+    #[test]
+    fn corrupt_entries_are_rejected() {
+        let good = encoded(&layers());
+        // Any changed bit of the entries or the CRC
+        for byte in [6, 9, KEY_MAP_HEADER_LEN, entry_offset(2, LOAD_CHUNK_ENTRIES) + 3, LEN - 1] {
+            let mut buf = good;
+            buf[byte] ^= 0x10;
+            check_rejected(&buf, |e| matches!(e, KeyMapError::BadCrc));
+        }
+        // An interrupted save
+        let mut buf = good;
+        buf[LEN / 2..].fill(0xFF);
+        check_rejected(&buf, |e| matches!(e, KeyMapError::BadCrc));
+
+        // Unknown key and modifier codes, with a matching CRC
+        for (byte, value) in [(1, 0x7F), (3, 0x01), (5, 0xFF)] {
+            let mut buf = good;
+            buf[entry_offset(0, N - 1) + byte] = value;
+            reseal(&mut buf);
+            check_rejected(&buf, |e| matches!(e, KeyMapError::BadEntry));
+        }
+        // The bytes of an absent mapping are not looked at
+        let mut buf = good;
+        buf[entry_offset(0, 0) + 4] = 0xFF;
+        reseal(&mut buf);
+        let mut decoded = [[None; N]; N_LAYERS];
+        decode_key_map(&buf, &mut decoded).unwrap();
+        assert_eq!(encoded(&decoded), good);
+    }
+
+    #[test]
+    fn short_buffers_are_rejected() {
+        let good = encoded(&layers());
+        let mut decoded = [[None; N]; N_LAYERS];
+        assert!(matches!(decode_key_map(&good[..KEY_MAP_HEADER_LEN - 1], &mut decoded), Err(KeyMapError::Missing)));
+        assert!(matches!(decode_key_map(&good[..LEN - 1], &mut decoded), Err(KeyMapError::BufferTooSmall)));
+        assert_eq!(encoded(&decoded), encoded(&[[None; N]; N_LAYERS]));
+    }
+
+    #[test]
+    fn storage_errors_are_passed_on() {
+        let mut storage = MemStorage::new();
+        let mut buf = [0; LEN];
+        save_key_map(&mut storage, 0, &layers(), &mut buf).unwrap();
+        storage.fail_reads = true;
+        let mut loaded = [[None; N]; N_LAYERS];
+        assert!(matches!(load_key_map(&mut storage, 0, &mut loaded), Err(KeyMapError::Storage(()))));
+        let mut small = [0; LEN - 1];
+        let result = save_key_map(&mut storage, 0, &layers(), &mut small);
+        assert!(matches!(result, Err(KeyMapError::BufferTooSmall)));
+    }
+}
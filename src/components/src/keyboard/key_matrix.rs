@@ -1,13 +1,97 @@
-
 use rp_pico::hal as hal;
 use core::convert::Infallible;
 use embedded_hal::digital::v2::{InputPin, OutputPin};
 
+use crate::keyboard::diagnostics::MatrixStats;
+
 pub const N_ROWS: usize = 3;
 pub const N_COLS: usize = 7;
 pub const N_KEYS : usize = N_COLS * N_ROWS;
 pub const PIN_SETTLE_TIME_US: u32 = 200;
 pub const DEBOUNCE_US: u32 = 20_000;
+// Bit set in the state mask returned by a matrix when a key press
+// was suppressed due to ghosting, the lower 21 bits hold the key states
+pub const GHOST_FLAG: u32 = 1 << 31;
+
+// A backend that reads the raw state of the key matrix
+pub trait MatrixScanner {
+    // Reads the raw, non-debounced, column states of every row.
+    // Bit n of a row is set if the key in column n is pressed
+    fn scan(&mut self, raw_rows: &mut [u32; N_ROWS]);
+
+    // Changes the time given to the pins to settle after a row is driven
+    fn set_settle_time_us(&mut self, settle_us: u32);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatrixTiming {
+    pub debounce_us: u32,
+    pub settle_us: u32,
+    // Builds with a diode per key can turn this off, see `KeyMatrix::set_anti_ghosting`
+    pub anti_ghosting: bool,
+}
+
+impl MatrixTiming {
+    pub const fn new() -> Self {
+        MatrixTiming {
+            debounce_us: DEBOUNCE_US,
+            settle_us: PIN_SETTLE_TIME_US,
+            anti_ghosting: true,
+        }
+    }
+}
+
+// Scans the matrix by driving the rows from the CPU, one row
+// at a time, busy-waiting for the pins to settle in between
+pub struct GpioScanner<'a, 'p, 't> {
+    row_pins: &'a mut [&'p mut dyn OutputPin<Error = Infallible>],
+    col_pins: &'a [&'p dyn InputPin<Error = Infallible>],
+    settle_us: u32,
+    timer: &'t hal::Timer,
+}
+
+impl<'a, 'p, 't> GpioScanner<'a, 'p, 't> {
+    pub fn new(
+        row_pins: &'a mut [&'p mut dyn OutputPin<Error = Infallible>],
+        col_pins: &'a [&'p dyn InputPin<Error = Infallible>],
+        timer: &'t hal::Timer,
+    ) -> Self {
+        Self {
+            row_pins,
+            col_pins,
+            settle_us: PIN_SETTLE_TIME_US,
+            timer,
+        }
+    }
+
+    // Returns the raw column states of a single row
+    pub fn scan_row(&mut self, row: usize) -> u32 {
+        self.row_pins[row].set_low().unwrap();
+        // Delay until pin has setteled
+        let t0 = self.timer.get_counter_low();
+        while self.timer.get_counter_low() - t0  < self.settle_us {
+            cortex_m::asm::nop();
+        }
+        let mut raw: u32 = 0;
+        for col in (0..self.col_pins.len()).into_iter() {
+            raw |= (self.col_pins[col].is_low().unwrap() as u32) << col;
+        }
+        self.row_pins[row].set_high().unwrap();
+        raw
+    }
+}
+
+impl<'a, 'p, 't> MatrixScanner for GpioScanner<'a, 'p, 't> {
+    fn scan(&mut self, raw_rows: &mut [u32; N_ROWS]) {
+        for row in (0..self.row_pins.len()).into_iter() {
+            raw_rows[row] = self.scan_row(row);
+        }
+    }
+
+    fn set_settle_time_us(&mut self, settle_us: u32) {
+        self.settle_us = settle_us;
+    }
+}
 
 pub struct KeyMatrix<'t> {
     // Hold the time a change event happended for
@@ -16,6 +100,17 @@ pub struct KeyMatrix<'t> {
     last_key_states: [bool; N_COLS * N_ROWS],
     // Hold the time the last row was polled
     last_polled: [u32; N_ROWS],
+    // Raw, non-debounced, column states of each row
+    // as seen during the most recent scan, and the scan before that
+    raw_rows: [u32; N_ROWS],
+    prev_raw_rows: [u32; N_ROWS],
+    // Mask of keys that read as pressed but were suppressed
+    // because they are part of an ambiguous key rectangle
+    suppressed: u32,
+    anti_ghosting: bool,
+    debounce_us: u32,
+    // Optional per-key switch statistics
+    stats: Option<MatrixStats>,
     timer: &'t hal::Timer,
 }
 
@@ -27,71 +122,134 @@ impl<'t> KeyMatrix<'t> {
             last_key_states: [false; N_COLS * N_ROWS],
             last_events: [timer.get_counter_low(); N_ROWS * N_COLS],
             last_polled: [timer.get_counter_low(); N_ROWS],
+            raw_rows: [0; N_ROWS],
+            prev_raw_rows: [0; N_ROWS],
+            suppressed: 0,
+            anti_ghosting: true,
+            debounce_us: DEBOUNCE_US,
+            stats: None,
+        }
+    }
+
+    // Starts collecting per-key statistics, continuing from the given
+    // statistics, e.g. ones previously persisted to the EEPROM
+    pub fn enable_stats(&mut self, stats: MatrixStats) {
+        self.stats = Some(stats);
+    }
+
+    pub fn disable_stats(&mut self) {
+        self.stats = None;
+    }
+
+    pub fn stats(&self) -> Option<&MatrixStats> {
+        self.stats.as_ref()
+    }
+
+    pub fn stats_mut(&mut self) -> Option<&mut MatrixStats> {
+        self.stats.as_mut()
+    }
+
+    pub fn set_debounce_us(&mut self, debounce_us: u32) {
+        self.debounce_us = debounce_us;
+    }
+
+    // Anti-ghosting is enabled by default. Builds with per-key diodes
+    // may disable it to allow four keys forming a rectangle to be pressed
+    pub fn set_anti_ghosting(&mut self, enabled: bool) {
+        self.anti_ghosting = enabled;
+        if !enabled {
+            self.suppressed = 0;
         }
     }
-    // Returns the current state of the pins
-    // as a 32-bit mask where each bit corresponds
-    // to a pin, in this case only the lowest 21 bits
+
+    // Returns true if any key press is currently being suppressed
+    // due to an ambiguous (ghosting) key pattern
+    pub fn is_ghosting(&self) -> bool {
+        self.suppressed != 0
+    }
+
+    // Scans the matrix using the given backend and returns the
+    // debounced state of all keys as a 32-bit mask where each bit
+    // corresponds to a key, in this case only the lowest 21 bits
     // are used
-    pub fn poll_row_states(
-        &mut self,
-        row_pins: &mut [&mut dyn OutputPin<Error = Infallible>],
-        col_pins: &[&dyn InputPin<Error = Infallible>],
-    ) -> u32  {
+    pub fn poll(&mut self, scanner: &mut impl MatrixScanner) -> u32 {
+        self.prev_raw_rows = self.raw_rows;
+        scanner.scan(&mut self.raw_rows);
+        let now = self.timer.get_counter_low();
+        self.last_polled = [now; N_ROWS];
+
         let mut key_states: u32 = 0;
-        for row in (0..row_pins.len()).into_iter() {
-            row_pins[row].set_low().unwrap();
-            // Delay until pin has setteled
-            let t0 = self.timer.get_counter_low();
-            while self.timer.get_counter_low() - t0 < PIN_SETTLE_TIME_US {
-                cortex_m::asm::nop();
-            }
-            let now = self.timer.get_counter_low();
-            for col in (0..col_pins.len()).into_iter() {
-                let id = col + row * N_COLS;
-                let key_state = col_pins[col].is_low().unwrap();
-                if key_state != self.last_key_states[id] &&
-                    now - self.last_events[id] > DEBOUNCE_US {
-                    self.last_events[id] = now;
-                    key_states |= (key_state as u32) << id;
-                    self.last_key_states[id] = key_state;
-                } else {
-                    key_states |= (self.last_key_states[id] as u32) << id;
-                }
-            }
-            row_pins[row].set_high().unwrap();
+        for row in (0..N_ROWS).into_iter() {
+            key_states |= self.debounce_row(row, now);
         }
         key_states
     }
 
-    pub fn poll_row_state(
-        &mut self,
-        row: usize,
-        row_pins: &mut [&mut dyn OutputPin<Error = Infallible>],
-        col_pins: &[&dyn InputPin<Error = Infallible>],
-    ) -> u32  {
+    // Debounces the raw column states of a single row and
+    // returns the resulting key states of that row
+    fn debounce_row(&mut self, row: usize, now: u32) -> u32 {
         let mut key_states: u32 = 0;
-        row_pins[row].set_low().unwrap();
-        // Delay until pin has setteled
-        let t0 = self.timer.get_counter_low();
-        while self.timer.get_counter_low() - t0  < PIN_SETTLE_TIME_US {
-            cortex_m::asm::nop();
-        }
-        let now = self.timer.get_counter_low();
+        let raw = self.raw_rows[row];
+        let ghost_cols = if self.anti_ghosting {
+            self.ghost_columns(row)
+        } else {
+            0
+        };
+
         // Loop through columns and check if key is pressed
-        for col in (0..col_pins.len()).into_iter() {
+        for col in (0..N_COLS).into_iter() {
             let id = col + row * N_COLS;
-            let key_state = col_pins[col].is_low().unwrap();
+            let key_state = (raw >> col) & 0x1 == 1;
+            self.suppressed &= !(1 << id);
             if key_state != self.last_key_states[id] &&
-                now - self.last_events[id] > DEBOUNCE_US {
+                now - self.last_events[id] > self.debounce_us {
+                // A new press that is a corner of a key rectangle can not
+                // be told apart from a phantom key, so it is held back
+                // until the pattern is no longer ambiguous
+                if key_state && (ghost_cols >> col) & 0x1 == 1 {
+                    self.suppressed |= 1 << id;
+                    continue;
+                }
+                if let Some(stats) = self.stats.as_mut() {
+                    if key_state {
+                        stats.record_press(id);
+                    } else {
+                        stats.record_release(id, now - self.last_events[id]);
+                    }
+                }
                 self.last_events[id] = now;
                 key_states |= (key_state as u32) << id;
                 self.last_key_states[id] = key_state;
             } else {
+                // The key changed state again within the debounce window of
+                // its last accepted change, count each such edge as chatter
+                let prev_state = (self.prev_raw_rows[row] >> col) & 0x1 == 1;
+                if key_state != self.last_key_states[id] && key_state != prev_state {
+                    if let Some(stats) = self.stats.as_mut() {
+                        stats.record_chatter(id);
+                    }
+                }
                 key_states |= (self.last_key_states[id] as u32) << id;
             }
         }
-        row_pins[row].set_high().unwrap();
         key_states
     }
+
+    // Without diodes, pressing three corners of a rectangle in the matrix
+    // makes the fourth corner read as pressed. A rectangle exists whenever
+    // two rows share two or more pressed columns. Returns the mask of columns
+    // in the given row that are corners of such a rectangle
+    fn ghost_columns(&self, row: usize) -> u32 {
+        let mut cols: u32 = 0;
+        for other in (0..N_ROWS).into_iter() {
+            if other == row {
+                continue;
+            }
+            let shared = self.raw_rows[row] & self.raw_rows[other];
+            if shared.count_ones() >= 2 {
+                cols |= shared;
+            }
+        }
+        cols
+    }
 }
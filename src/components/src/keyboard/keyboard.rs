@@ -6,6 +6,7 @@ use hid::page::Keyboard;
 use heapless::spsc::Queue;
 
 use crate::keyboard::types::*;
+use crate::keyboard::layout::KeyId;
 
 const N_KEYS: usize = 21;
 
@@ -15,6 +16,19 @@ pub const HOLD_PRESS_MIN_US: u32 = 500_000;
 pub const HOLD_PRESS_PERIOD: u32 = 50_000;
 pub const N_LAYERS: usize = 5;
 
+// Report telling the host that the current key state could not be resolved,
+// e.g. when the matrix suppressed a ghosted key press. Every key slot holds
+// ErrorRollOver and nothing else is reported
+pub const ERROR_ROLL_OVER_REPORT: [Keyboard; 32] = [Keyboard::ErrorRollOver; 32];
+
+// A change of key-map profile asked for by a key. The profiles
+// themselves are kept by whoever owns the keyboard
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileRequest {
+    Next,
+    Set(usize),
+}
+
 
 pub struct LayeredKeyboard<'t, 'q, const N: usize> {
     report_tx: Producer<'q, Keyboard, 32>,
@@ -25,14 +39,17 @@ pub struct LayeredKeyboard<'t, 'q, const N: usize> {
     // Id of the any currently held key with a held press mapping
     // If multiple such keys are held then only the id of the most recent
     // is stored in this option
-    held_key: Option<usize>,
-    double_key : Option<usize>,
+    held_key: Option<KeyId>,
+    double_key : Option<KeyId>,
     // Array of timestamps of when each key was last pressed
     last_press_t: [u32; N],
     timer: &'t hal::Timer,
     pub layer: usize,
     last_layer: usize,
     is_layer_held: bool,
+    // Set when a mapping is changed, until taken by whoever stores the key-map
+    key_map_changed: bool,
+    profile_request: Option<ProfileRequest>,
 }
 
 impl<'t, 'q, const N: usize> LayeredKeyboard <'t,'q, N> {
@@ -54,6 +71,8 @@ impl<'t, 'q, const N: usize> LayeredKeyboard <'t,'q, N> {
             last_press_t: [timer.get_counter_low(); N],
             last_state_b: [false; N],
             is_layer_held: false,
+            key_map_changed: false,
+            profile_request: None,
         }
     }
 
@@ -66,19 +85,85 @@ impl<'t, 'q, const N: usize> LayeredKeyboard <'t,'q, N> {
         
     }
 
-    fn get_key_map(&self, id: usize) -> Option<LayerKeyMap> {
-        self.layers[self.layer][id]
+    fn get_key_map(&self, id: KeyId) -> Option<LayerKeyMap> {
+        self.layers[self.layer][id.index()]
+    }
+
+    // Returns the mapping of a key on the given layer
+    pub fn key_map(&self, layer: usize, id: KeyId) -> Option<LayerKeyMap> {
+        self.layers[layer][id.index()]
+    }
+
+    // Changes the mapping of a key on the given layer
+    pub fn set_key_map(&mut self, layer: usize, id: KeyId, key_map: Option<LayerKeyMap>) {
+        self.layers[layer][id.index()] = key_map;
+        self.key_map_changed = true;
+    }
+
+    pub fn layers(&self) -> &[[Option<LayerKeyMap>; N]; N_LAYERS] {
+        &self.layers
+    }
+
+    // Returns true once after any mapping has been changed
+    pub fn take_key_map_changed(&mut self) -> bool {
+        core::mem::replace(&mut self.key_map_changed, false)
+    }
+
+    // Replaces the whole key-map, e.g. when switching profile. The
+    // keyboard returns to the base layer and held keys are dropped
+    pub fn set_layers(&mut self, layers: [[Option<LayerKeyMap>; N]; N_LAYERS]) {
+        self.layers = layers;
+        self.layer = 0;
+        self.last_layer = 0;
+        self.is_layer_held = false;
+        self.held_key = None;
+        self.double_key = None;
+        self.key_states = [KeyState::None; N];
+        self.key_map_changed = false;
+    }
+
+    // Moves to a layer, as a key setting the layer would
+    pub fn set_layer(&mut self, layer: usize) {
+        self.layer = layer;
+        self.last_layer = layer;
+        self.key_states = [KeyState::None; N];
+    }
+
+    // Asks for a change of profile, as a profile key would
+    pub fn request_profile(&mut self, request: ProfileRequest) {
+        self.profile_request = Some(request);
+    }
+
+    pub fn take_profile_request(&mut self) -> Option<ProfileRequest> {
+        self.profile_request.take()
+    }
+
+    // Feeds a single key press or release, that happened at time `t`,
+    // to the keyboard. Used for keys whose changes are reported as
+    // timestamped events rather than as part of the pin states, the
+    // change is picked up by the next call to `get_report`
+    pub fn key_changed(&mut self, id: KeyId, pressed: bool, t: u32) {
+        let i = id.index();
+        if pressed == self.last_state_b[i] {
+            return;
+        }
+        if !pressed {
+            self.key_released(id, t);
+        } else if t.wrapping_sub(self.last_press_t[i]) > DEBOUNCE_US {
+            self.last_press_t[i] = t;
+            self.key_pressed(id);
+        }
+        self.last_state_b[i] = pressed;
     }
 
     // Function that gets run each time a key gets pressed
-    fn key_pressed(&mut self, id: usize) {
-        self.key_states[id] = KeyState::FirstPress;
+    fn key_pressed(&mut self, id: KeyId) {
+        self.key_states[id.index()] = KeyState::FirstPress;
     }
 
     // Function that gets run each time a key is released
-    fn key_released(&mut self, id: usize) {
+    fn key_released(&mut self, id: KeyId, now: u32) {
         let key_map = self.get_key_map(id);
-        let now = self.timer.get_counter_low();
 
         if self.held_key.is_some() {
             let held_id = self.held_key.unwrap();
@@ -90,15 +175,21 @@ impl<'t, 'q, const N: usize> LayeredKeyboard <'t,'q, N> {
         // Buttons with held press mapping fire their key pressed
         // when released rather than on the press if the total key
         // down time is less than than the minimum held press time.
+        let state = self.key_states[id.index()];
         if key_map.is_some() && key_map.unwrap().held_press.is_some() && (
-            self.key_states[id] == KeyState::Pressed || self.key_states[id] == KeyState::FirstPress) {
-            if now - self.last_press_t[id] < HOLD_PRESS_MIN_US  {
+            state == KeyState::Pressed || state == KeyState::FirstPress) {
+            if now - self.last_press_t[id.index()] < HOLD_PRESS_MIN_US  {
                 key_map.unwrap().pressed.into_keyboard_iter()
                     .filter(|r| *r != Keyboard::NoEventIndicated)
                     .for_each(|r| {let _ = self.report_tx.enqueue(r); });
             }
+        } else if state == KeyState::FirstPress {
+            // The key was pressed and released again before the press was
+            // processed, which happens when both changes are reported in the
+            // same batch of events, fire the press so it is not lost
+            let _ = self.event_queue.enqueue(KeyEvent::Pressed(id));
         }
-        self.key_states[id] = KeyState::None;
+        self.key_states[id.index()] = KeyState::None;
 
     }
 
@@ -110,27 +201,19 @@ impl<'t, 'q, const N: usize> LayeredKeyboard <'t,'q, N> {
         let mut is_pressed: bool;
         let now: u32 = self.timer.get_counter_low();
 
-        for id in (0..N).into_iter() {
-            is_pressed = (pin_states >> id) & 0x1 == 1;
-            if !is_pressed {
-                if self.last_state_b[id] {
-                    self.key_released(id);
-                }
-            } else {
-                if !self.last_state_b[id] && now - self.last_press_t[id] > DEBOUNCE_US {
-                    self.last_press_t[id] = now;
-                    self.key_pressed(id);
-                }
-            }
+        for id in (0..N).into_iter().map(KeyId::from_index) {
+            is_pressed = (pin_states >> id.index()) & 0x1 == 1;
+            self.key_changed(id, is_pressed, now);
         }
         self.update_last_state(pin_states);
 
         let now = self.timer.get_counter_low();
 
         // Processes the map for layer events
-        for id in (0..self.key_states.len()).into_iter() {
+        for id in (0..self.key_states.len()).into_iter().map(KeyId::from_index) {
             // Map key states to key events that will then get mapped to key-presses
-            let state = self.key_states[id];
+            let i = id.index();
+            let state = self.key_states[i];
 
             let key_map = match self.get_key_map(id) {
                 Some(m) => m,
@@ -138,8 +221,8 @@ impl<'t, 'q, const N: usize> LayeredKeyboard <'t,'q, N> {
                     if self.layer == 0 {
                         continue;
                     }
-                    if self.layers[0][id].is_some() {
-                        self.layers[0][id].unwrap()
+                    if self.layers[0][i].is_some() {
+                        self.layers[0][i].unwrap()
                     } else {
                         continue;
                     }
@@ -156,7 +239,7 @@ impl<'t, 'q, const N: usize> LayeredKeyboard <'t,'q, N> {
                     // the newly pressed key
                     if self.held_key.is_some() && self.held_key.unwrap() != id {
                         let held_id = self.held_key.unwrap();
-                        self.key_states[held_id] = KeyState::HeldPressed;
+                        self.key_states[held_id.index()] = KeyState::HeldPressed;
                         let _ = self.event_queue.enqueue(KeyEvent::HeldPress(held_id));
                         self.held_key = None;
                     } else {
@@ -176,12 +259,12 @@ impl<'t, 'q, const N: usize> LayeredKeyboard <'t,'q, N> {
 
             // Transistion key state to held press if it has been held for longer
             // than the set hold press time, else set the key state to pressed
-            if state == KeyState::Pressed && now - self.last_press_t[id] >= HOLD_PRESS_MIN_US {
-                self.key_states[id] = KeyState::HeldPressed;
+            if state == KeyState::Pressed && now - self.last_press_t[i] >= HOLD_PRESS_MIN_US {
+                self.key_states[i] = KeyState::HeldPressed;
             }
 
             if state == KeyState::FirstPress {
-                self.key_states[id] = KeyState::Pressed;
+                self.key_states[i] = KeyState::Pressed;
             }
         }
 
@@ -191,7 +274,7 @@ impl<'t, 'q, const N: usize> LayeredKeyboard <'t,'q, N> {
 
             let (id, mapping) = match event {
                 KeyEvent::Pressed(id) => {
-                    let key_map = match self.layers[self.layer][id] {
+                    let key_map = match self.get_key_map(id) {
                         Some(m) => m,
                         None => {
                             // If you are not on the base layer,
@@ -200,8 +283,8 @@ impl<'t, 'q, const N: usize> LayeredKeyboard <'t,'q, N> {
                             if self.layer == 0 {
                                 continue;
                             }
-                            if self.layers[0][id].is_some() {
-                                self.layers[0][id].unwrap()
+                            if self.layers[0][id.index()].is_some() {
+                                self.layers[0][id.index()].unwrap()
                             } else {
                                 continue;
                             }
@@ -214,14 +297,14 @@ impl<'t, 'q, const N: usize> LayeredKeyboard <'t,'q, N> {
                     }
                 }
                 KeyEvent::HeldPress(id) => {
-                    let key_map = match self.layers[self.layer][id] {
+                    let key_map = match self.get_key_map(id) {
                         Some(m) => m,
                         None => {
                             if self.layer == 0 {
                                 continue;
                             }
-                            if self.layers[0][id].is_some() {
-                                self.layers[0][id].unwrap()
+                            if self.layers[0][id.index()].is_some() {
+                                self.layers[0][id.index()].unwrap()
                             } else {
                                 continue;
                             }
@@ -259,13 +342,21 @@ impl<'t, 'q, const N: usize> LayeredKeyboard <'t,'q, N> {
                         if !self.is_layer_held {
                             self.last_layer = self.layer;
                             self.key_states = [KeyState::None; N];
-                            self.key_states[id] = KeyState::Pressed;
+                            self.key_states[id.index()] = KeyState::Pressed;
                         }
                         self.layer = held_layer;
                     }
                     is_layer_held = true;
                     continue;
                 }
+                KeyPress::ProfileNext => {
+                    self.profile_request = Some(ProfileRequest::Next);
+                    continue;
+                }
+                KeyPress::ProfileSet0 | KeyPress::ProfileSet1 | KeyPress::ProfileSet2 | KeyPress::ProfileSet3 => {
+                    self.profile_request = Some(ProfileRequest::Set(mapping.key as usize - 0x10D));
+                    continue;
+                }
                 _ => {}
             }
             // Maps the key state into a series of HID key events
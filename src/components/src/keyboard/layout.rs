@@ -0,0 +1,295 @@
+/*
+* Description of the physical layout of the keyboard, and the
+* identifiers used to address each physical key.
+*
+* Every key has a unique `KeyId`. The keys of the left half matrix come
+* first, followed by the keys of the right half matrix, then the extra
+* buttons and finally the rotary encoder directions:
+*
+*   0..21   Left half matrix, row-major
+*   21..42  Right half matrix, row-major
+*   42..46  Extra buttons
+*   46..48  Encoder, clockwise and counter-clockwise
+*
+* Key-maps cover the matrix keys only, the extra buttons and the encoder
+* are described but not read by the firmware yet
+*/
+use crate::keyboard::key_matrix::{N_COLS, N_KEYS, N_ROWS};
+
+pub const N_HALVES: usize = 2;
+pub const N_MATRIX_KEYS: usize = N_HALVES * N_KEYS;
+pub const N_EXTRA_BUTTONS: usize = 4;
+// The encoder is addressed as one key per rotation direction
+pub const N_ENCODER_KEYS: usize = 2;
+pub const N_KEY_IDS: usize = N_MATRIX_KEYS + N_EXTRA_BUTTONS + N_ENCODER_KEYS;
+
+const BUTTONS_START: usize = N_MATRIX_KEYS;
+const ENCODER_START: usize = BUTTONS_START + N_EXTRA_BUTTONS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Half {
+    Left,
+    Right,
+}
+
+impl Half {
+    // Offset of the first key of the half in the combined key state
+    pub const fn offset(self) -> usize {
+        match self {
+            Half::Left => 0,
+            Half::Right => N_KEYS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPosition {
+    Matrix { half: Half, row: u8, col: u8 },
+    Button { half: Half, index: u8 },
+    Encoder { half: Half, clockwise: bool },
+}
+
+impl KeyPosition {
+    pub const fn half(self) -> Half {
+        match self {
+            KeyPosition::Matrix { half, .. } => half,
+            KeyPosition::Button { half, .. } => half,
+            KeyPosition::Encoder { half, .. } => half,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KeyId(u8);
+
+impl KeyId {
+    // Returns None if the index does not belong to a physical key
+    pub const fn new(index: usize) -> Option<Self> {
+        if index < N_KEY_IDS {
+            Some(KeyId(index as u8))
+        } else {
+            None
+        }
+    }
+
+    // Same as `new` but only checking the index in debug builds, for
+    // use where the index is known to be within range
+    pub const fn from_index(index: usize) -> Self {
+        debug_assert!(index < N_KEY_IDS);
+        KeyId(index as u8)
+    }
+
+    pub const fn from_matrix(half: Half, row: usize, col: usize) -> Self {
+        KeyId((half.offset() + row * N_COLS + col) as u8)
+    }
+
+    pub const fn from_position(position: KeyPosition) -> Self {
+        match position {
+            KeyPosition::Matrix { half, row, col } => {
+                KeyId::from_matrix(half, row as usize, col as usize)
+            }
+            KeyPosition::Button { index, .. } => KeyId((BUTTONS_START + index as usize) as u8),
+            KeyPosition::Encoder { clockwise, .. } => {
+                KeyId((ENCODER_START + !clockwise as usize) as u8)
+            }
+        }
+    }
+
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    pub const fn position(self) -> KeyPosition {
+        let index = self.0 as usize;
+        if index < N_MATRIX_KEYS {
+            let half = if index < N_KEYS { Half::Left } else { Half::Right };
+            let local = index - half.offset();
+            KeyPosition::Matrix {
+                half,
+                row: (local / N_COLS) as u8,
+                col: (local % N_COLS) as u8,
+            }
+        } else if index < ENCODER_START {
+            let button = index - BUTTONS_START;
+            KeyPosition::Button {
+                half: BUTTON_HALVES[button],
+                index: button as u8,
+            }
+        } else {
+            KeyPosition::Encoder {
+                half: ENCODER_HALF,
+                clockwise: index == ENCODER_START,
+            }
+        }
+    }
+
+    pub const fn half(self) -> Half {
+        self.position().half()
+    }
+
+    // Index of the key within the matrix of its half, which is also the
+    // bit of the key in the state mask read from that half and the index
+    // of the LED under the key. None for keys outside the matrix
+    pub const fn matrix_index(self) -> Option<usize> {
+        match self.position() {
+            KeyPosition::Matrix { row, col, .. } => Some(row as usize * N_COLS + col as usize),
+            _ => None,
+        }
+    }
+
+    pub const fn is_matrix_key(self) -> bool {
+        (self.0 as usize) < N_MATRIX_KEYS
+    }
+}
+
+impl From<KeyId> for usize {
+    fn from(id: KeyId) -> usize {
+        id.index()
+    }
+}
+
+// Returns an iterator over the ids of all matrix keys of a half
+pub fn matrix_keys(half: Half) -> impl Iterator<Item = KeyId> {
+    (half.offset()..half.offset() + N_KEYS).map(KeyId::from_index)
+}
+
+// Combines the key state masks read from each half into a single
+// mask where bit n holds the state of the key with id n
+pub fn combine_halves(left: u32, right: u32) -> u64 {
+    ((left as u64) << Half::Left.offset()) | ((right as u64) << Half::Right.offset())
+}
+
+// The half each extra button, and the encoder, are located on
+const BUTTON_HALVES: [Half; N_EXTRA_BUTTONS] = [Half::Left, Half::Left, Half::Right, Half::Right];
+const ENCODER_HALF: Half = Half::Left;
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyDescription {
+    pub id: KeyId,
+    pub position: KeyPosition,
+    // Optional physical position of the key center, in quarter key units
+    // from the top left corner of the board. The halves are drawn side by
+    // side, `HALF_GAP` apart. The placement of the extra buttons and the
+    // encoder differs between builds, so they have none
+    pub x: Option<u16>,
+    pub y: Option<u16>,
+}
+
+pub struct BoardLayout {
+    pub halves: u8,
+    pub rows: u8,
+    pub cols: u8,
+    pub extra_buttons: u8,
+    pub has_encoder: bool,
+    pub keys: [KeyDescription; N_KEY_IDS],
+}
+
+impl BoardLayout {
+    pub fn key(&self, id: KeyId) -> &KeyDescription {
+        &self.keys[id.index()]
+    }
+}
+
+// Space between the halves, in quarter key units
+const HALF_GAP: u16 = 4;
+
+const fn describe_keys() -> [KeyDescription; N_KEY_IDS] {
+    let mut keys = [KeyDescription {
+        id: KeyId(0),
+        position: KeyPosition::Matrix { half: Half::Left, row: 0, col: 0 },
+        x: None,
+        y: None,
+    }; N_KEY_IDS];
+    let mut i = 0;
+    while i < N_KEY_IDS {
+        let id = KeyId(i as u8);
+        keys[i].id = id;
+        keys[i].position = id.position();
+        // The columns of both halves run from left to right
+        if let KeyPosition::Matrix { half, row, col } = keys[i].position {
+            let left = match half {
+                Half::Left => 0,
+                Half::Right => 4 * N_COLS as u16 + HALF_GAP,
+            };
+            keys[i].x = Some(left + 4 * col as u16 + 2);
+            keys[i].y = Some(4 * row as u16 + 2);
+        }
+        i += 1;
+    }
+    keys
+}
+
+pub const KALLISTO_LAYOUT: BoardLayout = BoardLayout {
+    halves: N_HALVES as u8,
+    rows: N_ROWS as u8,
+    cols: N_COLS as u8,
+    extra_buttons: N_EXTRA_BUTTONS as u8,
+    has_encoder: true,
+    keys: describe_keys(),
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_and_positions_match() {
+        for i in 0..N_KEY_IDS {
+            let id = KeyId::new(i).unwrap();
+            assert_eq!(KeyId::from_position(id.position()), id);
+            assert_eq!(KALLISTO_LAYOUT.key(id).id, id);
+            assert_eq!(KALLISTO_LAYOUT.key(id).position, id.position());
+        }
+        assert_eq!(KeyId::new(N_KEY_IDS), None);
+        assert_eq!(KeyId::from_matrix(Half::Right, 1, 2).index(), N_KEYS + N_COLS + 2);
+        assert_eq!(KeyId::from_index(N_MATRIX_KEYS).position(), KeyPosition::Button { half: Half::Left, index: 0 });
+        assert_eq!(
+            KeyId::from_index(N_KEY_IDS - 1).position(),
+            KeyPosition::Encoder { half: Half::Left, clockwise: false }
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn ids_out_of_range_are_caught_in_debug_builds() {
+        KeyId::from_index(N_KEY_IDS);
+    }
+
+    #[test]
+    fn only_matrix_keys_have_a_matrix_index() {
+        for half in [Half::Left, Half::Right] {
+            let indices: Vec<usize> = matrix_keys(half).map(|id| id.matrix_index().unwrap()).collect();
+            assert_eq!(indices, (0..N_KEYS).collect::<Vec<_>>());
+            assert!(matrix_keys(half).all(|id| id.is_matrix_key() && id.half() == half));
+        }
+        for i in N_MATRIX_KEYS..N_KEY_IDS {
+            assert_eq!(KeyId::from_index(i).matrix_index(), None);
+            assert!(!KeyId::from_index(i).is_matrix_key());
+        }
+    }
+
+    #[test]
+    fn matrix_keys_are_placed_on_a_grid() {
+        let place = |half, row, col| {
+            let key = KALLISTO_LAYOUT.key(KeyId::from_matrix(half, row, col));
+            (key.x.unwrap(), key.y.unwrap())
+        };
+        assert_eq!(place(Half::Left, 0, 0), (2, 2));
+        assert_eq!(place(Half::Left, 2, 6), (26, 10));
+        // The right half starts a key to the right of the left half
+        assert_eq!(place(Half::Right, 0, 0), (34, 2));
+        assert_eq!(place(Half::Right, 1, 6), (58, 6));
+        for i in N_MATRIX_KEYS..N_KEY_IDS {
+            let key = KALLISTO_LAYOUT.key(KeyId::from_index(i));
+            assert_eq!((key.x, key.y), (None, None));
+        }
+    }
+
+    #[test]
+    fn halves_are_combined_in_id_order() {
+        let left_key = KeyId::from_matrix(Half::Left, 2, 3);
+        let right_key = KeyId::from_matrix(Half::Right, 0, 1);
+        let state = combine_halves(1 << left_key.matrix_index().unwrap(), 1 << right_key.matrix_index().unwrap());
+        assert_eq!(state, (1 << left_key.index()) | (1 << right_key.index()));
+    }
+}
@@ -1,3 +1,7 @@
+pub mod diagnostics;
 pub mod key_matrix;
+pub mod layout;
+pub mod pio_matrix;
 pub mod types;
 pub mod keyboard;
+pub mod key_map_store;
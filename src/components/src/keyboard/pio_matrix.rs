@@ -0,0 +1,228 @@
+/*
+* Key matrix scanning offloaded to a PIO state machine.
+*
+* The state machine drives one row low at a time, waits for the pins to
+* settle and samples all columns at once. Each word fed to it holds the
+* row pattern in the lower half and the settle time in the upper half. Row patterns are fed to the
+* state machine by one DMA channel reading from a ring buffer, and the
+* sampled columns are written by a second DMA channel into another ring
+* buffer. Both rings have the same length and advance in lock-step, so
+* slot n of the sample ring always holds the latest sample of row n.
+* The CPU only has to read the sample ring.
+*/
+use rp_pico::hal as hal;
+use rp_pico::pac;
+use hal::dma::{Channel, ChannelIndex};
+use hal::pio::{
+    PIOBuilder, PIOExt, PinDir, Running, Rx, ShiftDirection, StateMachine,
+    StateMachineIndex, Tx, UninitStateMachine, PIO,
+};
+
+use crate::keyboard::key_matrix::{MatrixScanner, N_COLS, N_ROWS, PIN_SETTLE_TIME_US};
+
+// The ring buffers hold one slot per row plus one idle slot
+// where no row is driven, to make the ring a power of two
+const RING_LEN: usize = 4;
+// Ring size as log2 of the ring length in bytes
+const RING_SIZE_BITS: u8 = 4;
+// Frequency the state machine is clocked at, one cycle per microsecond
+const PIO_FREQ_HZ: u32 = 1_000_000;
+// Bits of a ring word holding the row pattern, the settle
+// time in microseconds is held in the bits above
+const PATTERN_BITS: u32 = 16;
+const PATTERN_MASK: u32 = (1 << PATTERN_BITS) - 1;
+// The DMA channels are re-armed when they have less than
+// this many transfers left
+const DMA_REARM_THRESHOLD: u32 = 0x1000;
+
+#[repr(C, align(16))]
+pub struct ScanBuffer(pub [u32; RING_LEN]);
+
+impl ScanBuffer {
+    pub const fn new() -> Self {
+        ScanBuffer([0; RING_LEN])
+    }
+}
+
+pub struct PioScanner<P: PIOExt, SM: StateMachineIndex, TC: ChannelIndex, RC: ChannelIndex> {
+    _sm: StateMachine<(P, SM), Running>,
+    _rx: Rx<(P, SM)>,
+    _tx: Tx<(P, SM)>,
+    _tx_ch: Channel<TC>,
+    _rx_ch: Channel<RC>,
+    // Row patterns written to the row pins, relative to the row pin
+    // base, along with the settle time
+    patterns: &'static mut ScanBuffer,
+    // Sampled column states, written by DMA
+    samples: &'static mut ScanBuffer,
+    // The columns are sampled in GPIO order, if the column pins are
+    // wired in descending GPIO order the sampled bits need to be reversed
+    cols_reversed: bool,
+}
+
+impl<P, SM, TC, RC> PioScanner<P, SM, TC, RC>
+where
+    P: PIOExt,
+    SM: StateMachineIndex,
+    TC: ChannelIndex,
+    RC: ChannelIndex,
+{
+    // Sets up the state machine and DMA channels and starts scanning.
+    // `row_pins` holds the GPIO number of each row, the row pins must
+    // all lie within 16 pins of the lowest one. The column pins must be
+    // consecutive GPIOs starting at `col_base`. All row and column pins
+    // must already be set to the function of the given PIO block, with
+    // the column pins pulled up
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        tx_ch: Channel<TC>,
+        rx_ch: Channel<RC>,
+        row_pins: [u8; N_ROWS],
+        col_base: u8,
+        cols_reversed: bool,
+        patterns: &'static mut ScanBuffer,
+        samples: &'static mut ScanBuffer,
+        sys_freq: u32,
+    ) -> Self {
+        let program = pio_proc::pio_asm!(
+            ".wrap_target",
+            "    pull block",
+            // Drive the row pattern, the active row is low
+            "    out pins, 16",
+            // Wait one cycle per microsecond of settle time
+            "    out x, 16",
+            "settle:",
+            "    jmp x-- settle",
+            "    in pins, 7",
+            "    push block",
+            ".wrap",
+        );
+
+        let row_base = *row_pins.iter().min().unwrap();
+        let row_count = row_pins.iter().max().unwrap() - row_base + 1;
+        let all_high: u32 = row_pins
+            .iter()
+            .fold(0, |acc, p| acc | (1 << (p - row_base)));
+        let settle = settle_count(PIN_SETTLE_TIME_US);
+        for (row, pin) in row_pins.iter().enumerate() {
+            patterns.0[row] = settle | all_high & !(1 << (pin - row_base));
+        }
+        patterns.0[RING_LEN - 1] = settle | all_high;
+        samples.0 = [0; RING_LEN];
+
+        let installed = pio.install(&program.program).unwrap();
+        let (mut sm, rx, tx) = PIOBuilder::from_program(installed)
+            .out_pins(row_base, row_count)
+            .in_pin_base(col_base)
+            .out_shift_direction(ShiftDirection::Right)
+            .in_shift_direction(ShiftDirection::Left)
+            .clock_divisor_fixed_point((sys_freq / PIO_FREQ_HZ) as u16, 0)
+            .build(sm);
+        sm.set_pindirs(row_pins.iter().map(|p| (*p, PinDir::Output)));
+
+        let tx_dreq = (P::id() * 8 + SM::id()) as u8;
+        let rx_dreq = tx_dreq + 4;
+        start_ring_dma(
+            TC::id(),
+            patterns.0.as_ptr() as u32,
+            tx.fifo_address() as u32,
+            true,
+            tx_dreq,
+        );
+        start_ring_dma(
+            RC::id(),
+            rx.fifo_address() as u32,
+            samples.0.as_mut_ptr() as u32,
+            false,
+            rx_dreq,
+        );
+        let sm = sm.start();
+
+        PioScanner {
+            _sm: sm,
+            _rx: rx,
+            _tx: tx,
+            _tx_ch: tx_ch,
+            _rx_ch: rx_ch,
+            patterns,
+            samples,
+            cols_reversed,
+        }
+    }
+
+    // The DMA channels stop after 2^32 transfers, roughly ten days of
+    // continuous scanning. Once both have finished they are restarted,
+    // since they stop at the same ring position they stay in lock-step
+    fn rearm(&mut self) {
+        let dma = unsafe { &*pac::DMA::ptr() };
+        let tx = &dma.ch[TC::id() as usize];
+        let rx = &dma.ch[RC::id() as usize];
+        if rx.ch_trans_count.read().bits() > DMA_REARM_THRESHOLD {
+            return;
+        }
+        if tx.ch_ctrl_trig.read().busy().bit_is_set() || rx.ch_ctrl_trig.read().busy().bit_is_set() {
+            return;
+        }
+        rx.ch_al1_trans_count_trig.write(|w| unsafe { w.bits(u32::MAX) });
+        tx.ch_al1_trans_count_trig.write(|w| unsafe { w.bits(u32::MAX) });
+    }
+}
+
+impl<P, SM, TC, RC> MatrixScanner for PioScanner<P, SM, TC, RC>
+where
+    P: PIOExt,
+    SM: StateMachineIndex,
+    TC: ChannelIndex,
+    RC: ChannelIndex,
+{
+    fn set_settle_time_us(&mut self, settle_us: u32) {
+        // The ring is read by DMA, each word is replaced in a single write
+        let settle = settle_count(settle_us);
+        for slot in self.patterns.0.iter_mut() {
+            unsafe { core::ptr::write_volatile(slot, settle | *slot & PATTERN_MASK) };
+        }
+    }
+
+    fn scan(&mut self, raw_rows: &mut [u32; N_ROWS]) {
+        self.rearm();
+        for row in (0..N_ROWS).into_iter() {
+            let sample = unsafe { core::ptr::read_volatile(&self.samples.0[row]) };
+            // Columns are pulled up, so a pressed key reads low
+            let pressed = !sample & ((1 << N_COLS) - 1);
+            raw_rows[row] = if self.cols_reversed {
+                pressed.reverse_bits() >> (32 - N_COLS)
+            } else {
+                pressed
+            };
+        }
+    }
+}
+
+// The settle time as placed in a ring word. The settle loop runs one
+// cycle more than the count, and the count is limited to 16 bits
+fn settle_count(settle_us: u32) -> u32 {
+    settle_us.saturating_sub(1).min(0xFFFF) << PATTERN_BITS
+}
+
+// Starts a DMA channel moving words between a PIO FIFO and a ring buffer,
+// paced by the given DREQ. The ring wraps on the read side when feeding
+// the state machine and on the write side when draining it
+fn start_ring_dma(ch: u8, read_addr: u32, write_addr: u32, ring_read: bool, dreq: u8) {
+    let dma = unsafe { &*pac::DMA::ptr() };
+    let regs = &dma.ch[ch as usize];
+    regs.ch_read_addr.write(|w| unsafe { w.bits(read_addr) });
+    regs.ch_write_addr.write(|w| unsafe { w.bits(write_addr) });
+    regs.ch_trans_count.write(|w| unsafe { w.bits(u32::MAX) });
+    regs.ch_ctrl_trig.write(|w| unsafe {
+        w.data_size().size_word()
+            .incr_read().bit(ring_read)
+            .incr_write().bit(!ring_read)
+            .ring_sel().bit(!ring_read)
+            .ring_size().bits(RING_SIZE_BITS)
+            // Chaining to itself disables chaining
+            .chain_to().bits(ch)
+            .treq_sel().bits(dreq)
+            .en().set_bit()
+    });
+}
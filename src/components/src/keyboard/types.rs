@@ -1,6 +1,7 @@
 use hid::page::Keyboard;
 use usbd_human_interface_device as hid;
 use num_enum::{TryFromPrimitive, TryFromPrimitiveError};
+use crate::keyboard::layout::KeyId;
 
 #[repr(u16)]
 #[derive(Clone, Copy, TryFromPrimitive)]
@@ -191,6 +192,11 @@ pub enum KeyPress {
     LayerHold2 = 0x0109,
     LayerHold3 = 0x010A,
     LayerHold4 = 0x010B,
+    ProfileNext = 0x010C,
+    ProfileSet0 = 0x010D,
+    ProfileSet1 = 0x010E,
+    ProfileSet2 = 0x010F,
+    ProfileSet3 = 0x0110,
 }
 
 #[repr(u8)]
@@ -208,9 +214,9 @@ pub enum ModifierKey {
 
 #[derive(Debug)]
 pub enum KeyEvent {
-    Pressed(usize),
-    DoublePress(usize),
-    HeldPress(usize),
+    Pressed(KeyId),
+    DoublePress(KeyId),
+    HeldPress(KeyId),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -1,6 +1,14 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![allow(dead_code)]
 
 pub mod at24c;
+pub mod config;
+pub mod flash;
 pub mod keyboard;
+pub mod lighting;
 pub mod i2c;
+pub mod link;
+pub mod protocol;
+pub mod settings;
+pub mod transport;
+pub mod update;
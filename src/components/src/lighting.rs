@@ -0,0 +1,138 @@
+/*
+* Per-key RGB lighting, shared by both halves so that the slave can
+* show the same state as the master, e.g. the active layer
+*/
+use rp_pico::hal as hal;
+use num_enum::TryFromPrimitive;
+use smart_leds::RGB8;
+
+use crate::keyboard::key_matrix::N_KEYS;
+use crate::keyboard::layout::{matrix_keys, Half, KeyId};
+use crate::link::LinkStatus;
+
+// Bits of the host LED state, in the order of the HID LED report
+pub const HOST_LED_NUM_LOCK: u8 = 1 << 0;
+pub const HOST_LED_CAPS_LOCK: u8 = 1 << 1;
+pub const HOST_LED_SCROLL_LOCK: u8 = 1 << 2;
+
+// The key mapped to Caps Lock on the base layer, lit while Caps Lock is on
+pub const CAPS_LOCK_INDICATOR: KeyId = KeyId::from_matrix(Half::Left, 0, 0);
+
+pub const DEFAULT_BRIGHTNESS: u8 = 64;
+// Time for one full cycle of the rainbow animation
+const RAINBOW_PERIOD_S: f32 = 10.0;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, TryFromPrimitive)]
+pub enum LightingMode {
+    Off = 0,
+    // Slowly cycling rainbow, every third key offset in hue
+    Rainbow = 1,
+    // All keys lit in a color given by the active layer
+    Layer = 2,
+}
+
+pub struct Lighting {
+    pub mode: LightingMode,
+    // Brightness scale applied when writing the LEDs, out of 255
+    pub brightness: u8,
+    pub layer: u8,
+    pub host_leds: u8,
+    // Status of the link between the halves, shown in place of
+    // the regular lighting while the link is not connected
+    pub link: LinkStatus,
+    // Set on the master while the slave speaks a different protocol
+    pub incompatible: bool,
+    // Animation phase, 0..1
+    t: f32,
+}
+
+impl Lighting {
+    pub fn new() -> Self {
+        Lighting {
+            mode: LightingMode::Rainbow,
+            brightness: DEFAULT_BRIGHTNESS,
+            layer: 0,
+            host_leds: 0,
+            link: LinkStatus::Connected,
+            incompatible: false,
+            t: 0.0,
+        }
+    }
+
+    // Advances the animations by the given time in seconds
+    pub fn tick(&mut self, dt: f32) {
+        self.t += dt / RAINBOW_PERIOD_S;
+        while self.t > 1.0 {
+            self.t -= 1.0;
+        }
+    }
+
+    // Renders the LEDs of one half, the LED of each key is indexed
+    // by the index of the key within the matrix of its half
+    pub fn render(&self, half: Half, leds: &mut [RGB8; N_KEYS]) {
+        let sin = hal::rom_data::float_funcs::fsin;
+        for id in matrix_keys(half) {
+            let i = id.matrix_index().unwrap();
+            // An offset to give 3 consecutive LEDs a different color:
+            let hue_offs = match i % 3 {
+                1 => 0.25,
+                2 => 0.5,
+                _ => 0.0,
+            };
+
+            let sin_11 = sin((self.t + hue_offs) * 2.0 * core::f32::consts::PI);
+            // Bring -1..1 sine range to 0..1 range:
+            let sin_01 = (sin_11 + 1.0) * 0.5;
+
+            // Pulsing magenta for an incompatible slave, amber while the link
+            // between the halves is unreliable and pulsing red while it is down
+            let (hue, sat, val) = match (self.link, self.mode) {
+                _ if self.incompatible => (300.0, 1.0, sin_01),
+                (LinkStatus::Degraded, _) => (40.0, 1.0, 1.0),
+                (LinkStatus::Disconnected, _) => (0.0, 1.0, sin_01),
+                (_, LightingMode::Off) => (0.0, 0.0, 0.0),
+                (_, LightingMode::Rainbow) => (360.0 * sin_01, 1.0, 1.0),
+                (_, LightingMode::Layer) => ((self.layer as f32 * 72.0) % 360.0, 1.0, 1.0),
+            };
+
+            leds[i] = if id == CAPS_LOCK_INDICATOR && self.host_leds & HOST_LED_CAPS_LOCK != 0 {
+                (255, 255, 255).into()
+            } else {
+                hsv2rgb_u8(hue, sat, val).into()
+            };
+        }
+    }
+}
+
+pub fn hsv2rgb(hue: f32, sat: f32, val: f32) -> (f32, f32, f32) {
+    let c = val * sat;
+    let v = (hue / 60.0) % 2.0 - 1.0;
+    let v = if v < 0.0 { -v } else { v };
+    let x = c * (1.0 - v);
+    let m = val - c;
+    let (r, g, b) = if hue < 60.0 {
+        (c, x, 0.0)
+    } else if hue < 120.0 {
+        (x, c, 0.0)
+    } else if hue < 180.0 {
+        (0.0, c, x)
+    } else if hue < 240.0 {
+        (0.0, x, c)
+    } else if hue < 300.0 {
+        (x, 0.0, c)
+    } else {
+        (c, 0.0, x)
+    };
+    (r + m, g + m, b + m)
+}
+
+pub fn hsv2rgb_u8(h: f32, s: f32, v: f32) -> (u8, u8, u8) {
+    let r = hsv2rgb(h, s, v);
+
+    (
+        (r.0 * 255.0) as u8,
+        (r.1 * 255.0) as u8,
+        (r.2 * 255.0) as u8,
+    )
+}
@@ -0,0 +1,144 @@
+/*
+* Health monitoring of the link between the master and slave halves
+*/
+
+// Number of consecutive failed transfers before the link is considered degraded.
+// While degraded the last known key states of the slave are kept
+pub const DEGRADED_THRESHOLD: u16 = 3;
+// Number of consecutive failed transfers before the link is considered
+// down, at which point all keys of the slave half are released
+pub const DISCONNECT_THRESHOLD: u16 = 20;
+// While disconnected the slave is polled with an exponentially
+// increasing delay, starting at the minimum and capped at the maximum
+pub const MIN_RETRY_US: u32 = 2_000;
+pub const MAX_RETRY_US: u32 = 500_000;
+// Events older than this, e.g. queued on the slave while the
+// link was down, are dropped rather than replayed
+pub const STALE_EVENT_US: u32 = 50_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkStatus {
+    Connected,
+    Degraded,
+    Disconnected,
+}
+
+// Transitions of the link status the caller needs to act upon
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkEvent {
+    None,
+    Connected,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinkStats {
+    // Total number of failed transfers, including corrupt frames
+    pub errors: u32,
+    // Number of received frames that failed validation
+    pub corrupt_frames: u32,
+    // Number of received frames with the same sequence
+    // number as the previous frame
+    pub stale_frames: u32,
+    pub disconnects: u32,
+    // Number of times the link has come up, including the first time
+    pub connects: u32,
+}
+
+pub struct LinkMonitor {
+    status: LinkStatus,
+    consecutive_errors: u16,
+    last_seq: Option<u8>,
+    retry_delay_us: u32,
+    next_retry: u32,
+    stats: LinkStats,
+}
+
+impl LinkMonitor {
+    // The link starts out disconnected, until the first valid frame is received
+    pub fn new() -> Self {
+        LinkMonitor {
+            status: LinkStatus::Disconnected,
+            consecutive_errors: DISCONNECT_THRESHOLD,
+            last_seq: None,
+            retry_delay_us: MIN_RETRY_US,
+            next_retry: 0,
+            stats: LinkStats {
+                errors: 0,
+                corrupt_frames: 0,
+                stale_frames: 0,
+                disconnects: 0,
+                connects: 0,
+            },
+        }
+    }
+
+    pub fn status(&self) -> LinkStatus {
+        self.status
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    // Returns true if the slave should be polled at this time.
+    // While connected, or degraded, it is polled on every tick
+    pub fn should_poll(&self, now: u32) -> bool {
+        self.status != LinkStatus::Disconnected
+            || now.wrapping_sub(self.next_retry) < u32::MAX / 2
+    }
+
+    // Call when a valid frame is received
+    pub fn frame_received(&mut self, now: u32, seq: u8) -> LinkEvent {
+        // The slave increments the sequence number of every frame it
+        // sends, if it stops doing so it is frozen and its key states
+        // can no longer be trusted
+        if self.last_seq == Some(seq) {
+            self.stats.stale_frames += 1;
+            return self.error(now);
+        }
+        self.last_seq = Some(seq);
+        self.consecutive_errors = 0;
+        self.retry_delay_us = MIN_RETRY_US;
+        let was_disconnected = self.status == LinkStatus::Disconnected;
+        self.status = LinkStatus::Connected;
+        if was_disconnected {
+            self.stats.connects += 1;
+            LinkEvent::Connected
+        } else {
+            LinkEvent::None
+        }
+    }
+
+    // Call when a received frame fails validation
+    pub fn frame_corrupt(&mut self, now: u32) -> LinkEvent {
+        self.stats.corrupt_frames += 1;
+        self.error(now)
+    }
+
+    // Call when a transfer fails
+    pub fn error(&mut self, now: u32) -> LinkEvent {
+        self.stats.errors += 1;
+        self.consecutive_errors = self.consecutive_errors.saturating_add(1);
+
+        if self.status == LinkStatus::Disconnected {
+            self.next_retry = now.wrapping_add(self.retry_delay_us);
+            self.retry_delay_us = (self.retry_delay_us * 2).min(MAX_RETRY_US);
+            return LinkEvent::None;
+        }
+
+        if self.consecutive_errors >= DISCONNECT_THRESHOLD {
+            self.status = LinkStatus::Disconnected;
+            self.stats.disconnects += 1;
+            self.last_seq = None;
+            self.retry_delay_us = MIN_RETRY_US;
+            self.next_retry = now.wrapping_add(self.retry_delay_us);
+            LinkEvent::Disconnected
+        } else {
+            if self.consecutive_errors >= DEGRADED_THRESHOLD {
+                self.status = LinkStatus::Degraded;
+            }
+            LinkEvent::None
+        }
+    }
+}
@@ -0,0 +1,1071 @@
+/*
+* Framed protocol used on the link between the master and slave halves.
+*
+* Every frame has the following layout, multi-byte fields are big-endian:
+*
+*   0       Sync byte, 0xA5
+*   1       Protocol version
+*   2       Sequence number, incremented for every frame sent
+*   3       Payload type
+*   4       Payload length, n
+*   5..5+n  Payload
+*   5+n     CRC-16/CCITT-FALSE of bytes 1..5+n
+*
+* When the link comes up the halves exchange their `LinkInfo`, the master
+* in a Hello command and the slave in a LinkInfo frame, which the slave
+* also sends in answer to the first poll after it starts. The layout of
+* the LinkInfo frame is the same in every protocol version, so that the
+* master can tell which version the slave speaks even if it doesn't
+* speak it itself
+*/
+use num_enum::TryFromPrimitive;
+use heapless::{Deque, Vec};
+
+use crate::keyboard::key_matrix::MatrixTiming;
+use crate::lighting::LightingMode;
+use crate::update::{UpdateHeader, UPDATE_CHUNK_LEN, UPDATE_HEADER_LEN};
+
+// I2C address of the slave half
+pub const SLAVE_ADDRESS: u8 = 0x33;
+pub const SYNC: u8 = 0xA5;
+pub const PROTOCOL_VERSION: u8 = 6;
+pub const HEADER_LEN: usize = 5;
+pub const CRC_LEN: usize = 2;
+pub const MAX_PAYLOAD_LEN: usize = 32;
+pub const MAX_FRAME_LEN: usize = HEADER_LEN + MAX_PAYLOAD_LEN + CRC_LEN;
+
+pub const KEY_STATE_PAYLOAD_LEN: usize = 4;
+pub const KEY_STATE_FRAME_LEN: usize = frame_len(KEY_STATE_PAYLOAD_LEN);
+
+// Each key event is sent as the key index, with the top bit set for
+// presses, followed by the age of the event in microseconds
+pub const KEY_EVENT_LEN: usize = 3;
+pub const MAX_EVENTS_PER_FRAME: usize = 2;
+// Key states, command acknowledge, update status, event count and the events
+pub const KEY_EVENTS_PAYLOAD_LEN: usize = 4 + 1 + 1 + 1 + KEY_EVENT_LEN * MAX_EVENTS_PER_FRAME;
+pub const KEY_EVENTS_FRAME_LEN: usize = frame_len(KEY_EVENTS_PAYLOAD_LEN);
+const KEY_PRESSED_BIT: u8 = 0x80;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, TryFromPrimitive)]
+pub enum PayloadType {
+    // The debounced key states of the slave as a 32-bit mask
+    KeyState = 0x01,
+    // Timestamped key press and release events of the slave, followed
+    // by its key states after the events have been applied
+    KeyEvents = 0x02,
+    // A command from the master to the slave
+    Command = 0x03,
+    // Sent by the master on serial transports to ask for the key
+    // events frame, without a payload. Over I2C the master reads instead
+    FrameRequest = 0x04,
+    // Sent by the master to the host during a firmware update of the
+    // slave, holding an `UpdateStatus`
+    UpdateStatus = 0x05,
+    // The `LinkInfo` of the slave, sent in place of a key events frame
+    LinkInfo = 0x06,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameError {
+    // The buffer is too small to hold the frame
+    BufferTooSmall,
+    BadSync,
+    UnsupportedVersion(u8),
+    UnknownPayload(u8),
+    // The payload length is larger than the maximum payload length
+    BadLength,
+    BadCrc,
+}
+
+// Total length of a frame with the given payload length
+pub const fn frame_len(payload_len: usize) -> usize {
+    HEADER_LEN + payload_len + CRC_LEN
+}
+
+#[derive(Debug)]
+pub struct Frame<'a> {
+    pub seq: u8,
+    pub payload_type: PayloadType,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    // Writes the frame to the buffer, returns the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, FrameError> {
+        let len = frame_len(self.payload.len());
+        if self.payload.len() > MAX_PAYLOAD_LEN {
+            return Err(FrameError::BadLength);
+        }
+        if buf.len() < len {
+            return Err(FrameError::BufferTooSmall);
+        }
+        buf[0] = SYNC;
+        buf[1] = PROTOCOL_VERSION;
+        buf[2] = self.seq;
+        buf[3] = self.payload_type as u8;
+        buf[4] = self.payload.len() as u8;
+        buf[HEADER_LEN..HEADER_LEN + self.payload.len()].copy_from_slice(self.payload);
+        let crc = crc16(&buf[1..len - CRC_LEN]);
+        buf[len - CRC_LEN..len].copy_from_slice(&crc.to_be_bytes());
+        Ok(len)
+    }
+
+    // Parses and validates a frame at the start of the buffer,
+    // any bytes after the end of the frame are ignored
+    pub fn decode(buf: &'a [u8]) -> Result<Self, FrameError> {
+        Self::decode_frame(buf, true)
+    }
+
+    fn decode_frame(buf: &'a [u8], check_version: bool) -> Result<Self, FrameError> {
+        if buf.len() < HEADER_LEN + CRC_LEN {
+            return Err(FrameError::BufferTooSmall);
+        }
+        if buf[0] != SYNC {
+            return Err(FrameError::BadSync);
+        }
+        if check_version && buf[1] != PROTOCOL_VERSION {
+            return Err(FrameError::UnsupportedVersion(buf[1]));
+        }
+        let payload_len = buf[4] as usize;
+        if payload_len > MAX_PAYLOAD_LEN {
+            return Err(FrameError::BadLength);
+        }
+        let len = frame_len(payload_len);
+        if buf.len() < len {
+            return Err(FrameError::BufferTooSmall);
+        }
+        let crc = u16::from_be_bytes([buf[len - 2], buf[len - 1]]);
+        if crc != crc16(&buf[1..len - CRC_LEN]) {
+            return Err(FrameError::BadCrc);
+        }
+        let payload_type = match PayloadType::try_from_primitive(buf[3]) {
+            Ok(t) => t,
+            Err(_) => return Err(FrameError::UnknownPayload(buf[3])),
+        };
+        Ok(Frame {
+            seq: buf[2],
+            payload_type,
+            payload: &buf[HEADER_LEN..HEADER_LEN + payload_len],
+        })
+    }
+}
+
+// Keeps track of the sequence number of outgoing frames
+pub struct FrameEncoder {
+    seq: u8,
+}
+
+impl FrameEncoder {
+    pub const fn new() -> Self {
+        FrameEncoder { seq: 0 }
+    }
+
+    pub const fn starting_at(seq: u8) -> Self {
+        FrameEncoder { seq }
+    }
+
+    pub fn encode(
+        &mut self,
+        payload_type: PayloadType,
+        payload: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, FrameError> {
+        let len = Frame {
+            seq: self.seq,
+            payload_type,
+            payload,
+        }
+        .encode(buf)?;
+        self.seq = self.seq.wrapping_add(1);
+        Ok(len)
+    }
+}
+
+// Encodes the key states of the slave into a key state frame
+pub fn encode_key_state(
+    encoder: &mut FrameEncoder,
+    key_states: u32,
+    buf: &mut [u8],
+) -> Result<usize, FrameError> {
+    encoder.encode(PayloadType::KeyState, &key_states.to_be_bytes(), buf)
+}
+
+// Decodes a key state frame, returns the sequence number
+// of the frame and the key states it holds
+pub fn decode_key_state(buf: &[u8]) -> Result<(u8, u32), FrameError> {
+    let frame = Frame::decode(buf)?;
+    if frame.payload_type != PayloadType::KeyState {
+        return Err(FrameError::UnknownPayload(frame.payload_type as u8));
+    }
+    if frame.payload.len() != KEY_STATE_PAYLOAD_LEN {
+        return Err(FrameError::BadLength);
+    }
+    let p = frame.payload;
+    Ok((frame.seq, u32::from_be_bytes([p[0], p[1], p[2], p[3]])))
+}
+
+// A key press or release on one half. The key is the index of
+// the key within the matrix of the half, and the time is the
+// time of the change in microseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyChange {
+    pub key: u8,
+    pub pressed: bool,
+    pub time: u32,
+}
+
+pub struct KeyEventsFrame {
+    pub seq: u8,
+    pub key_states: u32,
+    // Sequence number of the last command frame applied by the slave
+    pub ack_seq: u8,
+    // The `UpdateStatus` of the slave
+    pub update_status: u8,
+    pub events: Vec<KeyChange, MAX_EVENTS_PER_FRAME>,
+}
+
+// Encodes key events into a key event frame. Event times are taken from
+// the clock of the sender and are sent as their age relative to `now`,
+// which should be the time the frame is sent, so that the receiver can
+// place them on its own clock. The payload is always padded to its
+// maximum length so that all key event frames have the same length
+pub fn encode_key_events(
+    encoder: &mut FrameEncoder,
+    key_states: u32,
+    ack_seq: u8,
+    update_status: u8,
+    events: &[KeyChange],
+    now: u32,
+    buf: &mut [u8],
+) -> Result<usize, FrameError> {
+    if events.len() > MAX_EVENTS_PER_FRAME {
+        return Err(FrameError::BadLength);
+    }
+    let mut payload = [0; KEY_EVENTS_PAYLOAD_LEN];
+    payload[0..4].copy_from_slice(&key_states.to_be_bytes());
+    payload[4] = ack_seq;
+    payload[5] = update_status;
+    payload[6] = events.len() as u8;
+    for (i, event) in events.iter().enumerate() {
+        let offset = 7 + i * KEY_EVENT_LEN;
+        // Events older than the age field can hold are sent with the maximum age
+        let age = now.wrapping_sub(event.time).min(u16::MAX as u32) as u16;
+        payload[offset] = event.key | if event.pressed { KEY_PRESSED_BIT } else { 0 };
+        payload[offset + 1..offset + 3].copy_from_slice(&age.to_be_bytes());
+    }
+    encoder.encode(PayloadType::KeyEvents, &payload, buf)
+}
+
+// Decodes a key event frame. `frame_time` is the time, on the clock of
+// the receiver, at which the frame was sent. The times of the returned
+// events are on the clock of the receiver
+pub fn decode_key_events(buf: &[u8], frame_time: u32) -> Result<KeyEventsFrame, FrameError> {
+    let frame = Frame::decode(buf)?;
+    if frame.payload_type != PayloadType::KeyEvents {
+        return Err(FrameError::UnknownPayload(frame.payload_type as u8));
+    }
+    let p = frame.payload;
+    if p.len() != KEY_EVENTS_PAYLOAD_LEN || p[6] as usize > MAX_EVENTS_PER_FRAME {
+        return Err(FrameError::BadLength);
+    }
+    let mut events = Vec::new();
+    for i in 0..p[6] as usize {
+        let offset = 7 + i * KEY_EVENT_LEN;
+        let age = u16::from_be_bytes([p[offset + 1], p[offset + 2]]);
+        let _ = events.push(KeyChange {
+            key: p[offset] & !KEY_PRESSED_BIT,
+            pressed: p[offset] & KEY_PRESSED_BIT != 0,
+            time: frame_time.wrapping_sub(age as u32),
+        });
+    }
+    Ok(KeyEventsFrame {
+        seq: frame.seq,
+        key_states: u32::from_be_bytes([p[0], p[1], p[2], p[3]]),
+        ack_seq: p[4],
+        update_status: p[5],
+        events,
+    })
+}
+
+// Capability flags of a half
+pub const CAP_ENCODER: u8 = 0x01;
+
+// Versions and hardware of a half, exchanged when the link comes up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkInfo {
+    pub firmware_version: u32,
+    pub protocol_version: u8,
+    // CAP_* flags
+    pub capabilities: u8,
+    pub led_count: u8,
+    pub matrix_rows: u8,
+    pub matrix_cols: u8,
+}
+
+pub const LINK_INFO_LEN: usize = 9;
+
+// How well the master can work with a slave
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compatibility {
+    Full,
+    // The same protocol but a different firmware version or hardware,
+    // the master works with what the slave reports
+    Degraded,
+    // A different protocol, the slave can't be used
+    Incompatible,
+}
+
+impl LinkInfo {
+    pub fn to_bytes(&self) -> [u8; LINK_INFO_LEN] {
+        let mut buf = [0; LINK_INFO_LEN];
+        buf[0..4].copy_from_slice(&self.firmware_version.to_be_bytes());
+        buf[4] = self.protocol_version;
+        buf[5] = self.capabilities;
+        buf[6] = self.led_count;
+        buf[7] = self.matrix_rows;
+        buf[8] = self.matrix_cols;
+        buf
+    }
+
+    // The buffer must hold at least LINK_INFO_LEN bytes
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        LinkInfo {
+            firmware_version: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            protocol_version: buf[4],
+            capabilities: buf[5],
+            led_count: buf[6],
+            matrix_rows: buf[7],
+            matrix_cols: buf[8],
+        }
+    }
+
+    pub fn matrix_keys(&self) -> usize {
+        self.matrix_rows as usize * self.matrix_cols as usize
+    }
+
+    // Compatibility of the other half with this one
+    pub fn compatibility(&self, other: &LinkInfo) -> Compatibility {
+        if other.protocol_version != self.protocol_version {
+            Compatibility::Incompatible
+        } else if other == self {
+            Compatibility::Full
+        } else {
+            Compatibility::Degraded
+        }
+    }
+}
+
+// Encodes the link info of the slave, padded to the length of a
+// key events frame so that every frame the slave sends has the same length
+pub fn encode_link_info(
+    encoder: &mut FrameEncoder,
+    info: &LinkInfo,
+    buf: &mut [u8],
+) -> Result<usize, FrameError> {
+    let mut payload = [0; KEY_EVENTS_PAYLOAD_LEN];
+    payload[..LINK_INFO_LEN].copy_from_slice(&info.to_bytes());
+    encoder.encode(PayloadType::LinkInfo, &payload, buf)
+}
+
+// Decodes a link info frame of any protocol version, returns the
+// sequence number of the frame and the link info it holds
+pub fn decode_link_info(buf: &[u8]) -> Result<(u8, LinkInfo), FrameError> {
+    let frame = Frame::decode_frame(buf, false)?;
+    if frame.payload_type != PayloadType::LinkInfo {
+        return Err(FrameError::UnknownPayload(frame.payload_type as u8));
+    }
+    if frame.payload.len() < LINK_INFO_LEN {
+        return Err(FrameError::BadLength);
+    }
+    Ok((frame.seq, LinkInfo::from_bytes(frame.payload)))
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, TryFromPrimitive)]
+enum CommandId {
+    SetLayer = 0x01,
+    SetHostLeds = 0x02,
+    SetLighting = 0x03,
+    SetMatrixTiming = 0x04,
+    UpdateBegin = 0x05,
+    UpdateData = 0x06,
+    UpdateEnd = 0x07,
+    Hello = 0x08,
+}
+
+// Commands sent from the master to the slave
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    // The active key-map layer
+    SetLayer(u8),
+    // Host LED state, as the `HOST_LED_*` bits of the lighting module
+    SetHostLeds(u8),
+    SetLighting { mode: LightingMode, brightness: u8 },
+    SetMatrixTiming(MatrixTiming),
+    // Starts a firmware update of the slave
+    UpdateBegin(UpdateHeader),
+    // A chunk of the firmware image, `len` bytes of `data` at `offset`
+    UpdateData { offset: u32, len: u8, data: [u8; UPDATE_CHUNK_LEN] },
+    // Completes a firmware update, the slave verifies the image and resets
+    UpdateEnd,
+    // The link info of the master, sent when the link comes up. The
+    // slave answers the next poll with its own link info
+    Hello(LinkInfo),
+}
+
+pub const MAX_COMMAND_LEN: usize = 1 + 4 + UPDATE_CHUNK_LEN;
+pub const MAX_COMMAND_FRAME_LEN: usize = frame_len(MAX_COMMAND_LEN);
+
+impl Command {
+    fn id(&self) -> CommandId {
+        match self {
+            Command::SetLayer(_) => CommandId::SetLayer,
+            Command::SetHostLeds(_) => CommandId::SetHostLeds,
+            Command::SetLighting { .. } => CommandId::SetLighting,
+            Command::SetMatrixTiming(_) => CommandId::SetMatrixTiming,
+            Command::UpdateBegin(_) => CommandId::UpdateBegin,
+            Command::UpdateData { .. } => CommandId::UpdateData,
+            Command::UpdateEnd => CommandId::UpdateEnd,
+            Command::Hello(_) => CommandId::Hello,
+        }
+    }
+
+    // Settings only need their latest value to be sent, while every
+    // command of a firmware update has to reach the slave
+    fn coalesces(&self) -> bool {
+        !matches!(
+            self,
+            Command::UpdateBegin(_) | Command::UpdateData { .. } | Command::UpdateEnd
+        )
+    }
+
+    // Writes the command id followed by its arguments,
+    // returns the number of bytes written
+    pub fn encode(&self, buf: &mut [u8; MAX_COMMAND_LEN]) -> usize {
+        buf[0] = self.id() as u8;
+        match self {
+            Command::SetLayer(layer) => {
+                buf[1] = *layer;
+                2
+            }
+            Command::SetHostLeds(leds) => {
+                buf[1] = *leds;
+                2
+            }
+            Command::SetLighting { mode, brightness } => {
+                buf[1] = *mode as u8;
+                buf[2] = *brightness;
+                3
+            }
+            Command::SetMatrixTiming(timing) => {
+                buf[1..5].copy_from_slice(&timing.debounce_us.to_be_bytes());
+                buf[5..9].copy_from_slice(&timing.settle_us.to_be_bytes());
+                buf[9] = timing.anti_ghosting as u8;
+                10
+            }
+            Command::UpdateBegin(header) => {
+                buf[1..1 + UPDATE_HEADER_LEN].copy_from_slice(&header.to_bytes());
+                1 + UPDATE_HEADER_LEN
+            }
+            Command::UpdateData { offset, len, data } => {
+                let len = (*len as usize).min(UPDATE_CHUNK_LEN);
+                buf[1..5].copy_from_slice(&offset.to_be_bytes());
+                buf[5..5 + len].copy_from_slice(&data[..len]);
+                5 + len
+            }
+            Command::UpdateEnd => 1,
+            Command::Hello(info) => {
+                buf[1..1 + LINK_INFO_LEN].copy_from_slice(&info.to_bytes());
+                1 + LINK_INFO_LEN
+            }
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Command, FrameError> {
+        if buf.is_empty() {
+            return Err(FrameError::BadLength);
+        }
+        let id = match CommandId::try_from_primitive(buf[0]) {
+            Ok(id) => id,
+            Err(_) => return Err(FrameError::UnknownPayload(buf[0])),
+        };
+        let len = match id {
+            CommandId::SetLayer | CommandId::SetHostLeds => 2,
+            CommandId::SetLighting => 3,
+            CommandId::SetMatrixTiming => 10,
+            CommandId::UpdateBegin => 1 + UPDATE_HEADER_LEN,
+            // Any chunk length from 1 to UPDATE_CHUNK_LEN
+            CommandId::UpdateData => buf.len().clamp(6, 5 + UPDATE_CHUNK_LEN),
+            CommandId::UpdateEnd => 1,
+            CommandId::Hello => 1 + LINK_INFO_LEN,
+        };
+        if buf.len() != len {
+            return Err(FrameError::BadLength);
+        }
+        Ok(match id {
+            CommandId::SetLayer => Command::SetLayer(buf[1]),
+            CommandId::SetHostLeds => Command::SetHostLeds(buf[1]),
+            CommandId::SetLighting => Command::SetLighting {
+                mode: match LightingMode::try_from_primitive(buf[1]) {
+                    Ok(mode) => mode,
+                    Err(_) => return Err(FrameError::UnknownPayload(buf[1])),
+                },
+                brightness: buf[2],
+            },
+            CommandId::SetMatrixTiming => Command::SetMatrixTiming(MatrixTiming {
+                debounce_us: u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]),
+                settle_us: u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]),
+                anti_ghosting: buf[9] != 0,
+            }),
+            CommandId::UpdateBegin => Command::UpdateBegin(UpdateHeader::from_bytes(&buf[1..])),
+            CommandId::UpdateData => {
+                let mut data = [0; UPDATE_CHUNK_LEN];
+                data[..len - 5].copy_from_slice(&buf[5..]);
+                Command::UpdateData {
+                    offset: u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]),
+                    len: (len - 5) as u8,
+                    data,
+                }
+            }
+            CommandId::UpdateEnd => Command::UpdateEnd,
+            CommandId::Hello => Command::Hello(LinkInfo::from_bytes(&buf[1..])),
+        })
+    }
+}
+
+// Decodes a command frame, returns the sequence number
+// of the frame, used to acknowledge it, and the command
+pub fn decode_command(buf: &[u8]) -> Result<(u8, Command), FrameError> {
+    let frame = Frame::decode(buf)?;
+    if frame.payload_type != PayloadType::Command {
+        return Err(FrameError::UnknownPayload(frame.payload_type as u8));
+    }
+    Ok((frame.seq, Command::decode(frame.payload)?))
+}
+
+// Commands are retransmitted if not acknowledged within this time
+pub const COMMAND_RESEND_US: u32 = 10_000;
+const COMMAND_QUEUE_LEN: usize = 16;
+// Firmware update commands that may be queued at once. The settings
+// coalesce, so there are at most two of each kind, one in flight and
+// one queued, which leaves them room in the rest of the queue
+const MAX_UPDATE_COMMANDS: usize = 4;
+
+// Queue of commands waiting to be sent to, and acknowledged by,
+// the slave. Commands are sent one at a time in order
+pub struct CommandQueue {
+    pending: Deque<Command, COMMAND_QUEUE_LEN>,
+    encoder: FrameEncoder,
+    // Sequence number and send time of the command in flight
+    in_flight: Option<(u8, u32)>,
+}
+
+impl CommandQueue {
+    pub fn new() -> Self {
+        CommandQueue {
+            pending: Deque::new(),
+            // The slave acknowledges 0 before it has received any
+            // command, so 0 is never used as a sequence number
+            encoder: FrameEncoder::starting_at(1),
+            in_flight: None,
+        }
+    }
+
+    // Queues a setting. A queued setting of the same kind that has
+    // not yet been sent is replaced, since only the latest value matters.
+    // Firmware update commands are queued with `push_update` instead
+    pub fn push(&mut self, command: Command) {
+        if !command.coalesces() {
+            let _ = self.push_update(command);
+            return;
+        }
+        let in_flight = self.in_flight.is_some();
+        for (i, queued) in self.pending.iter_mut().enumerate() {
+            if queued.id() == command.id() && !(i == 0 && in_flight) {
+                *queued = command;
+                return;
+            }
+        }
+        // Settings always have room, see MAX_UPDATE_COMMANDS
+        let _ = self.pending.push_back(command);
+    }
+
+    // Queues a firmware update command. These are never replaced or
+    // dropped, instead the command is handed back if there is no room
+    // for it, to be sent again once the slave has caught up
+    pub fn push_update(&mut self, command: Command) -> Result<(), Command> {
+        let updates = self.pending.iter().filter(|queued| !queued.coalesces()).count();
+        if updates >= MAX_UPDATE_COMMANDS {
+            return Err(command);
+        }
+        self.pending.push_back(command)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    // Returns the frame of the next command to send, if one is due,
+    // either because nothing is in flight or the command in flight
+    // was not acknowledged in time
+    pub fn next_frame(&mut self, now: u32, buf: &mut [u8]) -> Option<usize> {
+        let command = *self.pending.front()?;
+        let mut payload = [0; MAX_COMMAND_LEN];
+        let len = command.encode(&mut payload);
+        let seq = match self.in_flight {
+            // A resent command keeps its sequence number, so that the
+            // slave can tell it from a new one if it was already applied
+            Some((seq, sent)) => {
+                if now.wrapping_sub(sent) < COMMAND_RESEND_US {
+                    return None;
+                }
+                seq
+            }
+            None => {
+                let seq = self.encoder.seq;
+                self.encoder.seq = seq.wrapping_add(1).max(1);
+                seq
+            }
+        };
+        let len = Frame {
+            seq,
+            payload_type: PayloadType::Command,
+            payload: &payload[..len],
+        }
+        .encode(buf)
+        .ok()?;
+        self.in_flight = Some((seq, now));
+        Some(len)
+    }
+
+    // Call with the acknowledged sequence number of every frame received from the slave
+    pub fn ack(&mut self, ack_seq: u8) {
+        if let Some((seq, _)) = self.in_flight {
+            if seq == ack_seq {
+                let _ = self.pending.pop_front();
+                self.in_flight = None;
+            }
+        }
+    }
+}
+
+// Applies the commands received by the slave at most once. A command
+// is resent by the master until its acknowledgement arrives, so it is
+// received again whenever an acknowledgement is lost
+pub struct CommandReceiver {
+    // Sequence number of the last command applied
+    ack_seq: u8,
+}
+
+impl CommandReceiver {
+    pub const fn new() -> Self {
+        CommandReceiver { ack_seq: 0 }
+    }
+
+    // Sequence number to acknowledge in the next key events frame
+    pub fn ack_seq(&self) -> u8 {
+        self.ack_seq
+    }
+
+    // Decodes a command frame, returns the command if it is to be applied.
+    // Corrupt commands are not acknowledged, the master sends them again,
+    // and a resent command that was already applied is only acknowledged again
+    pub fn receive(&mut self, buf: &[u8]) -> Option<Command> {
+        let (seq, command) = decode_command(buf).ok()?;
+        if seq == self.ack_seq {
+            return None;
+        }
+        self.ack_seq = seq;
+        Some(command)
+    }
+}
+
+// Approximate time it takes to transfer a frame of the given length
+// over an I2C bus, 9 clock cycles per byte, not counting the address
+pub const fn i2c_transfer_time_us(len: usize, bus_freq_hz: u32) -> u32 {
+    (len as u32 + 1) * 9 * 1_000_000 / bus_freq_hz
+}
+
+// Approximate time it takes to transfer a frame of the given length
+// over a serial link, 10 bits per byte with the start and stop bits
+pub const fn uart_transfer_time_us(len: usize, baud_rate: u32) -> u32 {
+    (len as u32 * 10 * 1_000_000) / baud_rate
+}
+
+// CRC-16/CCITT-FALSE, polynomial 0x1021 with an initial value of 0xFFFF
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data.iter() {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAYLOAD: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    fn key_state_frame(seq: u8) -> ([u8; KEY_STATE_FRAME_LEN], usize) {
+        let mut buf = [0; KEY_STATE_FRAME_LEN];
+        let len = encode_key_state(&mut FrameEncoder::starting_at(seq), 0x0012_3456, &mut buf).unwrap();
+        (buf, len)
+    }
+
+    // Fixes up the CRC after the header or payload has been changed
+    fn reseal(buf: &mut [u8]) {
+        let len = frame_len(buf[4] as usize);
+        let crc = crc16(&buf[1..len - CRC_LEN]);
+        buf[len - CRC_LEN..len].copy_from_slice(&crc.to_be_bytes());
+    }
+
+    #[test]
+    fn crc_is_ccitt_false() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(&[]), 0xFFFF);
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut buf = [0; MAX_FRAME_LEN + 3];
+        let frame = Frame { seq: 7, payload_type: PayloadType::KeyState, payload: &PAYLOAD };
+        let len = frame.encode(&mut buf).unwrap();
+        assert_eq!(len, frame_len(PAYLOAD.len()));
+        assert_eq!(buf[..HEADER_LEN], [SYNC, PROTOCOL_VERSION, 7, PayloadType::KeyState as u8, 4]);
+        // Bytes after the frame are ignored
+        buf[len..].fill(0xAA);
+        let decoded = Frame::decode(&buf).unwrap();
+        assert_eq!((decoded.seq, decoded.payload_type, decoded.payload), (7, PayloadType::KeyState, &PAYLOAD[..]));
+
+        // Empty and full payloads
+        for payload in [&[][..], &[0x5A; MAX_PAYLOAD_LEN][..]] {
+            let frame = Frame { seq: 0, payload_type: PayloadType::FrameRequest, payload };
+            let len = frame.encode(&mut buf).unwrap();
+            assert_eq!(Frame::decode(&buf[..len]).unwrap().payload, payload);
+        }
+    }
+
+    #[test]
+    fn oversized_frames_are_not_encoded() {
+        let mut buf = [0; MAX_FRAME_LEN + 1];
+        let payload = [0; MAX_PAYLOAD_LEN + 1];
+        let frame = Frame { seq: 0, payload_type: PayloadType::Command, payload: &payload };
+        assert_eq!(frame.encode(&mut buf), Err(FrameError::BadLength));
+        let frame = Frame { seq: 0, payload_type: PayloadType::Command, payload: &PAYLOAD };
+        assert_eq!(frame.encode(&mut buf[..frame_len(4) - 1]), Err(FrameError::BufferTooSmall));
+    }
+
+    #[test]
+    fn corrupt_frames_fail_the_crc() {
+        let (frame, len) = key_state_frame(3);
+        // Every bit of the sequence number, the payload and the CRC itself
+        for byte in (2..3).chain(HEADER_LEN..len) {
+            for bit in 0..8 {
+                let mut buf = frame;
+                buf[byte] ^= 1 << bit;
+                assert_eq!(decode_key_state(&buf), Err(FrameError::BadCrc), "byte {} bit {}", byte, bit);
+            }
+        }
+        assert_eq!(decode_key_state(&frame), Ok((3, 0x0012_3456)));
+    }
+
+    #[test]
+    fn truncated_frames_are_rejected() {
+        let (frame, len) = key_state_frame(0);
+        for end in 0..len {
+            assert_eq!(Frame::decode(&frame[..end]).err(), Some(FrameError::BufferTooSmall), "{} bytes", end);
+        }
+        // A length beyond the largest payload is not waited for
+        let mut buf = [0; MAX_FRAME_LEN + 8];
+        buf[..len].copy_from_slice(&frame[..len]);
+        buf[4] = MAX_PAYLOAD_LEN as u8 + 1;
+        assert_eq!(Frame::decode(&buf).err(), Some(FrameError::BadLength));
+    }
+
+    #[test]
+    fn frames_must_start_with_sync() {
+        let (frame, _) = key_state_frame(0);
+        for sync in [0x00, 0xFF, SYNC ^ 0x01, PROTOCOL_VERSION] {
+            let mut buf = frame;
+            buf[0] = sync;
+            assert_eq!(Frame::decode(&buf).err(), Some(FrameError::BadSync));
+        }
+        // A frame that starts a byte late is not found
+        let mut buf = [0; KEY_STATE_FRAME_LEN + 1];
+        buf[1..].copy_from_slice(&frame);
+        assert_eq!(Frame::decode(&buf).err(), Some(FrameError::BadSync));
+    }
+
+    #[test]
+    fn unknown_payload_types_are_rejected() {
+        let (frame, _) = key_state_frame(0);
+        for payload_type in [0x00, 0x07, 0xFF] {
+            let mut buf = frame;
+            buf[3] = payload_type;
+            reseal(&mut buf);
+            assert_eq!(Frame::decode(&buf).err(), Some(FrameError::UnknownPayload(payload_type)));
+        }
+        // Known payload types that are not the one expected
+        assert_eq!(decode_key_events(&frame, 0).err(), Some(FrameError::UnknownPayload(PayloadType::KeyState as u8)));
+        assert_eq!(decode_command(&frame).err(), Some(FrameError::UnknownPayload(PayloadType::KeyState as u8)));
+    }
+
+    #[test]
+    fn other_protocol_versions_are_rejected() {
+        let (frame, _) = key_state_frame(0);
+        let mut buf = frame;
+        buf[1] = PROTOCOL_VERSION + 1;
+        reseal(&mut buf);
+        assert_eq!(Frame::decode(&buf).err(), Some(FrameError::UnsupportedVersion(PROTOCOL_VERSION + 1)));
+
+        // Except for the link info, so that the version of the other half can be told
+        let info = LinkInfo {
+            firmware_version: 0x0001_0203,
+            protocol_version: PROTOCOL_VERSION + 1,
+            capabilities: CAP_ENCODER,
+            led_count: 21,
+            matrix_rows: 3,
+            matrix_cols: 7,
+        };
+        let mut buf = [0; KEY_EVENTS_FRAME_LEN];
+        let len = encode_link_info(&mut FrameEncoder::starting_at(9), &info, &mut buf).unwrap();
+        assert_eq!(len, KEY_EVENTS_FRAME_LEN);
+        buf[1] = PROTOCOL_VERSION + 1;
+        reseal(&mut buf);
+        assert_eq!(decode_link_info(&buf), Ok((9, info)));
+        let ours = LinkInfo { protocol_version: PROTOCOL_VERSION, ..info };
+        assert_eq!(ours.compatibility(&info), Compatibility::Incompatible);
+        assert_eq!(ours.compatibility(&LinkInfo { led_count: 20, ..ours }), Compatibility::Degraded);
+        assert_eq!(ours.compatibility(&ours), Compatibility::Full);
+    }
+
+    #[test]
+    fn sequence_numbers_count_up_and_wrap() {
+        let mut encoder = FrameEncoder::starting_at(254);
+        let mut buf = [0; KEY_STATE_FRAME_LEN];
+        let seqs: Vec<u8, 4> = (0..4)
+            .map(|_| {
+                encode_key_state(&mut encoder, 0, &mut buf).unwrap();
+                decode_key_state(&buf).unwrap().0
+            })
+            .collect();
+        assert_eq!(seqs, [254, 255, 0, 1]);
+        // A failed encode does not use up a sequence number
+        assert!(encode_key_state(&mut encoder, 0, &mut buf[..4]).is_err());
+        encode_key_state(&mut encoder, 0, &mut buf).unwrap();
+        assert_eq!(decode_key_state(&buf).unwrap().0, 2);
+    }
+
+    #[test]
+    fn key_events_carry_their_age() {
+        let events = [
+            KeyChange { key: 20, pressed: true, time: 199_000 },
+            // Older than the age field can hold
+            KeyChange { key: 0, pressed: false, time: 0 },
+        ];
+        let mut buf = [0; KEY_EVENTS_FRAME_LEN];
+        let len = encode_key_events(&mut FrameEncoder::new(), 0x1F_FFFF, 5, 2, &events, 200_000, &mut buf).unwrap();
+        assert_eq!(len, KEY_EVENTS_FRAME_LEN);
+        // Received on a clock 1000 us ahead of the sender's
+        let frame = decode_key_events(&buf, 201_000).unwrap();
+        assert_eq!((frame.key_states, frame.ack_seq, frame.update_status), (0x1F_FFFF, 5, 2));
+        assert_eq!(
+            frame.events,
+            [
+                KeyChange { key: 20, pressed: true, time: 200_000 },
+                KeyChange { key: 0, pressed: false, time: 201_000 - u16::MAX as u32 },
+            ]
+        );
+
+        let too_many = [events[0]; MAX_EVENTS_PER_FRAME + 1];
+        let result = encode_key_events(&mut FrameEncoder::new(), 0, 0, 0, &too_many, 0, &mut buf);
+        assert_eq!(result, Err(FrameError::BadLength));
+        // An event count beyond the maximum
+        encode_key_events(&mut FrameEncoder::new(), 0, 0, 0, &[], 0, &mut buf).unwrap();
+        buf[HEADER_LEN + 6] = MAX_EVENTS_PER_FRAME as u8 + 1;
+        reseal(&mut buf);
+        assert_eq!(decode_key_events(&buf, 0).err(), Some(FrameError::BadLength));
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let mut data = [0; UPDATE_CHUNK_LEN];
+        data[..3].copy_from_slice(&[1, 2, 3]);
+        let commands = [
+            Command::SetLayer(3),
+            Command::SetHostLeds(0x05),
+            Command::SetLighting { mode: LightingMode::Layer, brightness: 200 },
+            Command::SetMatrixTiming(MatrixTiming { debounce_us: 5_000, settle_us: 30, anti_ghosting: false }),
+            Command::UpdateBegin(UpdateHeader { version: 0x0102_0304, size: 0x1_0000, crc: 0xDEAD_BEEF }),
+            Command::UpdateData { offset: 0x40, len: 3, data },
+            Command::UpdateData { offset: 0x80, len: UPDATE_CHUNK_LEN as u8, data: [0xEE; UPDATE_CHUNK_LEN] },
+            Command::UpdateEnd,
+            Command::Hello(LinkInfo {
+                firmware_version: 1,
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: 0,
+                led_count: 21,
+                matrix_rows: 3,
+                matrix_cols: 7,
+            }),
+        ];
+        let mut payload = [0; MAX_COMMAND_LEN];
+        let mut buf = [0; MAX_COMMAND_FRAME_LEN];
+        for command in commands {
+            let len = command.encode(&mut payload);
+            assert_eq!(Command::decode(&payload[..len]), Ok(command));
+            let len = FrameEncoder::starting_at(4).encode(PayloadType::Command, &payload[..len], &mut buf).unwrap();
+            assert_eq!(decode_command(&buf[..len]), Ok((4, command)));
+        }
+    }
+
+    #[test]
+    fn malformed_commands_are_rejected() {
+        assert_eq!(Command::decode(&[]), Err(FrameError::BadLength));
+        assert_eq!(Command::decode(&[0x00]), Err(FrameError::UnknownPayload(0x00)));
+        assert_eq!(Command::decode(&[0x09, 0]), Err(FrameError::UnknownPayload(0x09)));
+        // Too short and too long
+        assert_eq!(Command::decode(&[CommandId::SetLayer as u8]), Err(FrameError::BadLength));
+        assert_eq!(Command::decode(&[CommandId::SetLayer as u8, 1, 2]), Err(FrameError::BadLength));
+        assert_eq!(Command::decode(&[CommandId::UpdateData as u8, 0, 0, 0, 0]), Err(FrameError::BadLength));
+        // An unknown lighting mode
+        assert_eq!(Command::decode(&[CommandId::SetLighting as u8, 9, 0]), Err(FrameError::UnknownPayload(9)));
+    }
+
+    // Sends the next command of the queue to the receiver, returns the
+    // sequence number and the command if the receiver applied it
+    fn deliver(queue: &mut CommandQueue, receiver: &mut CommandReceiver, now: u32) -> Option<(u8, Command)> {
+        let mut buf = [0; MAX_COMMAND_FRAME_LEN];
+        let len = queue.next_frame(now, &mut buf)?;
+        let seq = decode_command(&buf[..len]).unwrap().0;
+        let applied = receiver.receive(&buf[..len]).map(|command| (seq, command));
+        queue.ack(receiver.ack_seq());
+        applied
+    }
+
+    #[test]
+    fn commands_are_applied_once_and_acknowledged() {
+        let mut queue = CommandQueue::new();
+        let mut receiver = CommandReceiver::new();
+        assert!(queue.is_empty());
+        assert_eq!(receiver.ack_seq(), 0);
+        // The acknowledgement of a slave that has received nothing yet
+        queue.ack(0);
+
+        queue.push(Command::SetLayer(1));
+        queue.push(Command::SetHostLeds(2));
+        // The first command has sequence number 1, not the 0 a fresh receiver acknowledges
+        let mut buf = [0; MAX_COMMAND_FRAME_LEN];
+        let len = queue.next_frame(0, &mut buf).unwrap();
+        assert_eq!(decode_command(&buf[..len]), Ok((1, Command::SetLayer(1))));
+        queue.ack(0);
+        assert_eq!(queue.next_frame(COMMAND_RESEND_US - 1, &mut buf), None);
+        assert_eq!(receiver.receive(&buf[..len]), Some(Command::SetLayer(1)));
+        assert_eq!(receiver.ack_seq(), 1);
+
+        // The acknowledgement is lost, the command is resent with
+        // the same sequence number and not applied again
+        let len = queue.next_frame(COMMAND_RESEND_US, &mut buf).unwrap();
+        assert_eq!(decode_command(&buf[..len]), Ok((1, Command::SetLayer(1))));
+        assert_eq!(receiver.receive(&buf[..len]), None);
+        assert_eq!(receiver.ack_seq(), 1);
+        queue.ack(receiver.ack_seq());
+
+        assert_eq!(deliver(&mut queue, &mut receiver, 0), Some((2, Command::SetHostLeds(2))));
+        assert!(queue.is_empty());
+        assert_eq!(queue.next_frame(COMMAND_RESEND_US, &mut buf), None);
+    }
+
+    #[test]
+    fn corrupt_commands_are_not_acknowledged() {
+        let mut queue = CommandQueue::new();
+        let mut receiver = CommandReceiver::new();
+        queue.push(Command::SetLayer(1));
+        let mut buf = [0; MAX_COMMAND_FRAME_LEN];
+        let len = queue.next_frame(0, &mut buf).unwrap();
+        buf[HEADER_LEN + 1] ^= 0x01;
+        assert_eq!(receiver.receive(&buf[..len]), None);
+        queue.ack(receiver.ack_seq());
+        assert!(!queue.is_empty());
+        assert_eq!(deliver(&mut queue, &mut receiver, COMMAND_RESEND_US), Some((1, Command::SetLayer(1))));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around_zero() {
+        let mut queue = CommandQueue::new();
+        let mut receiver = CommandReceiver::new();
+        let mut seqs = std::vec::Vec::new();
+        for i in 0..600u32 {
+            queue.push(Command::SetLayer(i as u8));
+            let (seq, command) = deliver(&mut queue, &mut receiver, i * COMMAND_RESEND_US).unwrap();
+            assert_eq!(command, Command::SetLayer(i as u8));
+            seqs.push(seq);
+        }
+        assert!(queue.is_empty());
+        assert_eq!(seqs[253..257], [254, 255, 1, 2]);
+        assert!(!seqs.contains(&0));
+
+        // A slave that restarts once the numbers have wrapped still
+        // applies the next command rather than taking it for one it has seen
+        let mut receiver = CommandReceiver::new();
+        queue.push(Command::SetLayer(9));
+        queue.ack(receiver.ack_seq());
+        assert!(!queue.is_empty());
+        assert!(deliver(&mut queue, &mut receiver, 0).is_some());
+    }
+
+    #[test]
+    fn queued_settings_are_replaced_by_newer_ones() {
+        let mut queue = CommandQueue::new();
+        let mut receiver = CommandReceiver::new();
+        let mut buf = [0; MAX_COMMAND_FRAME_LEN];
+        queue.push(Command::SetLayer(1));
+        let len = queue.next_frame(0, &mut buf).unwrap();
+        // The command in flight is kept, the one queued after it is replaced
+        queue.push(Command::SetLayer(2));
+        queue.push(Command::SetHostLeds(1));
+        queue.push(Command::SetLayer(3));
+        assert_eq!(receiver.receive(&buf[..len]), Some(Command::SetLayer(1)));
+        queue.ack(receiver.ack_seq());
+        assert_eq!(deliver(&mut queue, &mut receiver, 0), Some((2, Command::SetLayer(3))));
+        assert_eq!(deliver(&mut queue, &mut receiver, 0), Some((3, Command::SetHostLeds(1))));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn update_commands_are_handed_back_when_the_queue_is_full() {
+        let mut queue = CommandQueue::new();
+        let mut receiver = CommandReceiver::new();
+        let data = |offset: u32| {
+            let mut data = [0; UPDATE_CHUNK_LEN];
+            data[0] = offset as u8;
+            Command::UpdateData { offset, len: 1, data }
+        };
+        for i in 0..MAX_UPDATE_COMMANDS as u32 {
+            assert_eq!(queue.push_update(data(i)), Ok(()));
+        }
+        assert_eq!(queue.push_update(data(99)), Err(data(99)));
+        // `push` drops an update command that doesn't fit
+        queue.push(Command::UpdateEnd);
+        // Settings still have room
+        queue.push(Command::SetLayer(4));
+
+        // Once the slave has caught up there is room again
+        assert_eq!(deliver(&mut queue, &mut receiver, 0), Some((1, data(0))));
+        assert_eq!(queue.push_update(data(4)), Ok(()));
+        assert_eq!(queue.push_update(data(5)), Err(data(5)));
+        let applied: std::vec::Vec<Command> =
+            core::iter::from_fn(|| deliver(&mut queue, &mut receiver, 0).map(|(_, command)| command)).collect();
+        assert_eq!(applied, [data(1), data(2), data(3), Command::SetLayer(4), data(4)]);
+    }
+}
@@ -0,0 +1,586 @@
+/*
+* Log-structured store of small settings, such as the brightness or the
+* matrix timing, kept in a region of the EEPROM or of the flash.
+*
+* The region is split into two banks, one of which is active at a time.
+* Each bank starts with an 8 byte header, all fields big-endian:
+*   0..2   magic, "ST"
+*   2..6   generation, incremented each time the store moves bank
+*   6..8   CRC-16 of the above
+* followed by a log of records, each replacing any earlier value of its key:
+*   0      key
+*   1      length of the value, 0 removes the setting
+*   2..    value
+*   ..+2   CRC-16 of the generation of the bank followed by the above
+* The log ends at the first record that fails its CRC. Covering the
+* generation means records left from earlier use of the bank are never
+* taken as valid, even where an erase of the bank was cut short.
+*
+* The store is kept in NOR flash, or storage that behaves like it, so that
+* records are only ever written to erased space and a bank is only erased
+* when the store moves to it. Setting a value appends a single record, so an
+* interrupted write leaves either the old or the new value. It may also leave
+* part of the record after the end of the log, in which case the next value
+* is not appended but written along with the others to the other bank.
+*
+* Once the active bank is full the other bank is erased, the live values
+* are copied to it, and only when all of them have been written is its
+* header, with the next generation, written. Until then the old bank, having
+* the later generation, remains the active one. Alternating between the
+* banks, and appending rather than rewriting values in place, spreads the
+* writes over the whole region
+*/
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use heapless::Vec;
+
+use crate::protocol::crc16;
+
+pub const SETTINGS_MAGIC: u16 = 0x5354;
+pub const SETTINGS_HEADER_LEN: u32 = 8;
+// Longest value of a setting
+pub const MAX_VALUE_LEN: usize = 32;
+// Number of different keys the store can hold
+pub const MAX_SETTINGS: usize = 32;
+const RECORD_OVERHEAD: usize = 4;
+const MAX_RECORD_LEN: usize = MAX_VALUE_LEN + RECORD_OVERHEAD;
+
+#[derive(Debug)]
+pub enum SettingsError<E> {
+    Storage(E),
+    // The live values don't fit in a bank, or there are too many keys
+    Full,
+    ValueTooLong,
+}
+
+// A value that can be kept in the store
+pub trait SettingValue: Sized {
+    // Serializes the value into `buf`, returning the number of bytes used
+    fn to_bytes(&self, buf: &mut [u8; MAX_VALUE_LEN]) -> usize;
+    // None if the bytes are not a valid value
+    fn from_bytes(buf: &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_setting_value {
+    ($($t:ty),*) => {
+        $(
+            impl SettingValue for $t {
+                fn to_bytes(&self, buf: &mut [u8; MAX_VALUE_LEN]) -> usize {
+                    let bytes = self.to_be_bytes();
+                    buf[..bytes.len()].copy_from_slice(&bytes);
+                    bytes.len()
+                }
+
+                fn from_bytes(buf: &[u8]) -> Option<Self> {
+                    Some(<$t>::from_be_bytes(buf.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_setting_value!(u8, u16, u32, i8, i16, i32);
+
+impl SettingValue for bool {
+    fn to_bytes(&self, buf: &mut [u8; MAX_VALUE_LEN]) -> usize {
+        buf[0] = *self as u8;
+        1
+    }
+
+    fn from_bytes(buf: &[u8]) -> Option<Self> {
+        match buf {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+// A typed setting, with the value used while none is stored
+pub struct Setting<T> {
+    pub key: u8,
+    pub default: T,
+}
+
+impl<T: SettingValue> Setting<T> {
+    pub const fn new(key: u8, default: T) -> Self {
+        Setting { key, default }
+    }
+}
+
+// Offsets of the live records are kept in RAM, the values are read from
+// storage when asked for. The storage is passed to each call, so that
+// the rest of it can be used for other purposes in between
+pub struct SettingsStore {
+    base: u32,
+    bank_size: u32,
+    // Index of the active bank, and its generation
+    bank: u32,
+    generation: u32,
+    // Offset in the active bank where the next record goes
+    end: u32,
+    // Key and offset of the latest record of each key
+    index: Vec<(u8, u32), MAX_SETTINGS>,
+    // Set when the space after the log may not be erased
+    dirty: bool,
+}
+
+impl SettingsStore {
+    // Opens the store in the `2 * bank_size` bytes at `base`, formatting it if
+    // neither bank holds a valid header. Both must be multiples of the erase
+    // size of the storage, which must allow writes of single bytes
+    pub fn mount<S: NorFlash>(storage: &mut S, base: u32, bank_size: u32) -> Result<Self, SettingsError<S::Error>> {
+        let mut store = SettingsStore {
+            base,
+            bank_size,
+            bank: 0,
+            generation: 0,
+            end: SETTINGS_HEADER_LEN,
+            index: Vec::new(),
+            dirty: false,
+        };
+        let headers = [
+            store.read_header(storage, 0)?,
+            store.read_header(storage, 1)?,
+        ];
+        let active = match headers {
+            [Some(a), Some(b)] => Some(if b.wrapping_sub(a) as i32 > 0 { (1, b) } else { (0, a) }),
+            [Some(a), None] => Some((0, a)),
+            [None, Some(b)] => Some((1, b)),
+            [None, None] => None,
+        };
+        match active {
+            Some((bank, generation)) => {
+                store.bank = bank;
+                store.generation = generation;
+                store.scan(storage)?;
+            }
+            None => {
+                store.erase_bank(storage, 0)?;
+                store.write_header(storage, 0, 1)?;
+            }
+        }
+        Ok(store)
+    }
+
+    fn bank_address(&self, bank: u32) -> u32 {
+        self.base + bank * self.bank_size
+    }
+
+    // Returns the generation of a bank with a valid header
+    fn read_header<S: ReadNorFlash>(&self, storage: &mut S, bank: u32) -> Result<Option<u32>, SettingsError<S::Error>> {
+        let mut header = [0; SETTINGS_HEADER_LEN as usize];
+        storage.read(self.bank_address(bank), &mut header).map_err(SettingsError::Storage)?;
+        let valid = u16::from_be_bytes([header[0], header[1]]) == SETTINGS_MAGIC
+            && u16::from_be_bytes([header[6], header[7]]) == crc16(&header[..6]);
+        Ok(valid.then(|| u32::from_be_bytes([header[2], header[3], header[4], header[5]])))
+    }
+
+    // Makes `bank` the active bank, holding the records already written to it
+    fn write_header<S: NorFlash>(&mut self, storage: &mut S, bank: u32, generation: u32) -> Result<(), SettingsError<S::Error>> {
+        let mut header = [0; SETTINGS_HEADER_LEN as usize];
+        header[0..2].copy_from_slice(&SETTINGS_MAGIC.to_be_bytes());
+        header[2..6].copy_from_slice(&generation.to_be_bytes());
+        let crc = crc16(&header[..6]);
+        header[6..8].copy_from_slice(&crc.to_be_bytes());
+        storage.write(self.bank_address(bank), &header).map_err(SettingsError::Storage)?;
+        self.bank = bank;
+        self.generation = generation;
+        Ok(())
+    }
+
+    fn erase_bank<S: NorFlash>(&self, storage: &mut S, bank: u32) -> Result<(), SettingsError<S::Error>> {
+        let address = self.bank_address(bank);
+        storage.erase(address, address + self.bank_size).map_err(SettingsError::Storage)
+    }
+
+    // Reads the record at `offset` of the active bank into `buf`, following
+    // its generation, returning its length. None if there is no valid record
+    fn read_record<S: ReadNorFlash>(
+        &self,
+        storage: &mut S,
+        offset: u32,
+        buf: &mut [u8; MAX_RECORD_LEN + 4],
+    ) -> Result<Option<usize>, SettingsError<S::Error>> {
+        let address = self.bank_address(self.bank) + offset;
+        if offset + RECORD_OVERHEAD as u32 > self.bank_size {
+            return Ok(None);
+        }
+        buf[..4].copy_from_slice(&self.generation.to_be_bytes());
+        storage.read(address, &mut buf[4..6]).map_err(SettingsError::Storage)?;
+        let value_len = buf[5] as usize;
+        let len = value_len + RECORD_OVERHEAD;
+        if value_len > MAX_VALUE_LEN || offset + len as u32 > self.bank_size {
+            return Ok(None);
+        }
+        storage.read(address + 2, &mut buf[6..4 + len]).map_err(SettingsError::Storage)?;
+        let crc = u16::from_be_bytes([buf[2 + len], buf[3 + len]]);
+        Ok((crc16(&buf[..2 + len]) == crc).then_some(len))
+    }
+
+    // Rebuilds the index from the log of the active bank, and checks
+    // that the space after it is erased
+    fn scan<S: ReadNorFlash>(&mut self, storage: &mut S) -> Result<(), SettingsError<S::Error>> {
+        let mut buf = [0; MAX_RECORD_LEN + 4];
+        self.index.clear();
+        self.end = SETTINGS_HEADER_LEN;
+        while let Some(len) = self.read_record(storage, self.end, &mut buf)? {
+            // Keys beyond what the index can hold are ignored
+            let _ = self.index_record(buf[4], self.end);
+            self.end += len as u32;
+        }
+        let mut offset = self.end;
+        self.dirty = false;
+        while offset < self.bank_size && !self.dirty {
+            let len = (self.bank_size - offset).min(buf.len() as u32) as usize;
+            storage
+                .read(self.bank_address(self.bank) + offset, &mut buf[..len])
+                .map_err(SettingsError::Storage)?;
+            self.dirty = buf[..len].iter().any(|b| *b != 0xFF);
+            offset += len as u32;
+        }
+        Ok(())
+    }
+
+    fn index_record(&mut self, key: u8, offset: u32) -> Result<(), ()> {
+        match self.index.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = offset,
+            None => self.index.push((key, offset)).map_err(|_| ())?,
+        }
+        Ok(())
+    }
+
+    fn record_offset(&self, key: u8) -> Option<u32> {
+        self.index.iter().find(|(k, _)| *k == key).map(|(_, offset)| *offset)
+    }
+
+    // Copies the value of `key` into `buf`, returning its length.
+    // None if the key has no value
+    pub fn read<S: ReadNorFlash>(
+        &self,
+        storage: &mut S,
+        key: u8,
+        buf: &mut [u8; MAX_VALUE_LEN],
+    ) -> Result<Option<usize>, SettingsError<S::Error>> {
+        let offset = match self.record_offset(key) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let mut record = [0; MAX_RECORD_LEN + 4];
+        match self.read_record(storage, offset, &mut record)? {
+            Some(len) if len > RECORD_OVERHEAD => {
+                let value_len = len - RECORD_OVERHEAD;
+                buf[..value_len].copy_from_slice(&record[6..6 + value_len]);
+                Ok(Some(value_len))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Stores `value` as the value of `key`, an empty value removes it.
+    // Nothing is written if the value is unchanged
+    pub fn write<S: NorFlash>(&mut self, storage: &mut S, key: u8, value: &[u8]) -> Result<(), SettingsError<S::Error>> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(SettingsError::ValueTooLong);
+        }
+        let mut current = [0; MAX_VALUE_LEN];
+        match self.read(storage, key, &mut current)? {
+            Some(len) if current[..len] == *value => return Ok(()),
+            None if value.is_empty() => return Ok(()),
+            _ => {}
+        }
+        if self.record_offset(key).is_none() && self.index.is_full() {
+            return Err(SettingsError::Full);
+        }
+        let len = (value.len() + RECORD_OVERHEAD) as u32;
+        if self.dirty || self.end + len > self.bank_size {
+            self.compact(storage)?;
+            if self.end + len > self.bank_size {
+                return Err(SettingsError::Full);
+            }
+        }
+        let offset = self.end;
+        // A write that fails may leave part of the record behind
+        self.dirty = true;
+        self.append(storage, self.bank, self.generation, offset, key, value)?;
+        self.dirty = false;
+        self.end += len;
+        let _ = self.index_record(key, offset);
+        Ok(())
+    }
+
+    fn append<S: NorFlash>(
+        &self,
+        storage: &mut S,
+        bank: u32,
+        generation: u32,
+        offset: u32,
+        key: u8,
+        value: &[u8],
+    ) -> Result<(), SettingsError<S::Error>> {
+        let mut record = [0; MAX_RECORD_LEN + 4];
+        let len = value.len() + RECORD_OVERHEAD;
+        record[..4].copy_from_slice(&generation.to_be_bytes());
+        record[4] = key;
+        record[5] = value.len() as u8;
+        record[6..6 + value.len()].copy_from_slice(value);
+        let crc = crc16(&record[..2 + len]);
+        record[2 + len..4 + len].copy_from_slice(&crc.to_be_bytes());
+        storage
+            .write(self.bank_address(bank) + offset, &record[4..4 + len])
+            .map_err(SettingsError::Storage)
+    }
+
+    // Copies the live values to the other bank and makes it the active one
+    fn compact<S: NorFlash>(&mut self, storage: &mut S) -> Result<(), SettingsError<S::Error>> {
+        let bank = 1 - self.bank;
+        let generation = self.generation.wrapping_add(1);
+        self.erase_bank(storage, bank)?;
+        let mut index: Vec<(u8, u32), MAX_SETTINGS> = Vec::new();
+        let mut end = SETTINGS_HEADER_LEN;
+        let mut value = [0; MAX_VALUE_LEN];
+        for (key, _) in self.index.iter() {
+            let len = match self.read(storage, *key, &mut value)? {
+                Some(len) => len,
+                // Removed settings are dropped
+                None => continue,
+            };
+            if end + (len + RECORD_OVERHEAD) as u32 > self.bank_size {
+                return Err(SettingsError::Full);
+            }
+            self.append(storage, bank, generation, end, *key, &value[..len])?;
+            let _ = index.push((*key, end));
+            end += (len + RECORD_OVERHEAD) as u32;
+        }
+        self.write_header(storage, bank, generation)?;
+        self.index = index;
+        self.end = end;
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Returns the stored value of a setting, or its default if
+    // none is stored or it can't be read
+    pub fn get<S: ReadNorFlash, T: SettingValue + Copy>(&self, storage: &mut S, setting: &Setting<T>) -> T {
+        let mut buf = [0; MAX_VALUE_LEN];
+        match self.read(storage, setting.key, &mut buf) {
+            Ok(Some(len)) => T::from_bytes(&buf[..len]).unwrap_or(setting.default),
+            _ => setting.default,
+        }
+    }
+
+    pub fn set<S: NorFlash, T: SettingValue>(
+        &mut self,
+        storage: &mut S,
+        setting: &Setting<T>,
+        value: T,
+    ) -> Result<(), SettingsError<S::Error>> {
+        let mut buf = [0; MAX_VALUE_LEN];
+        let len = value.to_bytes(&mut buf);
+        self.write(storage, setting.key, &buf[..len])
+    }
+
+    // Removes a setting, so that its default is used
+    pub fn reset<S: NorFlash, T>(&mut self, storage: &mut S, setting: &Setting<T>) -> Result<(), SettingsError<S::Error>> {
+        self.write(storage, setting.key, &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{check_erase, ErrorType, NorFlashError, NorFlashErrorKind};
+
+    const BANK_SIZE: u32 = 64;
+    const VALUE: Setting<u16> = Setting::new(1, 0);
+    const FILLER: Setting<u8> = Setting::new(2, 0);
+    const OLD: u16 = 1000;
+    const NEW: u16 = 2000;
+
+    #[derive(Debug)]
+    struct PowerCut;
+
+    impl NorFlashError for PowerCut {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    // Flash that loses power after a number of bytes have been programmed
+    // or erased. The operation in progress is cut short, optionally leaving
+    // the byte where it stopped half done, and every later one fails
+    struct PowerCutStorage {
+        memory: Vec<u8, 256>,
+        bytes_left: Option<usize>,
+        garble: bool,
+        powered: bool,
+        // Number of bytes programmed or erased
+        written: usize,
+        erases: usize,
+    }
+
+    impl PowerCutStorage {
+        fn new() -> Self {
+            let mut memory = Vec::new();
+            memory.resize(2 * BANK_SIZE as usize, 0xFF).unwrap();
+            PowerCutStorage { memory, bytes_left: None, garble: false, powered: true, written: 0, erases: 0 }
+        }
+
+        // Programs the bytes given by `data` from `offset`, which like on
+        // flash can only clear bits, or erases them, as far as the power lasts
+        fn apply(&mut self, offset: u32, len: usize, erase: bool, data: impl Fn(usize) -> u8) -> Result<(), PowerCut> {
+            if !self.powered {
+                return Err(PowerCut);
+            }
+            let offset = offset as usize;
+            let done = len.min(self.bytes_left.unwrap_or(usize::MAX));
+            for i in 0..done {
+                let byte = &mut self.memory[offset + i];
+                *byte = if erase { 0xFF } else { *byte & data(i) };
+            }
+            self.written += done;
+            if done == len {
+                self.bytes_left = self.bytes_left.map(|left| left - done);
+                return Ok(());
+            }
+            if self.garble {
+                let byte = &mut self.memory[offset + done];
+                *byte = if erase { *byte | 0xF0 } else { *byte & (data(done) | 0x0F) };
+            }
+            self.powered = false;
+            Err(PowerCut)
+        }
+    }
+
+    impl ErrorType for PowerCutStorage {
+        type Error = PowerCut;
+    }
+
+    impl ReadNorFlash for PowerCutStorage {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerCut> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.memory[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.memory.len()
+        }
+    }
+
+    impl NorFlash for PowerCutStorage {
+        const WRITE_SIZE: usize = 1;
+        const ERASE_SIZE: usize = 32;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), PowerCut> {
+            assert_eq!(check_erase(self, from, to), Ok(()));
+            self.erases += 1;
+            self.apply(from, (to - from) as usize, true, |_| 0xFF)
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerCut> {
+            self.apply(offset, bytes.len(), false, |i| bytes[i])
+        }
+    }
+
+    // Storage holding VALUE set to OLD, after `fill` changes of FILLER
+    fn prepared(fill: u8) -> PowerCutStorage {
+        let mut storage = PowerCutStorage::new();
+        let mut store = SettingsStore::mount(&mut storage, 0, BANK_SIZE).unwrap();
+        store.set(&mut storage, &VALUE, OLD).unwrap();
+        for i in 0..fill {
+            store.set(&mut storage, &FILLER, i + 1).unwrap();
+        }
+        storage
+    }
+
+    // Cuts the power at every byte of setting VALUE to NEW, with and without
+    // a garbled byte, and checks what mount finds after. Returns the number
+    // of bytes written by the set
+    fn check_power_loss(fill: u8) -> usize {
+        let mut storage = prepared(fill);
+        let mut store = SettingsStore::mount(&mut storage, 0, BANK_SIZE).unwrap();
+        storage.written = 0;
+        store.set(&mut storage, &VALUE, NEW).unwrap();
+        let total = storage.written;
+        assert!(total > 0);
+
+        for cut in 0..total {
+            for garble in [false, true] {
+                let mut storage = prepared(fill);
+                let mut store = SettingsStore::mount(&mut storage, 0, BANK_SIZE).unwrap();
+                storage.bytes_left = Some(cut);
+                storage.garble = garble;
+                assert!(store.set(&mut storage, &VALUE, NEW).is_err());
+
+                storage.bytes_left = None;
+                storage.powered = true;
+                let mut store = SettingsStore::mount(&mut storage, 0, BANK_SIZE).unwrap();
+                let value = store.get(&mut storage, &VALUE);
+                assert!(
+                    value == OLD || value == NEW,
+                    "fill {} cut at {} of {} bytes, garbled {}: read {}",
+                    fill, cut, total, garble, value,
+                );
+                assert_eq!(store.get(&mut storage, &FILLER), fill);
+
+                // The store carries on from where it was left
+                store.set(&mut storage, &VALUE, NEW + 1).unwrap();
+                let mut store = SettingsStore::mount(&mut storage, 0, BANK_SIZE).unwrap();
+                assert_eq!(store.get(&mut storage, &VALUE), NEW + 1);
+                store.set(&mut storage, &FILLER, fill + 1).unwrap();
+                assert_eq!(store.get(&mut storage, &FILLER), fill + 1);
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn values_survive_remount() {
+        let mut storage = PowerCutStorage::new();
+        let mut store = SettingsStore::mount(&mut storage, 0, BANK_SIZE).unwrap();
+        assert_eq!(store.get(&mut storage, &VALUE), 0);
+        store.set(&mut storage, &VALUE, OLD).unwrap();
+        store.set(&mut storage, &FILLER, 7).unwrap();
+        let mut store = SettingsStore::mount(&mut storage, 0, BANK_SIZE).unwrap();
+        assert_eq!(store.get(&mut storage, &VALUE), OLD);
+        assert_eq!(store.get(&mut storage, &FILLER), 7);
+        store.reset(&mut storage, &FILLER).unwrap();
+        let store = SettingsStore::mount(&mut storage, 0, BANK_SIZE).unwrap();
+        assert_eq!(store.get(&mut storage, &FILLER), 0);
+    }
+
+    #[test]
+    fn banks_are_erased_when_compacting() {
+        let mut storage = PowerCutStorage::new();
+        let mut store = SettingsStore::mount(&mut storage, 0, BANK_SIZE).unwrap();
+        assert_eq!(storage.erases, 1);
+        let mut moves = 0;
+        for i in 0..40 {
+            let bank = store.bank;
+            store.set(&mut storage, &FILLER, i).unwrap();
+            if store.bank != bank {
+                moves += 1;
+            }
+            assert_eq!(storage.erases, 1 + moves);
+        }
+        assert!(moves >= 2);
+    }
+
+    #[test]
+    fn power_loss_while_appending() {
+        // The bank has room for the new record
+        for fill in 0..3 {
+            assert_eq!(check_power_loss(fill), 6);
+        }
+    }
+
+    #[test]
+    fn power_loss_while_compacting() {
+        // Enough fillers that setting the value moves the store to the other
+        // bank, and back again, starting at each position in the bank
+        let compactions = (3..30).filter(|fill| check_power_loss(*fill) > 6).count();
+        assert!(compactions >= 2);
+    }
+}
@@ -0,0 +1,149 @@
+/*
+* The I2C transport, the master is the bus controller and the slave
+* a peripheral at `SLAVE_ADDRESS`. Command frames are written to the
+* slave and the key events frame is read from it
+*/
+use rp_pico::hal as hal;
+use embedded_hal::blocking::i2c::{Read, Write};
+use hal::i2c::peripheral::{I2CEvent, I2CPeripheralEventIterator};
+use hal::pac;
+
+use crate::protocol::{i2c_transfer_time_us, MAX_FRAME_LEN, SLAVE_ADDRESS};
+use crate::transport::{MasterTransport, SlaveEvent, SlaveTransport, TransportError};
+
+pub struct I2cMaster<I> {
+    i2c: I,
+    bus_freq_hz: u32,
+}
+
+impl<I: Read + Write> I2cMaster<I> {
+    pub fn new(i2c: I, bus_freq_hz: u32) -> Self {
+        I2cMaster { i2c, bus_freq_hz }
+    }
+
+    // Returns the bus, e.g. to recover it
+    pub fn free(self) -> I {
+        self.i2c
+    }
+}
+
+impl<I: Read + Write> MasterTransport for I2cMaster<I> {
+    fn send_command(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        self.i2c
+            .write(SLAVE_ADDRESS, frame)
+            .map_err(|_| TransportError::Bus)
+    }
+
+    // Nothing to do, the slave builds the frame as it is read
+    fn request_frame(&mut self) -> Result<(), TransportError> {
+        Ok(())
+    }
+
+    fn read_frame(&mut self, buf: &mut [u8]) -> nb::Result<usize, TransportError> {
+        self.i2c
+            .read(SLAVE_ADDRESS, buf)
+            .map_err(|_| nb::Error::Other(TransportError::Bus))?;
+        Ok(buf.len())
+    }
+
+    fn frame_time_us(&self, len: usize) -> u32 {
+        i2c_transfer_time_us(len, self.bus_freq_hz)
+    }
+}
+
+pub struct I2cSlave<P> {
+    i2c: I2CPeripheralEventIterator<pac::I2C1, P>,
+    tx_buf: [u8; MAX_FRAME_LEN],
+    tx_len: usize,
+    // Number of bytes of the frame sent to the master
+    tx_pos: usize,
+    // Set once the frame has been asked for in the current transfer
+    requested: bool,
+    rx_buf: [u8; MAX_FRAME_LEN],
+    rx_len: usize,
+    command_len: usize,
+}
+
+impl<P> I2cSlave<P> {
+    pub fn new(i2c: I2CPeripheralEventIterator<pac::I2C1, P>) -> Self {
+        I2cSlave {
+            i2c,
+            tx_buf: [0; MAX_FRAME_LEN],
+            tx_len: 0,
+            tx_pos: 0,
+            requested: false,
+            rx_buf: [0; MAX_FRAME_LEN],
+            rx_len: 0,
+            command_len: 0,
+        }
+    }
+
+    // The TX FIFO is smaller than a frame, so the frame is
+    // written in parts as the master reads it
+    fn write_pending(&mut self) {
+        if self.tx_pos < self.tx_len {
+            self.tx_pos += self.i2c.write(&self.tx_buf[self.tx_pos..self.tx_len]);
+        } else {
+            let _ = self.i2c.write(&[0]);
+        }
+    }
+}
+
+impl<P> SlaveTransport for I2cSlave<P> {
+    fn poll(&mut self) -> Option<SlaveEvent> {
+        match self.i2c.next()? {
+            I2CEvent::Start | I2CEvent::Restart => {
+                self.requested = false;
+                self.rx_len = 0;
+                None
+            }
+            // The frame is asked for on the first read of every transfer, so that
+            // the ages of the events are relative to the time it is sent, and
+            // no events are consumed by transfers writing commands
+            I2CEvent::TransferRead => {
+                if !self.requested {
+                    self.requested = true;
+                    self.tx_len = 0;
+                    self.tx_pos = 0;
+                    return Some(SlaveEvent::FrameRequested);
+                }
+                self.write_pending();
+                None
+            }
+            I2CEvent::TransferWrite => {
+                if self.rx_len < self.rx_buf.len() {
+                    self.rx_len += self.i2c.read(&mut self.rx_buf[self.rx_len..]);
+                } else {
+                    // Drain bytes beyond the largest frame,
+                    // the frame is dropped once complete
+                    let mut overflow = [0; 16];
+                    let _ = self.i2c.read(&mut overflow);
+                    self.rx_len = self.rx_buf.len() + 1;
+                }
+                None
+            }
+            I2CEvent::Stop => {
+                let len = self.rx_len;
+                self.rx_len = 0;
+                if len == 0 || len > self.rx_buf.len() {
+                    return None;
+                }
+                self.command_len = len;
+                Some(SlaveEvent::CommandReceived)
+            }
+        }
+    }
+
+    fn respond(&mut self, frame: &[u8]) {
+        let len = frame.len().min(MAX_FRAME_LEN);
+        self.tx_buf[..len].copy_from_slice(&frame[..len]);
+        self.tx_len = len;
+        self.tx_pos = 0;
+        // The read that asked for the frame is still waiting for data
+        self.write_pending();
+    }
+
+    fn command(&self) -> &[u8] {
+        &self.rx_buf[..self.command_len]
+    }
+}
@@ -0,0 +1,289 @@
+/*
+* In-memory serial link, connecting a `SerialMaster` directly to a
+* `SerialSlave` so that the link protocol can be run on the host
+*/
+use core::convert::Infallible;
+
+use embedded_hal::serial;
+use heapless::spsc::{Consumer, Producer, Queue};
+
+// Room for a few frames in each direction
+const LOOPBACK_LEN: usize = 128;
+
+pub struct Loopback {
+    master_to_slave: Queue<u8, LOOPBACK_LEN>,
+    slave_to_master: Queue<u8, LOOPBACK_LEN>,
+}
+
+impl Loopback {
+    pub const fn new() -> Self {
+        Loopback {
+            master_to_slave: Queue::new(),
+            slave_to_master: Queue::new(),
+        }
+    }
+
+    // Returns the master and slave ends of the link
+    pub fn split(&mut self) -> (LoopbackPort<'_>, LoopbackPort<'_>) {
+        let (m_tx, s_rx) = self.master_to_slave.split();
+        let (s_tx, m_rx) = self.slave_to_master.split();
+        (
+            LoopbackPort { tx: m_tx, rx: m_rx },
+            LoopbackPort { tx: s_tx, rx: s_rx },
+        )
+    }
+}
+
+pub struct LoopbackPort<'a> {
+    tx: Producer<'a, u8, LOOPBACK_LEN>,
+    rx: Consumer<'a, u8, LOOPBACK_LEN>,
+}
+
+impl<'a> serial::Read<u8> for LoopbackPort<'a> {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        self.rx.dequeue().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl<'a> serial::Write<u8> for LoopbackPort<'a> {
+    type Error = Infallible;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Infallible> {
+        self.tx.enqueue(word).map_err(|_| nb::Error::WouldBlock)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use crate::lighting::LightingMode;
+    use crate::protocol::{
+        decode_key_events, encode_key_events, Command, CommandQueue, CommandReceiver, FrameEncoder, FrameError,
+        KeyChange, KeyEventsFrame, COMMAND_RESEND_US, KEY_EVENTS_FRAME_LEN, MAX_COMMAND_FRAME_LEN,
+    };
+    use crate::transport::serial::{SerialMaster, SerialSlave, UART_BAUD_RATE};
+    use crate::transport::{MasterTransport, SlaveEvent, SlaveTransport};
+
+    // A port that flips the bits of one of the bytes written through it,
+    // the byte the given number of bytes after the one written next
+    struct Corrupting<'a> {
+        port: LoopbackPort<'a>,
+        corrupt: Rc<Cell<Option<usize>>>,
+    }
+
+    impl<'a> serial::Read<u8> for Corrupting<'a> {
+        type Error = Infallible;
+
+        fn read(&mut self) -> nb::Result<u8, Infallible> {
+            self.port.read()
+        }
+    }
+
+    impl<'a> serial::Write<u8> for Corrupting<'a> {
+        type Error = Infallible;
+
+        fn write(&mut self, word: u8) -> nb::Result<(), Infallible> {
+            let word = match self.corrupt.get() {
+                Some(0) => {
+                    self.corrupt.set(None);
+                    !word
+                }
+                Some(n) => {
+                    self.corrupt.set(Some(n - 1));
+                    word
+                }
+                None => word,
+            };
+            self.port.write(word)
+        }
+
+        fn flush(&mut self) -> nb::Result<(), Infallible> {
+            self.port.flush()
+        }
+    }
+
+    // Offset of the first payload byte, corrupting it fails the CRC
+    // but leaves the frame length intact
+    const PAYLOAD_OFFSET: usize = 5;
+
+    // Both halves of the link, with the command handling of the firmware
+    struct Link<'a> {
+        master: SerialMaster<Corrupting<'a>>,
+        slave: SerialSlave<Corrupting<'a>>,
+        corrupt_master: Rc<Cell<Option<usize>>>,
+        corrupt_slave: Rc<Cell<Option<usize>>>,
+        commands: CommandQueue,
+        receiver: CommandReceiver,
+        encoder: FrameEncoder,
+        // Commands applied by the slave, in order
+        applied: Vec<Command>,
+    }
+
+    impl<'a> Link<'a> {
+        fn new(loopback: &'a mut Loopback) -> Self {
+            let (master, slave) = loopback.split();
+            let corrupt_master = Rc::new(Cell::new(None));
+            let corrupt_slave = Rc::new(Cell::new(None));
+            Link {
+                master: SerialMaster::new(
+                    Corrupting {
+                        port: master,
+                        corrupt: corrupt_master.clone(),
+                    },
+                    UART_BAUD_RATE,
+                ),
+                slave: SerialSlave::new(Corrupting {
+                    port: slave,
+                    corrupt: corrupt_slave.clone(),
+                }),
+                corrupt_master,
+                corrupt_slave,
+                commands: CommandQueue::new(),
+                receiver: CommandReceiver::new(),
+                encoder: FrameEncoder::new(),
+                applied: Vec::new(),
+            }
+        }
+
+        // Sends the next command if one is due, returns whether one was sent
+        fn send_command(&mut self, now: u32) -> bool {
+            let mut buf = [0; MAX_COMMAND_FRAME_LEN];
+            match self.commands.next_frame(now, &mut buf) {
+                Some(len) => {
+                    self.master.send_command(&buf[..len]).unwrap();
+                    true
+                }
+                None => false,
+            }
+        }
+
+        // Runs the slave until it has handled everything sent to it,
+        // answering frame requests with the given events as of `now`
+        fn run_slave(&mut self, events: &[KeyChange], now: u32) {
+            while let Some(event) = self.slave.poll() {
+                match event {
+                    SlaveEvent::CommandReceived => {
+                        if let Some(command) = self.receiver.receive(self.slave.command()) {
+                            self.applied.push(command);
+                        }
+                    }
+                    SlaveEvent::FrameRequested => {
+                        let mut buf = [0; KEY_EVENTS_FRAME_LEN];
+                        let ack_seq = self.receiver.ack_seq();
+                        let len = encode_key_events(&mut self.encoder, 0, ack_seq, 0, events, now, &mut buf).unwrap();
+                        self.slave.respond(&buf[..len]);
+                    }
+                }
+            }
+        }
+
+        // Polls the slave for its key events frame, as the master does on
+        // every scan, acknowledging the commands it has applied. The slave
+        // builds the frame at `slave_now`, and the master dates it `frame_time`
+        fn poll(&mut self, events: &[KeyChange], slave_now: u32, frame_time: u32) -> Result<KeyEventsFrame, FrameError> {
+            self.master.request_frame().unwrap();
+            self.run_slave(events, slave_now);
+            let mut buf = [0; KEY_EVENTS_FRAME_LEN];
+            let len = self.master.read_frame(&mut buf).unwrap();
+            let frame = decode_key_events(&buf[..len], frame_time)?;
+            self.commands.ack(frame.ack_seq);
+            Ok(frame)
+        }
+    }
+
+    #[test]
+    fn corrupt_command_is_resent() {
+        let mut loopback = Loopback::new();
+        let mut link = Link::new(&mut loopback);
+        link.commands.push(Command::SetLayer(2));
+
+        link.corrupt_master.set(Some(PAYLOAD_OFFSET));
+        assert!(link.send_command(0));
+        link.run_slave(&[], 0);
+        assert_eq!(link.applied, []);
+        assert!(link.poll(&[], 0, 0).is_ok());
+        assert!(!link.commands.is_empty());
+
+        // The command is only resent once the acknowledgement is overdue
+        assert!(!link.send_command(COMMAND_RESEND_US - 1));
+        assert!(link.send_command(COMMAND_RESEND_US));
+        link.run_slave(&[], 0);
+        assert_eq!(link.applied, [Command::SetLayer(2)]);
+        assert!(link.poll(&[], 0, 0).is_ok());
+        assert!(link.commands.is_empty());
+    }
+
+    #[test]
+    fn resent_command_is_applied_once() {
+        let mut loopback = Loopback::new();
+        let mut link = Link::new(&mut loopback);
+        let lighting = Command::SetLighting {
+            mode: LightingMode::Layer,
+            brightness: 100,
+        };
+        link.commands.push(Command::SetLayer(1));
+        link.commands.push(lighting);
+
+        // The acknowledgement is lost, so the master sends the command again
+        assert!(link.send_command(0));
+        link.corrupt_slave.set(Some(PAYLOAD_OFFSET));
+        assert_eq!(link.poll(&[], 0, 0).err(), Some(FrameError::BadCrc));
+        assert!(link.send_command(COMMAND_RESEND_US));
+        assert!(link.poll(&[], 0, 0).is_ok());
+        assert_eq!(link.applied, [Command::SetLayer(1)]);
+
+        // The acknowledgement of the resent command lets the next one through
+        assert!(link.send_command(COMMAND_RESEND_US));
+        assert!(link.poll(&[], 0, 0).is_ok());
+        assert!(link.commands.is_empty());
+        assert_eq!(link.applied, [Command::SetLayer(1), lighting]);
+    }
+
+    #[test]
+    fn event_times_are_moved_to_the_master_clock() {
+        let mut loopback = Loopback::new();
+        let mut link = Link::new(&mut loopback);
+        let events = [
+            KeyChange {
+                key: 3,
+                pressed: true,
+                time: 1_000,
+            },
+            KeyChange {
+                key: 3,
+                pressed: false,
+                time: 1_400,
+            },
+        ];
+        let frame = link.poll(&events, 2_000, 50_000).unwrap();
+        assert_eq!(frame.events[0], KeyChange { time: 49_000, ..events[0] });
+        assert_eq!(frame.events[1], KeyChange { time: 49_400, ..events[1] });
+
+        // Across a wrap of the slave clock
+        let events = [KeyChange {
+            key: 7,
+            pressed: true,
+            time: u32::MAX - 99,
+        }];
+        let frame = link.poll(&events, 200, 50_000).unwrap();
+        assert_eq!(frame.events[0].time, 50_000 - 300);
+
+        // Events older than the age field can hold are dated as old as it allows
+        let events = [KeyChange {
+            key: 7,
+            pressed: false,
+            time: 0,
+        }];
+        let frame = link.poll(&events, 100_000, 100_000).unwrap();
+        assert_eq!(frame.events[0].time, 100_000 - u16::MAX as u32);
+    }
+}
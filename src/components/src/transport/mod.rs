@@ -0,0 +1,91 @@
+/*
+* Transports carrying the framed protocol between the master and slave
+* halves. The master always initiates: it sends command frames and asks
+* the slave for its key events frame, which the slave builds on request.
+*
+* The I2C transport is the default. The serial transports run over any
+* byte stream, either a hardware UART or a single-wire half-duplex PIO
+* UART, and the loopback connects both ends of a serial transport in
+* memory so that the link can be run without hardware
+*/
+pub mod i2c;
+pub mod loopback;
+pub mod pio_uart;
+pub mod serial;
+
+use rp_pico::hal as hal;
+use hal::timer::Timer;
+
+use crate::protocol::FrameError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportError {
+    // The underlying bus or serial port reported an error
+    Bus,
+    // A frame could not be encoded
+    Frame(FrameError),
+    // The slave did not send a whole frame in time
+    Timeout,
+}
+
+pub trait MasterTransport {
+    // Sends a command frame to the slave
+    fn send_command(&mut self, frame: &[u8]) -> Result<(), TransportError>;
+
+    // Asks the slave to send its key events frame
+    fn request_frame(&mut self) -> Result<(), TransportError>;
+
+    // Reads the frame asked for with `request_frame` into the buffer,
+    // returns WouldBlock until the whole frame has been received
+    fn read_frame(&mut self, buf: &mut [u8]) -> nb::Result<usize, TransportError>;
+
+    // Drops a partially received frame, e.g. after a timeout
+    fn reset(&mut self) {}
+
+    // Time from the slave building a frame of the given length
+    // until the master has received all of it
+    fn frame_time_us(&self, len: usize) -> u32;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlaveEvent {
+    // The master asked for the key events frame,
+    // answer by calling `respond`
+    FrameRequested,
+    // A command frame was received, available from `command`
+    CommandReceived,
+}
+
+pub trait SlaveTransport {
+    // Services the transport, call continuously from the main loop
+    fn poll(&mut self) -> Option<SlaveEvent>;
+
+    // Sends a frame in answer to `SlaveEvent::FrameRequested`
+    fn respond(&mut self, frame: &[u8]);
+
+    // The last command frame received
+    fn command(&self) -> &[u8];
+}
+
+// Asks the slave for its key events frame and waits for it,
+// returns the length of the frame read into the buffer
+pub fn poll_frame<T: MasterTransport>(
+    transport: &mut T,
+    timer: &Timer,
+    timeout_us: u32,
+    buf: &mut [u8],
+) -> Result<usize, TransportError> {
+    transport.request_frame()?;
+    let start = timer.get_counter_low();
+    loop {
+        match transport.read_frame(buf) {
+            Ok(len) => return Ok(len),
+            Err(nb::Error::Other(e)) => return Err(e),
+            Err(nb::Error::WouldBlock) => {}
+        }
+        if timer.get_counter_low().wrapping_sub(start) > timeout_us {
+            transport.reset();
+            return Err(TransportError::Timeout);
+        }
+    }
+}
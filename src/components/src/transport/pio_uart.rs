@@ -0,0 +1,165 @@
+/*
+* Single-wire half-duplex UART on PIO, for links with a single data line.
+*
+* The line is open-drain: it is pulled up, and driven low by making the
+* pin an output with its level preset to low. The transmitting state
+* machine shifts the inverted data bits out to the pin direction, so a
+* 0 bit drives the line and a 1 bit releases it. A second state machine
+* receives on the same pin, and so also receives every byte sent. Those
+* echoed bytes are counted when sent and dropped when received
+*/
+use core::convert::Infallible;
+
+use rp_pico::hal as hal;
+use embedded_hal::serial;
+use hal::pio::{
+    PIOBuilder, PIOExt, PinDir, PinState, Running, Rx, ShiftDirection, StateMachine,
+    StateMachineIndex, Tx, UninitStateMachine, PIO,
+};
+
+// Baud rate of the PIO link, lower than the hardware UART
+// since the open-drain line has slower rising edges
+pub const PIO_UART_BAUD_RATE: u32 = 500_000;
+// Both programs run at 8 cycles per bit
+const CYCLES_PER_BIT: u32 = 8;
+
+pub struct PioUart<P: PIOExt, TS: StateMachineIndex, RS: StateMachineIndex> {
+    _tx_sm: StateMachine<(P, TS), Running>,
+    _rx_sm: StateMachine<(P, RS), Running>,
+    tx: Tx<(P, TS)>,
+    rx: Rx<(P, RS)>,
+    // Number of bytes sent whose echo has not yet been received
+    echo: usize,
+}
+
+impl<P, TS, RS> PioUart<P, TS, RS>
+where
+    P: PIOExt,
+    TS: StateMachineIndex,
+    RS: StateMachineIndex,
+{
+    // Sets up both state machines on the given pin, which must already
+    // be set to the function of the PIO block and pulled up
+    pub fn new(
+        pio: &mut PIO<P>,
+        tx_sm: UninitStateMachine<(P, TS)>,
+        rx_sm: UninitStateMachine<(P, RS)>,
+        pin: u8,
+        baud_rate: u32,
+        sys_freq: u32,
+    ) -> Self {
+        let tx_program = pio_proc::pio_asm!(
+            ".wrap_target",
+            "    pull block",
+            // Start bit, drive the line low
+            "    set pindirs, 1 [7]",
+            "    set x, 7",
+            "bitloop:",
+            "    out pindirs, 1 [6]",
+            "    jmp x-- bitloop",
+            // Stop bit, release the line
+            "    set pindirs, 0 [7]",
+            ".wrap",
+        );
+        let rx_program = pio_proc::pio_asm!(
+            "start:",
+            "    wait 0 pin 0",
+            // Sample in the middle of each bit
+            "    set x, 7 [10]",
+            "bitloop:",
+            "    in pins, 1",
+            "    jmp x-- bitloop [6]",
+            "    jmp pin good_stop",
+            // Framing error, drop the byte and wait for the line to idle
+            "    mov isr, null",
+            "    wait 1 pin 0",
+            "    jmp start",
+            "good_stop:",
+            "    push",
+        );
+
+        // Clock divider as 16.8 fixed point
+        let div = (sys_freq as u64 * 256) / (baud_rate as u64 * CYCLES_PER_BIT as u64);
+        let (div_int, div_frac) = ((div >> 8) as u16, (div & 0xFF) as u8);
+
+        let installed = pio.install(&tx_program.program).unwrap();
+        let (mut tx_sm, _, tx) = PIOBuilder::from_program(installed)
+            .out_pins(pin, 1)
+            .set_pins(pin, 1)
+            .out_shift_direction(ShiftDirection::Right)
+            .clock_divisor_fixed_point(div_int, div_frac)
+            .build(tx_sm);
+        // The pin is only ever driven low, and is released by default
+        tx_sm.set_pins([(pin, PinState::Low)]);
+        tx_sm.set_pindirs([(pin, PinDir::Input)]);
+
+        let installed = pio.install(&rx_program.program).unwrap();
+        let (rx_sm, rx, _) = PIOBuilder::from_program(installed)
+            .in_pin_base(pin)
+            .jmp_pin(pin)
+            .in_shift_direction(ShiftDirection::Right)
+            .clock_divisor_fixed_point(div_int, div_frac)
+            .build(rx_sm);
+
+        PioUart {
+            _tx_sm: tx_sm.start(),
+            _rx_sm: rx_sm.start(),
+            tx,
+            rx,
+            echo: 0,
+        }
+    }
+}
+
+impl<P, TS, RS> serial::Read<u8> for PioUart<P, TS, RS>
+where
+    P: PIOExt,
+    TS: StateMachineIndex,
+    RS: StateMachineIndex,
+{
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        loop {
+            let word = self.rx.read().ok_or(nb::Error::WouldBlock)?;
+            if self.echo > 0 {
+                self.echo -= 1;
+                continue;
+            }
+            // Bits are shifted in from the left
+            return Ok((word >> 24) as u8);
+        }
+    }
+}
+
+impl<P, TS, RS> serial::Write<u8> for PioUart<P, TS, RS>
+where
+    P: PIOExt,
+    TS: StateMachineIndex,
+    RS: StateMachineIndex,
+{
+    type Error = Infallible;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Infallible> {
+        // A 1 in the pin direction drives the line low
+        if !self.tx.write(!word as u32) {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.echo += 1;
+        Ok(())
+    }
+
+    // Waits for the last byte to be sent and drops all echoed bytes,
+    // so that a reply can't be mistaken for an echo or the other way around
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        if !self.tx.is_empty() || !self.tx.has_stalled() {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.tx.clear_stalled_flag();
+        while self.echo > 0 && self.rx.read().is_some() {
+            self.echo -= 1;
+        }
+        self.echo = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+/*
+* Transport over a serial byte stream, used with a hardware UART or the
+* half-duplex PIO UART. Unlike I2C a byte stream has no transfer
+* boundaries, so frames are found by their sync byte and length, and the
+* master asks for the key events frame by sending a `FrameRequest` frame
+*/
+use embedded_hal::serial;
+
+use crate::protocol::{
+    frame_len, uart_transfer_time_us, Frame, FrameEncoder, PayloadType, HEADER_LEN,
+    MAX_FRAME_LEN, MAX_PAYLOAD_LEN, SYNC,
+};
+use crate::transport::{MasterTransport, SlaveEvent, SlaveTransport, TransportError};
+
+// Baud rate of the hardware UART link
+pub const UART_BAUD_RATE: u32 = 1_000_000;
+
+// Reassembles frames from a byte stream
+pub struct FrameReader {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+}
+
+impl FrameReader {
+    pub const fn new() -> Self {
+        FrameReader {
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+        }
+    }
+
+    // Adds a received byte, returns true once a whole frame has been
+    // received. Bytes outside a frame are skipped until the next sync
+    // byte. The frame is not validated, only its length is known
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.len == 0 && byte != SYNC {
+            return false;
+        }
+        if self.len == self.buf.len() {
+            self.clear();
+            return false;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        if self.len <= HEADER_LEN {
+            return false;
+        }
+        let payload_len = self.buf[4] as usize;
+        if payload_len > MAX_PAYLOAD_LEN {
+            self.clear();
+            return false;
+        }
+        self.len == frame_len(payload_len)
+    }
+
+    pub fn frame(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+fn write_all<S: serial::Write<u8>>(serial: &mut S, bytes: &[u8]) -> Result<(), TransportError> {
+    for byte in bytes.iter() {
+        nb::block!(serial.write(*byte)).map_err(|_| TransportError::Bus)?;
+    }
+    // On a half-duplex line this also discards the echo of the bytes sent
+    nb::block!(serial.flush()).map_err(|_| TransportError::Bus)
+}
+
+pub struct SerialMaster<S> {
+    serial: S,
+    baud_rate: u32,
+    reader: FrameReader,
+    encoder: FrameEncoder,
+}
+
+impl<S: serial::Read<u8> + serial::Write<u8>> SerialMaster<S> {
+    pub fn new(serial: S, baud_rate: u32) -> Self {
+        SerialMaster {
+            serial,
+            baud_rate,
+            reader: FrameReader::new(),
+            encoder: FrameEncoder::new(),
+        }
+    }
+
+    pub fn free(self) -> S {
+        self.serial
+    }
+}
+
+impl<S: serial::Read<u8> + serial::Write<u8>> MasterTransport for SerialMaster<S> {
+    fn send_command(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        write_all(&mut self.serial, frame)
+    }
+
+    fn request_frame(&mut self) -> Result<(), TransportError> {
+        // Anything received so far is left over from an earlier, failed request
+        self.reader.clear();
+        while self.serial.read().is_ok() {}
+        let mut buf = [0; frame_len(0)];
+        let len = self
+            .encoder
+            .encode(PayloadType::FrameRequest, &[], &mut buf)
+            .map_err(TransportError::Frame)?;
+        write_all(&mut self.serial, &buf[..len])
+    }
+
+    fn read_frame(&mut self, buf: &mut [u8]) -> nb::Result<usize, TransportError> {
+        loop {
+            let byte = match self.serial.read() {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => return Err(nb::Error::WouldBlock),
+                Err(nb::Error::Other(_)) => {
+                    self.reader.clear();
+                    return Err(nb::Error::Other(TransportError::Bus));
+                }
+            };
+            if self.reader.push(byte) {
+                let frame = self.reader.frame();
+                let len = frame.len().min(buf.len());
+                buf[..len].copy_from_slice(&frame[..len]);
+                self.reader.clear();
+                return Ok(len);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.reader.clear();
+    }
+
+    fn frame_time_us(&self, len: usize) -> u32 {
+        uart_transfer_time_us(len, self.baud_rate)
+    }
+}
+
+pub struct SerialSlave<S> {
+    serial: S,
+    reader: FrameReader,
+    command: [u8; MAX_FRAME_LEN],
+    command_len: usize,
+}
+
+impl<S: serial::Read<u8> + serial::Write<u8>> SerialSlave<S> {
+    pub fn new(serial: S) -> Self {
+        SerialSlave {
+            serial,
+            reader: FrameReader::new(),
+            command: [0; MAX_FRAME_LEN],
+            command_len: 0,
+        }
+    }
+
+    pub fn free(self) -> S {
+        self.serial
+    }
+}
+
+impl<S: serial::Read<u8> + serial::Write<u8>> SlaveTransport for SerialSlave<S> {
+    fn poll(&mut self) -> Option<SlaveEvent> {
+        loop {
+            let byte = match self.serial.read() {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => return None,
+                Err(nb::Error::Other(_)) => {
+                    self.reader.clear();
+                    return None;
+                }
+            };
+            if !self.reader.push(byte) {
+                continue;
+            }
+            // Corrupt frames are dropped, the master times out and asks again
+            let event = match Frame::decode(self.reader.frame()) {
+                Ok(frame) if frame.payload_type == PayloadType::FrameRequest => {
+                    Some(SlaveEvent::FrameRequested)
+                }
+                Ok(frame) if frame.payload_type == PayloadType::Command => {
+                    let frame = self.reader.frame();
+                    self.command[..frame.len()].copy_from_slice(frame);
+                    self.command_len = frame.len();
+                    Some(SlaveEvent::CommandReceived)
+                }
+                _ => None,
+            };
+            self.reader.clear();
+            if event.is_some() {
+                return event;
+            }
+        }
+    }
+
+    fn respond(&mut self, frame: &[u8]) {
+        let _ = write_all(&mut self.serial, frame);
+    }
+
+    fn command(&self) -> &[u8] {
+        &self.command[..self.command_len]
+    }
+}
@@ -0,0 +1,923 @@
+/*
+* Firmware update of the slave half through the master.
+*
+* After the 'U' command the host sends the image to the master over the
+* serial port as command frames, in the link frame format, holding:
+*
+*   UpdateBegin   version, size and CRC-32 of the image
+*   UpdateData    offset followed by up to 24 bytes of the image
+*   UpdateEnd     no arguments
+*
+* and waits for an `UpdateStatus` frame after each one. The master
+* forwards every frame to the slave as a command and answers the host
+* once the slave has acknowledged it, with the status the slave reports
+* in its key events frames. A corrupt frame, or one the master has no
+* room for yet, is answered with `BadFrame` and should be sent again.
+* UpdateBegin is only answered once the slave has erased the staging
+* area, which it does a sector at a time so that it keeps servicing the
+* link, and which takes a few seconds for a large image. The master
+* stays in update mode until the update completes or fails, or until
+* neither the host nor the slave has made progress for a while.
+*
+* The slave writes the image to a staging area in flash, leaving the
+* running firmware untouched until the whole image has been received and
+* verified. It then records the image in the metadata sector and resets,
+* and the image is copied into place at the next start-up. Images that
+* are not newer than the running firmware are rejected, both when the
+* update begins and when it is installed, so an update can't roll the
+* firmware back to an older version.
+*
+* The copy marks each sector in the metadata sector once it is done, and
+* copies sector 0 last, which holds the second stage bootloader, the
+* vector table and the code resuming an install. If power is lost during
+* the copy the install is resumed at the next start-up, see `flash.rs`,
+* except while sector 0 itself is written, after which the board only
+* starts in the USB boot mode
+*/
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use num_enum::TryFromPrimitive;
+
+use crate::protocol::{decode_command, Command, FrameEncoder, PayloadType, MAX_FRAME_LEN};
+use crate::transport::serial::FrameReader;
+
+// Flash layout, the running firmware is at the start of flash,
+// and is kept below STAGING_OFFSET by memory.x
+pub const STAGING_OFFSET: u32 = 0x10_0000;
+pub const MAX_IMAGE_SIZE: u32 = 0xF_0000;
+// One sector recording a verified image in the staging area
+pub const META_OFFSET: u32 = STAGING_OFFSET + MAX_IMAGE_SIZE;
+const META_MAGIC: u32 = 0x4B55_5044;
+const META_LEN: usize = 16;
+// The page after the metadata records the progress of the install, one
+// byte per sector of the image, cleared once the sector has been copied,
+// and its last byte, cleared once the install has started
+pub const PROGRESS_OFFSET: u32 = META_OFFSET + PAGE_LEN as u32;
+const PROGRESS_STARTED: u32 = PAGE_LEN as u32 - 1;
+// Flash sector, the unit images are installed in
+const SECTOR_LEN: u32 = 4096;
+
+pub const UPDATE_HEADER_LEN: usize = 12;
+// Image bytes per UpdateData command
+pub const UPDATE_CHUNK_LEN: usize = 24;
+// The master leaves update mode if the host is silent for this long
+pub const UPDATE_IDLE_TIMEOUT_US: u32 = 10_000_000;
+// Time the slave waits after verifying an image before resetting,
+// so that the master sees its status
+pub const UPDATE_RESET_DELAY_US: u32 = 100_000;
+const PAGE_LEN: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UpdateHeader {
+    // Firmware version of the image, compared with FIRMWARE_VERSION
+    pub version: u32,
+    pub size: u32,
+    // CRC-32 of the whole image
+    pub crc: u32,
+}
+
+impl UpdateHeader {
+    pub fn to_bytes(&self) -> [u8; UPDATE_HEADER_LEN] {
+        let mut buf = [0; UPDATE_HEADER_LEN];
+        buf[0..4].copy_from_slice(&self.version.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.size.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.crc.to_be_bytes());
+        buf
+    }
+
+    // The buffer must hold at least UPDATE_HEADER_LEN bytes
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        UpdateHeader {
+            version: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            size: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            crc: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, TryFromPrimitive)]
+pub enum UpdateStatus {
+    Idle = 0,
+    // The update has begun, or the last chunk has been written
+    Receiving = 1,
+    // The image has been verified, the slave is about to reset
+    Ready = 2,
+    // The image is not newer than the running firmware
+    TooOld = 3,
+    TooLarge = 4,
+    // A chunk did not follow the previous one, or the image was short
+    BadOffset = 5,
+    // The image written to flash does not match its CRC
+    BadCrc = 6,
+    FlashError = 7,
+    // Sent by the master for a corrupt frame from the host,
+    // or one it has no room for yet
+    BadFrame = 8,
+    // The staging area is being erased, after UpdateBegin
+    Erasing = 9,
+}
+
+impl UpdateStatus {
+    // Errors other than BadFrame abort the update
+    pub fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            UpdateStatus::Idle
+                | UpdateStatus::Erasing
+                | UpdateStatus::Receiving
+                | UpdateStatus::Ready
+                | UpdateStatus::BadFrame
+        )
+    }
+}
+
+// Writes an image to the staging area as it is received, on the slave
+pub struct UpdateReceiver<F> {
+    flash: F,
+    current_version: u32,
+    status: UpdateStatus,
+    header: UpdateHeader,
+    // Number of bytes of the staging area erased so far
+    erased: u32,
+    // Number of image bytes received
+    received: u32,
+    // Image bytes not yet written, the page at the end of `received`
+    page: [u8; PAGE_LEN],
+}
+
+impl<F: NorFlash> UpdateReceiver<F> {
+    pub fn new(flash: F, current_version: u32) -> Self {
+        UpdateReceiver {
+            flash,
+            current_version,
+            status: UpdateStatus::Idle,
+            header: UpdateHeader { version: 0, size: 0, crc: 0 },
+            erased: 0,
+            received: 0,
+            page: [0xFF; PAGE_LEN],
+        }
+    }
+
+    pub fn status(&self) -> UpdateStatus {
+        self.status
+    }
+
+    // Starts an update. The part of the staging area the image needs
+    // is then erased by `poll`, the status is Erasing until it is done
+    pub fn begin(&mut self, header: UpdateHeader) -> UpdateStatus {
+        self.header = header;
+        self.erased = 0;
+        self.received = 0;
+        self.page = [0xFF; PAGE_LEN];
+        self.status = if header.version <= self.current_version {
+            UpdateStatus::TooOld
+        } else if header.size == 0 || header.size > MAX_IMAGE_SIZE {
+            UpdateStatus::TooLarge
+        } else {
+            UpdateStatus::Erasing
+        };
+        self.status
+    }
+
+    // Erases the next sector of the staging area while the status is
+    // Erasing, call continuously from the main loop. Erasing a sector
+    // takes tens of milliseconds, so the slave only stalls that long
+    // at a time rather than for the whole staging area
+    pub fn poll(&mut self) -> UpdateStatus {
+        if self.status != UpdateStatus::Erasing {
+            return self.status;
+        }
+        let from = STAGING_OFFSET + self.erased;
+        self.status = match self.flash.erase(from, from + F::ERASE_SIZE as u32) {
+            Ok(()) => {
+                self.erased += F::ERASE_SIZE as u32;
+                if self.erased >= self.header.size {
+                    UpdateStatus::Receiving
+                } else {
+                    UpdateStatus::Erasing
+                }
+            }
+            Err(_) => UpdateStatus::FlashError,
+        };
+        self.status
+    }
+
+    // Adds a chunk of the image, which must directly follow the previous one
+    pub fn data(&mut self, offset: u32, data: &[u8]) -> UpdateStatus {
+        if self.status != UpdateStatus::Receiving {
+            return self.status;
+        }
+        if offset != self.received {
+            self.status = UpdateStatus::BadOffset;
+            return self.status;
+        }
+        if self.received + data.len() as u32 > self.header.size {
+            self.status = UpdateStatus::TooLarge;
+            return self.status;
+        }
+        for byte in data.iter() {
+            self.page[self.received as usize % PAGE_LEN] = *byte;
+            self.received += 1;
+            if self.received as usize % PAGE_LEN == 0 && !self.write_page() {
+                break;
+            }
+        }
+        self.status
+    }
+
+    // Completes the update, verifying the image and recording it in
+    // the metadata sector. The slave should reset once this is Ready
+    pub fn end(&mut self) -> UpdateStatus {
+        if self.status != UpdateStatus::Receiving {
+            return self.status;
+        }
+        if self.received != self.header.size {
+            self.status = UpdateStatus::BadOffset;
+            return self.status;
+        }
+        if self.received as usize % PAGE_LEN != 0 && !self.write_page() {
+            return self.status;
+        }
+        self.status = match image_crc(&mut self.flash, self.header.size) {
+            Some(crc) if crc == self.header.crc => match write_meta(&mut self.flash, &self.header) {
+                Ok(()) => UpdateStatus::Ready,
+                Err(_) => UpdateStatus::FlashError,
+            },
+            Some(_) => UpdateStatus::BadCrc,
+            None => UpdateStatus::FlashError,
+        };
+        self.status
+    }
+
+    // Writes the page holding the last byte received, padded with 0xFF
+    fn write_page(&mut self) -> bool {
+        let start = (self.received - 1) / PAGE_LEN as u32 * PAGE_LEN as u32;
+        let len = self.received - start;
+        for byte in self.page[len as usize..].iter_mut() {
+            *byte = 0xFF;
+        }
+        let ok = self.flash.write(STAGING_OFFSET + start, &self.page).is_ok();
+        self.page = [0xFF; PAGE_LEN];
+        if !ok {
+            self.status = UpdateStatus::FlashError;
+        }
+        ok
+    }
+}
+
+fn image_crc<F: ReadNorFlash>(flash: &mut F, size: u32) -> Option<u32> {
+    let mut buf = [0; PAGE_LEN];
+    let mut crc = CRC32_INIT;
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(PAGE_LEN as u32) as usize;
+        flash.read(STAGING_OFFSET + offset, &mut buf[..len]).ok()?;
+        crc = crc32_update(crc, &buf[..len]);
+        offset += len as u32;
+    }
+    Some(crc32_finish(crc))
+}
+
+fn write_meta<F: NorFlash>(flash: &mut F, header: &UpdateHeader) -> Result<(), F::Error> {
+    let mut page = [0xFF; PAGE_LEN];
+    page[0..4].copy_from_slice(&META_MAGIC.to_be_bytes());
+    page[4..META_LEN].copy_from_slice(&header.to_bytes());
+    flash.erase(META_OFFSET, META_OFFSET + F::ERASE_SIZE as u32)?;
+    flash.write(META_OFFSET, &page)
+}
+
+// Returns the size of the staged image if it should be installed, that is
+// if one has been recorded, is newer than the running firmware and is
+// intact. Call at start-up and install the image with `flash::install_image`
+pub fn pending_image<F: ReadNorFlash>(flash: &mut F, current_version: u32) -> Option<u32> {
+    let mut meta = [0; META_LEN];
+    flash.read(META_OFFSET, &mut meta).ok()?;
+    if u32::from_be_bytes([meta[0], meta[1], meta[2], meta[3]]) != META_MAGIC {
+        return None;
+    }
+    let header = UpdateHeader::from_bytes(&meta[4..]);
+    if header.version <= current_version || header.size == 0 || header.size > MAX_IMAGE_SIZE {
+        return None;
+    }
+    // Sector 0 is copied last, once it has been the image is installed
+    let mut progress = [0; 1];
+    flash.read(PROGRESS_OFFSET, &mut progress).ok()?;
+    if progress[0] == 0 {
+        return None;
+    }
+    if image_crc(flash, header.size)? != header.crc {
+        return None;
+    }
+    Some(header.size)
+}
+
+// Flash access of the installer, which runs with the firmware in flash
+// being overwritten. Offsets are relative to the start of flash. Callers
+// inline everything, see `flash::install_image`
+pub trait InstallFlash {
+    fn read_word(&mut self, offset: u32) -> u32;
+
+    fn erase_sector(&mut self, offset: u32);
+
+    // Programs whole pages from RAM
+    fn program(&mut self, offset: u32, data: *const u8, len: usize);
+}
+
+// Copies the image of `len` bytes staged at `from` over the firmware at
+// the start of flash, skipping the sectors the progress page marks as
+// copied, so that an interrupted install can be resumed. `buf` must hold
+// a sector. Written to run from RAM without any calls into flash: only
+// volatile accesses, no checked arithmetic and no indexing
+#[inline(always)]
+pub unsafe fn install_sectors<F: InstallFlash>(flash: &mut F, from: u32, len: u32, buf: *mut u32) {
+    let sectors = len.wrapping_add(SECTOR_LEN - 1) / SECTOR_LEN;
+    mark_progress(flash, PROGRESS_STARTED, buf);
+    let mut i = 1;
+    loop {
+        // Sector 0 last, after all the others
+        let sector = if i < sectors { i } else { 0 };
+        if !has_progress(flash, sector) {
+            let offset = sector.wrapping_mul(SECTOR_LEN);
+            let mut word = 0;
+            while word < SECTOR_LEN / 4 {
+                let value = flash.read_word(from.wrapping_add(offset).wrapping_add(word << 2));
+                core::ptr::write_volatile(buf.add(word as usize), value);
+                word = word.wrapping_add(1);
+            }
+            flash.erase_sector(offset);
+            flash.program(offset, buf as *const u8, SECTOR_LEN as usize);
+            mark_progress(flash, sector, buf);
+        }
+        if sector == 0 {
+            return;
+        }
+        i = i.wrapping_add(1);
+    }
+}
+
+// Returns the size of the image whose install has started but not
+// completed, for `install_sectors` to resume, if there is one
+#[inline(always)]
+pub fn interrupted_install<F: InstallFlash>(flash: &mut F) -> Option<u32> {
+    // The metadata is big-endian, the words read little-endian
+    if flash.read_word(META_OFFSET) != META_MAGIC.swap_bytes() {
+        return None;
+    }
+    let size = flash.read_word(META_OFFSET + 8).swap_bytes();
+    if size == 0 || size > MAX_IMAGE_SIZE {
+        return None;
+    }
+    if !has_progress(flash, PROGRESS_STARTED) || has_progress(flash, 0) {
+        return None;
+    }
+    Some(size)
+}
+
+#[inline(always)]
+fn has_progress<F: InstallFlash>(flash: &mut F, index: u32) -> bool {
+    let word = flash.read_word(PROGRESS_OFFSET.wrapping_add(index & !3));
+    (word >> ((index & 3) << 3)) & 0xFF == 0
+}
+
+// Clears a byte of the progress page by programming the page with only
+// that byte cleared, programming leaves the bytes that are set unchanged
+#[inline(always)]
+unsafe fn mark_progress<F: InstallFlash>(flash: &mut F, index: u32, buf: *mut u32) {
+    let mut word = 0;
+    while word < PAGE_LEN / 4 {
+        core::ptr::write_volatile(buf.add(word), 0xFFFF_FFFF);
+        word = word.wrapping_add(1);
+    }
+    core::ptr::write_volatile((buf as *mut u8).add(index as usize), 0);
+    flash.program(PROGRESS_OFFSET, buf as *const u8, PAGE_LEN);
+}
+
+// Relays an update from the host to the slave, on the master
+pub struct UpdateRelay {
+    reader: FrameReader,
+    encoder: FrameEncoder,
+    // Set while a command from the host waits to be acknowledged by the slave
+    waiting: bool,
+    response: Option<UpdateStatus>,
+    // Time the host sent a byte, or the slave reported
+    // its status while a command was waiting
+    last_activity: u32,
+    finished: bool,
+}
+
+impl UpdateRelay {
+    pub fn new(now: u32) -> Self {
+        UpdateRelay {
+            reader: FrameReader::new(),
+            encoder: FrameEncoder::new(),
+            waiting: false,
+            response: None,
+            last_activity: now,
+            finished: false,
+        }
+    }
+
+    // Adds a byte received from the host, returns the command to
+    // queue for the slave once a whole frame has been received
+    pub fn host_byte(&mut self, byte: u8, now: u32) -> Option<Command> {
+        self.last_activity = now;
+        if !self.reader.push(byte) {
+            return None;
+        }
+        let command = match decode_command(self.reader.frame()) {
+            Ok((
+                _,
+                command @ (Command::UpdateBegin(_) | Command::UpdateData { .. } | Command::UpdateEnd),
+            )) => Some(command),
+            _ => None,
+        };
+        self.reader.clear();
+        match command {
+            Some(_) => self.waiting = true,
+            None => self.response = Some(UpdateStatus::BadFrame),
+        }
+        command
+    }
+
+    // Call when the command returned by `host_byte` could not be queued,
+    // the host is asked to send it again
+    pub fn command_rejected(&mut self) {
+        self.waiting = false;
+        self.response = Some(UpdateStatus::BadFrame);
+    }
+
+    // Call with the update status of every frame received from the slave,
+    // and whether all queued commands have been acknowledged. The host is
+    // answered once the slave is done with the command, which for
+    // UpdateBegin means once it has erased the staging area
+    pub fn slave_status(&mut self, status: u8, commands_done: bool, now: u32) {
+        if !self.waiting {
+            return;
+        }
+        // The host is waiting on the slave, which is still making progress
+        self.last_activity = now;
+        let status = UpdateStatus::try_from_primitive(status).unwrap_or(UpdateStatus::FlashError);
+        if status.is_error() || (commands_done && status != UpdateStatus::Erasing) {
+            self.waiting = false;
+            self.response = Some(status);
+        }
+    }
+
+    // Returns the next frame to send to the host, if any
+    pub fn response(&mut self, buf: &mut [u8; MAX_FRAME_LEN]) -> Option<usize> {
+        let status = self.response.take()?;
+        if status == UpdateStatus::Ready || status.is_error() {
+            self.finished = true;
+        }
+        self.encoder.encode(PayloadType::UpdateStatus, &[status as u8], buf).ok()
+    }
+
+    // The update is over once it has completed or failed, or once neither
+    // the host nor the slave has made progress for UPDATE_IDLE_TIMEOUT_US
+    pub fn is_finished(&self, now: u32) -> bool {
+        self.finished || now.wrapping_sub(self.last_activity) > UPDATE_IDLE_TIMEOUT_US
+    }
+}
+
+pub const CRC32_INIT: u32 = 0xFFFF_FFFF;
+
+// CRC-32 as used by zlib, reflected polynomial 0xEDB88320
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xEDB8_8320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+pub fn crc32_finish(crc: u32) -> u32 {
+    !crc
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_finish(crc32_update(CRC32_INIT, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{check_erase, check_read, check_write, ErrorType, NorFlashErrorKind};
+
+    use crate::protocol::{CommandQueue, CommandReceiver, Frame, MAX_COMMAND_FRAME_LEN, MAX_COMMAND_LEN};
+
+    const CURRENT_VERSION: u32 = 7;
+    // Time a sector erase blocks the slave for
+    const ERASE_TIME_US: u32 = 50_000;
+    // Time between polls of the slave by the master
+    const POLL_TIME_US: u32 = 1_000;
+
+    // Flash in RAM, programming can only clear bits like on NOR flash
+    pub struct MemFlash {
+        pub data: std::vec::Vec<u8>,
+    }
+
+    impl MemFlash {
+        pub fn new() -> Self {
+            MemFlash {
+                data: vec![0xFF; 2 * 1024 * 1024],
+            }
+        }
+    }
+
+    impl ErrorType for MemFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for MemFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            check_read(self, offset, bytes.len())?;
+            bytes.copy_from_slice(&self.data[offset as usize..offset as usize + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for MemFlash {
+        const WRITE_SIZE: usize = 256;
+        const ERASE_SIZE: usize = 4096;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            check_erase(self, from, to)?;
+            self.data[from as usize..to as usize].fill(0xFF);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            check_write(self, offset, bytes.len())?;
+            for (byte, new) in self.data[offset as usize..].iter_mut().zip(bytes) {
+                *byte &= *new;
+            }
+            Ok(())
+        }
+    }
+
+    // The host, the master relaying its frames and the slave, with the
+    // link between the halves simulated by handing the command frames over
+    struct Update {
+        now: u32,
+        host: FrameEncoder,
+        relay: UpdateRelay,
+        commands: CommandQueue,
+        receiver: CommandReceiver,
+        slave: UpdateReceiver<MemFlash>,
+        // Every command frame with a number divisible by this is lost
+        drop_every: Option<u32>,
+        frames: u32,
+    }
+
+    impl Update {
+        fn new() -> Self {
+            Update {
+                now: 0,
+                host: FrameEncoder::new(),
+                relay: UpdateRelay::new(0),
+                commands: CommandQueue::new(),
+                receiver: CommandReceiver::new(),
+                slave: UpdateReceiver::new(MemFlash::new(), CURRENT_VERSION),
+                drop_every: None,
+                frames: 0,
+            }
+        }
+
+        // One turn of the main loops of both halves
+        fn step(&mut self) {
+            let erasing = self.slave.status() == UpdateStatus::Erasing;
+            self.slave.poll();
+            self.now += if erasing { ERASE_TIME_US } else { POLL_TIME_US };
+
+            let mut buf = [0; MAX_COMMAND_FRAME_LEN];
+            if let Some(len) = self.commands.next_frame(self.now, &mut buf) {
+                self.frames += 1;
+                if !matches!(self.drop_every, Some(n) if self.frames % n == 0) {
+                    match self.receiver.receive(&buf[..len]) {
+                        Some(Command::UpdateBegin(header)) => {
+                            self.slave.begin(header);
+                        }
+                        Some(Command::UpdateData { offset, len, data }) => {
+                            self.slave.data(offset, &data[..len as usize]);
+                        }
+                        Some(Command::UpdateEnd) => {
+                            self.slave.end();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            self.commands.ack(self.receiver.ack_seq());
+            self.relay.slave_status(self.slave.status() as u8, self.commands.is_empty(), self.now);
+        }
+
+        // Sends the bytes of a frame from the host, returns the status it is answered with
+        fn send_bytes(&mut self, bytes: &[u8]) -> UpdateStatus {
+            for byte in bytes {
+                if let Some(command) = self.relay.host_byte(*byte, self.now) {
+                    if self.commands.push_update(command).is_err() {
+                        self.relay.command_rejected();
+                    }
+                }
+            }
+            let mut buf = [0; MAX_FRAME_LEN];
+            loop {
+                if let Some(len) = self.relay.response(&mut buf) {
+                    let frame = Frame::decode(&buf[..len]).unwrap();
+                    assert_eq!(frame.payload_type, PayloadType::UpdateStatus);
+                    return UpdateStatus::try_from_primitive(frame.payload[0]).unwrap();
+                }
+                assert!(!self.relay.is_finished(self.now), "update mode left while waiting on the slave");
+                self.step();
+            }
+        }
+
+        // Sends a command from the host, again for as long as it is answered with BadFrame
+        fn send(&mut self, command: Command) -> UpdateStatus {
+            let mut payload = [0; MAX_COMMAND_LEN];
+            let len = command.encode(&mut payload);
+            loop {
+                let mut buf = [0; MAX_FRAME_LEN];
+                let frame_len = self.host.encode(PayloadType::Command, &payload[..len], &mut buf).unwrap();
+                match self.send_bytes(&buf[..frame_len]) {
+                    UpdateStatus::BadFrame => {}
+                    status => return status,
+                }
+            }
+        }
+
+        // Sends a whole image, returns the status of the first command
+        // that fails, or that of UpdateEnd
+        fn send_image(&mut self, header: UpdateHeader, image: &[u8]) -> UpdateStatus {
+            let status = self.send(Command::UpdateBegin(header));
+            if status != UpdateStatus::Receiving {
+                return status;
+            }
+            for (i, chunk) in image.chunks(UPDATE_CHUNK_LEN).enumerate() {
+                let mut data = [0; UPDATE_CHUNK_LEN];
+                data[..chunk.len()].copy_from_slice(chunk);
+                let status = self.send(Command::UpdateData {
+                    offset: (i * UPDATE_CHUNK_LEN) as u32,
+                    len: chunk.len() as u8,
+                    data,
+                });
+                if status != UpdateStatus::Receiving {
+                    return status;
+                }
+            }
+            self.send(Command::UpdateEnd)
+        }
+    }
+
+    fn image(len: usize) -> std::vec::Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    fn header(version: u32, image: &[u8]) -> UpdateHeader {
+        UpdateHeader {
+            version,
+            size: image.len() as u32,
+            crc: crc32(image),
+        }
+    }
+
+    #[test]
+    fn image_is_staged() {
+        let mut update = Update::new();
+        let image = image(5_000);
+        assert_eq!(update.send_image(header(CURRENT_VERSION + 1, &image), &image), UpdateStatus::Ready);
+        assert!(update.relay.is_finished(update.now));
+
+        let flash = &mut update.slave.flash;
+        let staged = &flash.data[STAGING_OFFSET as usize..STAGING_OFFSET as usize + image.len()];
+        assert_eq!(staged, &image[..]);
+        assert_eq!(pending_image(flash, CURRENT_VERSION), Some(image.len() as u32));
+        // The image is not installed over firmware as new or newer
+        assert_eq!(pending_image(flash, CURRENT_VERSION + 1), None);
+    }
+
+    #[test]
+    fn lost_and_corrupt_frames_are_sent_again() {
+        let mut update = Update::new();
+        let image = image(1_000);
+        assert_eq!(update.send(Command::UpdateBegin(header(CURRENT_VERSION + 1, &image))), UpdateStatus::Receiving);
+
+        // A corrupt frame from the host is answered with BadFrame, without leaving update mode
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = update.host.encode(PayloadType::Command, &[0x07], &mut buf).unwrap();
+        buf[len - 1] ^= 0xFF;
+        assert_eq!(update.send_bytes(&buf[..len]), UpdateStatus::BadFrame);
+        assert!(!update.relay.is_finished(update.now));
+
+        let mut update = Update::new();
+        update.drop_every = Some(3);
+        assert_eq!(update.send_image(header(CURRENT_VERSION + 1, &image), &image), UpdateStatus::Ready);
+        assert_eq!(pending_image(&mut update.slave.flash, CURRENT_VERSION), Some(image.len() as u32));
+    }
+
+    #[test]
+    fn erase_keeps_update_mode() {
+        // Erasing the largest image takes longer than the idle timeout
+        let mut update = Update::new();
+        let header = UpdateHeader {
+            version: CURRENT_VERSION + 1,
+            size: MAX_IMAGE_SIZE,
+            crc: 0,
+        };
+        assert!(MAX_IMAGE_SIZE / MemFlash::ERASE_SIZE as u32 * ERASE_TIME_US > UPDATE_IDLE_TIMEOUT_US);
+        assert_eq!(update.send(Command::UpdateBegin(header)), UpdateStatus::Receiving);
+        assert!(update.now > UPDATE_IDLE_TIMEOUT_US);
+        assert!(!update.relay.is_finished(update.now));
+
+        // Update mode is left once both the host and slave have been idle for the timeout
+        assert!(!update.relay.is_finished(update.now + UPDATE_IDLE_TIMEOUT_US));
+        assert!(update.relay.is_finished(update.now + UPDATE_IDLE_TIMEOUT_US + 1));
+    }
+
+    #[test]
+    fn older_image_is_rejected() {
+        let mut update = Update::new();
+        let image = image(1_000);
+        assert_eq!(update.send_image(header(CURRENT_VERSION, &image), &image), UpdateStatus::TooOld);
+        assert!(update.relay.is_finished(update.now));
+        assert_eq!(pending_image(&mut update.slave.flash, CURRENT_VERSION), None);
+    }
+
+    #[test]
+    fn corrupt_image_is_rejected() {
+        let mut update = Update::new();
+        let image = image(1_000);
+        let mut header = header(CURRENT_VERSION + 1, &image);
+        header.crc ^= 1;
+        assert_eq!(update.send_image(header, &image), UpdateStatus::BadCrc);
+        assert_eq!(pending_image(&mut update.slave.flash, CURRENT_VERSION), None);
+
+        let mut update = Update::new();
+        let header = UpdateHeader {
+            size: MAX_IMAGE_SIZE + 1,
+            ..header
+        };
+        assert_eq!(update.send(Command::UpdateBegin(header)), UpdateStatus::TooLarge);
+    }
+
+    // Flash that loses power after a number of erase and program
+    // operations, leaving the operation it loses power in half done
+    struct PowerCutFlash {
+        data: std::vec::Vec<u8>,
+        ops_left: Option<usize>,
+        powered: bool,
+        // Number of times each sector of the firmware has been erased
+        erases: std::vec::Vec<usize>,
+    }
+
+    impl PowerCutFlash {
+        // Returns how much of the next operation is done, None if none of it
+        fn operation(&mut self, len: usize) -> Option<usize> {
+            if !self.powered {
+                return None;
+            }
+            match self.ops_left {
+                Some(0) => {
+                    self.powered = false;
+                    Some(len / 2)
+                }
+                Some(n) => {
+                    self.ops_left = Some(n - 1);
+                    Some(len)
+                }
+                None => Some(len),
+            }
+        }
+    }
+
+    impl InstallFlash for PowerCutFlash {
+        fn read_word(&mut self, offset: u32) -> u32 {
+            let o = offset as usize;
+            u32::from_le_bytes(self.data[o..o + 4].try_into().unwrap())
+        }
+
+        fn erase_sector(&mut self, offset: u32) {
+            let sector = (offset / SECTOR_LEN) as usize;
+            // Sector 0 is only erased once all the others have been copied
+            if sector == 0 && self.powered {
+                let sectors = self.erases.len() as u32;
+                assert!((1..sectors).all(|s| has_progress(self, s)));
+            }
+            if let Some(done) = self.operation(SECTOR_LEN as usize) {
+                self.erases[sector] += 1;
+                self.data[offset as usize..offset as usize + done].fill(0xFF);
+            }
+        }
+
+        fn program(&mut self, offset: u32, data: *const u8, len: usize) {
+            let data = unsafe { std::slice::from_raw_parts(data, len) };
+            if let Some(done) = self.operation(len) {
+                for (byte, new) in self.data[offset as usize..].iter_mut().zip(&data[..done]) {
+                    *byte &= *new;
+                }
+            }
+        }
+    }
+
+    impl ErrorType for PowerCutFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for PowerCutFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            check_read(self, offset, bytes.len())?;
+            bytes.copy_from_slice(&self.data[offset as usize..offset as usize + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    // Flash with the running firmware and a verified image staged
+    fn staged_flash(firmware: &[u8], image: &[u8]) -> PowerCutFlash {
+        let mut flash = MemFlash::new();
+        flash.data[..firmware.len()].copy_from_slice(firmware);
+        flash.write(STAGING_OFFSET, &pad(image)).unwrap();
+        write_meta(&mut flash, &header(CURRENT_VERSION + 1, image)).unwrap();
+        PowerCutFlash {
+            data: flash.data,
+            ops_left: None,
+            powered: true,
+            erases: vec![0; (image.len() + SECTOR_LEN as usize - 1) / SECTOR_LEN as usize],
+        }
+    }
+
+    fn pad(image: &[u8]) -> std::vec::Vec<u8> {
+        let mut padded = image.to_vec();
+        padded.resize((image.len() + PAGE_LEN - 1) / PAGE_LEN * PAGE_LEN, 0xFF);
+        padded
+    }
+
+    // What the firmware does at start-up, first in `__pre_init` and
+    // then in `main`, with the firmware version of the old image
+    fn start_up(flash: &mut PowerCutFlash) {
+        let mut buf = [0u32; SECTOR_LEN as usize / 4];
+        if let Some(len) = interrupted_install(flash) {
+            unsafe { install_sectors(flash, STAGING_OFFSET, len, buf.as_mut_ptr()) };
+        } else if let Some(len) = pending_image(flash, CURRENT_VERSION) {
+            unsafe { install_sectors(flash, STAGING_OFFSET, len, buf.as_mut_ptr()) };
+        }
+    }
+
+    #[test]
+    fn image_is_installed() {
+        let image = image(5 * SECTOR_LEN as usize + 100);
+        let mut flash = staged_flash(&[0x11; 6 * SECTOR_LEN as usize], &image);
+        start_up(&mut flash);
+        assert_eq!(&flash.data[..image.len()], &image[..]);
+        assert!(flash.erases.iter().all(|n| *n == 1));
+
+        // Nothing is installed again at the next start-up, even by the old firmware
+        start_up(&mut flash);
+        assert!(flash.erases.iter().all(|n| *n == 1));
+        assert_eq!(interrupted_install(&mut flash), None);
+        assert_eq!(pending_image(&mut flash, CURRENT_VERSION), None);
+    }
+
+    #[test]
+    fn install_resumes_after_power_loss() {
+        let image = image(5 * SECTOR_LEN as usize + 100);
+        let firmware = vec![0x11; 6 * SECTOR_LEN as usize];
+        // Every sector is erased and programmed, and marked, plus the start mark.
+        // Losing power while sector 0 is written leaves the board in the USB
+        // boot mode, since the bootloader is broken, the copy is still checked
+        let ops = 3 * 6 + 1;
+        for cut in 0..ops {
+            let mut flash = staged_flash(&firmware, &image);
+            flash.ops_left = Some(cut);
+            start_up(&mut flash);
+            assert!(!flash.powered);
+
+            flash.ops_left = None;
+            flash.powered = true;
+            start_up(&mut flash);
+            assert_eq!(&flash.data[..image.len()], &image[..], "power lost after {} operations", cut);
+            // Only the sector being copied when power was lost is copied again
+            assert!(flash.erases.iter().all(|n| *n >= 1));
+            assert!(flash.erases.iter().sum::<usize>() <= flash.erases.len() + 1);
+            assert_eq!(interrupted_install(&mut flash), None);
+        }
+    }
+
+}
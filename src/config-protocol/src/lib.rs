@@ -0,0 +1,74 @@
+/*
+* Wire definitions of the configuration protocol of the keyboard, spoken
+* over its vendor-defined raw HID interface. The requests and the layout
+* of their data are described in components/src/config.rs
+*/
+#![no_std]
+
+// USB ids of the keyboard, and the usage page of the configuration interface
+pub const VENDOR_ID: u16 = 0x1209;
+pub const PRODUCT_ID: u16 = 0x0001;
+pub const USAGE_PAGE: u16 = 0xFF4B;
+// Requests and responses are single reports of this length
+pub const REPORT_LEN: usize = 32;
+pub const PROTOCOL_VERSION: u8 = 2;
+
+// The first byte of a request, echoed by its response
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    GetInfo = 0x01,
+    GetKeyMap = 0x02,
+    SetKeyMap = 0x03,
+    GetTiming = 0x04,
+    SetTiming = 0x05,
+    Commit = 0x06,
+    GetKeyStats = 0x07,
+    EnterBootloader = 0x08,
+}
+
+impl Command {
+    pub fn from_u8(id: u8) -> Option<Self> {
+        Some(match id {
+            0x01 => Command::GetInfo,
+            0x02 => Command::GetKeyMap,
+            0x03 => Command::SetKeyMap,
+            0x04 => Command::GetTiming,
+            0x05 => Command::SetTiming,
+            0x06 => Command::Commit,
+            0x07 => Command::GetKeyStats,
+            0x08 => Command::EnterBootloader,
+            _ => return None,
+        })
+    }
+}
+
+// The second byte of a response
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Ok = 0x00,
+    UnknownCommand = 0x01,
+    // The arguments are malformed, e.g. an unknown key code
+    BadRequest = 0x02,
+    // The layer or key does not exist
+    OutOfRange = 0x03,
+    // The key-map was not stored
+    StorageFailed = 0x04,
+    // The key-map was stored, but the timing was not
+    SettingsFailed = 0x05,
+}
+
+impl Status {
+    pub fn from_u8(status: u8) -> Option<Self> {
+        Some(match status {
+            0x00 => Status::Ok,
+            0x01 => Status::UnknownCommand,
+            0x02 => Status::BadRequest,
+            0x03 => Status::OutOfRange,
+            0x04 => Status::StorageFailed,
+            0x05 => Status::SettingsFailed,
+            _ => return None,
+        })
+    }
+}
@@ -7,9 +7,11 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also compiles the default key-map, `key_map.toml`, into Rust source.
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 
@@ -28,4 +30,13 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    // Compile the default key-map into Rust source, included by src/key_map.rs
+    let source = fs::read_to_string("key_map.toml").unwrap();
+    let key_map = match kallisto_keymap::compile(&source) {
+        Ok(key_map) => key_map,
+        Err(e) => panic!("key_map.toml:{}", e),
+    };
+    fs::write(out.join("key_map.rs"), key_map.to_rust()).unwrap();
+    println!("cargo:rerun-if-changed=key_map.toml");
 }
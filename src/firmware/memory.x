@@ -0,0 +1,56 @@
+MEMORY {
+    BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
+    /*
+     * The firmware ends where the staging area of slave firmware updates
+     * starts, STAGING_OFFSET in components/src/update.rs, which is followed
+     * by the update metadata. The last 32K hold the key-map and settings
+     * on builds without the EEPROM
+     */
+    FLASH : ORIGIN = 0x10000100, LENGTH = 1024K - 0x100
+    RAM   : ORIGIN = 0x20000000, LENGTH = 256K
+}
+
+EXTERN(BOOT2_FIRMWARE)
+
+SECTIONS {
+    /* ### Boot loader */
+    .boot2 ORIGIN(BOOT2) :
+    {
+        KEEP(*(.boot2));
+    } > BOOT2
+} INSERT BEFORE .text;
+
+/*
+ * ### Firmware installer, see components/src/flash.rs
+ *
+ * The code resuming an interrupted install and the copy routine it loads
+ * into RAM are placed right after the vector table, followed by the reset
+ * handler at the start of .text, so that all of it is in the first sector
+ * of flash, which an install replaces last
+ */
+SECTIONS {
+    .installer : ALIGN(4)
+    {
+        KEEP(*(.installer .installer.*));
+        . = ALIGN(4);
+    } > FLASH
+
+    .installer_ram : ALIGN(4)
+    {
+        __sinstaller_ram = .;
+        *(.installer_ram .installer_ram.*);
+        . = ALIGN(4);
+        __einstaller_ram = .;
+    } > RAM AT > FLASH
+    __siinstaller_ram = LOADADDR(.installer_ram);
+} INSERT AFTER .vector_table;
+
+_stext = __siinstaller_ram + SIZEOF(.installer_ram);
+
+/* Room for the reset handler, which calls the installer through __pre_init */
+ASSERT(_stext + 0x100 <= ORIGIN(BOOT2) + 4K, "
+ERROR: the firmware installer does not fit in the first sector of flash");
+
+/* Initialised data is stored after the code, it must not reach into the staging area either */
+ASSERT(LOADADDR(.data) + SIZEOF(.data) <= 0x10100000, "
+ERROR: the firmware overlaps the staging area of firmware updates");
@@ -0,0 +1,366 @@
+/*
+* Description of the two halves, and detection of the role and
+* handedness of the half the firmware is running on.
+*
+* Both halves run the same firmware. The halves are mirror images
+* of each other, so each has its own pin map:
+*
+*               Left                  Right
+*   Rows        GPIO0, 1, 2           GPIO27, 26, 22
+*   Columns     GPIO3..9              GPIO21..15
+*   LEDs        GPIO16                GPIO14
+*   I2C link    GPIO18 SDA, 19 SCL    GPIO2 SDA, 3 SCL
+*   UART link   GPIO28 TX, 17 RX      GPIO0 TX, 1 RX
+*   PIO link    GPIO18                GPIO2
+*   EEPROM      GPIO12 SDA, 13 SCL    GPIO12 SDA, 13 SCL
+*   Strap       GPIO10                GPIO10
+*   Encoder     GPIO22 A, 21 B, 20 SW -
+*
+* The half connected to USB is the master. VBUS alone does not tell
+* which half that is, since the link cable also carries 5V to the VBUS
+* of the other half. A half without VBUS is always the slave, a half with
+* VBUS becomes the master once it has been addressed by a USB host.
+*
+* The handedness is read from the strap pin, tied low on a left half
+* and high on a right half. If the strap is left open it is read from
+* the EEPROM instead
+*/
+use rp_pico::hal as hal;
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::InputPin;
+use embedded_storage::{ReadStorage, Storage};
+use fugit::HertzU32;
+use hal::gpio::{Pin, PinId, PinMode, ValidPinMode};
+use hal::pac;
+use hal::timer::Timer;
+use usb_device::bus::UsbBus;
+use usb_device::class::UsbClass;
+use usb_device::device::{UsbDevice, UsbDeviceState};
+
+use kallisto_components::keyboard::key_matrix::N_KEYS;
+use kallisto_components::keyboard::layout::{Half, KALLISTO_LAYOUT};
+use kallisto_components::protocol::{LinkInfo, CAP_ENCODER, PROTOCOL_VERSION};
+use kallisto_components::transport::{MasterTransport, SlaveTransport};
+
+use crate::FIRMWARE_VERSION;
+
+#[cfg(not(any(feature = "uart-link", feature = "pio-link")))]
+use fugit::RateExtU32;
+#[cfg(not(any(feature = "uart-link", feature = "pio-link")))]
+use hal::gpio::{BankPinId, FunctionI2C};
+#[cfg(not(any(feature = "uart-link", feature = "pio-link")))]
+use hal::i2c::{Controller, SclPin, SdaPin, I2C};
+#[cfg(not(any(feature = "uart-link", feature = "pio-link")))]
+use kallisto_components::i2c::recover_controller;
+#[cfg(not(any(feature = "uart-link", feature = "pio-link")))]
+use kallisto_components::protocol::SLAVE_ADDRESS;
+#[cfg(not(any(feature = "uart-link", feature = "pio-link")))]
+use kallisto_components::transport::i2c::{I2cMaster, I2cSlave};
+#[cfg(any(feature = "uart-link", feature = "pio-link"))]
+use kallisto_components::transport::serial::{SerialMaster, SerialSlave};
+#[cfg(feature = "uart-link")]
+use fugit::RateExtU32;
+#[cfg(feature = "uart-link")]
+use hal::gpio::FunctionUart;
+#[cfg(feature = "uart-link")]
+use hal::uart::{DataBits, Enabled, StopBits, UartConfig, UartPeripheral, ValidUartPinout};
+#[cfg(feature = "uart-link")]
+use kallisto_components::transport::serial::UART_BAUD_RATE;
+#[cfg(feature = "pio-link")]
+use hal::pio::{UninitStateMachine, PIO, SM0, SM1};
+#[cfg(feature = "pio-link")]
+use kallisto_components::transport::pio_uart::{PioUart, PIO_UART_BAUD_RATE};
+
+pub const I2C_FREQ_HZ: u32 = 400_000;
+// Time given to the host to address the keyboard, after which
+// a half with VBUS concludes that it is not connected to a host
+const ENUMERATION_TIMEOUT_US: u32 = 1_500_000;
+// Time given to the strap pin to follow its pull resistor
+const STRAP_SETTLE_US: u32 = 100;
+
+// The key-map profiles are kept at the start of the EEPROM, each in a
+// slot with room for a key-map of 42 keys (1480 bytes)
+pub const KEY_MAP_ADDRESS: u32 = 0x0000;
+pub const KEY_MAP_SLOT_SIZE: u32 = 0x0600;
+pub const N_PROFILES: u8 = 4;
+// The switch statistics of the master half follow the profiles
+pub const STATS_ADDRESS: u32 = 0x1800;
+// The handedness is kept in the last page of the EEPROM, or of the storage region in flash
+const HANDEDNESS_ADDRESS: u32 = 0x7FC0;
+const HANDEDNESS_MAGIC: u8 = 0xA4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    // Connected to the host, runs the keyboard
+    Master,
+    // Scans its matrix and reports to the master
+    Slave,
+}
+
+// Frequencies of the clocks the link peripherals run from
+#[derive(Debug, Clone, Copy)]
+pub struct ClockFreqs {
+    pub system: HertzU32,
+    pub peripheral: HertzU32,
+}
+
+pub struct PinMap {
+    pub rows: [u8; 3],
+    // The columns are consecutive GPIOs starting at the base,
+    // in descending order on the right half
+    pub col_base: u8,
+    pub cols_reversed: bool,
+    // Data line of the link, also used by the PIO link
+    pub link_sda: u8,
+    pub encoder: bool,
+}
+
+pub const LEFT_PINS: PinMap = PinMap {
+    rows: [0, 1, 2],
+    col_base: 3,
+    cols_reversed: false,
+    link_sda: 18,
+    encoder: true,
+};
+
+pub const RIGHT_PINS: PinMap = PinMap {
+    rows: [27, 26, 22],
+    col_base: 15,
+    cols_reversed: true,
+    link_sda: 2,
+    encoder: false,
+};
+
+pub fn pin_map(half: Half) -> &'static PinMap {
+    match half {
+        Half::Left => &LEFT_PINS,
+        Half::Right => &RIGHT_PINS,
+    }
+}
+
+// Link info of this half, exchanged with the other half when the link comes up
+pub fn link_info(half: Half) -> LinkInfo {
+    LinkInfo {
+        firmware_version: FIRMWARE_VERSION,
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: if pin_map(half).encoder { CAP_ENCODER } else { 0 },
+        // One LED per key
+        led_count: N_KEYS as u8,
+        matrix_rows: KALLISTO_LAYOUT.rows,
+        matrix_cols: KALLISTO_LAYOUT.cols,
+    }
+}
+
+// Polls the USB device until it is addressed by a host, or the timeout expires
+pub fn detect_role<B: UsbBus>(
+    vbus: bool,
+    usb_dev: &mut UsbDevice<B>,
+    classes: &mut [&mut dyn UsbClass<B>],
+    timer: &Timer,
+) -> Role {
+    if !vbus {
+        return Role::Slave;
+    }
+    let start = timer.get_counter_low();
+    while timer.get_counter_low().wrapping_sub(start) < ENUMERATION_TIMEOUT_US {
+        usb_dev.poll(classes);
+        match usb_dev.state() {
+            UsbDeviceState::Addressed | UsbDeviceState::Configured => return Role::Master,
+            _ => {}
+        }
+    }
+    Role::Slave
+}
+
+// Reads the strap pin with its pull-up and then its pull-down enabled,
+// an open strap follows the pull resistor. Returns None if open
+pub fn read_strap<I, M, D>(pin: Pin<I, M>, delay: &mut D) -> Option<Half>
+where
+    I: PinId,
+    M: PinMode + ValidPinMode<I>,
+    D: DelayUs<u32>,
+{
+    let pin = pin.into_pull_up_input();
+    delay.delay_us(STRAP_SETTLE_US);
+    let pulled_up = pin.is_high().unwrap();
+    let pin = pin.into_pull_down_input();
+    delay.delay_us(STRAP_SETTLE_US);
+    let pulled_down = pin.is_high().unwrap();
+    let _ = pin.into_floating_disabled();
+    match (pulled_up, pulled_down) {
+        (false, false) => Some(Half::Left),
+        (true, true) => Some(Half::Right),
+        _ => None,
+    }
+}
+
+pub fn key_map_address(profile: u8) -> u32 {
+    KEY_MAP_ADDRESS + profile as u32 * KEY_MAP_SLOT_SIZE
+}
+
+// Returns None if no handedness has been stored
+pub fn load_handedness<S: ReadStorage>(storage: &mut S) -> Option<Half> {
+    let mut buf = [0; 2];
+    storage.read(HANDEDNESS_ADDRESS, &mut buf).ok()?;
+    match buf {
+        [HANDEDNESS_MAGIC, 0] => Some(Half::Left),
+        [HANDEDNESS_MAGIC, 1] => Some(Half::Right),
+        _ => None,
+    }
+}
+
+pub fn save_handedness<S: Storage>(storage: &mut S, half: Half) -> Result<(), S::Error> {
+    let side = match half {
+        Half::Left => 0,
+        Half::Right => 1,
+    };
+    storage.write(HANDEDNESS_ADDRESS, &[HANDEDNESS_MAGIC, side])
+}
+
+// The pins of the link between the halves, which become a master
+// or slave transport once the role of the half is known
+pub trait LinkPins {
+    // The peripheral driving the link
+    type Block;
+    type Master: MasterTransport;
+    type Slave: SlaveTransport;
+
+    fn into_master(self, block: Self::Block, resets: &mut pac::RESETS, clocks: ClockFreqs) -> Self::Master;
+
+    fn into_slave(self, block: Self::Block, resets: &mut pac::RESETS, clocks: ClockFreqs) -> Self::Slave;
+
+    // Called after repeated link failures, only the I2C link can be recovered
+    fn recover<D: DelayUs<u32>>(
+        master: Self::Master,
+        _resets: &mut pac::RESETS,
+        _clocks: ClockFreqs,
+        _delay: &mut D,
+    ) -> Self::Master {
+        master
+    }
+}
+
+#[cfg(not(any(feature = "uart-link", feature = "pio-link")))]
+impl<Sda, Scl> LinkPins for (Pin<Sda, FunctionI2C>, Pin<Scl, FunctionI2C>)
+where
+    Sda: PinId + BankPinId + SdaPin<pac::I2C1>,
+    Scl: PinId + BankPinId + SclPin<pac::I2C1>,
+    FunctionI2C: ValidPinMode<Sda> + ValidPinMode<Scl>,
+{
+    type Block = pac::I2C1;
+    type Master = I2cMaster<I2C<pac::I2C1, (Pin<Sda, FunctionI2C>, Pin<Scl, FunctionI2C>), Controller>>;
+    type Slave = I2cSlave<(Pin<Sda, FunctionI2C>, Pin<Scl, FunctionI2C>)>;
+
+    fn into_master(self, block: pac::I2C1, resets: &mut pac::RESETS, clocks: ClockFreqs) -> Self::Master {
+        let i2c = I2C::new_controller(
+            block,
+            self.0,
+            self.1,
+            I2C_FREQ_HZ.Hz(),
+            resets,
+            clocks.system,
+        );
+        I2cMaster::new(i2c, I2C_FREQ_HZ)
+    }
+
+    fn into_slave(self, block: pac::I2C1, resets: &mut pac::RESETS, _clocks: ClockFreqs) -> Self::Slave {
+        let i2c = I2C::new_peripheral_event_iterator(block, self.0, self.1, resets, SLAVE_ADDRESS as u16);
+        I2cSlave::new(i2c)
+    }
+
+    // Repeated failures may be caused by the slave holding SDA low
+    // after being reset mid-transfer, which wedges the bus until
+    // it is clocked free
+    fn recover<D: DelayUs<u32>>(
+        master: Self::Master,
+        resets: &mut pac::RESETS,
+        clocks: ClockFreqs,
+        delay: &mut D,
+    ) -> Self::Master {
+        let (i2c, _) = recover_controller(
+            master.free(),
+            I2C_FREQ_HZ.Hz(),
+            clocks.system,
+            resets,
+            delay,
+        );
+        I2cMaster::new(i2c, I2C_FREQ_HZ)
+    }
+}
+
+#[cfg(feature = "uart-link")]
+impl<Tx, Rx> LinkPins for (Pin<Tx, FunctionUart>, Pin<Rx, FunctionUart>)
+where
+    Tx: PinId,
+    Rx: PinId,
+    FunctionUart: ValidPinMode<Tx> + ValidPinMode<Rx>,
+    (Pin<Tx, FunctionUart>, Pin<Rx, FunctionUart>): ValidUartPinout<pac::UART0>,
+{
+    type Block = pac::UART0;
+    type Master = SerialMaster<UartPeripheral<Enabled, pac::UART0, (Pin<Tx, FunctionUart>, Pin<Rx, FunctionUart>)>>;
+    type Slave = SerialSlave<UartPeripheral<Enabled, pac::UART0, (Pin<Tx, FunctionUart>, Pin<Rx, FunctionUart>)>>;
+
+    fn into_master(self, block: pac::UART0, resets: &mut pac::RESETS, clocks: ClockFreqs) -> Self::Master {
+        SerialMaster::new(enable_uart(block, self, resets, clocks), UART_BAUD_RATE)
+    }
+
+    fn into_slave(self, block: pac::UART0, resets: &mut pac::RESETS, clocks: ClockFreqs) -> Self::Slave {
+        SerialSlave::new(enable_uart(block, self, resets, clocks))
+    }
+}
+
+#[cfg(feature = "uart-link")]
+fn enable_uart<P: ValidUartPinout<pac::UART0>>(
+    block: pac::UART0,
+    pins: P,
+    resets: &mut pac::RESETS,
+    clocks: ClockFreqs,
+) -> UartPeripheral<Enabled, pac::UART0, P> {
+    UartPeripheral::new(block, pins, resets)
+        .enable(
+            UartConfig::new(UART_BAUD_RATE.Hz(), DataBits::Eight, None, StopBits::One),
+            clocks.peripheral,
+        )
+        .unwrap()
+}
+
+// GPIO number of the data line, which must already
+// be set to the PIO1 function and pulled up
+#[cfg(feature = "pio-link")]
+pub struct PioLinkPin(pub u8);
+
+#[cfg(feature = "pio-link")]
+impl LinkPins for PioLinkPin {
+    type Block = (
+        PIO<pac::PIO1>,
+        UninitStateMachine<(pac::PIO1, SM0)>,
+        UninitStateMachine<(pac::PIO1, SM1)>,
+    );
+    type Master = SerialMaster<PioUart<pac::PIO1, SM0, SM1>>;
+    type Slave = SerialSlave<PioUart<pac::PIO1, SM0, SM1>>;
+
+    fn into_master(self, block: Self::Block, _resets: &mut pac::RESETS, clocks: ClockFreqs) -> Self::Master {
+        SerialMaster::new(pio_uart(self.0, block, clocks), PIO_UART_BAUD_RATE)
+    }
+
+    fn into_slave(self, block: Self::Block, _resets: &mut pac::RESETS, clocks: ClockFreqs) -> Self::Slave {
+        SerialSlave::new(pio_uart(self.0, block, clocks))
+    }
+}
+
+#[cfg(feature = "pio-link")]
+fn pio_uart(
+    pin: u8,
+    block: (PIO<pac::PIO1>, UninitStateMachine<(pac::PIO1, SM0)>, UninitStateMachine<(pac::PIO1, SM1)>),
+    clocks: ClockFreqs,
+) -> PioUart<pac::PIO1, SM0, SM1> {
+    let (mut pio, sm0, sm1) = block;
+    PioUart::new(
+        &mut pio,
+        sm0,
+        sm1,
+        pin,
+        PIO_UART_BAUD_RATE,
+        clocks.system.to_Hz(),
+    )
+}
@@ -0,0 +1,97 @@
+/*
+* Vendor-defined raw HID interface carrying the configuration protocol,
+* see kallisto_components::config. Each request and response is a
+* single 32 byte report
+*/
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usb_device::UsbError;
+use usbd_human_interface_device as hid;
+use hid::device::DeviceClass;
+use hid::interface::{RawInterface, RawInterfaceBuilder, RawInterfaceConfig, UsbAllocatable, UsbPacketSize};
+use hid::UsbHidError;
+use fugit::ExtU32;
+
+use kallisto_components::config::CONFIG_REPORT_LEN;
+
+// Vendor usage page 0xFF4B, with 32 byte input and output reports
+#[rustfmt::skip]
+const CONFIG_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x4B, 0xFF, // Usage Page (Vendor Defined 0xFF4B)
+    0x09, 0x01,       // Usage (0x01)
+    0xA1, 0x01,       // Collection (Application)
+    0x09, 0x02,       //   Usage (0x02)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x20,       //   Report Count (32)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    0x09, 0x03,       //   Usage (0x03)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x20,       //   Report Count (32)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute)
+    0xC0,             // End Collection
+];
+
+pub struct ConfigHid<'a, B: UsbBus> {
+    interface: RawInterface<'a, B>,
+}
+
+impl<'a, B: UsbBus> ConfigHid<'a, B> {
+    // Returns the next request of the host, if one has arrived
+    pub fn read_request(&mut self) -> Option<[u8; CONFIG_REPORT_LEN]> {
+        let mut report = [0; CONFIG_REPORT_LEN];
+        match self.interface.read_report(&mut report) {
+            Ok(len) if len == CONFIG_REPORT_LEN => Some(report),
+            _ => None,
+        }
+    }
+
+    pub fn write_response(&mut self, report: &[u8; CONFIG_REPORT_LEN]) -> Result<(), UsbError> {
+        self.interface.write_report(report).map(|_| ())
+    }
+}
+
+impl<'a, B: UsbBus> DeviceClass<'a> for ConfigHid<'a, B> {
+    type I = RawInterface<'a, B>;
+
+    fn interface(&mut self) -> &mut Self::I {
+        &mut self.interface
+    }
+
+    fn reset(&mut self) {}
+
+    fn tick(&mut self) -> Result<(), UsbHidError> {
+        Ok(())
+    }
+}
+
+pub struct ConfigHidConfig<'a> {
+    interface: RawInterfaceConfig<'a>,
+}
+
+impl<'a> Default for ConfigHidConfig<'a> {
+    fn default() -> Self {
+        ConfigHidConfig {
+            interface: RawInterfaceBuilder::new(CONFIG_REPORT_DESCRIPTOR)
+                .unwrap()
+                .description("Kallisto configuration")
+                .in_endpoint(UsbPacketSize::Bytes32, 10.millis())
+                .unwrap()
+                .with_out_endpoint(UsbPacketSize::Bytes32, 10.millis())
+                .unwrap()
+                .build(),
+        }
+    }
+}
+
+impl<'a, B: UsbBus + 'a> UsbAllocatable<'a, B> for ConfigHidConfig<'a> {
+    type Allocated = ConfigHid<'a, B>;
+
+    fn allocate(self, usb_alloc: &'a UsbBusAllocator<B>) -> Self::Allocated {
+        ConfigHid {
+            interface: RawInterface::new(usb_alloc, self.interface),
+        }
+    }
+}
@@ -0,0 +1,10 @@
+use kallisto_components::keyboard::types::*;
+use kallisto_components::keyboard::layout::N_MATRIX_KEYS;
+use kallisto_components::keyboard::key_map_store::Layers;
+
+// Each layer is indexed by `KeyId`, the left half
+// keys come first, followed by the right half keys
+pub const N_KEYS: usize = N_MATRIX_KEYS;
+
+// `DEFAULT_LAYERS`, compiled from key_map.toml by build.rs
+include!(concat!(env!("OUT_DIR"), "/key_map.rs"));
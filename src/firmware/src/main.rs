@@ -0,0 +1,489 @@
+#![no_std]
+#![no_main]
+
+pub mod board;
+pub mod config_hid;
+pub mod key_map;
+pub mod master;
+pub mod settings;
+pub mod storage;
+pub mod slave;
+
+// The macro for our start-up function
+use rp_pico::entry;
+
+// Ensure we halt the program on panic (if we don't mention this crate it won't
+// be linked)
+use panic_halt as _;
+
+// Pull in any important traits
+use rp_pico::hal::prelude::*;
+
+// A shorter alias for the Peripheral Access Crate, which provides low-level
+// register access
+use rp_pico::hal::{self, pac};
+
+// Import the Timer for Ws2812:
+use rp_pico::hal::timer::Timer;
+use hal::gpio::bank0::{Gpio12, Gpio13, Gpio25};
+use hal::gpio::{FunctionI2C, Pin, PushPullOutput};
+use hal::i2c::I2C;
+use hal::usb::UsbBus;
+
+use kallisto_components::at24c::{At24c, At24cMemSize};
+use kallisto_components::config::{PRODUCT_ID, VENDOR_ID};
+use kallisto_components::flash::{install_image, resume_install, FlashStorage, Rp2040Flash};
+use kallisto_components::i2c::recover_controller;
+use kallisto_components::update::{pending_image, STAGING_OFFSET};
+use kallisto_components::keyboard::key_matrix::MatrixScanner;
+use kallisto_components::keyboard::layout::Half;
+#[cfg(not(feature = "pio-matrix"))]
+use kallisto_components::keyboard::key_matrix::GpioScanner;
+#[cfg(feature = "pio-matrix")]
+use kallisto_components::keyboard::pio_matrix::{PioScanner, ScanBuffer};
+#[cfg(feature = "pio-matrix")]
+use hal::dma::DMAExt;
+use board::{detect_role, load_handedness, read_strap, ClockFreqs, LinkPins, Role};
+use storage::ConfigStorage;
+use config_hid::{ConfigHid, ConfigHidConfig};
+#[cfg(any(feature = "pio-matrix", feature = "pio-link"))]
+use board::pin_map;
+#[cfg(feature = "pio-link")]
+use board::PioLinkPin;
+
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use fugit::RateExtU32;
+
+use usbd_human_interface_device as hid;
+use hid::device::keyboard::NKROBootKeyboard;
+use hid::prelude::*;
+use hid::usb_class::UsbHidClass;
+
+use usb_device::{class_prelude::*, prelude::*};
+use usbd_serial::SerialPort;
+
+use hal::pio::PIOExt;
+use smart_leds::{SmartLedsWrite, RGB8};
+use ws2812_pio::Ws2812;
+
+// Version of this firmware, major << 16 | minor << 8 | patch of the
+// package version. Firmware updates of the slave only accept newer images
+pub const FIRMWARE_VERSION: u32 = 0x00_01_00;
+
+pub type UsbKeyboard<'a> = UsbHidClass<'a, UsbBus, frunk::HList!(NKROBootKeyboard<'a, UsbBus>)>;
+pub type ConfigUsbHid<'a> = UsbHidClass<'a, UsbBus, frunk::HList!(ConfigHid<'a, UsbBus>)>;
+pub type Eeprom<'a> = At24c<'a, I2C<pac::I2C0, (Pin<Gpio12, FunctionI2C>, Pin<Gpio13, FunctionI2C>)>>;
+
+// Hardware shared by both roles, set up before the role is known
+pub struct Board<'a> {
+    pub timer: &'a Timer,
+    pub clocks: ClockFreqs,
+    pub resets: pac::RESETS,
+    pub delay: cortex_m::delay::Delay,
+    pub led_pin: Pin<Gpio25, PushPullOutput>,
+    pub usb_dev: UsbDevice<'a, UsbBus>,
+    pub keyboard: UsbKeyboard<'a>,
+    pub serial: SerialPort<'a, UsbBus>,
+    pub config_hid: ConfigUsbHid<'a>,
+    // Key-map, settings and handedness
+    pub storage: ConfigStorage<'a>,
+}
+
+// Called by the reset handler before RAM is initialised. Finishes the
+// install of a firmware update that was interrupted by a power loss,
+// running from the first sector of flash, see memory.x
+#[no_mangle]
+#[link_section = ".installer"]
+pub unsafe extern "C" fn __pre_init() {
+    resume_install();
+}
+
+#[entry]
+fn main() -> ! {
+    // Grab our singleton objects
+    let mut pac = pac::Peripherals::take().unwrap();
+    let core = pac::CorePeripherals::take().unwrap();
+
+    // Set up the watchdog driver - needed by the clock setup code
+    let mut watchdog = hal::Watchdog::new(pac.WATCHDOG);
+
+    // Configure the clocks
+    //
+    // The default is to generate a 125 MHz system clock
+    let clocks = hal::clocks::init_clocks_and_plls(
+        rp_pico::XOSC_CRYSTAL_FREQ,
+        pac.XOSC,
+        pac.CLOCKS,
+        pac.PLL_SYS,
+        pac.PLL_USB,
+        &mut pac.RESETS,
+        &mut watchdog,
+    )
+    .ok()
+    .unwrap();
+    let clock_freqs = ClockFreqs {
+        system: clocks.system_clock.freq(),
+        peripheral: clocks.peripheral_clock.freq(),
+    };
+
+    // An image staged by a firmware update is installed before
+    // anything else is set up, the board resets once it is in place
+    if let Some(size) = pending_image(&mut Rp2040Flash::new(), FIRMWARE_VERSION) {
+        unsafe { install_image(STAGING_OFFSET, size) }
+    }
+
+    // The single-cycle I/O block controls our GPIO pins
+    let sio = hal::Sio::new(pac.SIO);
+
+    // Set the pins up according to their function on this particular board
+    let pins = rp_pico::Pins::new(
+        pac.IO_BANK0,
+        pac.PADS_BANK0,
+        sio.gpio_bank0,
+        &mut pac.RESETS,
+    );
+
+
+    let timer = Timer::new(pac.TIMER, &mut pac.RESETS);
+
+    let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+
+    // Set up smart leds
+    let (mut pio, sm0, sm1, _, _) = pac.PIO0.split(&mut pac.RESETS);
+
+    // USB is brought up on both halves, only the half
+    // connected to the host will ever be enumerated
+    let usb_bus = UsbBusAllocator::new(UsbBus::new(
+        pac.USBCTRL_REGS,
+        pac.USBCTRL_DPRAM,
+        clocks.usb_clock,
+        true,
+        &mut pac.RESETS,
+    ));
+
+    let mut keyboard = UsbHidClassBuilder::new()
+        .add_device(
+            usbd_human_interface_device::device::keyboard::NKROBootKeyboardConfig::default(),
+        )
+        .build(&usb_bus);
+
+    // Serial port used as a host interface for reading diagnostics
+    let mut serial = SerialPort::new(&usb_bus);
+
+    // Raw HID interface for changing the key-map and timing from the host
+    let mut config_hid = UsbHidClassBuilder::new()
+        .add_device(ConfigHidConfig::default())
+        .build(&usb_bus);
+
+    //https://pid.codes
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(VENDOR_ID, PRODUCT_ID))
+        .manufacturer("Nabla Electronics")
+        .product("Kallisto")
+        .serial_number("002")
+        .composite_with_iads()
+        .build();
+
+    let mut led_pin = pins.led.into_push_pull_output();
+    led_pin.set_high().unwrap();
+
+    let vbus = pins.vbus_detect.into_floating_input().is_high().unwrap();
+    let role = detect_role(vbus, &mut usb_dev, &mut [&mut keyboard, &mut serial, &mut config_hid], &timer);
+
+    let eeprom_i2c = I2C::i2c0(
+        pac.I2C0,
+        pins.gpio12.into_mode(),
+        pins.gpio13.into_mode(),
+        400.kHz(),
+        &mut pac.RESETS,
+        clocks.system_clock.freq(),
+    );
+    // If this half was reset in the middle of an EEPROM read, the EEPROM
+    // may still be holding SDA low waiting for the clock to go on
+    let (eeprom_i2c, _) = recover_controller(
+        eeprom_i2c,
+        400.kHz(),
+        clocks.system_clock.freq(),
+        &mut pac.RESETS,
+        &mut delay,
+    );
+    // A0..A2 are tied to ground on both halves
+    let mut eeprom = At24c::new(eeprom_i2c, At24cMemSize::Kb256, 0, &timer);
+    // Not every build has the EEPROM fitted, without it
+    // the storage region at the end of flash is used
+    let mut storage = if eeprom.is_present() {
+        ConfigStorage::Eeprom(eeprom)
+    } else {
+        ConfigStorage::Flash(FlashStorage::new())
+    };
+
+    // Without a strap or a stored handedness the halves fall back
+    // to the original arrangement, the right half as the master
+    let half = read_strap(pins.gpio10, &mut delay)
+        .or_else(|| load_handedness(&mut storage))
+        .unwrap_or(match role {
+            Role::Master => Half::Right,
+            Role::Slave => Half::Left,
+        });
+
+    led_pin.set_low().unwrap();
+
+    #[cfg(not(any(feature = "uart-link", feature = "pio-link")))]
+    let link_block = pac.I2C1;
+    #[cfg(feature = "uart-link")]
+    let link_block = pac.UART0;
+    // The half-duplex link runs on its own PIO block
+    #[cfg(feature = "pio-link")]
+    let link_block = {
+        let (pio1, link_sm0, link_sm1, _, _) = pac.PIO1.split(&mut pac.RESETS);
+        (pio1, link_sm0, link_sm1)
+    };
+
+    #[cfg(feature = "pio-matrix")]
+    let dma = pac.DMA.split(&mut pac.RESETS);
+
+    let board = Board {
+        timer: &timer,
+        clocks: clock_freqs,
+        resets: pac.RESETS,
+        delay,
+        led_pin,
+        usb_dev,
+        keyboard,
+        serial,
+        config_hid,
+        storage,
+    };
+    #[cfg(any(feature = "pio-matrix", feature = "pio-link"))]
+    let pin_map = pin_map(half);
+
+    match half {
+        Half::Left => {
+            // Instanciate a Ws2812 LED strip:
+            let ws = Ws2812::new(
+                pins.gpio16.into_mode(),
+                &mut pio,
+                sm0,
+                clocks.peripheral_clock.freq(),
+                timer.count_down(),
+            );
+
+            #[cfg(not(any(feature = "uart-link", feature = "pio-link")))]
+            let link = (
+                pins.gpio18.into_mode::<FunctionI2C>(),
+                pins.gpio19.into_mode::<FunctionI2C>(),
+            );
+            #[cfg(feature = "uart-link")]
+            let link = (
+                pins.gpio28.into_mode::<hal::gpio::FunctionUart>(),
+                pins.gpio17.into_mode::<hal::gpio::FunctionUart>(),
+            );
+            #[cfg(feature = "pio-link")]
+            let link = {
+                let _ = pins.gpio18.into_pull_up_input().into_mode::<hal::gpio::FunctionPio1>();
+                PioLinkPin(pin_map.link_sda)
+            };
+
+            // Set up all pins
+            #[cfg(not(feature = "pio-matrix"))]
+            let mut row_pin_0 = pins.gpio0.into_push_pull_output();
+            #[cfg(not(feature = "pio-matrix"))]
+            let mut row_pin_1 = pins.gpio1.into_push_pull_output();
+            #[cfg(not(feature = "pio-matrix"))]
+            let mut row_pin_2 = pins.gpio2.into_push_pull_output();
+
+            // Setting the output slew rate to fast is absolutley
+            // needed in order to prevent row cross-talk
+            #[cfg(not(feature = "pio-matrix"))]
+            row_pin_0.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+            #[cfg(not(feature = "pio-matrix"))]
+            row_pin_1.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+            #[cfg(not(feature = "pio-matrix"))]
+            row_pin_2.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+
+            // Set up our pins for the key matrix
+            #[cfg(not(feature = "pio-matrix"))]
+            let row_pins: &mut [&mut dyn OutputPin<Error = core::convert::Infallible>] = &mut [
+                &mut row_pin_0,
+                &mut row_pin_1,
+                &mut row_pin_2,
+            ];
+
+            #[cfg(not(feature = "pio-matrix"))]
+            let col_pins: &[&dyn InputPin<Error = core::convert::Infallible>] = &[
+                &pins.gpio3.into_pull_up_input(),
+                &pins.gpio4.into_pull_up_input(),
+                &pins.gpio5.into_pull_up_input(),
+                &pins.gpio6.into_pull_up_input(),
+                &pins.gpio7.into_pull_up_input(),
+                &pins.gpio8.into_pull_up_input(),
+                &pins.gpio9.into_pull_up_input(),
+            ];
+
+            #[cfg(not(feature = "pio-matrix"))]
+            let mut scanner = GpioScanner::new(row_pins, col_pins, &timer);
+
+            // When scanning with PIO the rows are driven, and the columns sampled,
+            // by a state machine and DMA, leaving the CPU free during the scan
+            #[cfg(feature = "pio-matrix")]
+            let mut scanner = {
+                let mut row_pin_0 = pins.gpio0.into_push_pull_output();
+                row_pin_0.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+                let _ = row_pin_0.into_mode::<hal::gpio::FunctionPio0>();
+                let mut row_pin_1 = pins.gpio1.into_push_pull_output();
+                row_pin_1.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+                let _ = row_pin_1.into_mode::<hal::gpio::FunctionPio0>();
+                let mut row_pin_2 = pins.gpio2.into_push_pull_output();
+                row_pin_2.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+                let _ = row_pin_2.into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio3.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio4.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio5.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio6.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio7.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio8.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio9.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                PioScanner::new(
+                    &mut pio,
+                    sm1,
+                    dma.ch0,
+                    dma.ch1,
+                    pin_map.rows,
+                    pin_map.col_base,
+                    pin_map.cols_reversed,
+                    cortex_m::singleton!(: ScanBuffer = ScanBuffer::new()).unwrap(),
+                    cortex_m::singleton!(: ScanBuffer = ScanBuffer::new()).unwrap(),
+                    clocks.system_clock.freq().to_Hz(),
+                )
+            };
+
+            start(role, half, &mut scanner, ws, link, link_block, board)
+        }
+        Half::Right => {
+            // Instanciate a Ws2812 LED strip:
+            let ws = Ws2812::new(
+                pins.gpio14.into_mode(),
+                &mut pio,
+                sm0,
+                clocks.peripheral_clock.freq(),
+                timer.count_down(),
+            );
+
+            #[cfg(not(any(feature = "uart-link", feature = "pio-link")))]
+            let link = (
+                pins.gpio2.into_mode::<FunctionI2C>(),
+                pins.gpio3.into_mode::<FunctionI2C>(),
+            );
+            #[cfg(feature = "uart-link")]
+            let link = (
+                pins.gpio0.into_mode::<hal::gpio::FunctionUart>(),
+                pins.gpio1.into_mode::<hal::gpio::FunctionUart>(),
+            );
+            #[cfg(feature = "pio-link")]
+            let link = {
+                let _ = pins.gpio2.into_pull_up_input().into_mode::<hal::gpio::FunctionPio1>();
+                PioLinkPin(pin_map.link_sda)
+            };
+
+            // Set up all pins
+            #[cfg(not(feature = "pio-matrix"))]
+            let mut row_pin_0 = pins.gpio27.into_push_pull_output();
+            #[cfg(not(feature = "pio-matrix"))]
+            let mut row_pin_1 = pins.gpio26.into_push_pull_output();
+            #[cfg(not(feature = "pio-matrix"))]
+            let mut row_pin_2 = pins.gpio22.into_push_pull_output();
+
+            // Setting the output slew rate to fast is absolutley
+            // needed in order to prevent row cross-talk
+            #[cfg(not(feature = "pio-matrix"))]
+            row_pin_0.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+            #[cfg(not(feature = "pio-matrix"))]
+            row_pin_1.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+            #[cfg(not(feature = "pio-matrix"))]
+            row_pin_2.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+
+            // Set up our pins for the key matrix
+            #[cfg(not(feature = "pio-matrix"))]
+            let row_pins: &mut [&mut dyn OutputPin<Error = core::convert::Infallible>] = &mut [
+                &mut row_pin_0,
+                &mut row_pin_1,
+                &mut row_pin_2,
+            ];
+
+            #[cfg(not(feature = "pio-matrix"))]
+            let col_pins: &[&dyn InputPin<Error = core::convert::Infallible>] = &[
+                &pins.gpio21.into_pull_up_input(),
+                &pins.gpio20.into_pull_up_input(),
+                &pins.gpio19.into_pull_up_input(),
+                &pins.gpio18.into_pull_up_input(),
+                &pins.gpio17.into_pull_up_input(),
+                &pins.gpio16.into_pull_up_input(),
+                &pins.gpio15.into_pull_up_input(),
+            ];
+
+            #[cfg(not(feature = "pio-matrix"))]
+            let mut scanner = GpioScanner::new(row_pins, col_pins, &timer);
+
+            #[cfg(feature = "pio-matrix")]
+            let mut scanner = {
+                let mut row_pin_0 = pins.gpio27.into_push_pull_output();
+                row_pin_0.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+                let _ = row_pin_0.into_mode::<hal::gpio::FunctionPio0>();
+                let mut row_pin_1 = pins.gpio26.into_push_pull_output();
+                row_pin_1.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+                let _ = row_pin_1.into_mode::<hal::gpio::FunctionPio0>();
+                let mut row_pin_2 = pins.gpio22.into_push_pull_output();
+                row_pin_2.set_slew_rate(hal::gpio::OutputSlewRate::Fast);
+                let _ = row_pin_2.into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio21.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio20.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio19.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio18.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio17.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio16.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                let _ = pins.gpio15.into_pull_up_input().into_mode::<hal::gpio::FunctionPio0>();
+                PioScanner::new(
+                    &mut pio,
+                    sm1,
+                    dma.ch0,
+                    dma.ch1,
+                    pin_map.rows,
+                    pin_map.col_base,
+                    pin_map.cols_reversed,
+                    cortex_m::singleton!(: ScanBuffer = ScanBuffer::new()).unwrap(),
+                    cortex_m::singleton!(: ScanBuffer = ScanBuffer::new()).unwrap(),
+                    clocks.system_clock.freq().to_Hz(),
+                )
+            };
+
+            start(role, half, &mut scanner, ws, link, link_block, board)
+        }
+    }
+}
+
+// Turns the link pins into the transport of the role and runs it
+fn start<S, W, L>(
+    role: Role,
+    half: Half,
+    scanner: &mut S,
+    ws: W,
+    link: L,
+    link_block: L::Block,
+    mut board: Board,
+) -> !
+where
+    S: MatrixScanner,
+    W: SmartLedsWrite<Color = RGB8>,
+    W::Error: core::fmt::Debug,
+    L: LinkPins,
+{
+    match role {
+        Role::Master => {
+            let transport = link.into_master(link_block, &mut board.resets, board.clocks);
+            master::run::<S, W, L>(half, scanner, ws, transport, board)
+        }
+        Role::Slave => {
+            let transport = link.into_slave(link_block, &mut board.resets, board.clocks);
+            slave::run::<S, W, L>(half, scanner, ws, transport, board)
+        }
+    }
+}
@@ -0,0 +1,684 @@
+/*
+* The master role, run by the half connected to the host. It scans its
+* own matrix, polls the slave for the key events of the other half and
+* runs the keyboard for both halves
+*/
+use rp_pico::hal as hal;
+use hal::gpio::PinState;
+
+use kallisto_components::i2c::RecoveryTrigger;
+use kallisto_components::transport::{poll_frame, MasterTransport};
+use kallisto_components::keyboard::key_matrix::{KeyMatrix, MatrixScanner, MatrixTiming, GHOST_FLAG};
+use kallisto_components::keyboard::layout::{combine_halves, matrix_keys, Half, KeyId, KALLISTO_LAYOUT, N_MATRIX_KEYS};
+use kallisto_components::keyboard::keyboard::{LayeredKeyboard, ProfileRequest, ERROR_ROLL_OVER_REPORT, N_LAYERS};
+use kallisto_components::keyboard::key_map_store::{encode_key_map, key_map_len, load_key_map, Layers};
+use kallisto_components::keyboard::types::*;
+use kallisto_components::keyboard::diagnostics::{MatrixStats, MATRIX_STATS_SIZE};
+use kallisto_components::keyboard::key_matrix::N_KEYS as N_HALF_KEYS;
+use kallisto_components::link::{LinkEvent, LinkMonitor, LinkStatus, STALE_EVENT_US};
+use kallisto_components::protocol::{
+    decode_key_events, decode_link_info, Command, CommandQueue, Compatibility, FrameError,
+    LinkInfo, PayloadType, KEY_EVENTS_FRAME_LEN, MAX_COMMAND_FRAME_LEN, MAX_FRAME_LEN,
+};
+use kallisto_components::settings::{SettingsError, SettingsStore};
+use kallisto_components::config::{ConfigRequest, ConfigResponse, ConfigStatus, KeyboardInfo};
+use kallisto_components::update::UpdateRelay;
+use kallisto_components::lighting::{
+    Lighting, HOST_LED_CAPS_LOCK, HOST_LED_NUM_LOCK, HOST_LED_SCROLL_LOCK,
+};
+use crate::board::{key_map_address, link_info, save_handedness, LinkPins, N_PROFILES, STATS_ADDRESS};
+use crate::key_map::*;
+use crate::settings::*;
+use crate::storage::ConfigStorage;
+use crate::{Board, FIRMWARE_VERSION};
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::timer::CountDown;
+
+use heapless::spsc::Queue;
+use heapless::String;
+use fugit::ExtU32;
+
+use usbd_human_interface_device as hid;
+use hid::page::Keyboard;
+use hid::prelude::*;
+
+use usb_device::prelude::*;
+use core::fmt::Write;
+
+use smart_leds::{brightness, SmartLedsWrite, RGB8};
+
+const STRIP_LEN: usize = 21;
+// Time the slave is given to answer a poll
+const LINK_TIMEOUT_US: u32 = 1_500;
+// Change of the brightness by each `+` or `-` from the host
+const BRIGHTNESS_STEP: u8 = 16;
+// Hello is sent again if the link info of the slave has not arrived within this time
+const HELLO_RETRY_US: u32 = 100_000;
+// Changed switch statistics are written back this often,
+// rarely enough not to wear out the storage
+const STATS_SAVE_INTERVAL_US: u32 = 30 * 60 * 1_000_000;
+
+pub fn run<S, W, L>(
+    half: Half,
+    scanner: &mut S,
+    mut ws: W,
+    mut transport: L::Master,
+    board: Board,
+) -> !
+where
+    S: MatrixScanner,
+    W: SmartLedsWrite<Color = RGB8>,
+    W::Error: core::fmt::Debug,
+    L: LinkPins,
+{
+    let Board {
+        timer,
+        clocks,
+        mut resets,
+        mut delay,
+        mut led_pin,
+        mut usb_dev,
+        mut keyboard,
+        mut serial,
+        mut config_hid,
+        mut storage,
+    } = board;
+    // The slave is the other half
+    let remote = match half {
+        Half::Left => Half::Right,
+        Half::Right => Half::Left,
+    };
+    // The slave builds each frame as it is asked for, so a frame
+    // is sent roughly this long before it has been received
+    let frame_time_us = transport.frame_time_us(KEY_EVENTS_FRAME_LEN);
+    // Link info of both halves. Until the slave has sent its
+    // own it is assumed to match the master
+    let local_info = link_info(half);
+    let mut remote_info: Option<LinkInfo> = None;
+    let mut hello_sent: u32 = 0;
+    // Protocol version of the slave, while it is not the one of the master
+    let mut incompatible: Option<u8> = None;
+
+    // Switch statistics are kept for the keys of this half only,
+    // carrying on from those stored
+    let mut key_matrix = KeyMatrix::new(timer);
+    key_matrix.enable_stats(MatrixStats::load(&mut storage, STATS_ADDRESS, half).unwrap_or(MatrixStats::new(half)));
+    let mut event_queue = Queue::<Keyboard, 32>::new();
+    let (tx, mut rx) = event_queue.split();
+
+    let mut leds: [RGB8; STRIP_LEN] = [(0, 0, 0).into(); STRIP_LEN];
+    let mut lighting = Lighting::new();
+    let mut matrix_timing = MatrixTiming::new();
+
+    // Settings stored in the EEPROM take the place of the defaults
+    let mut settings = SettingsStore::mount(&mut storage, SETTINGS_ADDRESS, settings_bank_size(&storage)).ok();
+    let mut profile = 0;
+    let mut default_layer = 0;
+    // Set when the brightness or the active profile has changed and
+    // is still to be stored
+    let mut brightness_unsaved = false;
+    let mut profile_unsaved = false;
+    if let Some(store) = settings.as_ref() {
+        lighting.brightness = store.get(&mut storage, &BRIGHTNESS);
+        default_layer = (store.get(&mut storage, &DEFAULT_LAYER) as usize).min(N_LAYERS - 1);
+        matrix_timing.debounce_us = store.get(&mut storage, &DEBOUNCE_US);
+        matrix_timing.settle_us = store.get(&mut storage, &SETTLE_US);
+        matrix_timing.anti_ghosting = store.get(&mut storage, &ANTI_GHOSTING);
+        profile = store.get(&mut storage, &ACTIVE_PROFILE).min(N_PROFILES - 1);
+    }
+    key_matrix.set_debounce_us(matrix_timing.debounce_us);
+    scanner.set_settle_time_us(matrix_timing.settle_us);
+    key_matrix.set_anti_ghosting(matrix_timing.anti_ghosting);
+
+    let mut kallisto = LayeredKeyboard::new(timer, profile_layers(&mut storage, profile), tx);
+    kallisto.set_layer(default_layer);
+    // Key-map changes of the host are only stored on Commit. Set
+    // while there are changes that have not been stored
+    let mut key_map_buf = [0; key_map_len(N_MATRIX_KEYS)];
+    let mut key_map_uncommitted = false;
+    // Serialized switch statistics, while they are being written back
+    let mut stats_buf = [0; MATRIX_STATS_SIZE];
+    let mut stats_saving = false;
+    let mut stats_saved = timer.get_counter_low();
+
+    let mut keyboard_timer = timer.count_down();
+    let mut tick_timer = timer.count_down();
+    let mut led_timer = timer.count_down();
+
+    led_timer.start(17.millis());
+    keyboard_timer.start(1.millis());
+    tick_timer.start(1.millis());
+
+    let mut frame_buf: [u8; KEY_EVENTS_FRAME_LEN] = [0; KEY_EVENTS_FRAME_LEN];
+    // Last key states received from the slave half
+    let mut remote_raw: u32 = 0;
+    let mut link = LinkMonitor::new();
+    let mut bus_recovery = RecoveryTrigger::new();
+    // Commands waiting to be sent to the slave
+    let mut commands = CommandQueue::new();
+    let mut command_buf: [u8; MAX_COMMAND_FRAME_LEN] = [0; MAX_COMMAND_FRAME_LEN];
+    let mut i: usize;
+    let mut report_buf: [Keyboard; 32];
+    // Set while either matrix suppresses ghosted key presses
+    let mut ghosting = false;
+    let mut serial_buf: [u8; 16] = [0; 16];
+    // Id of the next key whose statistics are to be sent over serial,
+    // set when the host requests a statistics dump
+    let mut stats_dump: Option<KeyId> = None;
+    // Set when the host requests the link statistics
+    let mut link_dump = false;
+    // Set when the host requests the link info of the slave
+    let mut info_dump = false;
+    // Firmware update of the slave in progress, while set
+    // all serial input is passed on to it
+    let mut update: Option<UpdateRelay> = None;
+    let mut update_buf: [u8; MAX_FRAME_LEN] = [0; MAX_FRAME_LEN];
+    // Response to the last request of the configuration interface, until it has been sent
+    let mut config_response: Option<ConfigResponse> = None;
+    // Set once the host has asked for the USB boot mode, entered after answering
+    let mut enter_bootloader = false;
+
+    loop {
+
+        if tick_timer.wait().is_ok() {
+            let _ = keyboard.tick();
+        }
+
+        if keyboard_timer.wait().is_ok() {
+            let local_state = key_matrix.poll(scanner);
+            let now = timer.get_counter_low();
+            // Failed transfers and corrupt frames keep the last known slave half
+            // key states, until enough consecutive errors have occurred for
+            // the link to be considered down
+            let mut recover_bus = false;
+            let link_event = if !link.should_poll(now) {
+                LinkEvent::None
+            } else if poll_frame(&mut transport, timer, LINK_TIMEOUT_US, &mut frame_buf).is_err() {
+                recover_bus = bus_recovery.error();
+                link.error(now)
+            } else {
+                bus_recovery.success();
+                let frame_time = timer.get_counter_low().wrapping_sub(frame_time_us);
+                match decode_key_events(&frame_buf, frame_time) {
+                    Err(FrameError::UnknownPayload(t)) if t == PayloadType::LinkInfo as u8 => {
+                        match decode_link_info(&frame_buf) {
+                            Ok((seq, info)) => {
+                                remote_info = Some(info);
+                                // A slave speaking another protocol can still send its
+                                // link info, but none of its other frames can be used
+                                if local_info.compatibility(&info) == Compatibility::Incompatible {
+                                    incompatible = Some(info.protocol_version);
+                                    link.frame_corrupt(now)
+                                } else {
+                                    link.frame_received(now, seq)
+                                }
+                            }
+                            Err(_) => link.frame_corrupt(now),
+                        }
+                    }
+                    Err(FrameError::UnsupportedVersion(version)) => {
+                        incompatible = Some(version);
+                        link.frame_corrupt(now)
+                    }
+                    Err(_) => link.frame_corrupt(now),
+                    Ok(frame) => {
+                        incompatible = None;
+                        let link_event = link.frame_received(now, frame.seq);
+                        commands.ack(frame.ack_seq);
+                        if let Some(relay) = update.as_mut() {
+                            relay.slave_status(frame.update_status, commands.is_empty(), now);
+                        }
+                        if link.status() != LinkStatus::Disconnected {
+                            // Slave half changes are fed to the keyboard with the
+                            // time they happened on the slave, on the master clock.
+                            // Events queued up while the link was down are dropped
+                            // Keys beyond the matrix the slave reports are ignored
+                            let remote_keys = remote_info.map_or(N_HALF_KEYS, |i| i.matrix_keys().min(N_HALF_KEYS));
+                            for event in frame.events.iter().filter(|e| {
+                                (e.key as usize) < remote_keys
+                                    && frame_time.wrapping_sub(e.time) < STALE_EVENT_US
+                            }) {
+                                let id = KeyId::from_index(remote.offset() + event.key as usize);
+                                kallisto.key_changed(id, event.pressed, event.time);
+                                remote_raw = (remote_raw & !(1 << event.key)) | ((event.pressed as u32) << event.key);
+                            }
+                            // Changes not covered by any event, e.g. from a lost
+                            // frame, are applied with the time of the frame
+                            let missed = (frame.key_states ^ remote_raw) & !GHOST_FLAG;
+                            for key in (0..remote_keys).into_iter().filter(|k| (missed >> k) & 0x1 == 1) {
+                                let id = KeyId::from_index(remote.offset() + key);
+                                kallisto.key_changed(id, (frame.key_states >> key) & 0x1 == 1, frame_time);
+                            }
+                            remote_raw = frame.key_states;
+                        }
+                        link_event
+                    }
+                }
+            };
+
+            if recover_bus {
+                transport = L::recover(transport, &mut resets, clocks, &mut delay);
+            }
+
+            // When the link goes down every slave half key is released
+            // at once, so that no key is left stuck
+            if link_event == LinkEvent::Disconnected {
+                for key in (0..N_HALF_KEYS).into_iter().filter(|k| (remote_raw >> k) & 0x1 == 1) {
+                    kallisto.key_changed(KeyId::from_index(remote.offset() + key), false, now);
+                }
+                remote_raw = 0;
+                // The slave may be replaced while the link is down
+                remote_info = None;
+            }
+
+            // The slave may have been reset while the link was down,
+            // so it is sent the complete state when the link comes up
+            // The link info is usually the first frame of a slave that has just
+            // started, otherwise the master asks for it with a hello
+            if link_event == LinkEvent::Connected {
+                if remote_info.is_none() {
+                    hello_sent = now;
+                    commands.push(Command::Hello(local_info));
+                }
+                commands.push(Command::SetLayer(lighting.layer));
+                commands.push(Command::SetHostLeds(lighting.host_leds));
+                commands.push(Command::SetLighting {
+                    mode: lighting.mode,
+                    brightness: lighting.brightness,
+                });
+                commands.push(Command::SetMatrixTiming(matrix_timing));
+            }
+
+            if remote_info.is_none()
+                && link.status() == LinkStatus::Connected
+                && now.wrapping_sub(hello_sent) > HELLO_RETRY_US
+            {
+                hello_sent = now;
+                commands.push(Command::Hello(local_info));
+            }
+
+            let state = match half {
+                Half::Left => combine_halves(local_state, remote_raw & !GHOST_FLAG),
+                Half::Right => combine_halves(remote_raw & !GHOST_FLAG, local_state),
+            };
+            kallisto.get_report(state);
+            let was_ghosting = ghosting;
+            ghosting = key_matrix.is_ghosting() || remote_raw & GHOST_FLAG != 0;
+            if kallisto.layer as u8 != lighting.layer {
+                lighting.layer = kallisto.layer as u8;
+                commands.push(Command::SetLayer(lighting.layer));
+            }
+
+            // At most one command is sent per tick, a failed write
+            // is retried once the command times out
+            if link.status() != LinkStatus::Disconnected {
+                if let Some(len) = commands.next_frame(now, &mut command_buf) {
+                    let _ = transport.send_command(&command_buf[..len]);
+                }
+            }
+            // The above code may be  somewhat time consuming so we check if we need to tick here
+            if tick_timer.wait().is_ok() {
+                let _ = keyboard.tick();
+            }
+            // A ghosting episode is reported once, on its own. The key
+            // events of this tick are left queued and reported next tick
+            if ghosting && !was_ghosting {
+                let _ = keyboard.device().write_report(ERROR_ROLL_OVER_REPORT);
+            } else {
+                report_buf = [Keyboard::NoEventIndicated; 32];
+                i = 0;
+                while rx.ready() {
+                    report_buf[i] = rx.dequeue().unwrap();
+                    i += 1;
+                }
+                let _ = keyboard.device().write_report(report_buf);
+            }
+        }
+
+        if tick_timer.wait().is_ok() {
+            let _ = keyboard.tick();
+        }
+
+        if usb_dev.poll(&mut [&mut keyboard, &mut serial, &mut config_hid]) {
+            match keyboard.device().read_report() {
+                Err(UsbError::WouldBlock) => {
+                    //do nothing
+                }
+                Err(e) => {
+                    loop {
+                        led_pin.set_high().unwrap();
+                        delay.delay_ms(100);
+                        led_pin.set_low().unwrap();
+                        delay.delay_ms(100);
+                    }
+                }
+                Ok(leds) => {
+                    led_pin.set_state(PinState::from(leds.num_lock)).ok();
+                    let host_leds = (leds.num_lock as u8 * HOST_LED_NUM_LOCK)
+                        | (leds.caps_lock as u8 * HOST_LED_CAPS_LOCK)
+                        | (leds.scroll_lock as u8 * HOST_LED_SCROLL_LOCK);
+                    if host_leds != lighting.host_leds {
+                        lighting.host_leds = host_leds;
+                        commands.push(Command::SetHostLeds(host_leds));
+                    }
+                }
+            }
+
+            // 's' dumps the switch statistics, 'r' resets them
+            // and 'l' dumps the link statistics. 'L' and 'R' store the
+            // handedness of this half, used when its strap is left open
+            // and taking effect at the next start-up. 'U' starts a
+            // firmware update of the slave, see the update module, and
+            // 'i' dumps the versions and capabilities of the slave
+            if let Ok(n) = serial.read(&mut serial_buf) {
+                let now = timer.get_counter_low();
+                for c in serial_buf[..n].iter() {
+                    if let Some(relay) = update.as_mut() {
+                        if let Some(command) = relay.host_byte(*c, now) {
+                            if commands.push_update(command).is_err() {
+                                relay.command_rejected();
+                            }
+                        }
+                        continue;
+                    }
+                    match c {
+                        b's' => stats_dump = matrix_keys(half).next(),
+                        b'r' => {
+                            if let Some(stats) = key_matrix.stats_mut() {
+                                stats.reset();
+                            }
+                        }
+                        b'l' => link_dump = true,
+                        b'i' => info_dump = true,
+                        b'L' | b'R' => {
+                            let side = if *c == b'L' { Half::Left } else { Half::Right };
+                            let mut line: String<64> = String::new();
+                            if let Err(e) = save_handedness(&mut storage, side) {
+                                let _ = write!(line, "{}\r\n", e);
+                            }
+                            let _ = serial.write(line.as_bytes());
+                        }
+                        b'U' => update = Some(UpdateRelay::new(now)),
+                        b'p' => kallisto.request_profile(ProfileRequest::Next),
+                        b'0'..=b'9' if *c - b'0' < N_PROFILES => kallisto.request_profile(ProfileRequest::Set((*c - b'0') as usize)),
+                        b'+' | b'-' => {
+                            lighting.brightness = if *c == b'+' {
+                                lighting.brightness.saturating_add(BRIGHTNESS_STEP)
+                            } else {
+                                lighting.brightness.saturating_sub(BRIGHTNESS_STEP)
+                            };
+                            commands.push(Command::SetLighting {
+                                mode: lighting.mode,
+                                brightness: lighting.brightness,
+                            });
+                            brightness_unsaved = true;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        // Requests of the configuration interface are handled one at a
+        // time, the next is read once the response has been sent
+        if config_response.is_none() {
+            if let Some(report) = config_hid.device().read_request() {
+                let (id, request) = ConfigRequest::decode(&report);
+                let response = match request {
+                    Err(status) => ConfigResponse::new(id, status),
+                    Ok(ConfigRequest::GetInfo) => ConfigResponse::new(id, ConfigStatus::Ok).info(&KeyboardInfo {
+                        firmware_version: FIRMWARE_VERSION,
+                        n_layers: N_LAYERS as u8,
+                        matrix_rows: KALLISTO_LAYOUT.rows,
+                        matrix_cols: KALLISTO_LAYOUT.cols,
+                        n_keys: N_MATRIX_KEYS as u8,
+                        n_profiles: N_PROFILES,
+                        active_profile: profile,
+                        extra_buttons: KALLISTO_LAYOUT.extra_buttons,
+                        has_encoder: KALLISTO_LAYOUT.has_encoder,
+                    }),
+                    Ok(ConfigRequest::GetKeyMap { layer, key }) => match config_key(layer, key) {
+                        Some(id_key) => ConfigResponse::new(id, ConfigStatus::Ok)
+                            .key_map(layer, key, kallisto.key_map(layer as usize, id_key)),
+                        None => ConfigResponse::new(id, ConfigStatus::OutOfRange),
+                    },
+                    Ok(ConfigRequest::SetKeyMap { layer, key, key_map }) => match config_key(layer, key) {
+                        Some(id_key) => {
+                            kallisto.set_key_map(layer as usize, id_key, key_map);
+                            ConfigResponse::new(id, ConfigStatus::Ok).key_map(layer, key, key_map)
+                        }
+                        None => ConfigResponse::new(id, ConfigStatus::OutOfRange),
+                    },
+                    Ok(ConfigRequest::GetTiming) => ConfigResponse::new(id, ConfigStatus::Ok).timing(&matrix_timing),
+                    Ok(ConfigRequest::SetTiming(timing)) => {
+                        matrix_timing = timing;
+                        key_matrix.set_debounce_us(matrix_timing.debounce_us);
+                        scanner.set_settle_time_us(matrix_timing.settle_us);
+                        key_matrix.set_anti_ghosting(matrix_timing.anti_ghosting);
+                        commands.push(Command::SetMatrixTiming(matrix_timing));
+                        ConfigResponse::new(id, ConfigStatus::Ok).timing(&matrix_timing)
+                    }
+                    // Waits for the key-map to be written, rather than
+                    // writing it in the background, before answering.
+                    // A key-map that failed to be written is written
+                    // again by the next Commit
+                    Ok(ConfigRequest::Commit) => {
+                        if stats_saving {
+                            let _ = nb::block!(storage.poll(&stats_buf));
+                            stats_saving = false;
+                        }
+                        key_map_uncommitted |= kallisto.take_key_map_changed();
+                        if key_map_uncommitted {
+                            key_map_uncommitted = !(encode_key_map(kallisto.layers(), &mut key_map_buf).is_ok()
+                                && storage.start_write(key_map_address(profile), &key_map_buf).is_ok()
+                                && nb::block!(storage.poll(&key_map_buf)).is_ok());
+                        }
+                        let settings_saved = match settings.as_mut() {
+                            Some(store) => {
+                                store.set(&mut storage, &DEBOUNCE_US, matrix_timing.debounce_us).is_ok()
+                                    && store.set(&mut storage, &SETTLE_US, matrix_timing.settle_us).is_ok()
+                                    && store.set(&mut storage, &ANTI_GHOSTING, matrix_timing.anti_ghosting).is_ok()
+                            }
+                            None => false,
+                        };
+                        let status = if key_map_uncommitted {
+                            ConfigStatus::StorageFailed
+                        } else if !settings_saved {
+                            ConfigStatus::SettingsFailed
+                        } else {
+                            ConfigStatus::Ok
+                        };
+                        ConfigResponse::new(id, status)
+                    }
+                    Ok(ConfigRequest::GetKeyStats { key }) => {
+                        let stats = KeyId::new(key as usize)
+                            .and_then(|k| key_matrix.stats().and_then(|stats| stats.key(k)));
+                        match stats {
+                            Some(stats) => ConfigResponse::new(id, ConfigStatus::Ok).key_stats(key, stats),
+                            None => ConfigResponse::new(id, ConfigStatus::OutOfRange),
+                        }
+                    }
+                    Ok(ConfigRequest::EnterBootloader) => {
+                        enter_bootloader = true;
+                        ConfigResponse::new(id, ConfigStatus::Ok)
+                    }
+                };
+                config_response = Some(response);
+            }
+        }
+        if let Some(response) = config_response.as_ref() {
+            if config_hid.device().write_response(response.report()).is_ok() {
+                config_response = None;
+                // Gives the host time to collect the response
+                if enter_bootloader {
+                    delay.delay_ms(50);
+                    hal::rom_data::reset_to_usb_boot(0, 0);
+                }
+            }
+        }
+
+        // Changed switch statistics are written back a page at a
+        // time, so the write cycles don't stall the keyboard
+        let now = timer.get_counter_low();
+        if stats_saving {
+            if !matches!(storage.poll(&stats_buf), Err(nb::Error::WouldBlock)) {
+                stats_saving = false;
+            }
+        } else if now.wrapping_sub(stats_saved) >= STATS_SAVE_INTERVAL_US {
+            stats_saved = now;
+            if let Some(stats) = key_matrix.stats_mut() {
+                if stats.take_changed() {
+                    stats.to_bytes(&mut stats_buf);
+                    stats_saving = storage.start_write(STATS_ADDRESS, &stats_buf).is_ok();
+                }
+            }
+        }
+
+        // A profile change waits for the statistics to be written, as the
+        // key-map of the profile is read from the storage. Changes of the
+        // key-map that have not been committed are dropped with it
+        if !stats_saving {
+            if let Some(request) = kallisto.take_profile_request() {
+                let next = match request {
+                    ProfileRequest::Next => (profile + 1) % N_PROFILES,
+                    ProfileRequest::Set(p) => (p as u8).min(N_PROFILES - 1),
+                };
+                if next != profile {
+                    profile = next;
+                    kallisto.set_layers(profile_layers(&mut storage, profile));
+                    key_map_uncommitted = false;
+                    kallisto.set_layer(default_layer);
+                    profile_unsaved = true;
+                }
+            }
+        }
+
+        // The brightness and the active profile are stored once no
+        // background write is in progress. A setting is tried again
+        // while the storage is busy, other failures are reported
+        if !stats_saving && (brightness_unsaved || profile_unsaved) {
+            if let Some(store) = settings.as_mut() {
+                let mut failed = None;
+                if brightness_unsaved {
+                    match store.set(&mut storage, &BRIGHTNESS, lighting.brightness) {
+                        Err(SettingsError::Storage(e)) if e.is_busy() => {}
+                        result => {
+                            brightness_unsaved = false;
+                            failed = failed.or(result.err());
+                        }
+                    }
+                }
+                if profile_unsaved {
+                    match store.set(&mut storage, &ACTIVE_PROFILE, profile) {
+                        Err(SettingsError::Storage(e)) if e.is_busy() => {}
+                        result => {
+                            profile_unsaved = false;
+                            failed = failed.or(result.err());
+                        }
+                    }
+                }
+                if let Some(e) = failed {
+                    let mut line: String<128> = String::new();
+                    let _ = write!(line, "setting not saved: {:?}\r\n", e);
+                    let _ = serial.write(line.as_bytes());
+                }
+            }
+        }
+
+        // Statistics are sent one key per iteration so that
+        // the serial buffer never overflows
+        if let (Some(id), Some(stats)) = (stats_dump, key_matrix.stats()) {
+            let key = stats.key(id).unwrap();
+            let mut line: String<64> = String::new();
+            let _ = write!(line, "{} {} {} {}\r\n", id.index(), key.presses, key.chatter, key.min_press_us);
+            if serial.write(line.as_bytes()).is_ok() {
+                stats_dump = matrix_keys(half).find(|k| *k > id);
+            }
+        }
+
+        if let Some(relay) = update.as_mut() {
+            if let Some(len) = relay.response(&mut update_buf) {
+                let _ = serial.write(&update_buf[..len]);
+            }
+            if relay.is_finished(timer.get_counter_low()) {
+                update = None;
+            }
+        }
+
+        if link_dump {
+            let stats = link.stats();
+            let mut line: String<96> = String::new();
+            let _ = write!(
+                line,
+                "link {:?} errors {} corrupt {} stale {} disconnects {} connects {}\r\n",
+                link.status(),
+                stats.errors,
+                stats.corrupt_frames,
+                stats.stale_frames,
+                stats.disconnects,
+                stats.connects,
+            );
+            if serial.write(line.as_bytes()).is_ok() {
+                link_dump = false;
+            }
+        }
+
+        if info_dump {
+            let mut line: String<96> = String::new();
+            let _ = match (incompatible, remote_info) {
+                (Some(version), _) => write!(
+                    line,
+                    "slave incompatible, protocol {} expected {}\r\n",
+                    version, local_info.protocol_version,
+                ),
+                (None, Some(info)) => write!(
+                    line,
+                    "slave {:?} firmware {:06x} protocol {} caps {:02x} leds {} matrix {}x{}\r\n",
+                    local_info.compatibility(&info),
+                    info.firmware_version,
+                    info.protocol_version,
+                    info.capabilities,
+                    info.led_count,
+                    info.matrix_rows,
+                    info.matrix_cols,
+                ),
+                (None, None) => write!(line, "slave unknown\r\n"),
+            };
+            if serial.write(line.as_bytes()).is_ok() {
+                info_dump = false;
+            }
+        }
+
+        if led_timer.wait().is_ok() {
+            // Rainbow while the link to the slave is up, amber while it
+            // is unreliable, pulsing red while it is down and pulsing
+            // magenta if the slave is incompatible
+            lighting.link = link.status();
+            lighting.incompatible = incompatible.is_some();
+            lighting.render(half, &mut leds);
+            ws.write(brightness(leds.iter().copied(), lighting.brightness))
+                .unwrap();
+
+            lighting.tick(17.0 / 1000.0);
+        }
+    }
+}
+
+// Returns the key-map of a profile, or the compiled-in
+// one if none is stored or it is corrupt
+fn profile_layers(storage: &mut ConfigStorage, profile: u8) -> Layers<N_MATRIX_KEYS> {
+    let mut layers = DEFAULT_LAYERS;
+    let _ = load_key_map(storage, key_map_address(profile), &mut layers);
+    layers
+}
+
+// The key addressed by a request of the configuration interface,
+// None if the layer or key does not exist
+fn config_key(layer: u8, key: u8) -> Option<KeyId> {
+    if (layer as usize) < N_LAYERS && (key as usize) < N_MATRIX_KEYS {
+        Some(KeyId::from_index(key as usize))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,31 @@
+/*
+* The settings kept in the settings store of the EEPROM, and where the
+* store is. Keys must never be reused for a setting of another type
+*/
+use kallisto_components::keyboard::key_matrix::MatrixTiming;
+use kallisto_components::lighting::DEFAULT_BRIGHTNESS;
+use kallisto_components::settings::Setting;
+
+use crate::storage::ConfigStorage;
+
+// Two banks below the sector holding the handedness
+pub const SETTINGS_ADDRESS: u32 = 0x5000;
+
+// A bank is a flash sector, or 8 pages on the EEPROM. The EEPROM erases a
+// bank by writing each page, about 5 ms apiece, so a sector sized bank
+// would stall the keyboard for a third of a second on each compaction
+pub fn settings_bank_size(storage: &ConfigStorage) -> u32 {
+    match storage {
+        ConfigStorage::Eeprom(_) => 8 * storage.erase_size(),
+        ConfigStorage::Flash(_) => storage.erase_size(),
+    }
+}
+
+pub const BRIGHTNESS: Setting<u8> = Setting::new(0x01, DEFAULT_BRIGHTNESS);
+// Layer the keyboard starts in
+pub const DEFAULT_LAYER: Setting<u8> = Setting::new(0x02, 0);
+pub const DEBOUNCE_US: Setting<u32> = Setting::new(0x03, MatrixTiming::new().debounce_us);
+pub const SETTLE_US: Setting<u32> = Setting::new(0x04, MatrixTiming::new().settle_us);
+// Key-map profile in use
+pub const ACTIVE_PROFILE: Setting<u8> = Setting::new(0x05, 0);
+pub const ANTI_GHOSTING: Setting<bool> = Setting::new(0x06, MatrixTiming::new().anti_ghosting);
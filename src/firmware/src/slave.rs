@@ -0,0 +1,182 @@
+/*
+* The slave role, run by the half not connected to the host. It scans its
+* matrix, reports key events to the master when polled and applies the
+* commands sent by the master
+*/
+use kallisto_components::keyboard::key_matrix::{KeyMatrix, MatrixScanner, GHOST_FLAG};
+use kallisto_components::keyboard::key_matrix::N_KEYS;
+use kallisto_components::keyboard::layout::Half;
+use kallisto_components::protocol::{
+    encode_key_events, encode_link_info, Command, CommandReceiver, FrameEncoder, KeyChange,
+    KEY_EVENTS_FRAME_LEN, MAX_EVENTS_PER_FRAME,
+};
+use kallisto_components::lighting::Lighting;
+use kallisto_components::flash::Rp2040Flash;
+use kallisto_components::update::{UpdateReceiver, UpdateStatus, UPDATE_RESET_DELAY_US};
+use kallisto_components::transport::{SlaveEvent, SlaveTransport};
+use crate::board::{link_info, LinkPins};
+use crate::{Board, FIRMWARE_VERSION};
+
+use embedded_hal::timer::CountDown;
+use heapless::{Vec, spsc::Queue};
+use fugit::ExtU32;
+
+use smart_leds::{brightness, SmartLedsWrite, RGB8};
+
+const STRIP_LEN: usize = 21;
+
+pub fn run<S, W, L>(
+    half: Half,
+    scanner: &mut S,
+    mut ws: W,
+    mut transport: L::Slave,
+    board: Board,
+) -> !
+where
+    S: MatrixScanner,
+    W: SmartLedsWrite<Color = RGB8>,
+    W::Error: core::fmt::Debug,
+    L: LinkPins,
+{
+    let timer = board.timer;
+    let mut key_matrix = KeyMatrix::new(timer);
+
+    let mut leds: [RGB8; STRIP_LEN] = [(0, 0, 0).into(); STRIP_LEN];
+    // Lighting state, kept in sync with the master by its commands
+    let mut lighting = Lighting::new();
+
+
+    let mut keyboard_timer = timer.count_down();
+    let mut led_timer = timer.count_down();
+
+    led_timer.start(17.millis());
+    keyboard_timer.start(1.millis());
+
+    let mut key_states: u32 = 0;
+    let mut last_key_states: u32 = 0;
+    // Key states as they will be seen by the master
+    // once it has received all events sent so far
+    let mut reported_states: u32 = 0;
+    // Key presses and releases waiting to be sent to the master
+    let mut key_events: Queue<KeyChange, 32> = Queue::new();
+    let mut frame_encoder = FrameEncoder::new();
+    let mut frame_buf: [u8; KEY_EVENTS_FRAME_LEN] = [0; KEY_EVENTS_FRAME_LEN];
+    // Acknowledges the commands of the master, applying each once
+    let mut commands = CommandReceiver::new();
+    // Firmware update sent through the master, and the
+    // time to reset at once a new image has been staged
+    let mut update = UpdateReceiver::new(Rp2040Flash::new(), FIRMWARE_VERSION);
+    let mut reset_at: Option<u32> = None;
+    // Set when the link info is to be sent in answer to the next poll,
+    // at start-up and when the master says hello
+    let mut send_info = true;
+
+    loop {
+
+        // Erases the staging area of an update that has begun
+        update.poll();
+
+        if let Some(time) = reset_at {
+            if timer.get_counter_low().wrapping_sub(time) < u32::MAX / 2 {
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+        }
+
+        if keyboard_timer.wait().is_ok() {
+            key_states = key_matrix.poll(scanner);
+            let now = timer.get_counter_low();
+            let changed = key_states ^ last_key_states;
+            for key in (0..N_KEYS).into_iter().filter(|k| (changed >> k) & 0x1 == 1) {
+                // If the queue is full the event is dropped, the master
+                // still picks up the change from the key states
+                let _ = key_events.enqueue(KeyChange {
+                    key: key as u8,
+                    pressed: (key_states >> key) & 0x1 == 1,
+                    time: now,
+                });
+            }
+            last_key_states = key_states;
+        }
+
+        if led_timer.wait().is_ok() {
+            lighting.render(half, &mut leds);
+            ws.write(brightness(leds.iter().copied(), lighting.brightness))
+                .unwrap();
+
+            lighting.tick(16.0 / 1000.0);
+        }
+
+        match transport.poll() {
+            // The frame is built when it is asked for, so that the
+            // ages of the events are relative to the time it is sent
+            Some(SlaveEvent::FrameRequested) if send_info => {
+                let _ = encode_link_info(&mut frame_encoder, &link_info(half), &mut frame_buf);
+                transport.respond(&frame_buf);
+                send_info = false;
+            }
+            Some(SlaveEvent::FrameRequested) => {
+                let mut events: Vec<KeyChange, MAX_EVENTS_PER_FRAME> = Vec::new();
+                while !events.is_full() {
+                    match key_events.dequeue() {
+                        Some(e) => {
+                            reported_states = (reported_states & !(1 << e.key)) | ((e.pressed as u32) << e.key);
+                            let _ = events.push(e);
+                        }
+                        None => break,
+                    }
+                }
+                // Once all queued events have been sent the reported states are
+                // in sync with the matrix, this also recovers from dropped events
+                if key_events.is_empty() {
+                    reported_states = last_key_states;
+                }
+                let mut states = reported_states;
+                if key_matrix.is_ghosting() {
+                    states |= GHOST_FLAG;
+                }
+                let now = timer.get_counter_low();
+                let _ = encode_key_events(
+                    &mut frame_encoder,
+                    states,
+                    commands.ack_seq(),
+                    update.status() as u8,
+                    &events,
+                    now,
+                    &mut frame_buf,
+                );
+                transport.respond(&frame_buf);
+            }
+            Some(SlaveEvent::CommandReceived) => {
+                if let Some(command) = commands.receive(transport.command()) {
+                    match command {
+                        Command::SetLayer(layer) => lighting.layer = layer,
+                        Command::SetHostLeds(leds) => lighting.host_leds = leds,
+                        Command::SetLighting { mode, brightness } => {
+                            lighting.mode = mode;
+                            lighting.brightness = brightness;
+                        }
+                        Command::SetMatrixTiming(timing) => {
+                            key_matrix.set_debounce_us(timing.debounce_us);
+                            scanner.set_settle_time_us(timing.settle_us);
+                            key_matrix.set_anti_ghosting(timing.anti_ghosting);
+                        }
+                        // The slave has no use for the link info of the master yet
+                        Command::Hello(_) => send_info = true,
+                        Command::UpdateBegin(header) => {
+                            update.begin(header);
+                        }
+                        Command::UpdateData { offset, len, data } => {
+                            update.data(offset, &data[..len as usize]);
+                        }
+                        Command::UpdateEnd => {
+                            if update.end() == UpdateStatus::Ready {
+                                reset_at = Some(timer.get_counter_low().wrapping_add(UPDATE_RESET_DELAY_US));
+                            }
+                        }
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,147 @@
+/*
+* Where the key-map, settings and handedness are kept: the EEPROM when
+* it is fitted, otherwise the storage region at the end of flash. Both
+* are laid out the same, see board.rs and settings.rs for the addresses
+*/
+use rp_pico::hal as hal;
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+use embedded_storage::{ReadStorage, Storage};
+
+use kallisto_components::at24c::{self, At24cError};
+use kallisto_components::flash::{FlashStorage, SECTOR_SIZE};
+
+use crate::Eeprom;
+
+pub enum ConfigStorage<'a> {
+    Eeprom(Eeprom<'a>),
+    Flash(FlashStorage),
+}
+
+#[derive(Debug)]
+pub enum ConfigStorageError {
+    Eeprom(At24cError<hal::i2c::Error>),
+    Flash(NorFlashErrorKind),
+}
+
+impl core::fmt::Display for ConfigStorageError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConfigStorageError::Eeprom(e) => write!(f, "{}", e),
+            ConfigStorageError::Flash(e) => write!(f, "flash storage failed: {:?}", e),
+        }
+    }
+}
+
+impl ConfigStorageError {
+    // The EEPROM is still busy with a write started
+    // earlier, the write can be tried again once it is done
+    pub fn is_busy(&self) -> bool {
+        matches!(self, ConfigStorageError::Eeprom(At24cError::Busy))
+    }
+}
+
+impl<'a> ConfigStorage<'a> {
+    // Starts writing `buf` at `offset`, which is completed by calling `poll`
+    // with the same buffer. The EEPROM is written a page per write cycle
+    // in the background, flash is written at once since it can't be read
+    // while it is being written
+    pub fn start_write(&mut self, offset: u32, buf: &[u8]) -> Result<(), ConfigStorageError> {
+        match self {
+            ConfigStorage::Eeprom(eeprom) => eeprom.start_write(offset, buf).map_err(ConfigStorageError::Eeprom),
+            ConfigStorage::Flash(flash) => Storage::write(flash, offset, buf).map_err(ConfigStorageError::Flash),
+        }
+    }
+
+    pub fn poll(&mut self, buf: &[u8]) -> nb::Result<(), ConfigStorageError> {
+        match self {
+            ConfigStorage::Eeprom(eeprom) => eeprom.poll(buf).map_err(|e| e.map(ConfigStorageError::Eeprom)),
+            ConfigStorage::Flash(_) => Ok(()),
+        }
+    }
+
+    // Smallest area that can be erased: a page of the EEPROM, a sector of flash
+    pub fn erase_size(&self) -> u32 {
+        match self {
+            ConfigStorage::Eeprom(_) => at24c::ERASE_SIZE as u32,
+            ConfigStorage::Flash(_) => SECTOR_SIZE,
+        }
+    }
+}
+
+impl<'a> ReadStorage for ConfigStorage<'a> {
+    type Error = ConfigStorageError;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        match self {
+            ConfigStorage::Eeprom(eeprom) => ReadStorage::read(eeprom, offset, bytes).map_err(ConfigStorageError::Eeprom),
+            ConfigStorage::Flash(flash) => ReadStorage::read(flash, offset, bytes).map_err(ConfigStorageError::Flash),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            ConfigStorage::Eeprom(eeprom) => ReadStorage::capacity(eeprom),
+            ConfigStorage::Flash(flash) => ReadStorage::capacity(flash),
+        }
+    }
+}
+
+impl<'a> Storage for ConfigStorage<'a> {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        match self {
+            ConfigStorage::Eeprom(eeprom) => Storage::write(eeprom, offset, bytes).map_err(ConfigStorageError::Eeprom),
+            ConfigStorage::Flash(flash) => Storage::write(flash, offset, bytes).map_err(ConfigStorageError::Flash),
+        }
+    }
+}
+
+impl NorFlashError for ConfigStorageError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            ConfigStorageError::Eeprom(e) => e.kind(),
+            ConfigStorageError::Flash(e) => *e,
+        }
+    }
+}
+
+// For the settings store. Erases are done a flash sector at a time, on the
+// EEPROM, which has no erase, by filling each page with 0xFF. ERASE_SIZE is
+// that of the EEPROM, flash erases not aligned to a sector are refused
+impl<'a> ErrorType for ConfigStorage<'a> {
+    type Error = ConfigStorageError;
+}
+
+impl<'a> ReadNorFlash for ConfigStorage<'a> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), ConfigStorageError> {
+        ReadStorage::read(self, offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        ReadStorage::capacity(self)
+    }
+}
+
+impl<'a> NorFlash for ConfigStorage<'a> {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = at24c::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), ConfigStorageError> {
+        if from % self.erase_size() != 0 || to % self.erase_size() != 0 {
+            return Err(ConfigStorageError::Flash(NorFlashErrorKind::NotAligned));
+        }
+        match self {
+            ConfigStorage::Eeprom(eeprom) => NorFlash::erase(eeprom, from, to).map_err(ConfigStorageError::Eeprom),
+            ConfigStorage::Flash(flash) => NorFlash::erase(flash, from, to).map_err(ConfigStorageError::Flash),
+        }
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), ConfigStorageError> {
+        match self {
+            ConfigStorage::Eeprom(eeprom) => NorFlash::write(eeprom, offset, bytes).map_err(ConfigStorageError::Eeprom),
+            ConfigStorage::Flash(flash) => NorFlash::write(flash, offset, bytes).map_err(ConfigStorageError::Flash),
+        }
+    }
+}
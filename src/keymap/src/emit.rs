@@ -0,0 +1,204 @@
+/*
+* Output of a key-map as Rust source for the firmware, or as text in the
+* key-map format
+*/
+use std::fmt::Write;
+
+use crate::key_map::{Entry, KeyMap, Mapping, N_LAYERS};
+use crate::keys::key_name;
+use crate::parse::CompiledKeyMap;
+
+impl CompiledKeyMap {
+    // Source of a `DEFAULT_LAYERS: Layers<N_KEYS>` constant, expecting
+    // `Layers`, `N_KEYS` and the types of keyboard::types in scope
+    pub fn to_rust(&self) -> String {
+        let mut out = String::new();
+        out.push_str("pub const DEFAULT_LAYERS: Layers<N_KEYS> = [\n");
+        for (i, entries) in self.key_map.layers.iter().enumerate() {
+            match self.layer_names.get(i) {
+                Some(name) => writeln!(out, "    // Layer {}, {}", i, name).unwrap(),
+                None => writeln!(out, "    // Layer {}, unused", i).unwrap(),
+            }
+            out.push_str("    [\n");
+            for (key, entry) in entries.iter().enumerate() {
+                let (half, index) = if key < self.rows * self.columns {
+                    ("Left", key)
+                } else {
+                    ("Right", key - self.rows * self.columns)
+                };
+                if index % self.columns == 0 {
+                    writeln!(out, "        // {} half, Row {}", half, index / self.columns + 1).unwrap();
+                }
+                writeln!(out, "        {},", rust_entry(entry)).unwrap();
+            }
+            out.push_str("    ],\n");
+        }
+        out.push_str("];\n");
+        out
+    }
+}
+
+fn rust_entry(entry: &Option<Entry>) -> String {
+    match entry {
+        None => "None".to_string(),
+        Some(entry) => format!(
+            "Some(LayerKeyMap {{ pressed: {}, held_press: {} }})",
+            rust_mapping(entry.pressed),
+            rust_mapping(entry.held_press)
+        ),
+    }
+}
+
+fn rust_mapping(mapping: Option<Mapping>) -> String {
+    let Some(mapping) = mapping else {
+        return "None".to_string();
+    };
+    let modifier = match mapping.modifier {
+        0 => "None".to_string(),
+        m => format!("Some(ModifierKey::{})", name(m as u16)),
+    };
+    format!("Some(KeyMapping {{ key: KeyPress::{}, modifier: {} }})", name(mapping.key), modifier)
+}
+
+// Only valid codes make it into a `KeyMap`
+fn name(code: u16) -> &'static str {
+    key_name(code).unwrap_or_else(|| panic!("unknown key code {:#06X}", code))
+}
+
+fn text_mapping(mapping: Option<Mapping>) -> String {
+    match mapping {
+        None => "_".to_string(),
+        Some(Mapping { key, modifier: 0 }) => name(key).to_string(),
+        Some(Mapping { key, modifier }) => format!("{}+{}", name(modifier as u16), name(key)),
+    }
+}
+
+pub fn text_entry(entry: &Option<Entry>) -> String {
+    match entry {
+        None => "_".to_string(),
+        Some(Entry { pressed, held_press: None }) => text_mapping(*pressed),
+        Some(Entry { pressed, held_press }) => format!("{}/{}", text_mapping(*pressed), text_mapping(*held_press)),
+    }
+}
+
+// Writes a key-map in the text format, for a keyboard with `rows` by
+// `columns` keys per half. Trailing unmapped layers are left out
+pub fn to_text(key_map: &KeyMap, rows: usize, columns: usize) -> String {
+    let mut out = String::new();
+    writeln!(out, "rows = {}\ncolumns = {}", rows, columns).unwrap();
+    let n_layers = key_map
+        .layers
+        .iter()
+        .rposition(|entries| entries.iter().any(Option::is_some))
+        .map_or(1, |last| last + 1)
+        .min(N_LAYERS);
+    for (i, entries) in key_map.layers.iter().take(n_layers).enumerate() {
+        // The keys of a row, left half first, as in the file
+        let grid: Vec<Vec<String>> = (0..rows)
+            .map(|row| {
+                let left = (0..columns).map(|col| row * columns + col);
+                let right = (0..columns).map(|col| (rows + row) * columns + col);
+                left.chain(right).map(|key| text_entry(&entries[key])).collect()
+            })
+            .collect();
+        let widths: Vec<usize> = (0..2 * columns)
+            .map(|col| grid.iter().map(|row| row[col].len()).max().unwrap_or(0))
+            .collect();
+        writeln!(out, "\n[[layers]]\nname = \"layer{}\"\nkeys = [", i).unwrap();
+        for row in &grid {
+            let cells: Vec<String> = row.iter().zip(&widths).map(|(key, width)| format!("{:width$}", key, width = width)).collect();
+            let line = format!("{} | {}", cells[..columns].join(" "), cells[columns..].join(" "));
+            writeln!(out, "    \"{}\",", line.trim_end()).unwrap();
+        }
+        out.push_str("]\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KEYS;
+    use crate::parse::compile;
+
+    const SMALL: &str = "rows = 1\ncolumns = 2\n\n[[layers]]\nname = \"base\"\nkeys = [\"Q/LeftShift LeftControl+C | hold:1 _\"]\n\
+                         \n[[layers]]\nname = \"nav\"\nkeys = [\"_/set:0 _ | _ _\"]\n";
+
+    #[test]
+    fn text_is_compiled_back_to_the_same_key_map() {
+        for source in [SMALL, include_str!("../../firmware/key_map.toml")] {
+            let compiled = compile(source).unwrap();
+            let text = to_text(&compiled.key_map, compiled.rows, compiled.columns);
+            let again = compile(&text).unwrap_or_else(|e| panic!("{}\n{}", e, text));
+            assert_eq!((again.rows, again.columns), (compiled.rows, compiled.columns));
+            assert_eq!(again.key_map, compiled.key_map);
+            assert_eq!(again.layer_names.len(), compiled.layer_names.len());
+        }
+    }
+
+    #[test]
+    fn text_is_a_grid_per_layer() {
+        let compiled = compile(SMALL).unwrap();
+        let expected = "rows = 1\ncolumns = 2\n\
+                        \n[[layers]]\nname = \"layer0\"\nkeys = [\n    \"Q/LeftShift LeftControl+C | LayerHold1 _\",\n]\n\
+                        \n[[layers]]\nname = \"layer1\"\nkeys = [\n    \"_/LayerSet0 _ | _ _\",\n]\n";
+        assert_eq!(to_text(&compiled.key_map, 1, 2), expected);
+
+        // An empty key-map keeps its first layer
+        let expected = "rows = 1\ncolumns = 1\n\n[[layers]]\nname = \"layer0\"\nkeys = [\n    \"_ | _\",\n]\n";
+        assert_eq!(to_text(&KeyMap::new(2), 1, 1), expected);
+    }
+
+    #[test]
+    fn rust_source_lists_every_layer_and_key() {
+        let rust = compile(SMALL).unwrap().to_rust();
+        let lines: Vec<&str> = rust.lines().collect();
+        // A comment, the brackets, a comment per row of a half and the 4 keys
+        assert_eq!(lines.len(), 2 + N_LAYERS * (3 + 2 + 4));
+        assert_eq!(
+            lines[..11],
+            [
+                "pub const DEFAULT_LAYERS: Layers<N_KEYS> = [",
+                "    // Layer 0, base",
+                "    [",
+                "        // Left half, Row 1",
+                "        Some(LayerKeyMap { pressed: Some(KeyMapping { key: KeyPress::Q, modifier: None }), \
+                 held_press: Some(KeyMapping { key: KeyPress::LeftShift, modifier: None }) }),",
+                "        Some(LayerKeyMap { pressed: Some(KeyMapping { key: KeyPress::C, \
+                 modifier: Some(ModifierKey::LeftControl) }), held_press: None }),",
+                "        // Right half, Row 1",
+                "        Some(LayerKeyMap { pressed: Some(KeyMapping { key: KeyPress::LayerHold1, modifier: None }), \
+                 held_press: None }),",
+                "        None,",
+                "    ],",
+                "    // Layer 1, nav",
+            ]
+        );
+        assert_eq!(lines[19..22], ["    // Layer 2, unused", "    [", "        // Left half, Row 1"]);
+        assert_eq!(lines[lines.len() - 2..], ["    ],", "];"]);
+    }
+
+    // The names and codes are those of the firmware's `KeyPress` and
+    // `ModifierKey`, which the emitted Rust refers to
+    #[test]
+    fn keys_are_those_of_the_firmware() {
+        let types = include_str!("../../components/src/keyboard/types.rs");
+        let mut variants = Vec::new();
+        for name in ["pub enum KeyPress {", "pub enum ModifierKey {"] {
+            let start = types.find(name).unwrap() + name.len();
+            let body = &types[start..start + types[start..].find('}').unwrap()];
+            for line in body.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with("//")) {
+                let (name, code) = line.trim_end_matches(',').split_once(" = ").unwrap();
+                let code = u16::from_str_radix(code.trim_start_matches("0x"), 16).unwrap();
+                variants.push((name.to_string(), code));
+            }
+        }
+        let keys: Vec<(String, u16)> = KEYS.iter().map(|(name, code)| (name.to_string(), *code)).collect();
+        for key in &keys {
+            assert!(variants.contains(key), "{:?} is not a firmware key", key);
+        }
+        for variant in &variants {
+            assert!(keys.contains(variant), "{:?} is missing from the key names", variant);
+        }
+    }
+}
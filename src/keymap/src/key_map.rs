@@ -0,0 +1,244 @@
+/*
+* The key-map as stored by the firmware, see
+* components/src/keyboard/key_map_store.rs for the format
+*/
+use crate::keys::{is_modifier, key_name};
+
+pub const N_LAYERS: usize = 5;
+pub const ENTRY_LEN: usize = 7;
+const KEY_MAP_MAGIC: u16 = 0x4B4D;
+const KEY_MAP_FORMAT_VERSION: u8 = 1;
+const KEY_MAP_HEADER_LEN: usize = 10;
+
+const FLAG_MAPPED: u8 = 0x01;
+const FLAG_PRESSED: u8 = 0x02;
+const FLAG_HELD_PRESS: u8 = 0x04;
+
+// A key code, a `KeyPress` of the firmware, with an optional modifier, 0 for none
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    pub key: u16,
+    pub modifier: u8,
+}
+
+impl Mapping {
+    // Whether the firmware knows the key code and modifier
+    pub fn is_valid(&self) -> bool {
+        key_name(self.key).is_some() && (self.modifier == 0 || is_modifier(self.modifier))
+    }
+}
+
+// The mappings of a key on one layer, None for an unmapped key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Entry {
+    pub pressed: Option<Mapping>,
+    pub held_press: Option<Mapping>,
+}
+
+pub fn encode_entry(entry: Option<Entry>) -> [u8; ENTRY_LEN] {
+    let mut buf = [0; ENTRY_LEN];
+    if let Some(entry) = entry {
+        buf[0] = FLAG_MAPPED;
+        for (flag, mapping, start) in [(FLAG_PRESSED, entry.pressed, 1), (FLAG_HELD_PRESS, entry.held_press, 4)] {
+            if let Some(mapping) = mapping {
+                buf[0] |= flag;
+                buf[start..start + 2].copy_from_slice(&mapping.key.to_be_bytes());
+                buf[start + 2] = mapping.modifier;
+            }
+        }
+    }
+    buf
+}
+
+// None if an entry holds an unknown key code or modifier
+pub fn decode_entry(buf: &[u8]) -> Option<Option<Entry>> {
+    if buf[0] & FLAG_MAPPED == 0 {
+        return Some(None);
+    }
+    let mapping = |flag: u8, start: usize| -> Option<Option<Mapping>> {
+        if buf[0] & flag == 0 {
+            return Some(None);
+        }
+        let mapping = Mapping {
+            key: u16::from_be_bytes([buf[start], buf[start + 1]]),
+            modifier: buf[start + 2],
+        };
+        mapping.is_valid().then_some(Some(mapping))
+    };
+    Some(Some(Entry {
+        pressed: mapping(FLAG_PRESSED, 1)?,
+        held_press: mapping(FLAG_HELD_PRESS, 4)?,
+    }))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlobError {
+    NotAKeyMap,
+    UnsupportedVersion(u8),
+    WrongShape,
+    BadCrc,
+    // Layer and key of an entry with an unknown key code
+    BadEntry(usize, usize),
+}
+
+impl core::fmt::Display for BlobError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BlobError::NotAKeyMap => write!(f, "not a key-map"),
+            BlobError::UnsupportedVersion(version) => write!(f, "unsupported format version {}", version),
+            BlobError::WrongShape => write!(f, "wrong number of layers or keys"),
+            BlobError::BadCrc => write!(f, "CRC mismatch"),
+            BlobError::BadEntry(layer, key) => write!(f, "layer {} key {}: unknown key code", layer, key),
+        }
+    }
+}
+
+// A complete key-map, `N_LAYERS` layers of the same number of keys,
+// indexed by `KeyId`
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMap {
+    pub layers: Vec<Vec<Option<Entry>>>,
+}
+
+impl KeyMap {
+    pub fn new(n_keys: usize) -> Self {
+        KeyMap {
+            layers: vec![vec![None; n_keys]; N_LAYERS],
+        }
+    }
+
+    pub fn n_keys(&self) -> usize {
+        self.layers[0].len()
+    }
+
+    // Serializes the key-map in the format stored by the firmware
+    pub fn to_blob(&self) -> Vec<u8> {
+        let mut entries = Vec::new();
+        for entry in self.layers.iter().flatten() {
+            entries.extend_from_slice(&encode_entry(*entry));
+        }
+        let mut blob = Vec::with_capacity(KEY_MAP_HEADER_LEN + entries.len());
+        blob.extend_from_slice(&KEY_MAP_MAGIC.to_be_bytes());
+        blob.push(KEY_MAP_FORMAT_VERSION);
+        blob.push(N_LAYERS as u8);
+        blob.extend_from_slice(&(self.n_keys() as u16).to_be_bytes());
+        blob.extend_from_slice(&crc32(&entries).to_be_bytes());
+        blob.extend_from_slice(&entries);
+        blob
+    }
+
+    pub fn from_blob(blob: &[u8]) -> Result<Self, BlobError> {
+        if blob.len() < KEY_MAP_HEADER_LEN || u16::from_be_bytes([blob[0], blob[1]]) != KEY_MAP_MAGIC {
+            return Err(BlobError::NotAKeyMap);
+        }
+        if blob[2] != KEY_MAP_FORMAT_VERSION {
+            return Err(BlobError::UnsupportedVersion(blob[2]));
+        }
+        let n_keys = u16::from_be_bytes([blob[4], blob[5]]) as usize;
+        let entries = &blob[KEY_MAP_HEADER_LEN..];
+        if blob[3] as usize != N_LAYERS || entries.len() != N_LAYERS * n_keys * ENTRY_LEN {
+            return Err(BlobError::WrongShape);
+        }
+        let crc = u32::from_be_bytes([blob[6], blob[7], blob[8], blob[9]]);
+        if crc32(entries) != crc {
+            return Err(BlobError::BadCrc);
+        }
+        let mut key_map = KeyMap::new(n_keys);
+        for (i, entry) in entries.chunks(ENTRY_LEN).enumerate() {
+            let (layer, key) = (i / n_keys, i % n_keys);
+            key_map.layers[layer][key] = decode_entry(entry).ok_or(BlobError::BadEntry(layer, key))?;
+        }
+        Ok(key_map)
+    }
+}
+
+// CRC-32 as used by zlib, the one of the firmware update module
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xEDB8_8320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two keys, Q held as LeftShift on layer 0 and LeftControl+C held on layer 4
+    fn key_map() -> KeyMap {
+        let mut key_map = KeyMap::new(2);
+        key_map.layers[0][0] = Some(Entry {
+            pressed: Some(Mapping { key: 0x14, modifier: 0 }),
+            held_press: Some(Mapping { key: 0xE1, modifier: 0 }),
+        });
+        key_map.layers[4][1] = Some(Entry {
+            pressed: None,
+            held_press: Some(Mapping { key: 0x06, modifier: 0xE0 }),
+        });
+        key_map
+    }
+
+    #[test]
+    fn crc_is_the_zlib_one() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    // The layout of components/src/keyboard/key_map_store.rs
+    #[test]
+    fn blob_is_in_the_stored_format() {
+        let blob = key_map().to_blob();
+        assert_eq!(blob.len(), KEY_MAP_HEADER_LEN + N_LAYERS * 2 * ENTRY_LEN);
+        assert_eq!(blob[..6], [b'K', b'M', 1, 5, 0, 2]);
+        let entries = &blob[KEY_MAP_HEADER_LEN..];
+        assert_eq!(blob[6..10], crc32(entries).to_be_bytes());
+        assert_eq!(entries[..ENTRY_LEN], [0x07, 0x00, 0x14, 0x00, 0x00, 0xE1, 0x00]);
+        assert!(entries[ENTRY_LEN..9 * ENTRY_LEN].iter().all(|b| *b == 0));
+        assert_eq!(entries[9 * ENTRY_LEN..], [0x05, 0x00, 0x00, 0x00, 0x00, 0x06, 0xE0]);
+        assert_eq!(KeyMap::from_blob(&blob), Ok(key_map()));
+    }
+
+    #[test]
+    fn bad_blobs_are_rejected() {
+        let blob = key_map().to_blob();
+        assert_eq!(KeyMap::from_blob(&blob[..9]), Err(BlobError::NotAKeyMap));
+        let mut bad = blob.clone();
+        bad[0] = b'X';
+        assert_eq!(KeyMap::from_blob(&bad), Err(BlobError::NotAKeyMap));
+        let mut bad = blob.clone();
+        bad[2] = 2;
+        assert_eq!(KeyMap::from_blob(&bad), Err(BlobError::UnsupportedVersion(2)));
+        let mut bad = blob.clone();
+        bad[3] = 4;
+        assert_eq!(KeyMap::from_blob(&bad), Err(BlobError::WrongShape));
+        assert_eq!(KeyMap::from_blob(&blob[..blob.len() - 1]), Err(BlobError::WrongShape));
+        let mut bad = blob.clone();
+        bad[KEY_MAP_HEADER_LEN + 2] ^= 1;
+        assert_eq!(KeyMap::from_blob(&bad), Err(BlobError::BadCrc));
+
+        // An unknown modifier of the second key of layer 4, with a good CRC
+        let mut bad = blob.clone();
+        bad[KEY_MAP_HEADER_LEN + 9 * ENTRY_LEN + 6] = 0x14;
+        let crc = crc32(&bad[KEY_MAP_HEADER_LEN..]);
+        bad[6..10].copy_from_slice(&crc.to_be_bytes());
+        assert_eq!(KeyMap::from_blob(&bad), Err(BlobError::BadEntry(4, 1)));
+    }
+
+    #[test]
+    fn absent_mappings_are_not_decoded() {
+        // Bytes of a mapping without its flag are ignored
+        let entry = decode_entry(&[0x03, 0x00, 0x04, 0x00, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(entry, Some(Some(Entry { pressed: Some(Mapping { key: 0x04, modifier: 0 }), held_press: None })));
+        assert_eq!(decode_entry(&[0x06, 0x00, 0x04, 0x00, 0x00, 0x04, 0x00]), Some(None));
+        assert_eq!(decode_entry(&[0x03, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00]), None);
+        assert_eq!(encode_entry(Some(Entry { pressed: None, held_press: None })), [0x01, 0, 0, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,43 @@
+/*
+* Text key-map format of Kallisto, compiled into the firmware by its build
+* script, or into the stored key-map by kallisto-cli.
+*
+* A key-map is a TOML file giving the matrix size of each half, followed
+* by up to five layers. The keys of a layer are given as a grid, one
+* string per row, listing the keys of the left half and then those of the
+* right half, optionally separated by `|`:
+*
+*   rows = 3
+*   columns = 7
+*
+*   [macros]
+*   exclaim = "RightShift+Keyboard1"
+*
+*   [[layers]]
+*   name = "base"
+*   keys = [
+*       "Tab Q W E R T Space | Y U I O P LeftBrace DeleteBackspace",
+*       ...
+*   ]
+*
+* Each key is one of
+*
+*   _                 unmapped
+*   ACTION            the name of a `KeyPress`, e.g. `Q`, `F1` or `LayerHold1`
+*   MODIFIER+ACTION   the action sent along with a modifier, e.g. `RightShift+Keyboard1`
+*   hold:LAYER        the layer, by name or number, is active while the key is held
+*   set:LAYER         the layer is made the active one
+*   MACRO             a name from the `[macros]` table
+*   PRESSED/HELD      a hold-tap key, sending HELD when held and PRESSED
+*                     otherwise, either may be `_`
+*
+* Errors are reported with the line and column they were found at
+*/
+mod emit;
+mod key_map;
+pub mod keys;
+mod parse;
+
+pub use emit::{text_entry, to_text};
+pub use key_map::{crc32, decode_entry, encode_entry, BlobError, Entry, KeyMap, Mapping, ENTRY_LEN, N_LAYERS};
+pub use parse::{compile, CompiledKeyMap, Error};
@@ -0,0 +1,417 @@
+/*
+* Parser and validator of the text key-map format, see lib.rs
+*/
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::key_map::{Entry, KeyMap, Mapping, N_LAYERS};
+use crate::keys::{key_code, modifier_code};
+
+// Codes of LayerSet0 and LayerHold0, the other layers follow
+const LAYER_SET_0: u16 = 0x0102;
+const LAYER_HOLD_0: u16 = 0x0107;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    // Position in the source, starting at 1
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Error {
+    fn at(source: &str, offset: usize, message: String) -> Self {
+        let before = &source[..offset.min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        Error { line, column, message }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourceFile {
+    rows: Spanned<usize>,
+    columns: Spanned<usize>,
+    #[serde(default)]
+    macros: BTreeMap<String, Spanned<String>>,
+    layers: Spanned<Vec<SourceLayer>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourceLayer {
+    name: Spanned<String>,
+    keys: Spanned<Vec<Spanned<String>>>,
+}
+
+// A compiled key-map file
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledKeyMap {
+    // Matrix size of each half
+    pub rows: usize,
+    pub columns: usize,
+    // Names of the layers defined, the remaining layers are left unmapped
+    pub layer_names: Vec<String>,
+    pub key_map: KeyMap,
+}
+
+pub fn compile(source: &str) -> Result<CompiledKeyMap, Error> {
+    let file: SourceFile = toml::from_str(source).map_err(|e| {
+        let offset = e.span().map_or(0, |span| span.start);
+        Error::at(source, offset, e.message().to_string())
+    })?;
+    Compiler { source, file: &file }.compile()
+}
+
+struct Compiler<'a> {
+    source: &'a str,
+    file: &'a SourceFile,
+}
+
+impl<'a> Compiler<'a> {
+    fn error(&self, offset: usize, message: String) -> Error {
+        Error::at(self.source, offset, message)
+    }
+
+    fn compile(&self) -> Result<CompiledKeyMap, Error> {
+        let rows = *self.file.rows.get_ref();
+        let columns = *self.file.columns.get_ref();
+        if rows == 0 {
+            return Err(self.error(self.file.rows.span().start, "there must be at least one row".to_string()));
+        }
+        if columns == 0 {
+            return Err(self.error(self.file.columns.span().start, "there must be at least one column".to_string()));
+        }
+        for (name, body) in &self.file.macros {
+            if key_code(name).is_some() {
+                return Err(self.error(body.span().start, format!("macro `{}` has the name of an action", name)));
+            }
+        }
+
+        let layers = self.file.layers.get_ref();
+        if layers.is_empty() || layers.len() > N_LAYERS {
+            let message = format!("expected 1 to {} layers, found {}", N_LAYERS, layers.len());
+            return Err(self.error(self.file.layers.span().start, message));
+        }
+        let mut layer_names: Vec<String> = Vec::new();
+        for layer in layers {
+            let name = layer.name.get_ref();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(self.error(layer.name.span().start, "layer names can't be empty or contain spaces".to_string()));
+            }
+            if layer_names.contains(name) {
+                return Err(self.error(layer.name.span().start, format!("layer `{}` is defined twice", name)));
+            }
+            layer_names.push(name.clone());
+        }
+
+        let mut key_map = KeyMap::new(2 * rows * columns);
+        for (layer, entries) in layers.iter().zip(key_map.layers.iter_mut()) {
+            let keys = layer.keys.get_ref();
+            if keys.len() != rows {
+                let message = format!("expected {} rows, found {}", rows, keys.len());
+                return Err(self.error(layer.keys.span().start, message));
+            }
+            for (row, line) in keys.iter().enumerate() {
+                let tokens = self.tokens(line);
+                if tokens.len() != 2 * columns {
+                    let offset = tokens.get(2 * columns).map_or(line.span().start, |(offset, _)| *offset);
+                    let message = format!("expected {} keys in the row, {} per half, found {}", 2 * columns, columns, tokens.len());
+                    return Err(self.error(offset, message));
+                }
+                for (col, (offset, token)) in tokens.into_iter().enumerate() {
+                    // Left half first, each half row-major
+                    let index = if col < columns {
+                        row * columns + col
+                    } else {
+                        (rows + row) * columns + col - columns
+                    };
+                    entries[index] = self.entry(token, offset, &layer_names)?;
+                }
+            }
+        }
+        Ok(CompiledKeyMap {
+            rows,
+            columns,
+            layer_names,
+            key_map,
+        })
+    }
+
+    // Whitespace separated tokens of a row with their offset in the source,
+    // leaving out the `|` separating the halves
+    fn tokens<'s>(&self, line: &'s Spanned<String>) -> Vec<(usize, &'s str)> {
+        let start = string_start(self.source, line.span(), line.get_ref());
+        let value = line.get_ref().as_str();
+        let mut tokens = Vec::new();
+        let mut token_start = None;
+        for (i, c) in value.char_indices().chain([(value.len(), ' ')]) {
+            match (c.is_whitespace(), token_start) {
+                (true, Some(s)) => {
+                    tokens.push((start.map_or(line.span().start, |start| start + s), &value[s..i]));
+                    token_start = None;
+                }
+                (false, None) => token_start = Some(i),
+                _ => {}
+            }
+        }
+        tokens.retain(|(_, token)| *token != "|");
+        tokens
+    }
+
+    // A key, either `_` for an unmapped key, or `PRESSED` or `PRESSED/HELD`
+    fn entry(&self, token: &str, offset: usize, layer_names: &[String]) -> Result<Option<Entry>, Error> {
+        if token == "_" {
+            return Ok(None);
+        }
+        let mut parts = token.split('/');
+        let pressed = parts.next().unwrap_or("");
+        let held = parts.next();
+        if parts.next().is_some() {
+            return Err(self.error(offset, format!("`{}` has more than one `/`", token)));
+        }
+        let entry = Entry {
+            pressed: self.mapping(pressed, offset, layer_names)?,
+            held_press: match held {
+                Some(held) => self.mapping(held, offset + pressed.len() + 1, layer_names)?,
+                None => None,
+            },
+        };
+        if entry.pressed.is_none() && entry.held_press.is_none() {
+            return Err(self.error(offset, format!("`{}` maps nothing, use `_` for an unmapped key", token)));
+        }
+        Ok(Some(entry))
+    }
+
+    // One side of a key: `_`, an action, `MODIFIER+ACTION`, a layer
+    // change `hold:LAYER` or `set:LAYER`, or the name of a macro
+    fn mapping(&self, text: &str, offset: usize, layer_names: &[String]) -> Result<Option<Mapping>, Error> {
+        if text == "_" {
+            return Ok(None);
+        }
+        if let Some(body) = self.file.macros.get(text) {
+            return self.macro_mapping(text, body).map(Some);
+        }
+        if let Some((kind, layer)) = text.split_once(':') {
+            let base = match kind {
+                "hold" => LAYER_HOLD_0,
+                "set" => LAYER_SET_0,
+                _ => return Err(self.error(offset, format!("unknown layer change `{}`, expected `hold` or `set`", kind))),
+            };
+            let index = layer_names
+                .iter()
+                .position(|name| name == layer)
+                .or_else(|| layer.parse().ok().filter(|i| *i < N_LAYERS));
+            return match index {
+                Some(index) => Ok(Some(Mapping {
+                    key: base + index as u16,
+                    modifier: 0,
+                })),
+                None => Err(self.error(offset + kind.len() + 1, format!("unknown layer `{}`", layer))),
+            };
+        }
+        let (modifier, key, key_offset) = match text.split_once('+') {
+            Some((modifier, key)) => match modifier_code(modifier) {
+                Some(code) => (code, key, offset + modifier.len() + 1),
+                None => return Err(self.error(offset, format!("`{}` is not a modifier", modifier))),
+            },
+            None => (0, text, offset),
+        };
+        match key_code(key) {
+            Some(key) => Ok(Some(Mapping { key, modifier })),
+            None if key.is_empty() => Err(self.error(key_offset, "missing action".to_string())),
+            None => Err(self.error(key_offset, format!("unknown action `{}`", key))),
+        }
+    }
+
+    // Macros name a single action, optionally with a modifier,
+    // since the firmware sends one key per press
+    fn macro_mapping(&self, name: &str, body: &Spanned<String>) -> Result<Mapping, Error> {
+        let offset = string_start(self.source, body.span(), body.get_ref()).unwrap_or(body.span().start);
+        let text = body.get_ref().trim();
+        let offset = offset + body.get_ref().find(text).unwrap_or(0);
+        if text.contains(char::is_whitespace) {
+            let message = format!("macro `{}` sends more than one key, which the firmware does not support", name);
+            return Err(self.error(offset, message));
+        }
+        if text.contains('/') || text.contains(':') || self.file.macros.contains_key(text) {
+            let message = format!("macro `{}` must be an action, optionally with a modifier", name);
+            return Err(self.error(offset, message));
+        }
+        match self.mapping(text, offset, &[])? {
+            Some(mapping) => Ok(mapping),
+            None => Err(self.error(offset, format!("macro `{}` is empty", name))),
+        }
+    }
+}
+
+// Offset of the value of a string in the source, None if the value
+// differs from the source text, i.e. the string has escapes
+fn string_start(source: &str, span: Range<usize>, value: &str) -> Option<usize> {
+    let raw = source.get(span.clone())?;
+    let quote = ["\"\"\"", "'''", "\"", "'"]
+        .into_iter()
+        .find(|quote| raw.len() >= 2 * quote.len() && raw.starts_with(quote) && raw.ends_with(quote))?;
+    let mut start = span.start + quote.len();
+    let mut inner = &raw[quote.len()..raw.len() - quote.len()];
+    // A newline right after the opening quotes of a multi-line string is not part of it
+    if quote.len() == 3 {
+        for newline in ["\r\n", "\n"] {
+            if let Some(rest) = inner.strip_prefix(newline) {
+                inner = rest;
+                start += newline.len();
+                break;
+            }
+        }
+    }
+    (inner == value).then_some(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A keyboard of 2 by 2 keys per half, with the keys of a layer given
+    // on lines 7 and 8, at column 6
+    fn source(rows: [&str; 2]) -> String {
+        format!(
+            "rows = 2\ncolumns = 2\n\n[[layers]]\nname = \"base\"\nkeys = [\n    \"{}\",\n    \"{}\",\n]\n\
+             \n[[layers]]\nname = \"nav\"\nkeys = [\"_ _ | _ _\", \"_ _ | _ _\"]\n\
+             \n[macros]\nbang = \"LeftShift+Keyboard1\"\n",
+            rows[0], rows[1]
+        )
+    }
+
+    fn mapping(key: &str, modifier: &str) -> Option<Mapping> {
+        let modifier = if modifier.is_empty() { 0 } else { modifier_code(modifier).unwrap() };
+        Some(Mapping { key: key_code(key).unwrap(), modifier })
+    }
+
+    fn key(key: &str) -> Option<Entry> {
+        Some(Entry { pressed: mapping(key, ""), held_press: None })
+    }
+
+    fn error(rows: [&str; 2]) -> (usize, usize, String) {
+        let error = compile(&source(rows)).unwrap_err();
+        (error.line, error.column, error.message)
+    }
+
+    #[test]
+    fn keys_are_numbered_left_half_first() {
+        let compiled = compile(&source(["A B | C D", "E F | G H"])).unwrap();
+        assert_eq!((compiled.rows, compiled.columns), (2, 2));
+        assert_eq!(compiled.layer_names, ["base", "nav"]);
+        let keys: Vec<Option<Entry>> = ["A", "B", "E", "F", "C", "D", "G", "H"].iter().map(|k| key(k)).collect();
+        assert_eq!(compiled.key_map.layers[0], keys);
+        assert_eq!(compiled.key_map.layers[1], vec![None; 8]);
+        // Layers that are not defined are left unmapped
+        assert!(compiled.key_map.layers[2..].iter().flatten().all(Option::is_none));
+
+        // The separator is optional
+        let compiled = compile(&source(["A B C D", "E F G H"])).unwrap();
+        assert_eq!(compiled.key_map.layers[0], keys);
+    }
+
+    #[test]
+    fn actions_are_compiled() {
+        let compiled = compile(&source(["Q/LeftShift _ | hold:nav set:1", "LeftControl+C bang | A/_ _/hold:0"])).unwrap();
+        let layer = &compiled.key_map.layers[0];
+        // A hold-tap key
+        assert_eq!(layer[0], Some(Entry { pressed: mapping("Q", ""), held_press: mapping("LeftShift", "") }));
+        assert_eq!(layer[1], None);
+        // Layer changes, by name and by number
+        assert_eq!(layer[4], key("LayerHold1"));
+        assert_eq!(layer[5], key("LayerSet1"));
+        // An action with a modifier, and a macro
+        assert_eq!(layer[2], Some(Entry { pressed: mapping("C", "LeftControl"), held_press: None }));
+        assert_eq!(layer[3], Some(Entry { pressed: mapping("Keyboard1", "LeftShift"), held_press: None }));
+        // Hold-tap keys with only one side
+        assert_eq!(layer[6], key("A"));
+        assert_eq!(layer[7], Some(Entry { pressed: None, held_press: mapping("LayerHold0", "") }));
+    }
+
+    #[test]
+    fn unknown_actions_are_reported_where_they_are() {
+        assert_eq!(error(["A B | C D", "E Bogus | G H"]), (8, 8, "unknown action `Bogus`".to_string()));
+        assert_eq!(error(["A B | C D", "E Foo+A | G H"]), (8, 8, "`Foo` is not a modifier".to_string()));
+        assert_eq!(error(["A B | C D", "E LeftShift+Bogus | G H"]), (8, 18, "unknown action `Bogus`".to_string()));
+        assert_eq!(error(["A B | C D", "E F | G H/Bogus"]), (8, 16, "unknown action `Bogus`".to_string()));
+        assert_eq!(error(["A B | C D", "E F | G A/B/C"]), (8, 14, "`A/B/C` has more than one `/`".to_string()));
+        assert_eq!(error(["A _/_ | C D", "E F | G H"]).0, 7);
+        assert_eq!(error(["A B | C D", "E F | G LeftShift+"]), (8, 24, "missing action".to_string()));
+    }
+
+    #[test]
+    fn wrong_row_widths_are_reported() {
+        // At the first key too many
+        let message = "expected 4 keys in the row, 2 per half, found 5".to_string();
+        assert_eq!(error(["A B | C D", "E F G | H I"]), (8, 16, message));
+        // At the start of a row with too few
+        let message = "expected 4 keys in the row, 2 per half, found 3".to_string();
+        assert_eq!(error(["A B | C", "E F | G H"]), (7, 5, message));
+    }
+
+    #[test]
+    fn wrong_row_counts_are_reported() {
+        let source = "rows = 2\ncolumns = 2\n\n[[layers]]\nname = \"base\"\nkeys = [\"A B | C D\"]\n";
+        let error = compile(source).unwrap_err();
+        assert_eq!((error.line, error.column), (6, 8));
+        assert_eq!(error.message, "expected 2 rows, found 1");
+    }
+
+    #[test]
+    fn unknown_layers_are_reported() {
+        assert_eq!(error(["A hold:7 | C D", "E F | G H"]), (7, 13, "unknown layer `7`".to_string()));
+        assert_eq!(error(["A B | C D", "E F | set:5 H"]), (8, 16, "unknown layer `5`".to_string()));
+        assert_eq!(error(["A B | C D", "E F | set:sym H"]), (8, 16, "unknown layer `sym`".to_string()));
+        let expected = (7, 8, "unknown layer change `toggle`, expected `hold` or `set`".to_string());
+        assert_eq!(error(["A toggle:1 | C D", "E F | G H"]), expected);
+    }
+
+    #[test]
+    fn layer_lists_are_checked() {
+        let layer = |name: &str| format!("\n[[layers]]\nname = \"{}\"\nkeys = [\"A | B\"]\n", name);
+        let header = "rows = 1\ncolumns = 1\n";
+        let six: String = (0..6).map(|i| layer(&format!("l{}", i))).collect();
+        let error = compile(&format!("{}{}", header, six)).unwrap_err();
+        assert_eq!(error.message, "expected 1 to 5 layers, found 6");
+
+        let error = compile(&format!("{}{}{}", header, layer("base"), layer("base"))).unwrap_err();
+        assert_eq!((error.line, error.column), (9, 8));
+        assert_eq!(error.message, "layer `base` is defined twice");
+
+        let error = compile(&format!("{}{}", header, layer("my layer"))).unwrap_err();
+        assert_eq!((error.line, error.column), (5, 8));
+    }
+
+    #[test]
+    fn macros_are_checked() {
+        let source = |body: &str| format!("rows = 1\ncolumns = 1\n[macros]\nm = \"{}\"\n[[layers]]\nname = \"base\"\nkeys = [\"m | _\"]\n", body);
+        assert_eq!(compile(&source("A")).unwrap().key_map.layers[0][0], key("A"));
+        let error = compile(&source("A B")).unwrap_err();
+        assert_eq!((error.line, error.column), (4, 6));
+        assert!(compile(&source("A/B")).is_err());
+        assert!(compile(&source("hold:0")).is_err());
+        let error = compile(&source("Bogus")).unwrap_err();
+        assert_eq!((error.line, error.column, error.message.as_str()), (4, 6, "unknown action `Bogus`"));
+    }
+
+    #[test]
+    fn toml_errors_have_a_position() {
+        let error = compile("rows = 2\ncolumns = \n").unwrap_err();
+        assert_eq!(error.line, 2);
+        let error = compile("rows = 2\ncolumns = 2\nlayers = []\nextra = 1\n").unwrap_err();
+        assert_eq!(error.line, 4);
+    }
+}